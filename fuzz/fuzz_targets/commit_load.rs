@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use git_rs::objects::commit::Commit;
+
+// Author and committer lines are parsed lazily, so ask for every part of them.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(commit) = Commit::load(&mut &data[..]) {
+        for person in commit.author().into_iter().chain(commit.committer().as_ref()) {
+            let _ = (person.name(), person.email(), person.timestamp(), person.timezone_offset());
+            let _ = (person.raw_name(), person.raw_email(), person.raw_at(), person.raw_offset());
+        }
+    }
+});
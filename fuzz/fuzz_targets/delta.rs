@@ -0,0 +1,34 @@
+#![no_main]
+
+use std::io::Read;
+use libfuzzer_sys::fuzz_target;
+
+use git_rs::delta::{ apply, DeltaDecoder, DeltaDecoderStream };
+
+// The first byte picks how much of the rest is the base; the remainder is the
+// delta. Applying it directly and through the stream must agree.
+fuzz_target!(|data: &[u8]| {
+    let (base_len, rest) = match data.split_first() {
+        Some((len, rest)) => ((*len as usize).min(rest.len()), rest),
+        None => return
+    };
+    let (base, instructions) = rest.split_at(base_len);
+
+    let mut applied = Vec::new();
+    let result = apply(instructions, base, &mut applied);
+
+    let decoder = match DeltaDecoder::new(instructions, base.to_vec()) {
+        Ok(xs) => xs,
+        Err(_) => {
+            assert!(result.is_err());
+            return
+        }
+    };
+
+    let mut stream: DeltaDecoderStream = decoder.into();
+    let mut streamed = Vec::new();
+    match stream.read_to_end(&mut streamed) {
+        Ok(_) => assert_eq!(result.ok(), Some(streamed.len())),
+        Err(_) => assert!(result.is_err())
+    }
+});
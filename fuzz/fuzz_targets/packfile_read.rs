@@ -0,0 +1,24 @@
+#![no_main]
+
+use std::io::Cursor;
+use libfuzzer_sys::fuzz_target;
+
+use git_rs::pack::read::packfile_read;
+use git_rs::stores::StorageSet;
+
+// Read an entry from the end of the input, so that offset deltas have
+// somewhere to look for their bases.
+fuzz_target!(|data: &[u8]| {
+    let (start, rest) = match data.split_first() {
+        Some((start, rest)) => ((*start as usize).min(rest.len()), rest),
+        None => return
+    };
+
+    let mut input = Cursor::new(rest);
+    input.set_position(start as u64);
+
+    let mut output = Vec::new();
+    if let Ok(meta) = packfile_read(&mut input, &mut output, &mut 0) {
+        let _ = meta.decompress(start as u64, &mut input, &mut output, None::<&StorageSet<()>>);
+    }
+});
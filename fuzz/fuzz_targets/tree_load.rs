@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use git_rs::objects::tree::Tree;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(tree) = Tree::load(&mut &data[..]) {
+        for entry in tree.entries().values() {
+            let _ = (entry.mode.is_tree(), entry.mode.is_submodule());
+        }
+    }
+});
@@ -0,0 +1,62 @@
+extern crate git_rs;
+
+use std::io::{ self, BufRead, BufWriter, Write };
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use clap::Parser;
+
+use git_rs::stores::fs as gitfs;
+use git_rs::id::Id;
+
+/// Print the objects named on stdin (one id per line) in `git cat-file --batch`
+/// format. Objects are printed in the order they're stored rather than the
+/// order given, as with `--unordered`; missing objects are listed last.
+#[derive(Parser)]
+struct Args {
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+
+    /// Only print each object's id, type and size.
+    #[clap(long)]
+    batch_check: bool,
+
+    /// Read packs in parallel.
+    #[clap(short, long)]
+    parallel: bool,
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let current_dir = args.cwd.clone().or_else(|| std::env::current_dir().ok()).unwrap();
+    let storage_set = gitfs::from(current_dir.as_path())?;
+
+    let mut ids = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() {
+            ids.push(Id::from_str(line)?);
+        }
+    }
+
+    let output = Mutex::new(BufWriter::new(io::stdout()));
+    let missing = storage_set.get_batch(&ids, args.parallel, |id, kind, data| {
+        let mut output = output.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(output, "{} {} {}", id, kind.as_str(), data.len())?;
+        if !args.batch_check {
+            output.write_all(data)?;
+            writeln!(output)?;
+        }
+        Ok(())
+    })?;
+
+    let mut output = output.into_inner().unwrap_or_else(|e| e.into_inner());
+    for id in missing {
+        writeln!(output, "{} missing", id)?;
+    }
+    output.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+extern crate git_rs;
+
+use git_rs::stores::stats::count_objects;
+use git_rs::objects::Type;
+use std::path::PathBuf;
+use clap::Parser;
+
+#[derive(Parser)]
+struct Args {
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+
+    /// Report packed objects, garbage, object types and delta chain lengths too.
+    #[clap(short, long)]
+    verbose: bool,
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let current_dir = args.cwd.or_else(|| std::env::current_dir().ok()).unwrap();
+    let stats = count_objects(current_dir.as_path())?;
+
+    if !args.verbose {
+        println!("{} objects, {} kilobytes", stats.loose_count(), stats.loose_size() / 1024);
+        return Ok(())
+    }
+
+    println!("count: {}", stats.loose_count());
+    println!("size: {}", stats.loose_size() / 1024);
+    println!("in-pack: {}", stats.packed_count());
+    println!("packs: {}", stats.pack_count());
+    println!("size-pack: {}", stats.pack_size() / 1024);
+    println!("garbage: {}", stats.garbage().len());
+    println!("size-garbage: {}", stats.garbage_size() / 1024);
+
+    for path in stats.garbage() {
+        println!("garbage found: {}", path.display());
+    }
+
+    for kind in [Type::Commit, Type::Tree, Type::Blob, Type::Tag].iter() {
+        println!(
+            "{}: {} loose, {} packed",
+            kind.as_str(),
+            stats.loose_types().get(*kind),
+            stats.packed_types().get(*kind)
+        );
+    }
+
+    for (length, count) in stats.chain_lengths() {
+        match length {
+            0 => println!("non delta: {} objects", count),
+            _ => println!("chain length = {}: {} objects", length, count)
+        }
+    }
+
+    Ok(())
+}
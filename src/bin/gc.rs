@@ -0,0 +1,101 @@
+extern crate git_rs;
+
+use std::path::PathBuf;
+use clap::Parser;
+
+use git_rs::stores::gc::{ gc, AutoLimits, Expire, GcOptions, RepackOptions };
+use git_rs::pack::window;
+
+/// Repack everything reachable from the repository's refs into a single pack,
+/// removing the packs and loose objects that makes redundant.
+#[derive(Parser)]
+struct Args {
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+
+    /// Only collect garbage if there are too many loose objects or packs.
+    #[clap(long)]
+    auto: bool,
+
+    /// With --auto, the number of loose objects to allow (0 to never repack for them.)
+    #[clap(long, default_value_t = 6700)]
+    auto_limit: usize,
+
+    /// With --auto, the number of packs to allow (0 to never repack for them.)
+    #[clap(long, default_value_t = 50)]
+    auto_pack_limit: usize,
+
+    /// How many objects to try as delta bases for each object; 0 disables deltas.
+    #[clap(long, default_value_t = 10)]
+    window: usize,
+
+    /// The longest delta chain to write.
+    #[clap(long, default_value_t = 50)]
+    depth: usize,
+
+    /// Drop unreachable objects from removed packs instead of writing them out loose.
+    #[clap(long)]
+    drop_unreachable: bool,
+
+    /// Prune unreachable loose objects older than this ("now", "never", or a
+    /// relative date like "2.weeks.ago".)
+    #[clap(long, default_value = "2.weeks.ago")]
+    prune: Expire,
+
+    /// Don't prune any loose objects.
+    #[clap(long)]
+    no_prune: bool,
+
+    /// Keep unreachable objects in a cruft pack instead of loose, dropping them
+    /// once they're older than --prune.
+    #[clap(long)]
+    cruft: bool,
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let current_dir = args.cwd.clone().or_else(|| std::env::current_dir().ok()).unwrap();
+    let options = GcOptions {
+        repack: RepackOptions {
+            deltas: window::Options { window: args.window, depth: args.depth, ..Default::default() },
+            keep_unreachable: !args.drop_unreachable,
+            cruft: None
+        },
+        auto: if args.auto {
+            Some(AutoLimits { loose_objects: args.auto_limit, packs: args.auto_pack_limit })
+        } else {
+            None
+        },
+        prune: if args.no_prune { Expire::Never } else { args.prune },
+        cruft: args.cruft
+    };
+
+    let collected = match gc(current_dir.as_path(), &options)? {
+        Some(xs) => xs,
+        None => return Ok(())
+    };
+    let repacked = collected.repacked();
+
+    if let Some(pack) = repacked.pack() {
+        println!("wrote {} ({} objects)", pack.display(), repacked.packed());
+    }
+
+    for pack in repacked.removed_packs() {
+        println!("removed {}", pack.display());
+    }
+
+    println!("removed {} loose objects", repacked.removed_loose());
+    if repacked.loosened() > 0 {
+        println!("kept {} unreachable objects as loose objects", repacked.loosened());
+    }
+    if let Some(pack) = repacked.cruft_pack() {
+        println!("wrote cruft pack {} ({} objects)", pack.display(), repacked.crufted());
+    }
+    if repacked.expired() > 0 {
+        println!("expired {} unreachable objects", repacked.expired());
+    }
+    println!("pruned {} unreachable objects", collected.pruned().objects().len());
+
+    Ok(())
+}
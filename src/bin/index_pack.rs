@@ -1,27 +1,119 @@
 extern crate git_rs;
 
-use std::io::{ self, Cursor };
+use std::io::{ self, BufWriter, Cursor, Write };
 use memmap::MmapOptions;
+use std::path::PathBuf;
 use std::fs::File;
+use clap::Parser;
 
-use git_rs::stores::fs as gitfs;
-use git_rs::pack::index::write;
+use git_rs::stores::{ fs as gitfs, Limits };
+use git_rs::pack::index::{ read, write_version, Version };
+use git_rs::pack::thin::fix_thin;
+use git_rs::pack::stream::index_stream;
+use git_rs::pack::rev;
+
+#[derive(Parser)]
+struct Args {
+    /// Write the index to this file instead of stdout.
+    #[clap(short)]
+    output: Option<PathBuf>,
+
+    /// Also write a reverse index (`.rev`) next to the index, or next to the
+    /// packfile if the index is written to stdout.
+    #[clap(long)]
+    rev_index: bool,
+
+    /// The index format to write (1 or 2.)
+    #[clap(long, default_value_t = 2)]
+    index_version: u32,
+
+    /// Complete a thin pack by appending the delta bases it refers to but doesn't
+    /// contain (read from the repository.) The packfile is rewritten in place.
+    #[clap(long)]
+    fix_thin: bool,
+
+    /// Read the packfile from stdin, saving it as `packfile` once it's indexed.
+    #[clap(long)]
+    stdin: bool,
+
+    /// Refuse objects (or delta instructions) that inflate to more than this
+    /// many bytes.
+    #[clap(long)]
+    max_object_size: Option<u64>,
+
+    /// Refuse deltas that produce objects of more than this many bytes.
+    #[clap(long)]
+    max_delta_size: Option<u64>,
+
+    packfile: PathBuf,
+}
 
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
     let current_dir = std::env::current_dir()?;
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        eprintln!("must provide a filename");
-        return Ok(())
+
+    let version = match args.index_version {
+        1 => Version::V1,
+        2 => Version::V2,
+        version => return Err(format!("unsupported index version {}", version).into())
+    };
+
+    let storage_set = gitfs::from(current_dir.as_path()).expect("failed to open storage")
+        .with_limits(Limits {
+            object_size: args.max_object_size,
+            delta_output_size: args.max_delta_size
+        });
+    let mut index_bytes = Vec::new();
+    if args.stdin {
+        let stdin = io::stdin();
+        index_stream(stdin.lock(), &args.packfile, &mut index_bytes, Some(&storage_set), version)?;
     }
 
-    let f = File::open(&args[1])?;
+    let f = File::open(&args.packfile)?;
     let mmap = unsafe { MmapOptions::new().map(&f)? };
 
     let cursor = Cursor::new(&mmap[..]);
 
-    let storage_set = gitfs::from(current_dir.as_path()).expect("failed to open storage");
-    write(cursor, &mut io::stdout(), Some(&storage_set))?;
+    if args.fix_thin {
+        index_bytes.clear();
+        let mut tmp = args.packfile.clone();
+        tmp.set_extension(format!("pack-tmp-{}", std::process::id()));
+
+        let mut output = BufWriter::new(File::create(&tmp)?);
+        let result = fix_thin(cursor, &mut output, &mut index_bytes, &storage_set, version)
+            .map_err(Box::<dyn std::error::Error>::from)
+            .and_then(|xs| {
+                output.flush()?;
+                Ok(xs)
+            });
+
+        match result {
+            Ok((_, appended)) => {
+                drop(mmap);
+                std::fs::rename(&tmp, &args.packfile)?;
+                eprintln!("completed with {} local objects", appended.len());
+            },
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e)
+            }
+        }
+    } else if !args.stdin {
+        write_version(cursor, &mut index_bytes, Some(&storage_set), version)?;
+    }
+
+    match &args.output {
+        Some(path) => File::create(path)?.write_all(&index_bytes)?,
+        None => io::stdout().write_all(&index_bytes)?
+    };
+
+    if args.rev_index {
+        let mut rev_path = args.output.unwrap_or(args.packfile);
+        rev_path.set_extension("rev");
+
+        let index = read(Cursor::new(&index_bytes))?;
+        rev::write(&index, &mut File::create(rev_path)?)?;
+    }
 
     Ok(())
 }
@@ -12,7 +12,7 @@ struct Args {
     branch: Option<String>,
 }
 
-pub fn main() -> std::io::Result<()> {
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     let current_dir = args.cwd.or_else(|| std::env::current_dir().ok()).unwrap();
@@ -32,6 +32,7 @@ pub fn main() -> std::io::Result<()> {
     };
 
     for (id, commit) in storage_set.commits(&id, None) {
+        let commit = commit.load().expect("failed to load commit");
         let message = std::str::from_utf8(commit.message()).expect("not utf8");
 
         let idx = message.find('\n').unwrap_or(message.len());
@@ -0,0 +1,93 @@
+extern crate git_rs;
+
+use std::io::{ self, BufRead, BufWriter, Write };
+use std::str::FromStr;
+use std::path::PathBuf;
+use std::fs::File;
+use clap::Parser;
+
+use git_rs::stores::fs as gitfs;
+use git_rs::pack::window::{ name_hash, write_with_deltas, Options };
+use git_rs::id::Id;
+
+/// Read object ids from stdin, one per line, and write them to a packfile named
+/// `<base-name>-<checksum>.pack` (and its index) like `git pack-objects`.
+#[derive(Parser)]
+struct Args {
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+
+    /// How many objects to try as delta bases for each object; 0 disables deltas.
+    #[clap(long, default_value_t = 10)]
+    window: usize,
+
+    /// The longest delta chain to write.
+    #[clap(long, default_value_t = 50)]
+    depth: usize,
+
+    /// Recompress every object instead of copying already-packed objects as-is.
+    #[clap(long)]
+    no_reuse_delta: bool,
+
+    /// Write a thin pack: keep deltas against objects that aren't in the pack.
+    #[clap(long)]
+    thin: bool,
+
+    base_name: PathBuf,
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let current_dir = args.cwd.clone().or_else(|| std::env::current_dir().ok()).unwrap();
+    let storage_set = gitfs::from(current_dir.as_path())?;
+
+    // accept `rev-list --objects` output, which may have a path after the id
+    let mut objects = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if let Some((id, path)) = line.split_once(' ').or_else(|| Some((line.trim(), ""))) {
+            if !id.is_empty() {
+                objects.push((Id::from_str(id)?, name_hash(path.as_bytes())));
+            }
+        }
+    }
+
+    let options = Options {
+        window: args.window,
+        depth: args.depth,
+        reuse: !args.no_reuse_delta,
+        thin: args.thin
+    };
+
+    let tmp = |extension: &str| {
+        let mut path = args.base_name.clone();
+        path.set_extension(format!("{}-tmp-{}", extension, std::process::id()));
+        path
+    };
+    let (pack_tmp, index_tmp) = (tmp("pack"), tmp("idx"));
+
+    let mut index = BufWriter::new(File::create(&index_tmp)?);
+    let result = write_with_deltas(&storage_set, &objects, options, BufWriter::new(File::create(&pack_tmp)?), &mut index)
+        .map_err(Box::<dyn std::error::Error>::from)
+        .and_then(|checksum| {
+            index.flush()?;
+            Ok(checksum)
+        });
+
+    let checksum = match result {
+        Ok(xs) => xs,
+        Err(e) => {
+            let _ = std::fs::remove_file(&pack_tmp);
+            let _ = std::fs::remove_file(&index_tmp);
+            return Err(e)
+        }
+    };
+
+    let name = format!("{}-{}", args.base_name.display(), checksum);
+    std::fs::rename(&pack_tmp, format!("{}.pack", name))?;
+    std::fs::rename(&index_tmp, format!("{}.idx", name))?;
+    println!("{}", checksum);
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+extern crate git_rs;
+
+use std::path::PathBuf;
+use clap::Parser;
+
+use git_rs::stores::gc::{ prune, Expire, PruneOptions };
+
+/// Remove loose objects that can't be reached from any ref, reflog or the index.
+#[derive(Parser)]
+struct Args {
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+
+    /// List the objects that would be removed (with their types) without removing them.
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// Also list the objects that are removed.
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Only remove objects older than this ("now", "never", or a relative date
+    /// like "2.weeks.ago".)
+    #[clap(long, default_value = "2.weeks.ago")]
+    expire: Expire,
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let current_dir = args.cwd.clone().or_else(|| std::env::current_dir().ok()).unwrap();
+    let options = PruneOptions { expire: args.expire, dry_run: args.dry_run };
+    let pruned = prune(current_dir.as_path(), &options)?;
+
+    if args.dry_run || args.verbose {
+        for (id, kind, _) in pruned.objects() {
+            println!("{} {}", id, kind.map(|kind| kind.as_str()).unwrap_or("unknown"));
+        }
+    }
+
+    Ok(())
+}
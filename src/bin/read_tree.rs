@@ -3,7 +3,7 @@ extern crate git_rs;
 use git_rs::stores::{fs as gitfs};
 use git_rs::refs::RefSet;
 
-pub fn main() -> std::io::Result<()> {
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let current_dir = std::env::current_dir()?;
     let storage_set = gitfs::from(current_dir.as_path())?;
     let ref_set = RefSet::from_path(current_dir.as_path())?;
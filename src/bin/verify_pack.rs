@@ -0,0 +1,85 @@
+extern crate git_rs;
+
+use memmap::MmapOptions;
+use std::path::{ Path, PathBuf };
+use std::fs::File;
+use clap::Parser;
+
+use git_rs::pack::verify::{ verify, Verification };
+
+#[derive(Parser)]
+struct Args {
+    /// List every object (id, type, size, size in packfile, offset and, for
+    /// deltas, depth and base), followed by a histogram of delta chain lengths.
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Packfiles (or their indexes) to verify.
+    #[clap(required = true)]
+    packs: Vec<PathBuf>,
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 { "object" } else { "objects" }
+}
+
+fn verify_path(path: &Path) -> Result<Verification, Box<dyn std::error::Error>> {
+    let pack = File::open(path.with_extension("pack"))?;
+    let index = File::open(path.with_extension("idx"))?;
+    let pack = unsafe { MmapOptions::new().map(&pack)? };
+    let index = unsafe { MmapOptions::new().map(&index)? };
+
+    Ok(verify(&pack[..], &index[..])?)
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let mut failed = false;
+    for path in &args.packs {
+        let pack_path = path.with_extension("pack");
+        let verification = match verify_path(path) {
+            Ok(xs) => xs,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                eprintln!("{}: bad", pack_path.display());
+                failed = true;
+                continue
+            }
+        };
+
+        if !args.verbose {
+            continue
+        }
+
+        for object in verification.objects() {
+            print!(
+                "{} {:<6} {} {} {}",
+                object.id(),
+                object.kind().as_str(),
+                object.size(),
+                object.stored_size(),
+                object.offset()
+            );
+
+            if let Some(base) = object.base() {
+                print!(" {} {}", object.depth(), base);
+            }
+            println!();
+        }
+
+        for (length, count) in verification.chain_lengths() {
+            match length {
+                0 => println!("non delta: {} {}", count, plural(count)),
+                _ => println!("chain length = {}: {} {}", length, count, plural(count))
+            }
+        }
+        println!("{}: ok", pack_path.display());
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
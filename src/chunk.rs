@@ -0,0 +1,126 @@
+use crypto::{ sha1::Sha1, digest::Digest };
+use std::convert::TryInto;
+use std::io::Write;
+
+use crate::errors::{ ErrorKind, Result };
+use crate::id::Id;
+
+/// A table of contents for git's "chunk format" files (multi-pack-indexes and
+/// commit-graphs.)
+///
+/// The table is a list of `(4 byte id, 8 byte offset)` entries, terminated by an
+/// entry with an id of zero whose offset marks the end of the final chunk.
+#[derive(Debug)]
+pub struct ChunkTable {
+    entries: Vec<([u8; 4], u64)>
+}
+
+impl ChunkTable {
+    /// Read a table of `count` chunks starting at `start` in `bytes`.
+    pub fn read(bytes: &[u8], start: usize, count: usize) -> Result<ChunkTable> {
+        let end = start + (count + 1) * 12;
+        if bytes.len() < end {
+            return Err(ErrorKind::CorruptedChunkTable.into())
+        }
+
+        let entries: Vec<_> = bytes[start..end].chunks(12).map(|entry| {
+            let id: [u8; 4] = entry[0..4].try_into().unwrap();
+            let offset = u64::from_be_bytes(entry[4..12].try_into().unwrap());
+            (id, offset)
+        }).collect();
+
+        let mut last = 0;
+        for (_, offset) in entries.iter() {
+            if *offset < last || *offset > bytes.len() as u64 {
+                return Err(ErrorKind::CorruptedChunkTable.into())
+            }
+            last = *offset;
+        }
+
+        Ok(ChunkTable {
+            entries
+        })
+    }
+
+    /// The number of bytes taken up by a table of `count` chunks.
+    pub fn size(count: usize) -> usize {
+        (count + 1) * 12
+    }
+
+    /// Return the bytes of the chunk named `id`, if present.
+    pub fn get<'a>(&self, id: &[u8; 4], bytes: &'a [u8]) -> Option<&'a [u8]> {
+        let idx = self.entries.iter().position(|(chunk_id, _)| chunk_id == id)?;
+        let start = self.entries[idx].1 as usize;
+        let end = self.entries.get(idx + 1)?.1 as usize;
+        bytes.get(start..end)
+    }
+
+    /// Return the bytes of the chunk named `id`, failing if it is missing.
+    pub fn require<'a>(&self, id: &[u8; 4], bytes: &'a [u8]) -> Result<&'a [u8]> {
+        match self.get(id, bytes) {
+            Some(xs) => Ok(xs),
+            None => Err(ErrorKind::MissingChunk(String::from_utf8_lossy(id).into_owned()).into())
+        }
+    }
+}
+
+/// Write a chunk format file: `header`, followed by a table of contents for
+/// `chunks`, the chunks themselves, and a trailing SHA-1 of everything before it.
+/// Returns the trailing checksum.
+pub fn write_chunks<W: Write>(header: &[u8], chunks: &[([u8; 4], Vec<u8>)], output: &mut W) -> Result<Id> {
+    let mut shasum = Sha1::new();
+    let mut emit = |bytes: &[u8]| -> Result<()> {
+        shasum.input(bytes);
+        output.write_all(bytes)?;
+        Ok(())
+    };
+
+    emit(header)?;
+
+    let mut offset = (header.len() + ChunkTable::size(chunks.len())) as u64;
+    for (id, chunk) in chunks {
+        emit(id)?;
+        emit(&offset.to_be_bytes())?;
+        offset += chunk.len() as u64;
+    }
+    emit(&[0u8; 4])?;
+    emit(&offset.to_be_bytes())?;
+
+    for (_, chunk) in chunks {
+        emit(chunk)?;
+    }
+
+    let mut checksum = [0u8; 20];
+    shasum.result(&mut checksum);
+    output.write_all(&checksum)?;
+    Ok(checksum.into())
+}
+
+/// Read the big-endian `u32` at `idx` (counted in `u32`s) in `bytes`.
+#[inline]
+pub fn be_u32(bytes: &[u8], idx: usize) -> u32 {
+    u32::from_be_bytes(bytes[idx * 4..idx * 4 + 4].try_into().unwrap())
+}
+
+/// Read the big-endian `u64` at `idx` (counted in `u64`s) in `bytes`.
+#[inline]
+pub fn be_u64(bytes: &[u8], idx: usize) -> u64 {
+    u64::from_be_bytes(bytes[idx * 8..idx * 8 + 8].try_into().unwrap())
+}
+
+/// Read a 256 entry fanout table, checking that it is non-decreasing.
+pub fn read_fanout(bytes: &[u8]) -> Result<[u32; 256]> {
+    if bytes.len() < 1024 {
+        return Err(ErrorKind::CorruptedChunkTable.into())
+    }
+
+    let mut fanout = [0u32; 256];
+    for (idx, slot) in fanout.iter_mut().enumerate() {
+        *slot = be_u32(bytes, idx);
+        if idx > 0 && *slot < be_u32(bytes, idx - 1) {
+            return Err(ErrorKind::CorruptedChunkTable.into())
+        }
+    }
+
+    Ok(fanout)
+}
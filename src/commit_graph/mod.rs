@@ -0,0 +1,455 @@
+use std::convert::TryInto;
+use std::path::Path;
+
+use crate::chunk::{ ChunkTable, be_u32, be_u64, read_fanout };
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::id::Id;
+
+pub mod write;
+
+const GRAPH_HEADER_SIZE: usize = 8;
+const GRAPH_PARENT_NONE: u32 = 0x7000_0000;
+const GRAPH_EXTRA_EDGES_NEEDED: u32 = 0x8000_0000;
+const GRAPH_LAST_EDGE: u32 = 0x8000_0000;
+const CORRECTED_DATE_OVERFLOW: u32 = 0x8000_0000;
+
+/// A commit-graph: a cache of the parents, root tree, commit time and generation
+/// number of commits, so that walking history doesn't require inflating and
+/// parsing every commit object.
+///
+/// A commit-graph is made of one or more layers. A repository either has a single
+/// layer at `objects/info/commit-graph`, or a chain of layers listed (base first)
+/// in `objects/info/commit-graphs/commit-graph-chain`. Commits are addressed by
+/// their position across all layers, base layer first.
+///
+/// See `gitformat-commit-graph(5)` for details on the format.
+#[derive(Debug)]
+pub struct CommitGraph {
+    layers: Vec<Layer>
+}
+
+#[derive(Debug)]
+struct Layer {
+    fanout: [u32; 256],
+    ids: Vec<Id>,
+    commits: Vec<CommitData>,
+    edges: Vec<u32>,
+    corrected_dates: Option<Vec<u64>>,
+    base_count: u32,
+    checksum: Id
+}
+
+#[derive(Debug)]
+struct CommitData {
+    tree: Id,
+    parent1: u32,
+    parent2: u32,
+    topological_level: u32,
+    commit_time: u64
+}
+
+/// The information a commit-graph stores about a single commit.
+#[derive(Debug, Clone)]
+pub struct GraphCommit {
+    position: u32,
+    tree: Id,
+    parents: Vec<Id>,
+    commit_time: u64,
+    topological_level: u32,
+    corrected_date: Option<u64>
+}
+
+impl GraphCommit {
+    /// The position of this commit across all layers of the commit-graph.
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn tree(&self) -> &Id {
+        &self.tree
+    }
+
+    pub fn parents(&self) -> &[Id] {
+        &self.parents
+    }
+
+    /// The committer time, in seconds since the UNIX epoch.
+    pub fn commit_time(&self) -> u64 {
+        self.commit_time
+    }
+
+    /// Generation number v1: one more than the maximum topological level of the
+    /// commit's parents.
+    pub fn topological_level(&self) -> u32 {
+        self.topological_level
+    }
+
+    /// Generation number v2, the "corrected commit date", if every layer of the
+    /// commit-graph records it.
+    pub fn corrected_date(&self) -> Option<u64> {
+        self.corrected_date
+    }
+
+    /// The best available generation number: corrected commit date if present,
+    /// otherwise topological level.
+    pub fn generation(&self) -> u64 {
+        self.corrected_date.unwrap_or(self.topological_level as u64)
+    }
+}
+
+impl CommitGraph {
+    /// Load the commit-graph for the repository at `path`, if it has one.
+    pub fn from_path(path: &Path) -> Result<Option<CommitGraph>> {
+        let mut info = path.to_path_buf();
+        info.push(".git");
+        info.push("objects");
+        info.push("info");
+
+        let single = info.join("commit-graph");
+        match std::fs::read(&single) {
+            Ok(bytes) => return Ok(Some(CommitGraph::read(&bytes).in_file(&single).during("reading commit-graph")?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(Error::from(e).in_file(&single))
+        };
+
+        let graphs = info.join("commit-graphs");
+        let chain_path = graphs.join("commit-graph-chain");
+        let chain = match std::fs::read_to_string(&chain_path) {
+            Ok(xs) => xs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::from(e).in_file(&chain_path))
+        };
+
+        let names: Vec<_> = chain.lines().map(|line| line.trim()).filter(|line| !line.is_empty()).collect();
+        Ok(Some(CommitGraph::read_layer_files(&graphs, &names).in_file(&chain_path).during("reading commit-graph")?))
+    }
+
+    // Read the chain made of the `graph-<name>.graph` files in `graphs`, base first.
+    fn read_layer_files<S: AsRef<str>>(graphs: &Path, names: &[S]) -> Result<CommitGraph> {
+        let layers = names.iter().map(|name| {
+            let layer = graphs.join(format!("graph-{}.graph", name.as_ref()));
+            std::fs::read(&layer).in_file(&layer)
+        }).collect::<Result<Vec<_>>>()?;
+
+        CommitGraph::read_chain(layers.iter().map(|xs| &xs[..]))
+    }
+
+    /// Read a single-layer commit-graph.
+    pub fn read(bytes: &[u8]) -> Result<CommitGraph> {
+        CommitGraph::read_chain(std::iter::once(bytes))
+    }
+
+    /// Read a chain of commit-graph layers, base layer first.
+    pub fn read_chain<'a, I: IntoIterator<Item = &'a [u8]>>(layers: I) -> Result<CommitGraph> {
+        let mut graph = CommitGraph {
+            layers: Vec::new()
+        };
+
+        for bytes in layers {
+            let layer = read_layer(bytes, &graph.layers)?;
+            graph.layers.push(layer);
+        }
+
+        // Generation number v2 is only usable if every layer has it.
+        if graph.layers.iter().any(|layer| layer.corrected_dates.is_none()) {
+            for layer in graph.layers.iter_mut() {
+                layer.corrected_dates = None;
+            }
+        }
+
+        Ok(graph)
+    }
+
+    /// The number of commits in the graph, across all layers.
+    pub fn len(&self) -> usize {
+        self.layers.last().map(|layer| layer.base_count as usize + layer.ids.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of layers in the graph.
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Whether every layer records corrected commit dates (generation number v2).
+    pub fn has_corrected_dates(&self) -> bool {
+        self.layers.iter().all(|layer| layer.corrected_dates.is_some())
+    }
+
+    /// The checksums of each layer, base layer first. For split commit-graphs
+    /// these are the names listed in `commit-graph-chain`.
+    pub fn layer_checksums(&self) -> Vec<&Id> {
+        self.layers.iter().map(|layer| &layer.checksum).collect()
+    }
+
+    /// Return the position of `id` across all layers.
+    pub fn position(&self, id: &Id) -> Option<u32> {
+        self.layers.iter().rev().find_map(|layer| {
+            let first = id.as_ref()[0] as usize;
+            let lo = if first > 0 { layer.fanout[first - 1] as usize } else { 0 };
+            let hi = layer.fanout[first] as usize;
+            let idx = lo + layer.ids.get(lo..hi)?.binary_search(id).ok()?;
+            Some(layer.base_count + idx as u32)
+        })
+    }
+
+    /// Return the id of the commit at `position`.
+    pub fn id_at(&self, position: u32) -> Option<&Id> {
+        let (layer, idx) = self.locate(position)?;
+        layer.ids.get(idx)
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.position(id).is_some()
+    }
+
+    pub fn get(&self, id: &Id) -> Option<GraphCommit> {
+        self.get_at(self.position(id)?)
+    }
+
+    /// Return the commit at `position`.
+    pub fn get_at(&self, position: u32) -> Option<GraphCommit> {
+        let (layer, idx) = self.locate(position)?;
+        let data = layer.commits.get(idx)?;
+
+        let mut parents = Vec::with_capacity(2);
+        if data.parent1 != GRAPH_PARENT_NONE {
+            parents.push(self.id_at(data.parent1)?.clone());
+        }
+
+        if data.parent2 & GRAPH_EXTRA_EDGES_NEEDED != 0 {
+            let mut edge = (data.parent2 & !GRAPH_EXTRA_EDGES_NEEDED) as usize;
+            loop {
+                let parent = *layer.edges.get(edge)?;
+                parents.push(self.id_at(parent & !GRAPH_LAST_EDGE)?.clone());
+                if parent & GRAPH_LAST_EDGE != 0 {
+                    break
+                }
+                edge += 1;
+            }
+        } else if data.parent2 != GRAPH_PARENT_NONE {
+            parents.push(self.id_at(data.parent2)?.clone());
+        }
+
+        Some(GraphCommit {
+            position,
+            tree: data.tree.clone(),
+            parents,
+            commit_time: data.commit_time,
+            topological_level: data.topological_level,
+            corrected_date: layer.corrected_dates.as_ref().map(|dates| dates[idx])
+        })
+    }
+
+    fn locate(&self, position: u32) -> Option<(&Layer, usize)> {
+        let layer = self.layers.iter().rev().find(|layer| position >= layer.base_count)?;
+        let idx = (position - layer.base_count) as usize;
+        if idx >= layer.ids.len() {
+            return None
+        }
+        Some((layer, idx))
+    }
+}
+
+fn read_layer(bytes: &[u8], bases: &[Layer]) -> Result<Layer> {
+    if bytes.len() < GRAPH_HEADER_SIZE + 20 || &bytes[0..4] != b"CGPH" {
+        return Err(ErrorKind::InvalidCommitGraph.into())
+    }
+
+    // version 1, sha1 object ids
+    if bytes[4] != 1 || bytes[5] != 1 {
+        return Err(ErrorKind::UnsupportedCommitGraphVersion.into())
+    }
+
+    let chunk_count = bytes[6] as usize;
+    let base_graph_count = bytes[7] as usize;
+    if base_graph_count != bases.len() {
+        return Err(ErrorKind::InvalidCommitGraph.into())
+    }
+
+    let table = ChunkTable::read(bytes, GRAPH_HEADER_SIZE, chunk_count)?;
+
+    if base_graph_count > 0 {
+        let base = table.require(b"BASE", bytes)?;
+        if base.len() < base_graph_count * 20 {
+            return Err(ErrorKind::InvalidCommitGraph.into())
+        }
+
+        for (expected, layer) in base.chunks(20).zip(bases.iter()) {
+            if expected != layer.checksum.as_ref() {
+                return Err(ErrorKind::InvalidCommitGraph.into())
+            }
+        }
+    }
+
+    let fanout = read_fanout(table.require(b"OIDF", bytes)?)?;
+    let commit_count = fanout[255] as usize;
+
+    let oidl = table.require(b"OIDL", bytes)?;
+    if oidl.len() < commit_count * 20 {
+        return Err(ErrorKind::InvalidCommitGraph.into())
+    }
+    let ids = Id::read_packed_ids(&mut &oidl[..], commit_count)?;
+
+    let cdat = table.require(b"CDAT", bytes)?;
+    if cdat.len() < commit_count * 36 {
+        return Err(ErrorKind::InvalidCommitGraph.into())
+    }
+
+    let commits: Vec<_> = cdat.chunks(36).take(commit_count).map(|entry| {
+        let words = &entry[20..];
+        let level_and_time_hi = be_u32(words, 2);
+        CommitData {
+            tree: Id::new(&entry[0..20]),
+            parent1: be_u32(words, 0),
+            parent2: be_u32(words, 1),
+            topological_level: level_and_time_hi >> 2,
+            commit_time: ((level_and_time_hi as u64 & 0x3) << 32) | be_u32(words, 3) as u64
+        }
+    }).collect();
+
+    let edges: Vec<u32> = table.get(b"EDGE", bytes).map(|edge| {
+        (0..edge.len() / 4).map(|idx| be_u32(edge, idx)).collect()
+    }).unwrap_or_default();
+
+    let corrected_dates = match table.get(b"GDA2", bytes) {
+        Some(gda2) if gda2.len() >= commit_count * 4 => {
+            let overflow = table.get(b"GDO2", bytes).unwrap_or(&[]);
+            let dates = commits.iter().enumerate().map(|(idx, commit)| {
+                let offset = be_u32(gda2, idx);
+                let offset = if offset & CORRECTED_DATE_OVERFLOW != 0 {
+                    let overflow_idx = (offset & !CORRECTED_DATE_OVERFLOW) as usize;
+                    if (overflow_idx + 1) * 8 > overflow.len() {
+                        return Err(ErrorKind::InvalidCommitGraph.into())
+                    }
+                    be_u64(overflow, overflow_idx)
+                } else {
+                    offset as u64
+                };
+                commit.commit_time.checked_add(offset).ok_or_else(|| ErrorKind::InvalidCommitGraph.into())
+            }).collect::<Result<Vec<_>>>()?;
+            Some(dates)
+        },
+        Some(_) => return Err(ErrorKind::InvalidCommitGraph.into()),
+        None => None
+    };
+
+    let base_count = bases.last().map(|layer| layer.base_count + layer.ids.len() as u32).unwrap_or(0);
+
+    // Parent positions refer to this layer or the layers beneath it.
+    let limit = base_count + commit_count as u32;
+    let parents_in_bounds = commits.iter().all(|commit| {
+        (commit.parent1 == GRAPH_PARENT_NONE || commit.parent1 < limit) &&
+        (commit.parent2 == GRAPH_PARENT_NONE ||
+            commit.parent2 & GRAPH_EXTRA_EDGES_NEEDED != 0 ||
+            commit.parent2 < limit)
+    }) && edges.iter().all(|edge| edge & !GRAPH_LAST_EDGE < limit);
+
+    if !parents_in_bounds {
+        return Err(ErrorKind::InvalidCommitGraph.into())
+    }
+
+    let checksum: [u8; 20] = bytes[bytes.len() - 20..].try_into().unwrap();
+
+    Ok(Layer {
+        fanout,
+        ids,
+        commits,
+        edges,
+        corrected_dates,
+        base_count,
+        checksum: checksum.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::chunk::{ write_chunks, ChunkTable };
+    use crate::errors::ErrorKind;
+    use crate::id::Id;
+    use super::{ CommitGraph, CORRECTED_DATE_OVERFLOW, GRAPH_HEADER_SIZE };
+
+    fn id(input: &str) -> Id {
+        Id::from_str(input).unwrap()
+    }
+
+    #[test]
+    fn read_commit_graph_works() {
+        let graph = CommitGraph::read(include_bytes!("../../fixtures/commit_graph")).expect("failed to parse");
+        assert_eq!(graph.len(), 5);
+        assert_eq!(graph.layer_count(), 1);
+
+        let root = graph.get(&id("787524db9b0dc673241741a8cd5c03deddf6f6aa")).expect("missing root");
+        assert!(root.parents().is_empty());
+        assert_eq!(root.commit_time(), 1_600_000_000);
+        assert_eq!(root.topological_level(), 1);
+
+        // an octopus merge, which needs the EDGE chunk
+        let merge = graph.get(&id("9d49f6b9da0ce02edceb49f7df7e547a9c968fb8")).expect("missing merge");
+        assert_eq!(merge.parents(), &[
+            id("16f1d83d4a25b64c71c1ca1c0785ba2f7980473d"),
+            id("67c5c34d22cdc50597aad21ab749b4440212c5a3"),
+            id("6c831b72c3c1281de911b411987b877c91b258d4")
+        ]);
+        assert_eq!(merge.commit_time(), 1_600_000_400);
+        assert_eq!(merge.topological_level(), 3);
+        assert_eq!(merge.generation(), 1_600_000_400);
+
+        assert!(graph.get(&id("280d5b3df76a66d33bd67ae2576f75bb6d3bed82")).is_none());
+    }
+
+    #[test]
+    fn read_commit_graph_chain_works() {
+        let graph = CommitGraph::read_chain(vec![
+            &include_bytes!("../../fixtures/commit_graph_chain_base")[..],
+            &include_bytes!("../../fixtures/commit_graph_chain_tip")[..]
+        ]).expect("failed to parse");
+
+        assert_eq!(graph.len(), 7);
+        assert_eq!(graph.layer_count(), 2);
+
+        let tip = graph.get(&id("280d5b3df76a66d33bd67ae2576f75bb6d3bed82")).expect("missing tip");
+        assert_eq!(tip.parents(), &[id("566a847e44469b5882b52114cef2096789506366")]);
+        assert!(tip.position() >= 5);
+
+        // parents may live in the base layer
+        let next = graph.get(&tip.parents()[0]).expect("missing parent");
+        assert_eq!(next.parents(), &[id("9d49f6b9da0ce02edceb49f7df7e547a9c968fb8")]);
+        assert_eq!(next.topological_level(), 4);
+        assert_eq!(next.commit_time(), 1_600_000_500);
+        assert!(graph.get(&next.parents()[0]).unwrap().position() < 5);
+    }
+
+    #[test]
+    fn chain_layers_need_their_bases() {
+        let result = CommitGraph::read_chain(vec![
+            &include_bytes!("../../fixtures/commit_graph_chain_tip")[..]
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn corrected_dates_past_the_end_of_time_are_errors() {
+        let bytes = include_bytes!("../../fixtures/commit_graph");
+        let table = ChunkTable::read(bytes, GRAPH_HEADER_SIZE, bytes[6] as usize).unwrap();
+        let mut chunks: Vec<([u8; 4], Vec<u8>)> = [b"OIDF", b"OIDL", b"CDAT"].iter()
+            .map(|id| (**id, table.require(id, bytes).unwrap().to_vec()))
+            .collect();
+
+        // the first commit's offset overflows into GDO2, which holds the largest offset there is
+        let mut gda2 = CORRECTED_DATE_OVERFLOW.to_be_bytes().to_vec();
+        gda2.resize(5 * 4, 0);
+        chunks.push((*b"GDA2", gda2));
+        chunks.push((*b"GDO2", u64::MAX.to_be_bytes().to_vec()));
+
+        let mut graph = Vec::new();
+        write_chunks(&[b'C', b'G', b'P', b'H', 1, 1, chunks.len() as u8, 0], &chunks, &mut graph).unwrap();
+        let error = CommitGraph::read(&graph).expect_err("the date overflows");
+        assert_eq!(error.kind(), &ErrorKind::InvalidCommitGraph);
+    }
+}
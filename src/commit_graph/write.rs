@@ -0,0 +1,570 @@
+use std::collections::{ HashMap, HashSet };
+use std::path::{ Path, PathBuf };
+use std::io::Write;
+
+use crate::chunk::write_chunks;
+use crate::commit_graph::CommitGraph;
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::stores::fs::Store as FsStore;
+use crate::objects::{ Object, Type };
+use crate::id::Id;
+
+const GRAPH_PARENT_NONE: u32 = 0x7000_0000;
+const GRAPH_EXTRA_EDGES_NEEDED: u32 = 0x8000_0000;
+const GRAPH_LAST_EDGE: u32 = 0x8000_0000;
+const GENERATION_NUMBER_V1_MAX: u32 = 0x3FFF_FFFF;
+const CORRECTED_DATE_OFFSET_MAX: u64 = 0x7FFF_FFFF;
+const CORRECTED_DATE_OVERFLOW: u32 = 0x8000_0000;
+const COMMIT_TIME_MAX: u64 = (1 << 34) - 1;
+const SPLIT_SIZE_MULTIPLE: usize = 2;
+
+/// How `write_to_path` should update a repository's commit-graph.
+#[derive(Debug, Copy, Clone)]
+pub enum Mode {
+    /// Write a single `objects/info/commit-graph` covering every reachable commit,
+    /// removing any split commit-graph chain.
+    Replace,
+
+    /// Add a layer to the split commit-graph chain holding only the commits that
+    /// aren't in the existing commit-graph, merging it with the layers beneath it
+    /// while they aren't more than twice its size. An existing single-file
+    /// commit-graph becomes the base of the chain.
+    Split
+}
+
+struct Entry {
+    id: Id,
+    tree: Id,
+    parents: Vec<Id>,
+    commit_time: u64
+}
+
+/// Return the ids of every commit stored in the repository's packfiles.
+pub fn packed_commits(storage_set: &StorageSet<FsStore>) -> Result<Vec<Id>> {
+    let packs = storage_set.backend().packs();
+    let mut commits = Vec::new();
+    for id in packs.ids() {
+        if let Some(Type::Commit) = packs.object_type(&id, storage_set)? {
+            commits.push(id);
+        }
+    }
+
+    Ok(commits)
+}
+
+/// Write a commit-graph layer containing every commit reachable from `starts`
+/// that isn't already in `base`. Without a `base` this writes a complete,
+/// single-file commit-graph. Returns the checksum of the new layer, which names
+/// the layer in a split commit-graph chain.
+pub fn write<S, W, I>(storage_set: &StorageSet<S>, starts: I, base: Option<&CommitGraph>, output: &mut W) -> Result<Id>
+    where S: Queryable,
+          W: Write,
+          I: IntoIterator<Item = Id> {
+    let entries = collect_commits(storage_set, starts, base)?;
+    write_layer(&entries, base, output)
+}
+
+/// Update the commit-graph of the repository at `path` to include every commit
+/// reachable from `starts`. Returns the checksum of the file or layer that was
+/// written, or `None` if there were no new commits to add to a split chain.
+pub fn write_to_path<S, I>(path: &Path, storage_set: &StorageSet<S>, starts: I, mode: Mode) -> Result<Option<Id>>
+    where S: Queryable,
+          I: IntoIterator<Item = Id> {
+    let mut info = path.to_path_buf();
+    info.push(".git");
+    info.push("objects");
+    info.push("info");
+    std::fs::create_dir_all(&info)?;
+    let graphs = info.join("commit-graphs");
+
+    match mode {
+        Mode::Replace => {
+            let entries = collect_commits(storage_set, starts, None)?;
+            let checksum = write_file(&info.join("commit-graph"), |output| write_layer(&entries, None, output))?;
+
+            match std::fs::remove_dir_all(&graphs) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => ()
+            };
+
+            Ok(Some(checksum))
+        },
+
+        Mode::Split => {
+            std::fs::create_dir_all(&graphs)?;
+            let graph = CommitGraph::from_path(path)?;
+
+            let single = info.join("commit-graph");
+            if single.exists() {
+                if let Some(graph) = &graph {
+                    let name = graphs.join(format!("graph-{}.graph", graph.layer_checksums()[0]));
+                    std::fs::rename(&single, name)?;
+                }
+            }
+
+            let mut entries = collect_commits(storage_set, starts, graph.as_ref())?;
+            let mut chain: Vec<String> = graph.as_ref()
+                .map(|graph| graph.layer_checksums().iter().map(|id| id.to_string()).collect())
+                .unwrap_or_default();
+
+            if entries.is_empty() {
+                if graph.is_some() {
+                    write_file(&graphs.join("commit-graph-chain"), |output| write_chain(&chain, output))?;
+                }
+                return Ok(None)
+            }
+
+            // As git does, fold the layers beneath into the new one while they hold no
+            // more than SPLIT_SIZE_MULTIPLE times as many commits, so the chain stays short.
+            let mut keep = chain.len();
+            if let Some(graph) = &graph {
+                let mut count = entries.len();
+                while keep > 0 && graph.layers[keep - 1].ids.len() <= SPLIT_SIZE_MULTIPLE * count {
+                    keep -= 1;
+                    count += graph.layers[keep].ids.len();
+                }
+
+                if keep < chain.len() {
+                    for position in graph.layers[keep].base_count..graph.len() as u32 {
+                        let commit = graph.get_at(position).ok_or(ErrorKind::InvalidCommitGraph)?;
+                        entries.push(Entry {
+                            id: graph.id_at(position).ok_or(ErrorKind::InvalidCommitGraph)?.clone(),
+                            tree: commit.tree().clone(),
+                            parents: commit.parents().to_vec(),
+                            commit_time: commit.commit_time()
+                        });
+                    }
+                    entries.sort_unstable_by(|lhs, rhs| lhs.id.cmp(&rhs.id));
+                }
+            }
+
+            let merged = chain.split_off(keep);
+            let base = match graph {
+                Some(graph) if merged.is_empty() => Some(graph),
+                _ if chain.is_empty() => None,
+                _ => Some(CommitGraph::read_layer_files(&graphs, &chain)?)
+            };
+
+            let mut layer = Vec::new();
+            let checksum = write_layer(&entries, base.as_ref(), &mut layer)?;
+            write_file(&graphs.join(format!("graph-{}.graph", checksum)), |output| {
+                output.write_all(&layer)?;
+                Ok(())
+            })?;
+
+            chain.push(checksum.to_string());
+            write_file(&graphs.join("commit-graph-chain"), |output| write_chain(&chain, output))?;
+
+            for name in merged {
+                match std::fs::remove_file(graphs.join(format!("graph-{}.graph", name))) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => ()
+                };
+            }
+
+            Ok(Some(checksum))
+        }
+    }
+}
+
+fn write_chain<W: Write>(chain: &[String], output: &mut W) -> Result<()> {
+    for line in chain {
+        writeln!(output, "{}", line)?;
+    }
+    Ok(())
+}
+
+// Write to a temporary file next to `target`, then move it into place.
+fn write_file<T, F>(target: &Path, func: F) -> Result<T>
+    where F: FnOnce(&mut std::io::BufWriter<std::fs::File>) -> Result<T> {
+    let mut tmp = PathBuf::from(target);
+    tmp.set_extension(format!("tmp-{}", std::process::id()));
+
+    let mut output = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    let result = func(&mut output).and_then(|xs| {
+        output.flush()?;
+        Ok(xs)
+    });
+
+    match result {
+        Ok(xs) => {
+            std::fs::rename(&tmp, target)?;
+            Ok(xs)
+        },
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+fn collect_commits<S, I>(storage_set: &StorageSet<S>, starts: I, base: Option<&CommitGraph>) -> Result<Vec<Entry>>
+    where S: Queryable,
+          I: IntoIterator<Item = Id> {
+    let mut seen = HashSet::new();
+    let mut stack: Vec<Id> = starts.into_iter().collect();
+    let mut entries = Vec::new();
+
+    while let Some(id) = stack.pop() {
+        if seen.contains(&id) || base.map(|base| base.contains(&id)).unwrap_or(false) {
+            continue
+        }
+        seen.insert(id.clone());
+
+        let commit = match storage_set.get_and_load(&id)? {
+            Some(Object::Commit(commit)) => commit,
+            Some(_) => continue,
+            None => return Err(ErrorKind::ObjectNotFound.into())
+        };
+
+        let tree = match commit.tree() {
+            Some(xs) => xs.clone(),
+            None => return Err(ErrorKind::InvalidCommitGraph.into())
+        };
+
+        let commit_time = commit.committer().as_ref()
+            .and_then(|committer| committer.timestamp())
+            .map(|timestamp| timestamp.timestamp().clamp(0, COMMIT_TIME_MAX as i64) as u64)
+            .unwrap_or(0);
+
+        stack.extend(commit.parents().iter().cloned());
+        entries.push(Entry {
+            id,
+            tree,
+            parents: commit.parents().to_vec(),
+            commit_time
+        });
+    }
+
+    entries.sort_unstable_by(|lhs, rhs| lhs.id.cmp(&rhs.id));
+    Ok(entries)
+}
+
+// Compute the topological level and corrected commit date of every entry. Parents
+// are always computed before their children, using an explicit stack rather than
+// recursion so that long histories can't overflow the call stack. Corrected dates
+// are `None` when a parent in `base` doesn't have one.
+fn compute_generations(entries: &[Entry], positions: &HashMap<&Id, usize>, base: Option<&CommitGraph>) -> Result<Vec<(u32, Option<u64>)>> {
+    let mut generations: Vec<Option<(u32, Option<u64>)>> = vec![None; entries.len()];
+    let mut stack = Vec::new();
+
+    for start in 0..entries.len() {
+        if generations[start].is_some() {
+            continue
+        }
+
+        stack.push(start);
+        while let Some(&idx) = stack.last() {
+            if generations[idx].is_some() {
+                stack.pop();
+                continue
+            }
+
+            let mut level = 0;
+            let mut corrected_date = Some(0);
+            let mut pending = false;
+            for parent in entries[idx].parents.iter() {
+                let (parent_level, parent_date) = match positions.get(parent) {
+                    Some(&parent_idx) => match generations[parent_idx] {
+                        Some(xs) => xs,
+                        None => {
+                            stack.push(parent_idx);
+                            pending = true;
+                            continue
+                        }
+                    },
+                    None => {
+                        let commit = match base.and_then(|base| base.get(parent)) {
+                            Some(xs) => xs,
+                            None => return Err(ErrorKind::ObjectNotFound.into())
+                        };
+                        (commit.topological_level(), commit.corrected_date())
+                    }
+                };
+
+                level = level.max(parent_level);
+                corrected_date = corrected_date.zip(parent_date).map(|(lhs, rhs)| lhs.max(rhs));
+            }
+
+            if pending {
+                continue
+            }
+
+            let entry = &entries[idx];
+            let has_parents = !entry.parents.is_empty();
+            generations[idx] = Some((
+                std::cmp::min(level + 1, GENERATION_NUMBER_V1_MAX),
+                corrected_date.map(|date| if has_parents { entry.commit_time.max(date + 1) } else { entry.commit_time })
+            ));
+            stack.pop();
+        }
+    }
+
+    Ok(generations.into_iter().map(|xs| xs.unwrap()).collect())
+}
+
+fn write_layer<W: Write>(entries: &[Entry], base: Option<&CommitGraph>, output: &mut W) -> Result<Id> {
+    let base_count = base.map(|base| base.len()).unwrap_or(0) as u32;
+    let positions: HashMap<&Id, usize> = entries.iter().enumerate().map(|(idx, entry)| (&entry.id, idx)).collect();
+
+    let position_of = |id: &Id| -> Result<u32> {
+        if let Some(idx) = positions.get(id) {
+            return Ok(base_count + *idx as u32)
+        }
+
+        match base.and_then(|base| base.position(id)) {
+            Some(xs) => Ok(xs),
+            None => Err(ErrorKind::ObjectNotFound.into())
+        }
+    };
+
+    let generations = compute_generations(entries, &positions, base)?;
+
+    // Readers ignore corrected dates unless every layer of a chain has them, so
+    // only write them if the base layers do.
+    let write_generation_data = base.is_none_or(|base| base.has_corrected_dates());
+
+    let mut fanout = [0u32; 256];
+    for entry in entries {
+        fanout[entry.id.as_ref()[0] as usize] += 1;
+    }
+    for idx in 1..256 {
+        fanout[idx] += fanout[idx - 1];
+    }
+
+    let mut oidf = Vec::with_capacity(1024);
+    for count in fanout.iter() {
+        oidf.extend_from_slice(&count.to_be_bytes());
+    }
+
+    let mut oidl = Vec::with_capacity(entries.len() * 20);
+    let mut cdat = Vec::with_capacity(entries.len() * 36);
+    let mut gda2 = Vec::with_capacity(entries.len() * 4);
+    let mut gdo2 = Vec::new();
+    let mut edge = Vec::new();
+
+    for (entry, (level, corrected_date)) in entries.iter().zip(generations) {
+        oidl.extend_from_slice(entry.id.as_ref());
+
+        cdat.extend_from_slice(entry.tree.as_ref());
+        let parent1 = match entry.parents.first() {
+            Some(parent) => position_of(parent)?,
+            None => GRAPH_PARENT_NONE
+        };
+        let parent2 = match entry.parents.len() {
+            0 | 1 => GRAPH_PARENT_NONE,
+            2 => position_of(&entry.parents[1])?,
+            _ => {
+                let edge_idx = (edge.len() / 4) as u32;
+                let extra = &entry.parents[1..];
+                for (idx, parent) in extra.iter().enumerate() {
+                    let mut position = position_of(parent)?;
+                    if idx == extra.len() - 1 {
+                        position |= GRAPH_LAST_EDGE;
+                    }
+                    edge.extend_from_slice(&position.to_be_bytes());
+                }
+                GRAPH_EXTRA_EDGES_NEEDED | edge_idx
+            }
+        };
+        cdat.extend_from_slice(&parent1.to_be_bytes());
+        cdat.extend_from_slice(&parent2.to_be_bytes());
+
+        let commit_time = std::cmp::min(entry.commit_time, COMMIT_TIME_MAX);
+        let level_and_time_hi = (level << 2) | (commit_time >> 32) as u32;
+        cdat.extend_from_slice(&level_and_time_hi.to_be_bytes());
+        cdat.extend_from_slice(&(commit_time as u32).to_be_bytes());
+
+        let corrected_date = match corrected_date {
+            Some(xs) if write_generation_data => xs,
+            _ => continue
+        };
+
+        let offset = corrected_date - entry.commit_time;
+        if offset > CORRECTED_DATE_OFFSET_MAX {
+            let overflow_idx = (gdo2.len() / 8) as u32;
+            gda2.extend_from_slice(&(CORRECTED_DATE_OVERFLOW | overflow_idx).to_be_bytes());
+            gdo2.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            gda2.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+    }
+
+    let mut chunks = vec![
+        (*b"OIDF", oidf),
+        (*b"OIDL", oidl),
+        (*b"CDAT", cdat)
+    ];
+
+    if write_generation_data {
+        chunks.push((*b"GDA2", gda2));
+    }
+
+    if !gdo2.is_empty() {
+        chunks.push((*b"GDO2", gdo2));
+    }
+
+    if !edge.is_empty() {
+        chunks.push((*b"EDGE", edge));
+    }
+
+    let base_layers = base.map(|base| base.layer_checksums()).unwrap_or_default();
+    if !base_layers.is_empty() {
+        let mut base_chunk = Vec::with_capacity(base_layers.len() * 20);
+        for checksum in base_layers.iter() {
+            base_chunk.extend_from_slice(checksum.as_ref());
+        }
+        chunks.push((*b"BASE", base_chunk));
+    }
+
+    let header = [b'C', b'G', b'P', b'H', 1, 1, chunks.len() as u8, base_layers.len() as u8];
+    write_chunks(&header, &chunks, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::str::FromStr;
+
+    use crate::test_support::{ git, git_at, scratch_repo, Scratch };
+
+    use crate::chunk::ChunkTable;
+    use crate::commit_graph::CommitGraph;
+    use crate::id::Id;
+    use super::Mode;
+
+    fn commit(root: &Path, message: &str, time: u64) {
+        git_at(root, &["commit", "-q", "--allow-empty", "-m", message], Some(time));
+    }
+
+    fn history(name: &str) -> Scratch {
+        let root = scratch_repo(name);
+
+        // a little history with a regular merge, an octopus merge, and commit times
+        // that go backwards (so corrected commit dates differ from commit times)
+        commit(&root, "root", 1_600_000_000);
+        for (branch, time) in [("b", 1_600_000_100), ("c", 1_600_000_200), ("d", 1_500_000_000)] {
+            git(&root, &["checkout", "-q", "-b", branch, "main"]);
+            commit(&root, branch, time);
+        }
+        git(&root, &["checkout", "-q", "main"]);
+        commit(&root, "main", 1_600_000_300);
+        git(&root, &["merge", "-q", "--no-ff", "--no-edit", "b", "c", "d"]);
+        commit(&root, "after", 1_400_000_000);
+        root
+    }
+
+    fn head(root: &Path) -> Id {
+        Id::from_str(git(root, &["rev-parse", "HEAD"]).trim()).unwrap()
+    }
+
+    #[test]
+    fn writes_commit_graphs_git_accepts() {
+        let root = history("commit-graph-write");
+        git(&root, &["repack", "-q", "-a", "-d"]);
+
+        let storage_set = root.storage();
+        let starts = super::packed_commits(&storage_set).expect("failed to list commits");
+        assert_eq!(starts.len(), 7);
+
+        super::write_to_path(&root, &storage_set, starts, Mode::Replace).expect("failed to write");
+        git(&root, &["commit-graph", "verify"]);
+
+        let ours = std::fs::read(root.join(".git/objects/info/commit-graph")).unwrap();
+        git(&root, &["commit-graph", "write", "--reachable"]);
+        let theirs = std::fs::read(root.join(".git/objects/info/commit-graph")).unwrap();
+        assert_eq!(ours, theirs);
+
+        let graph = CommitGraph::read(&ours).unwrap();
+        let merge = graph.get(&graph.get(&head(&root)).unwrap().parents()[0]).unwrap();
+        assert_eq!(merge.parents().len(), 4);
+    }
+
+    #[test]
+    fn writes_split_commit_graph_chains() {
+        let root = history("commit-graph-split");
+
+        let storage_set = root.storage();
+        let first = super::write_to_path(&root, &storage_set, vec![head(&root)], Mode::Split).expect("failed to write");
+        assert!(first.is_some());
+        git(&root, &["commit-graph", "verify"]);
+
+        commit(&root, "next", 1_600_000_500);
+        commit(&root, "last", 1_600_000_600);
+
+        let storage_set = root.storage();
+        let second = super::write_to_path(&root, &storage_set, vec![head(&root)], Mode::Split).expect("failed to write");
+        assert!(second.is_some());
+        git(&root, &["commit-graph", "verify"]);
+
+        let graph = CommitGraph::from_path(&root).unwrap().unwrap();
+        assert_eq!(graph.layer_count(), 2);
+        assert_eq!(graph.len(), 9);
+
+        // nothing new to add
+        let storage_set = root.storage();
+        let third = super::write_to_path(&root, &storage_set, vec![head(&root)], Mode::Split).expect("failed to write");
+        assert!(third.is_none());
+
+        // the two-commit layer is no bigger than twice the new one, so they're merged
+        let top = graph.layer_checksums()[1].to_string();
+        commit(&root, "merged", 1_600_000_700);
+        let storage_set = root.storage();
+        super::write_to_path(&root, &storage_set, vec![head(&root)], Mode::Split).expect("failed to write");
+        git(&root, &["commit-graph", "verify"]);
+
+        let graph = CommitGraph::from_path(&root).unwrap().unwrap();
+        assert_eq!(graph.layer_count(), 2);
+        assert_eq!(graph.len(), 10);
+        assert!(!root.join(format!(".git/objects/info/commit-graphs/graph-{}.graph", top)).exists());
+
+        // and enough new commits fold the whole chain into one layer
+        for (idx, time) in (1_600_000_800..1_600_000_805).enumerate() {
+            commit(&root, &format!("more {}", idx), time);
+        }
+        let storage_set = root.storage();
+        super::write_to_path(&root, &storage_set, vec![head(&root)], Mode::Split).expect("failed to write");
+        git(&root, &["commit-graph", "verify"]);
+
+        let graph = CommitGraph::from_path(&root).unwrap().unwrap();
+        assert_eq!(graph.layer_count(), 1);
+        assert_eq!(graph.len(), 15);
+        assert_eq!(std::fs::read_dir(root.join(".git/objects/info/commit-graphs")).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn split_layers_match_their_base_generation_data() {
+        let root = history("commit-graph-split-v1");
+        git(&root, &["-c", "commitGraph.generationVersion=1", "commit-graph", "write", "--reachable", "--split"]);
+
+        for (idx, time) in (1_600_000_500..1_600_000_520).enumerate() {
+            commit(&root, &format!("next {}", idx), time);
+        }
+
+        // enough new commits to merge with the base, which then gets corrected dates too
+        let storage_set = root.storage();
+        let merged = super::write_to_path(&root, &storage_set, vec![head(&root)], Mode::Split).expect("failed to write").unwrap();
+        let graph = CommitGraph::from_path(&root).unwrap().unwrap();
+        assert_eq!(graph.layer_count(), 1);
+        assert!(graph.has_corrected_dates());
+
+        git(&root, &["-c", "commitGraph.generationVersion=1", "commit-graph", "write", "--reachable", "--split=replace"]);
+        commit(&root, "last", 1_600_000_600);
+
+        // too few to merge, so the new layer has no GDA2 chunk either
+        let storage_set = root.storage();
+        let layer = super::write_to_path(&root, &storage_set, vec![head(&root)], Mode::Split).expect("failed to write").unwrap();
+        git(&root, &["commit-graph", "verify"]);
+
+        let graph = CommitGraph::from_path(&root).unwrap().unwrap();
+        assert_eq!(graph.layer_count(), 2);
+        assert_ne!(graph.layer_checksums()[0], &merged);
+        assert!(!graph.has_corrected_dates());
+
+        let bytes = std::fs::read(root.join(format!(".git/objects/info/commit-graphs/graph-{}.graph", layer))).unwrap();
+        let table = ChunkTable::read(&bytes, 8, bytes[6] as usize).unwrap();
+        assert!(table.get(b"GDA2", &bytes).is_none());
+        assert!(table.get(b"CDAT", &bytes).is_some());
+    }
+}
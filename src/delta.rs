@@ -1,5 +1,6 @@
 use crate::errors::{ ErrorKind, Result };
 
+use std::collections::HashMap;
 use std::convert::From;
 use std::io::Write;
 
@@ -43,13 +44,10 @@ pub struct DeltaDecoder {
 }
 
 impl DeltaDecoder {
+    /// Check the header of `instructions` against `base`. The instructions
+    /// themselves are checked as they're applied, by `DeltaDecoderStream`.
     pub fn new (instructions: &[u8], base: Vec<u8>) -> Result<DeltaDecoder> {
-        let (after_base_size, base_size) = read_varint(0, instructions);
-        let (index, output_size) = read_varint(after_base_size, instructions);
-
-        if base.len() != base_size {
-            return Err(ErrorKind::BadDeltaBase.into())
-        }
+        let (index, output_size) = read_header(instructions, base.len())?;
 
         Ok(DeltaDecoder {
             instructions: Vec::from(&instructions[index..]),
@@ -76,129 +74,448 @@ impl From<DeltaDecoder> for DeltaDecoderStream {
     }
 }
 
-fn read_varint(base_offset: usize, bytes: &[u8]) -> (usize, usize) {
-    let mut shift: usize = 0;
+/// Apply the delta `instructions` to `base`, writing the result to `output` as
+/// each instruction is read, without buffering it. Returns the size of the
+/// result.
+///
+/// To resolve a chain of deltas, apply each one to the result of the last,
+/// alternating between two buffers, and apply the last one to the caller's
+/// writer.
+pub fn apply<W: Write>(instructions: &[u8], base: &[u8], output: &mut W) -> Result<usize> {
+    let (mut index, output_size) = read_header(instructions, base.len())?;
+
+    let mut written = 0;
+    while index < instructions.len() {
+        let data = match next_instruction(instructions, &mut index, base.len())? {
+            Instruction::Copy(offset, extent) => &base[offset..offset + extent],
+            Instruction::Insert(start, extent) => &instructions[start..start + extent]
+        };
+
+        written = check_output(written, data.len(), output_size)?;
+        output.write_all(data)?;
+    }
+
+    if written != output_size {
+        return Err(ErrorKind::DeltaOutputSizeMismatch { expected: output_size as u64, actual: written as u64 }.into())
+    }
+
+    Ok(written)
+}
+
+/// Read the size of the object that applying the delta `instructions` would
+/// produce, from their header, without applying them.
+pub fn result_size(instructions: &[u8]) -> Result<usize> {
+    let (after_base_size, _) = read_varint(0, instructions).ok_or(ErrorKind::TruncatedDeltaInstructions)?;
+    let (_, output_size) = read_varint(after_base_size, instructions).ok_or(ErrorKind::TruncatedDeltaInstructions)?;
+    Ok(output_size)
+}
+
+// Read the base and result sizes at the start of delta instructions, checking
+// the former against `base_size`. Returns the index of the first instruction
+// and the size of the result.
+fn read_header(instructions: &[u8], base_size: usize) -> Result<(usize, usize)> {
+    let (after_base_size, expected_base_size) = read_varint(0, instructions).ok_or(ErrorKind::TruncatedDeltaInstructions)?;
+    let (index, output_size) = read_varint(after_base_size, instructions).ok_or(ErrorKind::TruncatedDeltaInstructions)?;
+
+    if base_size != expected_base_size {
+        return Err(ErrorKind::BadDeltaBase.into())
+    }
+
+    Ok((index, output_size))
+}
+
+#[derive(Debug)]
+enum Instruction {
+    // an offset into the base and a number of bytes to copy from it
+    Copy(usize, usize),
+
+    // the index of the bytes to insert in the instructions, and their number
+    Insert(usize, usize)
+}
+
+// Decode the instruction at `index`, leaving `index` just past it (and any
+// bytes it inserts.) Copies are checked against the size of the base, and
+// inserts against the end of the instructions, so the ranges returned can be
+// sliced without further checks.
+fn next_instruction(instructions: &[u8], index: &mut usize, base_size: usize) -> Result<Instruction> {
+    let cmd = *instructions.get(*index).ok_or(ErrorKind::TruncatedDeltaInstructions)?;
+    *index += 1;
+
+    if cmd == 0 {
+        return Err(ErrorKind::ReservedDeltaOpcode.into())
+    }
+
+    if (cmd & 0x80) == 0 {
+        let extent = cmd as usize;
+        if instructions.len() - *index < extent {
+            return Err(ErrorKind::TruncatedDeltaInstructions.into())
+        }
+
+        let start = *index;
+        *index += extent;
+        return Ok(Instruction::Insert(start, extent))
+    }
+
+    let (offset, extent) = read_copy(cmd, instructions, index).ok_or(ErrorKind::TruncatedDeltaInstructions)?;
+    if offset.checked_add(extent).is_none_or(|end| end > base_size) {
+        return Err(ErrorKind::DeltaCopyOutOfRange {
+            offset: offset as u64,
+            extent: extent as u64,
+            base_size: base_size as u64
+        }.into())
+    }
+
+    Ok(Instruction::Copy(offset, extent))
+}
+
+// Add `extent` bytes to the `written` so far, as long as that doesn't go past
+// `output_size`: a delta may not write more than it said it would.
+fn check_output(written: usize, extent: usize, output_size: usize) -> Result<usize> {
+    match written.checked_add(extent) {
+        Some(total) if total <= output_size => Ok(total),
+        _ => Err(ErrorKind::DeltaOutputSizeMismatch {
+            expected: output_size as u64,
+            actual: written as u64 + extent as u64
+        }.into())
+    }
+}
+
+// Copy instructions are followed by the bytes of the offset and extent flagged
+// in `cmd`; read them starting at `index`, leaving it just past them.
+fn read_copy(cmd: u8, instructions: &[u8], index: &mut usize) -> Option<(usize, usize)> {
+    let mut check = 1;
+    let mut offset: usize = 0;
+    let mut extent: usize = 0;
+
+    for i in 0..4 {
+        if (cmd & check) != 0 {
+            offset |= (*instructions.get(*index)? as usize) << (8 * i);
+            *index += 1;
+        }
+        check <<= 1;
+    }
+
+    for i in 0..3 {
+        if (cmd & check) != 0 {
+            extent |= (*instructions.get(*index)? as usize) << (8 * i);
+            *index += 1;
+        }
+        check <<= 1;
+    }
+
+    Some((offset, if extent == 0 { 0x10000 } else { extent }))
+}
+
+// Read a little-endian base-128 number starting at `base_offset`, returning the
+// offset just past it and its value. Fails if the bytes run out, or if the
+// number doesn't fit in a usize.
+fn read_varint(base_offset: usize, bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut shift: u32 = 0;
     let mut result: usize = 0;
     let mut offset = base_offset;
 
-    while {
-        let byt = bytes[offset];
-        result += ((byt & 0x7F) as usize) << shift;
-        shift += 7;
+    loop {
+        let byte = *bytes.get(offset)?;
         offset += 1;
-        byt >= 0x80
-    } {}
 
-    (offset, result)
+        let bits = (byte & 0x7F) as usize;
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            return None
+        }
+        result |= bits << shift;
+        shift += 7;
+
+        if byte < 0x80 {
+            return Some((offset, result))
+        }
+    }
 }
 
-impl std::io::Read for DeltaDecoderStream {
-    fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut written = 0;
-        loop {
-            let (next_state, exhausted) = match &self.state {
-                DeltaDecoderState::Done => {
-                    if self.written != self.output_size {
-                        return Err(std::io::ErrorKind::WriteZero.into())
+// The number of bytes hashed to find copy candidates; matches shorter than this
+// are only found by extending a longer match backwards.
+const WINDOW: usize = 16;
+
+// The most base offsets we keep for any one hash, so that very repetitive bases
+// don't make encoding quadratic.
+const BUCKET_LIMIT: usize = 64;
+
+// The most bytes a single copy or insert instruction will carry. (Copies can
+// describe up to 0xffffff bytes, but git never emits more than 0x10000.)
+const MAX_COPY: usize = 0x10000;
+const MAX_INSERT: usize = 0x7f;
+
+// Copy offsets are at most four bytes, so only this much of a base can be
+// copied from; anything past it is never matched.
+const MAX_COPY_END: usize = u32::MAX as usize;
+
+const HASH_MULTIPLIER: u32 = 0x0100_0193;
+
+/// Produces delta instructions that rebuild a target from `base`.
+///
+/// The base is indexed once, in `WINDOW`-byte blocks, so the same encoder can
+/// be used to try many targets against one base. Each position in the target is
+/// looked up by a rolling hash of the `WINDOW` bytes starting there; the longest
+/// verified match becomes a copy instruction, and everything else is inserted.
+pub struct DeltaEncoder<B: AsRef<[u8]>> {
+    base: B,
+    index: HashMap<u32, Vec<usize>>,
+    // the length of the prefix of the base that copies can reach
+    copyable: usize
+}
+
+impl<B: AsRef<[u8]>> DeltaEncoder<B> {
+    pub fn new(base: B) -> Self {
+        Self::with_copy_end(base, MAX_COPY_END)
+    }
+
+    // Index only the first `copy_end` bytes of `base`, so every match (and every
+    // copy instruction) ends before it.
+    fn with_copy_end(base: B, copy_end: usize) -> Self {
+        let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+        let copyable = base.as_ref().len().min(copy_end);
+        let bytes = &base.as_ref()[..copyable];
+        let mut offset = 0;
+        while offset + WINDOW <= bytes.len() {
+            let bucket = index.entry(window_hash(&bytes[offset..offset + WINDOW])).or_default();
+            if bucket.len() < BUCKET_LIMIT {
+                bucket.push(offset);
+            }
+            offset += WINDOW;
+        }
+
+        DeltaEncoder { base, index, copyable }
+    }
+
+    pub fn base(&self) -> &[u8] {
+        self.base.as_ref()
+    }
+
+    pub fn into_base(self) -> B {
+        self.base
+    }
+
+    /// Encode `target` as a delta against the base.
+    pub fn encode(&self, target: &[u8]) -> Vec<u8> {
+        self.encode_limited(target, usize::MAX).expect("no size limit")
+    }
+
+    /// Encode `target` as a delta against the base, giving up (and returning
+    /// `None`) as soon as the instructions would be bigger than `max_size`.
+    pub fn encode_limited(&self, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let base = self.base.as_ref();
+        let mut output = Vec::with_capacity(target.len() / 4 + 16);
+        write_varint(base.len(), &mut output);
+        write_varint(target.len(), &mut output);
+
+        let mut insert_from = 0;
+        let mut position = 0;
+        let mut hash = None;
+        while position + WINDOW <= target.len() {
+            let current = match hash {
+                Some(xs) => xs,
+                None => window_hash(&target[position..position + WINDOW])
+            };
+
+            match self.longest_match(current, &target[position..]) {
+                Some((mut offset, mut len)) => {
+                    // the bytes just before the match may match the base, too
+                    while offset > 0 && position > insert_from && base[offset - 1] == target[position - 1] {
+                        offset -= 1;
+                        position -= 1;
+                        len += 1;
                     }
-                    return Ok(written)
+
+                    write_inserts(&target[insert_from..position], &mut output);
+                    write_copies(offset, len, &mut output);
+                    position += len;
+                    insert_from = position;
+                    hash = None;
                 },
 
-                DeltaDecoderState::NextCommand => {
-                    if self.index >= self.instructions.len() {
-                        self.written += written;
-                        (DeltaDecoderState::Done, false)
-                    } else {
-                        let cmd = self.instructions[self.index];
-                        self.index += 1;
-                        let next = if (cmd & 0x80) != 0 {
-                            // copy
-                            let mut check = 1;
-                            let mut offset: usize = 0;
-                            let mut extent: usize = 0;
-
-                            for i in 0..4 {
-                                if (cmd & check) != 0 {
-                                    offset |= (self.instructions[self.index] as usize) << (8 * i);
-                                    self.index += 1;
-                                }
-                                check <<= 1;
-                            }
-
-                            for i in 0..3 {
-                                if (cmd & check) != 0 {
-                                    extent |= (self.instructions[self.index] as usize) << (8 * i);
-                                    self.index += 1;
-                                }
-                                check <<= 1;
-                            }
-
-                            // read 4 bytes out of delta -> extent
-
-                            extent &= 0x00FF_FFFF;
-                            extent = if extent == 0 { 0x10000 } else { extent };
-                            DeltaDecoderState::Copy(CopyState {
-                                offset,
-                                extent
-                            })
-                        } else {
-                            DeltaDecoderState::Insert(InsertState {
-                                extent: cmd as usize
-                            })
-                        };
-
-                        (next, false)
+                None => {
+                    if position + WINDOW < target.len() {
+                        let outgoing = target[position] as u32;
+                        let incoming = target[position + WINDOW] as u32;
+                        hash = Some(
+                            current.wrapping_sub(outgoing.wrapping_mul(WINDOW_POWER))
+                                .wrapping_mul(HASH_MULTIPLIER)
+                                .wrapping_add(incoming)
+                        );
                     }
+                    position += 1;
+                }
+            }
+
+            if output.len() > max_size {
+                return None
+            }
+        }
+
+        write_inserts(&target[insert_from..], &mut output);
+        if output.len() > max_size {
+            return None
+        }
+
+        Some(output)
+    }
+
+    // Find the base offset that matches the most bytes at the start of `target`.
+    fn longest_match(&self, hash: u32, target: &[u8]) -> Option<(usize, usize)> {
+        let base = &self.base.as_ref()[..self.copyable];
+        let mut best: Option<(usize, usize)> = None;
+        for offset in self.index.get(&hash)? {
+            let len = base[*offset..].iter()
+                .zip(target.iter())
+                .take_while(|(lhs, rhs)| lhs == rhs)
+                .count();
+
+            if len >= WINDOW && best.map(|(_, best_len)| len > best_len).unwrap_or(true) {
+                best = Some((*offset, len));
+            }
+        }
+        best
+    }
+}
+
+// HASH_MULTIPLIER ^ (WINDOW - 1): the weight of the oldest byte in a window.
+const WINDOW_POWER: u32 = {
+    let mut power = 1u32;
+    let mut idx = 1;
+    while idx < WINDOW {
+        power = power.wrapping_mul(HASH_MULTIPLIER);
+        idx += 1;
+    }
+    power
+};
+
+fn window_hash(window: &[u8]) -> u32 {
+    window.iter().fold(0u32, |acc, byte| acc.wrapping_mul(HASH_MULTIPLIER).wrapping_add(*byte as u32))
+}
+
+fn write_varint(mut value: usize, output: &mut Vec<u8>) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn write_inserts(mut data: &[u8], output: &mut Vec<u8>) {
+    while !data.is_empty() {
+        let len = data.len().min(MAX_INSERT);
+        output.push(len as u8);
+        output.extend_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+}
+
+// Copy instructions are 0x80, plus a bit for each non-zero byte of the offset (4
+// bytes) and extent (3 bytes) that follow, least significant first.
+fn write_copies(mut offset: usize, mut len: usize, output: &mut Vec<u8>) {
+    while len > 0 {
+        let extent = len.min(MAX_COPY);
+        let command = output.len();
+        output.push(0x80);
+
+        for (bit, byte) in (offset as u32).to_le_bytes().iter().enumerate() {
+            if *byte != 0 {
+                output[command] |= 1 << bit;
+                output.push(*byte);
+            }
+        }
+
+        for (bit, byte) in (extent as u32).to_le_bytes()[..3].iter().enumerate() {
+            if *byte != 0 {
+                output[command] |= 0x10 << bit;
+                output.push(*byte);
+            }
+        }
+
+        offset += extent;
+        len -= extent;
+    }
+}
+
+impl DeltaDecoderStream {
+    // Decode the next instruction, checking it against the base and the
+    // declared output size, or finish if there are none left.
+    fn next_state(&mut self) -> Result<DeltaDecoderState> {
+        if self.index >= self.instructions.len() {
+            if self.written != self.output_size {
+                return Err(ErrorKind::DeltaOutputSizeMismatch {
+                    expected: self.output_size as u64,
+                    actual: self.written as u64
+                }.into())
+            }
+            return Ok(DeltaDecoderState::Done)
+        }
+
+        let state = match next_instruction(&self.instructions, &mut self.index, self.inner.len())? {
+            Instruction::Copy(offset, extent) => DeltaDecoderState::Copy(CopyState { offset, extent }),
+            Instruction::Insert(start, extent) => {
+                // the bytes are read from the instructions as they're written
+                self.index = start;
+                DeltaDecoderState::Insert(InsertState { extent })
+            }
+        };
+
+        let extent = match &state {
+            DeltaDecoderState::Copy(copy) => copy.extent,
+            DeltaDecoderState::Insert(insert) => insert.extent,
+            _ => 0
+        };
+        self.written = check_output(self.written, extent, self.output_size)?;
+        Ok(state)
+    }
+}
+
+impl std::io::Read for DeltaDecoderStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            match &mut self.state {
+                DeltaDecoderState::Done => break,
+
+                DeltaDecoderState::NextCommand => {
+                    self.state = self.next_state()
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
                 },
 
                 DeltaDecoderState::Copy(state) => {
-                    let wrote = buf.write(&self.inner[state.offset .. state.offset + state.extent])?;
-
-                    let extent = state.extent - wrote;
-                    let offset = state.offset + wrote;
-                    written += wrote;
-                    if extent == 0 {
-                        (DeltaDecoderState::NextCommand, false)
-                    } else {
-                        (DeltaDecoderState::Copy(CopyState {
-                            extent,
-                            offset
-                        }), true)
+                    let len = state.extent.min(buf.len() - written);
+                    buf[written..written + len].copy_from_slice(&self.inner[state.offset..state.offset + len]);
+                    state.offset += len;
+                    state.extent -= len;
+                    written += len;
+                    if state.extent == 0 {
+                        self.state = DeltaDecoderState::NextCommand;
                     }
                 },
 
                 DeltaDecoderState::Insert(state) => {
-                    let wrote = buf.write(&self.instructions[self.index .. self.index + state.extent])?;
-                    self.index += wrote;
-                    let extent = state.extent - wrote;
-                    written += wrote;
-
-                    if extent == 0 {
-                        (DeltaDecoderState::NextCommand, false)
-                    } else {
-                        (DeltaDecoderState::Insert(InsertState {
-                            extent
-                        }), true)
+                    let len = state.extent.min(buf.len() - written);
+                    buf[written..written + len].copy_from_slice(&self.instructions[self.index..self.index + len]);
+                    self.index += len;
+                    state.extent -= len;
+                    written += len;
+                    if state.extent == 0 {
+                        self.state = DeltaDecoderState::NextCommand;
                     }
                 }
-            };
-
-            self.state = next_state;
-            if exhausted {
-                break
             }
         }
 
-        self.written += written;
         Ok(written)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{ DeltaDecoder, DeltaDecoderStream };
+    use super::{ DeltaDecoder, DeltaDecoderStream, DeltaEncoder };
     use std::io::Read;
 
+    use crate::errors::ErrorKind;
+
     use crate::objects::commit::Commit;
 
     #[test]
@@ -227,4 +544,152 @@ mod tests {
         let msg = std::str::from_utf8(commit.message()).expect("invalid string");
         assert_eq!(msg, "add assert.end() to utils tests\n");
     }
+
+    fn round_trip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let instructions = DeltaEncoder::new(base).encode(target);
+        let decoder = DeltaDecoder::new(&instructions, base.to_vec()).expect("wrong base size");
+        let mut stream: DeltaDecoderStream = decoder.into();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).expect("failed to apply delta");
+        assert_eq!(output, target);
+        instructions
+    }
+
+    // a deterministic stream of bytes that doesn't repeat itself
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn encodes_deltas_that_round_trip() {
+        let base = include_bytes!("../fixtures/delta_base");
+        let expected = include_bytes!("../fixtures/delta_expected");
+        round_trip(base, expected);
+
+        round_trip(b"", b"");
+        round_trip(b"", b"something from nothing");
+        round_trip(b"something from nothing", b"");
+        round_trip(&noise(1, 300), &noise(2, 300));
+
+        // a long insert spans several insert instructions
+        round_trip(b"short", &noise(3, 1000));
+    }
+
+    #[test]
+    fn copies_from_the_base() {
+        let base = noise(4, 200_000);
+
+        // identical objects are a handful of copies (each at most 0x10000 bytes)
+        let instructions = round_trip(&base, &base);
+        assert!(instructions.len() < 32, "{} bytes", instructions.len());
+
+        // small edits cost roughly their size
+        let mut target = base.clone();
+        target[1000..1010].copy_from_slice(b"0123456789");
+        target.splice(150_000..150_000, b"inserted".iter().cloned());
+        target.drain(50_000..50_100);
+        let instructions = round_trip(&base, &target);
+        assert!(instructions.len() < 128, "{} bytes", instructions.len());
+    }
+
+    #[test]
+    fn applies_into_writers() {
+        let base = include_bytes!("../fixtures/delta_base");
+        let instructions = include_bytes!("../fixtures/delta_instructions");
+        let expected = include_bytes!("../fixtures/delta_expected");
+
+        let mut output = Vec::new();
+        let written = super::apply(instructions, base, &mut output).expect("failed to apply delta");
+        assert_eq!(written, 282);
+        assert_eq!(&output[..], &expected[..]);
+
+        // a chain of deltas, each against the result of the last
+        let versions: Vec<Vec<u8>> = (0..5u8).map(|idx| {
+            let mut version = noise(6, 5000);
+            version[idx as usize * 1000] = idx;
+            version
+        }).collect();
+        let mut data = versions[0].clone();
+        let mut target = Vec::new();
+        for pair in versions.windows(2) {
+            let instructions = DeltaEncoder::new(&pair[0]).encode(&pair[1]);
+            target.clear();
+            super::apply(&instructions, &data, &mut target).expect("failed to apply delta");
+            std::mem::swap(&mut data, &mut target);
+        }
+        assert_eq!(&data, versions.last().unwrap());
+
+        assert!(super::apply(instructions, &base[1..], &mut Vec::new()).is_err());
+        assert!(super::apply(&instructions[..instructions.len() - 1], base, &mut Vec::new()).is_err());
+    }
+
+    // Apply `instructions` both ways, checking that they fail the same way.
+    fn apply_error(instructions: &[u8], base: &[u8]) -> ErrorKind {
+        let error = super::apply(instructions, base, &mut Vec::new()).expect_err("the delta is malformed");
+        let streamed = DeltaDecoder::new(instructions, base.to_vec()).and_then(|decoder| {
+            let mut stream: DeltaDecoderStream = decoder.into();
+            stream.read_to_end(&mut Vec::new())?;
+            Ok(())
+        }).expect_err("the delta is malformed");
+
+        assert_eq!(error.kind(), streamed.kind());
+        error.kind().clone()
+    }
+
+    #[test]
+    fn rejects_malformed_deltas() {
+        let base = b"0123456789";
+
+        // sizes, then instructions
+        assert_eq!(apply_error(&[10], base), ErrorKind::TruncatedDeltaInstructions);
+        assert_eq!(apply_error(&[10, 0x80], base), ErrorKind::TruncatedDeltaInstructions);
+        assert_eq!(apply_error(&[0xff; 12], base), ErrorKind::TruncatedDeltaInstructions);
+        assert_eq!(apply_error(&[9, 4, 0x90, 4], base), ErrorKind::BadDeltaBase);
+
+        assert_eq!(apply_error(&[10, 4, 0], base), ErrorKind::ReservedDeltaOpcode);
+        assert_eq!(apply_error(&[10, 4, 4, b'a'], base), ErrorKind::TruncatedDeltaInstructions);
+        assert_eq!(apply_error(&[10, 4, 0x91], base), ErrorKind::TruncatedDeltaInstructions);
+        assert_eq!(
+            apply_error(&[10, 4, 0x91, 8, 4], base),
+            ErrorKind::DeltaCopyOutOfRange { offset: 8, extent: 4, base_size: 10 }
+        );
+
+        // an extent of zero means 0x10000
+        assert_eq!(
+            apply_error(&[10, 4, 0x80], base),
+            ErrorKind::DeltaCopyOutOfRange { offset: 0, extent: 0x10000, base_size: 10 }
+        );
+
+        assert_eq!(apply_error(&[10, 4, 0x90, 3], base), ErrorKind::DeltaOutputSizeMismatch { expected: 4, actual: 3 });
+        assert_eq!(apply_error(&[10, 4, 0x90, 5], base), ErrorKind::DeltaOutputSizeMismatch { expected: 4, actual: 5 });
+
+        let mut output = Vec::new();
+        assert_eq!(super::apply(&[10, 4, 0x91, 2, 4], base, &mut output).expect("the delta is fine"), 4);
+        assert_eq!(&output, b"2345");
+    }
+
+    #[test]
+    fn gives_up_past_the_size_limit() {
+        let encoder = DeltaEncoder::new(b"base");
+        assert!(encoder.encode_limited(&noise(5, 1000), 100).is_none());
+        assert!(encoder.encode_limited(b"base", 100).is_some());
+    }
+
+    #[test]
+    fn never_copies_past_the_end_of_the_copyable_base() {
+        let base = noise(7, 4096);
+        let encoder = DeltaEncoder::with_copy_end(&base[..], 1000);
+        let instructions = encoder.encode(&base);
+
+        let mut output = Vec::new();
+        super::apply(&instructions, &base, &mut output).expect("failed to apply delta");
+        assert_eq!(output, base);
+
+        // everything from the end of the copyable part on has to be inserted
+        assert!(instructions.len() > 3096, "{} bytes", instructions.len());
+    }
 }
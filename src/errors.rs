@@ -1,21 +1,388 @@
-error_chain! {
-    foreign_links {
-        Io(::std::io::Error);
-        Encoding(::std::str::Utf8Error);
-        BadMode(::std::num::ParseIntError);
-    }
-
-    errors {
-        BadId
-        TruncatedDeltaOutput
-        BadDeltaBase
-        BadLooseObject
-        NotImplemented
-        CorruptedPackfile
-        InvalidPackfileIndex
-        UnsupportedPackfileIndexVersion
-        CorruptedPackfileIndex
-        NeedStorageSet
-        InvalidObjectType
+use std::path::{ Path, PathBuf };
+use std::fmt;
+
+use crate::id::Id;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// What went wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    Io(std::io::ErrorKind),
+    Encoding,
+    BadMode,
+    BadId,
+    InvalidRef,
+    BadDeltaBase,
+    TruncatedDeltaInstructions,
+    ReservedDeltaOpcode,
+    DeltaCopyOutOfRange { offset: u64, extent: u64, base_size: u64 },
+    DeltaOutputSizeMismatch { expected: u64, actual: u64 },
+    BadLooseObject,
+    CorruptedPackfile,
+    UnsupportedPackfileVersion(u32),
+    InvalidPackfileIndex,
+    UnsupportedPackfileIndexVersion,
+    OffsetTooLargeForIndexVersion { offset: u64, version: u32 },
+    CorruptedPackfileIndex,
+    NeedStorageSet,
+    InvalidObjectType,
+    InvalidTag,
+    InvalidMultiPackIndex,
+    UnsupportedMultiPackIndexVersion,
+    CorruptedChunkTable,
+    MissingChunk(String),
+    CorruptedReverseIndex,
+    UnsupportedReverseIndexVersion,
+    CorruptedMtimes,
+    UnsupportedMtimesVersion,
+    InvalidCommitGraph,
+    UnsupportedCommitGraphVersion,
+    CorruptedBitmapIndex,
+    UnsupportedBitmapIndexVersion,
+    ObjectNotFound,
+    MissingDeltaBase(Id),
+    ObjectHashMismatch(Id),
+    ObjectSizeMismatch { expected: u64, actual: u64 },
+    Crc32Mismatch { expected: u32, actual: u32 },
+    DuplicateObject(Id),
+    WrongObjectCount { expected: u32, actual: u32 },
+    CorruptedIndexFile,
+    UnsupportedIndexExtension(String),
+    InvalidExpiry(String),
+    SizeLimitExceeded { size: u64, limit: u64 }
+}
+
+impl ErrorKind {
+    /// Whether this kind of error means that an object isn't available (as
+    /// opposed to present, but damaged.)
+    pub fn is_missing(&self) -> bool {
+        matches!(self, ErrorKind::ObjectNotFound | ErrorKind::MissingDeltaBase(_))
+    }
+
+    /// Whether this kind of error means that stored data is damaged.
+    pub fn is_corrupt(&self) -> bool {
+        matches!(self,
+            ErrorKind::Encoding |
+            ErrorKind::BadMode |
+            ErrorKind::BadDeltaBase |
+            ErrorKind::TruncatedDeltaInstructions |
+            ErrorKind::ReservedDeltaOpcode |
+            ErrorKind::DeltaCopyOutOfRange { .. } |
+            ErrorKind::DeltaOutputSizeMismatch { .. } |
+            ErrorKind::BadLooseObject |
+            ErrorKind::CorruptedPackfile |
+            ErrorKind::InvalidPackfileIndex |
+            ErrorKind::CorruptedPackfileIndex |
+            ErrorKind::InvalidObjectType |
+            ErrorKind::InvalidTag |
+            ErrorKind::InvalidMultiPackIndex |
+            ErrorKind::CorruptedChunkTable |
+            ErrorKind::MissingChunk(_) |
+            ErrorKind::CorruptedReverseIndex |
+            ErrorKind::CorruptedMtimes |
+            ErrorKind::InvalidCommitGraph |
+            ErrorKind::CorruptedBitmapIndex |
+            ErrorKind::ObjectHashMismatch(_) |
+            ErrorKind::ObjectSizeMismatch { .. } |
+            ErrorKind::Crc32Mismatch { .. } |
+            ErrorKind::CorruptedIndexFile
+        )
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Io(kind) => write!(f, "i/o error ({:?})", kind),
+            ErrorKind::Encoding => write!(f, "invalid utf-8"),
+            ErrorKind::BadMode => write!(f, "invalid number"),
+            ErrorKind::BadId => write!(f, "invalid object id"),
+            ErrorKind::InvalidRef => write!(f, "invalid ref"),
+            ErrorKind::BadDeltaBase => write!(f, "delta base has the wrong size"),
+            ErrorKind::TruncatedDeltaInstructions => write!(f, "truncated or malformed delta instructions"),
+            ErrorKind::ReservedDeltaOpcode => write!(f, "delta uses the reserved instruction 0"),
+            ErrorKind::DeltaCopyOutOfRange { offset, extent, base_size } => {
+                write!(f, "delta copies {} bytes from offset {} of a {} byte base", extent, offset, base_size)
+            },
+            ErrorKind::DeltaOutputSizeMismatch { expected, actual } => {
+                write!(f, "delta should produce {} bytes, but produces {}", expected, actual)
+            },
+            ErrorKind::BadLooseObject => write!(f, "corrupted loose object"),
+            ErrorKind::CorruptedPackfile => write!(f, "corrupted packfile"),
+            ErrorKind::UnsupportedPackfileVersion(version) => write!(f, "unsupported packfile version {}", version),
+            ErrorKind::InvalidPackfileIndex => write!(f, "invalid packfile index"),
+            ErrorKind::UnsupportedPackfileIndexVersion => write!(f, "unsupported packfile index version"),
+            ErrorKind::OffsetTooLargeForIndexVersion { offset, version } => {
+                write!(f, "version {} packfile indexes can't hold offset {}", version, offset)
+            },
+            ErrorKind::CorruptedPackfileIndex => write!(f, "corrupted packfile index"),
+            ErrorKind::NeedStorageSet => write!(f, "a storage set is needed to resolve ref deltas"),
+            ErrorKind::InvalidObjectType => write!(f, "invalid object type"),
+            ErrorKind::InvalidTag => write!(f, "tag doesn't name a valid object"),
+            ErrorKind::InvalidMultiPackIndex => write!(f, "invalid multi-pack-index"),
+            ErrorKind::UnsupportedMultiPackIndexVersion => write!(f, "unsupported multi-pack-index version"),
+            ErrorKind::CorruptedChunkTable => write!(f, "corrupted chunk table"),
+            ErrorKind::MissingChunk(id) => write!(f, "missing chunk: {}", id),
+            ErrorKind::CorruptedReverseIndex => write!(f, "corrupted reverse index"),
+            ErrorKind::UnsupportedReverseIndexVersion => write!(f, "unsupported reverse index version"),
+            ErrorKind::CorruptedMtimes => write!(f, "corrupted pack mtimes"),
+            ErrorKind::UnsupportedMtimesVersion => write!(f, "unsupported pack mtimes version"),
+            ErrorKind::InvalidCommitGraph => write!(f, "invalid commit-graph"),
+            ErrorKind::UnsupportedCommitGraphVersion => write!(f, "unsupported commit-graph version"),
+            ErrorKind::CorruptedBitmapIndex => write!(f, "corrupted bitmap index"),
+            ErrorKind::UnsupportedBitmapIndexVersion => write!(f, "unsupported bitmap index version"),
+            ErrorKind::ObjectNotFound => write!(f, "object not found"),
+            ErrorKind::MissingDeltaBase(id) => write!(f, "missing delta base {}", id),
+            ErrorKind::ObjectHashMismatch(actual) => write!(f, "object contents hash to {}", actual),
+            ErrorKind::ObjectSizeMismatch { expected, actual } => {
+                write!(f, "object should be {} bytes, but is {} bytes", expected, actual)
+            },
+            ErrorKind::Crc32Mismatch { expected, actual } => {
+                write!(f, "packed object should have crc32 {:08x}, but has {:08x}", expected, actual)
+            },
+            ErrorKind::DuplicateObject(id) => write!(f, "object {} was written twice", id),
+            ErrorKind::WrongObjectCount { expected, actual } => {
+                write!(f, "packfile should have {} objects, but has {}", expected, actual)
+            },
+            ErrorKind::CorruptedIndexFile => write!(f, "corrupted index file"),
+            ErrorKind::UnsupportedIndexExtension(signature) => {
+                write!(f, "index file uses the unsupported {:?} extension", signature)
+            },
+            ErrorKind::InvalidExpiry(value) => write!(f, "invalid expiry date {:?}", value),
+            ErrorKind::SizeLimitExceeded { size, limit } => {
+                write!(f, "object of {} bytes is over the limit of {} bytes", size, limit)
+            }
+        }
+    }
+}
+
+/// An error, along with where it happened: the operation being performed, and
+/// (when known) the object, file and byte offset involved.
+///
+/// Context is added as the error propagates outwards using `ResultExt`; the
+/// innermost (most specific) value of each piece of context is kept.
+#[derive(Debug)]
+pub struct Error(Box<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    kind: ErrorKind,
+    operation: Option<&'static str>,
+    id: Option<Id>,
+    path: Option<PathBuf>,
+    offset: Option<u64>,
+    source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error(Box::new(Inner {
+            kind,
+            operation: None,
+            id: None,
+            path: None,
+            offset: None,
+            source: None
+        }))
+    }
+
+    pub fn with_source<E: std::error::Error + Send + Sync + 'static>(kind: ErrorKind, source: E) -> Self {
+        let mut error = Error::new(kind);
+        error.0.source = Some(Box::new(source));
+        error
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.0.kind
+    }
+
+    /// What we were doing when the error happened, e.g. "reading object".
+    pub fn operation(&self) -> Option<&'static str> {
+        self.0.operation
+    }
+
+    /// The object being read when the error happened.
+    pub fn id(&self) -> Option<&Id> {
+        self.0.id.as_ref()
+    }
+
+    /// The file being read when the error happened.
+    pub fn path(&self) -> Option<&Path> {
+        self.0.path.as_deref()
+    }
+
+    /// The offset into `path` at which the error happened.
+    pub fn offset(&self) -> Option<u64> {
+        self.0.offset
+    }
+
+    /// Treat a failure to inflate zlib data as `kind` (i.e., a corrupted object.)
+    pub fn inflate(kind: ErrorKind, error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::InvalidInput |
+            std::io::ErrorKind::InvalidData |
+            std::io::ErrorKind::UnexpectedEof => Error::with_source(kind, error),
+            _ => error.into()
+        }
+    }
+
+    pub fn is_missing(&self) -> bool {
+        self.0.kind.is_missing()
+    }
+
+    pub fn is_corrupt(&self) -> bool {
+        self.0.kind.is_corrupt()
+    }
+
+    pub fn during(mut self, operation: &'static str) -> Self {
+        self.0.operation.get_or_insert(operation);
+        self
+    }
+
+    pub fn for_object(mut self, id: &Id) -> Self {
+        if self.0.id.is_none() {
+            self.0.id = Some(id.clone());
+        }
+        self
+    }
+
+    pub fn in_file(mut self, path: &Path) -> Self {
+        if self.0.path.is_none() {
+            self.0.path = Some(path.to_path_buf());
+        }
+        self
+    }
+
+    pub fn at_offset(mut self, offset: u64) -> Self {
+        self.0.offset.get_or_insert(offset);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.kind)?;
+
+        if let Some(operation) = self.0.operation {
+            write!(f, " while {}", operation)?;
+        }
+
+        if let Some(id) = &self.0.id {
+            write!(f, " (object {})", id)?;
+        }
+
+        if let Some(path) = &self.0.path {
+            write!(f, " in {}", path.display())?;
+        }
+
+        if let Some(offset) = self.0.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source.as_ref().map(|source| source.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new(kind)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        // readers (like `DeltaDecoderStream`) pass our errors along wrapped in
+        // i/o errors
+        if error.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = error.into_inner().and_then(|inner| inner.downcast::<Error>().ok());
+            return *inner.expect("the error was checked to be ours")
+        }
+
+        Error::with_source(ErrorKind::Io(error.kind()), error)
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(error: std::str::Utf8Error) -> Self {
+        Error::with_source(ErrorKind::Encoding, error)
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(error: std::num::ParseIntError) -> Self {
+        Error::with_source(ErrorKind::BadMode, error)
+    }
+}
+
+/// Add context to the error in a `Result`.
+pub trait ResultExt<T> {
+    fn during(self, operation: &'static str) -> Result<T>;
+    fn for_object(self, id: &Id) -> Result<T>;
+    fn in_file(self, path: &Path) -> Result<T>;
+    fn at_offset(self, offset: u64) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn during(self, operation: &'static str) -> Result<T> {
+        self.map_err(|e| e.into().during(operation))
+    }
+
+    fn for_object(self, id: &Id) -> Result<T> {
+        self.map_err(|e| e.into().for_object(id))
+    }
+
+    fn in_file(self, path: &Path) -> Result<T> {
+        self.map_err(|e| e.into().in_file(path))
+    }
+
+    fn at_offset(self, offset: u64) -> Result<T> {
+        self.map_err(|e| e.into().at_offset(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+    use std::path::Path;
+
+    use super::{ Error, ErrorKind, ResultExt };
+    use crate::id::Id;
+
+    #[test]
+    fn keeps_the_innermost_context() {
+        let inner: super::Result<()> = Err(ErrorKind::CorruptedPackfile.into());
+        let error = inner
+            .at_offset(12)
+            .for_object(&Id::default())
+            .at_offset(0)
+            .in_file(Path::new("pack-1.pack"))
+            .during("reading object")
+            .unwrap_err();
+
+        assert_eq!(error.kind(), &ErrorKind::CorruptedPackfile);
+        assert_eq!(error.offset(), Some(12));
+        assert!(error.is_corrupt());
+        assert!(!error.is_missing());
+        assert_eq!(
+            error.to_string(),
+            format!("corrupted packfile while reading object (object {}) in pack-1.pack at offset 12", Id::default())
+        );
+    }
+
+    #[test]
+    fn keeps_foreign_errors_as_sources() {
+        let io: std::io::Result<()> = Err(std::io::ErrorKind::UnexpectedEof.into());
+        let error: Error = io.during("reading object").unwrap_err();
+
+        assert_eq!(error.kind(), &ErrorKind::Io(std::io::ErrorKind::UnexpectedEof));
+        assert!(error.source().is_some());
+        assert!(!error.is_corrupt());
     }
 }
@@ -1,4 +1,4 @@
-use chrono::{ DateTime, Utc, FixedOffset, NaiveDateTime };
+use chrono::{ DateTime, Utc, FixedOffset };
 use once_cell::sync::OnceCell;
 use std::ops::Deref;
 
@@ -97,10 +97,11 @@ impl ParsedHumanMetadata {
 
             let timestamp = timestamp_str.parse::<i64>()
                 .ok()
-                .and_then(|ts| NaiveDateTime::from_timestamp_opt(ts, 0))
-                .map(|naive| DateTime::<Utc>::from_utc(naive, Utc));
+                .and_then(|ts| DateTime::<Utc>::from_timestamp(ts, 0));
 
-            let timezone_offset = FixedOffset::east_opt(offset_mins * 60 + offset_hours * 60 * 60);
+            let timezone_offset = offset_hours.checked_mul(60 * 60)
+                .and_then(|seconds| seconds.checked_add(offset_mins * 60))
+                .and_then(FixedOffset::east_opt);
 
             Self {
                 name_end,
@@ -198,7 +199,8 @@ impl HumanMetadata {
             ParsedHumanMetadata::new(&self.data)
         });
 
-        &self.data[offsets.time_end + 1..]
+        // (there's no offset if the metadata couldn't be parsed)
+        self.data.get(offsets.time_end + 1..).unwrap_or_default()
     }
 }
 
@@ -1,8 +1,7 @@
-#[macro_use]
-extern crate error_chain;
-
 pub mod id;
 pub mod delta;
+pub mod chunk;
+pub mod commit_graph;
 pub mod errors;
 pub mod stores;
 pub mod objects;
@@ -11,6 +10,9 @@ pub mod refs;
 pub mod walk;
 pub mod human_metadata;
 
+#[cfg(test)]
+mod test_support;
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -1,13 +1,15 @@
+use crypto::{ sha1::Sha1, digest::Digest };
 use crate::pack::internal_type::PackfileType;
 use std::convert::{ TryFrom, From };
 use crate::errors::Result;
+use crate::id::Id;
 
 pub mod commit;
 pub mod blob;
 pub mod tree;
 pub mod tag;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Type {
     Commit,
     Tree,
@@ -70,6 +72,17 @@ impl Type {
         }
     }
 
+    /// Compute the id of an object of this type with contents `data`.
+    pub fn hash(&self, data: &[u8]) -> Id {
+        let mut hash = Sha1::new();
+        hash.input(format!("{} {}\0", self.as_str(), data.len()).as_bytes());
+        hash.input(data);
+
+        let mut id = [0u8; 20];
+        hash.result(&mut id);
+        id.into()
+    }
+
     pub fn load<T: std::io::Read>(&self, stream: &mut T) -> Result<Object> {
         match &self {
             Type::Commit => {
@@ -6,6 +6,17 @@ use crate::id::Id;
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct FileMode(u32);
 
+impl FileMode {
+    pub fn is_tree(&self) -> bool {
+        self.0 & 0o170000 == 0o040000
+    }
+
+    /// Whether the entry is a submodule commit, which lives in another repository.
+    pub fn is_submodule(&self) -> bool {
+        self.0 & 0o170000 == 0o160000
+    }
+}
+
 #[derive(Debug)]
 pub struct TreeEntry {
     pub mode: FileMode,
@@ -121,7 +132,7 @@ mod tests {
 
         assert_eq!(entries.join("\n"), "errors.rs\nid.rs\nlib.rs\nobjects");
         let tree_entries = entries.iter().map(|xs| tree.entries.get(xs.as_bytes()).unwrap());
-        let modes: Vec<FileMode> = tree_entries.clone().map(|xs| xs.mode.clone()).collect();
+        let modes: Vec<FileMode> = tree_entries.clone().map(|xs| xs.mode).collect();
         assert_eq!(
             modes,
             vec![FileMode(0o100644), FileMode(0o100644), FileMode(0o100644), FileMode(0o40000)]
@@ -2,8 +2,8 @@ use std::io::{ BufReader, SeekFrom };
 use std::io::{ Read, Write, Seek };
 
 use crate::stores::{ Queryable, StorageSet };
-use crate::pack::read::packfile_read;
-use crate::errors::Result;
+use crate::pack::read::{ packfile_read_cached, packfile_read_limited, packfile_read_type, DeltaBaseCache };
+use crate::errors::{ ErrorKind, Result };
 use crate::pack::Packfile;
 use crate::objects::Type;
 use crate::id::Id;
@@ -36,7 +36,7 @@ impl<R: Read + Seek + std::fmt::Debug> Packfile for Reader<R> {
         let mut buffered_file = BufReader::new(handle);
         buffered_file.seek(SeekFrom::Start(start))?;
 
-        let meta = packfile_read(&mut buffered_file, output, &mut 0)?;
+        let meta = packfile_read_limited(&mut buffered_file, output, &mut 0, backends.limits())?;
         let obj_type = meta.decompress(
             start,
             &mut buffered_file,
@@ -45,4 +45,35 @@ impl<R: Read + Seek + std::fmt::Debug> Packfile for Reader<R> {
         )?;
         Ok(obj_type)
     }
+
+    fn read_cached<W: Write, S: Queryable>(
+        &self,
+        start: u64,
+        output: &mut W,
+        backends: &StorageSet<S>,
+        cache: &mut DeltaBaseCache
+    ) -> Result<Type> {
+        let handle = (self.read)()?;
+        let mut buffered_file = BufReader::new(handle);
+        packfile_read_cached(&mut buffered_file, start, output, Some(backends), cache)
+    }
+
+    fn read_type<S: Queryable>(&self, start: u64, backends: &StorageSet<S>) -> Result<Type> {
+        let handle = (self.read)()?;
+        let mut buffered_file = BufReader::new(handle);
+        packfile_read_type(&mut buffered_file, start, Some(backends))
+    }
+
+    fn read_raw<W: Write>(&self, start: u64, end: u64, output: &mut W) -> Result<()> {
+        let mut handle = (self.read)()?;
+        let trailer = handle.seek(SeekFrom::End(0))?.saturating_sub(20);
+        let end = std::cmp::min(end, trailer);
+        if start > end {
+            return Err(ErrorKind::CorruptedPackfile.into())
+        }
+
+        handle.seek(SeekFrom::Start(start))?;
+        std::io::copy(&mut handle.take(end - start), output)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+
+use crate::errors::{ ErrorKind, Result };
+use crate::pack::index::Index;
+use crate::pack::ewah::Ewah;
+use crate::objects::Type;
+use crate::id::Id;
+
+const BITMAP_MAGIC: &[u8; 4] = b"BITM";
+const BITMAP_OPT_FULL_DAG: u16 = 0x1;
+const BITMAP_OPT_HASH_CACHE: u16 = 0x4;
+const BITMAP_OPT_LOOKUP_TABLE: u16 = 0x10;
+
+// git never writes xor chains that reach further back than this
+const MAX_XOR_OFFSET: u8 = 160;
+
+/// An uncompressed bitmap; bit `n` stands for the object at position `n` in
+/// packfile order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_words(words: Vec<u64>) -> Self {
+        Bitmap { words }
+    }
+
+    pub fn get(&self, bit: usize) -> bool {
+        self.words.get(bit / 64).map(|word| word & (1 << (bit % 64)) != 0).unwrap_or(false)
+    }
+
+    pub fn set(&mut self, bit: usize) {
+        if self.words.len() <= bit / 64 {
+            self.words.resize(bit / 64 + 1, 0);
+        }
+        self.words[bit / 64] |= 1 << (bit % 64);
+    }
+
+    pub fn or(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+
+        for (lhs, rhs) in self.words.iter_mut().zip(other.words.iter()) {
+            *lhs |= rhs;
+        }
+    }
+
+    pub fn xor(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+
+        for (lhs, rhs) in self.words.iter_mut().zip(other.words.iter()) {
+            *lhs ^= rhs;
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Count the bits set in both `self` and `other`.
+    pub fn count_ones_and(&self, other: &Bitmap) -> usize {
+        self.words.iter().zip(other.words.iter()).map(|(lhs, rhs)| (lhs & rhs).count_ones() as usize).sum()
+    }
+
+    /// Iterate over the positions of the bits that are set, in increasing order.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(idx, word)| {
+            let mut word = *word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None
+                }
+
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(idx * 64 + bit)
+            })
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: Id,
+    xor_offset: u8,
+    bitmap: Ewah
+}
+
+/// A reachability bitmap index (`.bitmap` file) for a single packfile.
+///
+/// Each selected commit has a bitmap of every object reachable from it; there
+/// are also bitmaps of every commit, tree, blob and tag in the pack. Bits are
+/// numbered by the object's position in the packfile (its offset order), so
+/// the index also keeps the mapping between packfile and index positions.
+#[derive(Debug)]
+pub struct BitmapIndex {
+    packfile_checksum: Id,
+    commits: Bitmap,
+    trees: Bitmap,
+    blobs: Bitmap,
+    tags: Bitmap,
+    entries: Vec<Entry>,
+    by_id: HashMap<Id, usize>,
+    name_hashes: Option<Vec<u32>>,
+    pack_order: Vec<u32>,
+    pack_positions: Vec<u32>
+}
+
+impl BitmapIndex {
+    /// The checksum of the packfile that this bitmap index describes.
+    pub fn packfile_checksum(&self) -> &Id {
+        &self.packfile_checksum
+    }
+
+    /// The number of commits that have a bitmap.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The ids of the commits that have a bitmap.
+    pub fn commits(&self) -> impl Iterator<Item = &Id> {
+        self.entries.iter().map(|entry| &entry.id)
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.by_id.contains_key(id)
+    }
+
+    /// The bitmap of every object of type `kind` in the packfile.
+    pub fn type_bitmap(&self, kind: Type) -> &Bitmap {
+        match kind {
+            Type::Commit => &self.commits,
+            Type::Tree => &self.trees,
+            Type::Blob => &self.blobs,
+            Type::Tag => &self.tags
+        }
+    }
+
+    /// Return the bitmap of objects reachable from the commit `id`, if it has one.
+    pub fn get(&self, id: &Id) -> Result<Option<Bitmap>> {
+        let mut idx = match self.by_id.get(id) {
+            Some(xs) => *xs,
+            None => return Ok(None)
+        };
+
+        // Entries may be stored xor'd against an earlier entry, which may in turn
+        // be xor'd against an earlier one; decode the chain from its base.
+        let mut chain = vec![idx];
+        while self.entries[idx].xor_offset > 0 {
+            idx -= self.entries[idx].xor_offset as usize;
+            chain.push(idx);
+        }
+
+        let mut bitmap = Bitmap::new();
+        for idx in chain.into_iter().rev() {
+            bitmap.xor(&self.entries[idx].bitmap.decode()?);
+        }
+
+        Ok(Some(bitmap))
+    }
+
+    /// The position in packfile order of the object at `index_position` in the
+    /// packfile index.
+    pub fn pack_position(&self, index_position: usize) -> Option<usize> {
+        self.pack_positions.get(index_position).map(|xs| *xs as usize)
+    }
+
+    /// The position in the packfile index of the object at `pack_position`.
+    pub fn index_position(&self, pack_position: usize) -> Option<usize> {
+        self.pack_order.get(pack_position).map(|xs| *xs as usize)
+    }
+
+    /// The path name hash recorded for the object at `index_position`, if the
+    /// bitmap index has a name-hash cache. Objects with the same hash are likely
+    /// to be good delta candidates for each other.
+    pub fn name_hash(&self, index_position: usize) -> Option<u32> {
+        self.name_hashes.as_ref()?.get(index_position).cloned()
+    }
+}
+
+/// Read a `.bitmap` file for the packfile described by `index`.
+pub fn read(bytes: &[u8], index: &Index) -> Result<BitmapIndex> {
+    if bytes.len() < 32 + 20 || &bytes[0..4] != BITMAP_MAGIC {
+        return Err(ErrorKind::CorruptedBitmapIndex.into())
+    }
+
+    if bytes[4..6] != [0, 1] {
+        return Err(ErrorKind::UnsupportedBitmapIndexVersion.into())
+    }
+
+    let options = u16::from_be_bytes([bytes[6], bytes[7]]);
+    if options & BITMAP_OPT_FULL_DAG == 0 {
+        return Err(ErrorKind::UnsupportedBitmapIndexVersion.into())
+    }
+
+    let entry_count = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+    let packfile_checksum = Id::new(&bytes[12..32]);
+    if &packfile_checksum != index.packfile_checksum() {
+        return Err(ErrorKind::CorruptedBitmapIndex.into())
+    }
+
+    // The optional extensions sit between the entries and the trailing checksum:
+    // the lookup table first, then the name-hash cache.
+    let mut end = bytes.len() - 20;
+    let mut name_hashes = None;
+    if options & BITMAP_OPT_HASH_CACHE != 0 {
+        let size = index.len() * 4;
+        if end < 32 + size {
+            return Err(ErrorKind::CorruptedBitmapIndex.into())
+        }
+
+        end -= size;
+        name_hashes = Some(bytes[end..end + size].chunks_exact(4).map(|xs| {
+            u32::from_be_bytes([xs[0], xs[1], xs[2], xs[3]])
+        }).collect());
+    }
+
+    if options & BITMAP_OPT_LOOKUP_TABLE != 0 {
+        // commit position, offset and xor row for each entry; we read every entry
+        // up front, so we don't need it.
+        let size = entry_count * 16;
+        if end < 32 + size {
+            return Err(ErrorKind::CorruptedBitmapIndex.into())
+        }
+        end -= size;
+    }
+
+    let bytes = &bytes[..end];
+    let mut cursor = 32;
+    let read_ewah = |cursor: &mut usize| -> Result<Ewah> {
+        let (ewah, len) = Ewah::read(&bytes[*cursor..])?;
+        *cursor += len;
+        Ok(ewah)
+    };
+
+    let commits = read_ewah(&mut cursor)?.decode()?;
+    let trees = read_ewah(&mut cursor)?.decode()?;
+    let blobs = read_ewah(&mut cursor)?.decode()?;
+    let tags = read_ewah(&mut cursor)?.decode()?;
+
+    let mut entries = Vec::with_capacity(entry_count);
+    let mut by_id = HashMap::with_capacity(entry_count);
+    for idx in 0..entry_count {
+        if bytes.len() < cursor + 6 {
+            return Err(ErrorKind::CorruptedBitmapIndex.into())
+        }
+
+        let position = u32::from_be_bytes([
+            bytes[cursor], bytes[cursor + 1], bytes[cursor + 2], bytes[cursor + 3]
+        ]) as usize;
+        let xor_offset = bytes[cursor + 4];
+        cursor += 6;
+
+        if xor_offset > MAX_XOR_OFFSET || xor_offset as usize > idx {
+            return Err(ErrorKind::CorruptedBitmapIndex.into())
+        }
+
+        let id = index.ids().get(position).ok_or(ErrorKind::CorruptedBitmapIndex)?.clone();
+        let bitmap = read_ewah(&mut cursor)?;
+
+        by_id.insert(id.clone(), idx);
+        entries.push(Entry { id, xor_offset, bitmap });
+    }
+
+    let pack_order = index.positions_by_offset();
+    let mut pack_positions = vec![0u32; pack_order.len()];
+    for (pack_position, index_position) in pack_order.iter().enumerate() {
+        pack_positions[*index_position as usize] = pack_position as u32;
+    }
+
+    Ok(BitmapIndex {
+        packfile_checksum,
+        commits,
+        trees,
+        blobs,
+        tags,
+        entries,
+        by_id,
+        name_hashes,
+        pack_order,
+        pack_positions
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::str::FromStr;
+
+    use crate::test_support::{ commit_file, git, scratch_repo };
+    use crate::pack::index::read as read_index;
+    use crate::objects::Type;
+    use crate::id::Id;
+
+    #[test]
+    fn read_bitmap_works() {
+        let index = read_index(Cursor::new(include_bytes!("../../fixtures/bitmap_pack_index").as_ref()))
+            .expect("failed to read index");
+        let bitmaps = super::read(include_bytes!("../../fixtures/pack_bitmap"), &index)
+            .expect("failed to read bitmap");
+
+        assert_eq!(bitmaps.len(), 6);
+        assert_eq!(bitmaps.type_bitmap(Type::Commit).count_ones(), 6);
+        assert_eq!(bitmaps.type_bitmap(Type::Tag).count_ones(), 1);
+        assert_eq!(bitmaps.type_bitmap(Type::Tree).count_ones(), 12);
+        assert_eq!(bitmaps.type_bitmap(Type::Blob).count_ones(), 12);
+
+        // `git rev-list --objects <id> | wc -l`
+        let head = Id::from_str("c124ce22f1658002a13c301f70da7f7b36db621a").unwrap();
+        let reachable = bitmaps.get(&head).expect("failed to decode").expect("head has a bitmap");
+        assert_eq!(reachable.count_ones(), 30);
+        assert_eq!(reachable.count_ones_and(bitmaps.type_bitmap(Type::Commit)), 6);
+
+        // the bits are in packfile order, so the first object is at the lowest offset
+        let first = bitmaps.index_position(0).unwrap();
+        assert_eq!(index.offsets()[first], 12);
+        assert_eq!(bitmaps.pack_position(first), Some(0));
+
+        assert!(bitmaps.name_hash(0).is_some());
+        assert!(bitmaps.get(index.packfile_checksum()).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bitmaps_for_other_packs() {
+        let index = read_index(Cursor::new(include_bytes!("../../fixtures/pack_index").as_ref()))
+            .expect("failed to read index");
+        assert!(super::read(include_bytes!("../../fixtures/pack_bitmap"), &index).is_err());
+    }
+
+    #[test]
+    fn reads_bitmaps_with_lookup_tables() {
+        let root = scratch_repo("bitmap-lookup-table");
+        for (idx, path) in ["a", "b/c", "b/d", "e"].iter().enumerate() {
+            commit_file(&root, path, &idx.to_string(), 1_600_000_000 + idx as u64 * 100);
+        }
+
+        let read = |lookup_table: &str| {
+            git(&root, &["-c", &format!("pack.writeBitmapLookupTable={}", lookup_table), "repack", "-adbq"]);
+            let pack_dir = root.join(".git/objects/pack");
+            let bitmap_path = std::fs::read_dir(&pack_dir).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| path.extension() == Some("bitmap".as_ref()))
+                .unwrap();
+            let bytes = std::fs::read(&bitmap_path).unwrap();
+            let index = read_index(Cursor::new(std::fs::read(bitmap_path.with_extension("idx")).unwrap()))
+                .expect("failed to read index");
+            let bitmaps = super::read(&bytes, &index).expect("failed to read bitmap");
+
+            let options = u16::from_be_bytes([bytes[6], bytes[7]]);
+            let mut counts: Vec<(Id, usize)> = bitmaps.commits().map(|id| {
+                (id.clone(), bitmaps.get(id).unwrap().unwrap().count_ones())
+            }).collect();
+            counts.sort();
+            (options & super::BITMAP_OPT_LOOKUP_TABLE != 0, counts)
+        };
+
+        let (has_table, without) = read("false");
+        assert!(!has_table);
+        let (has_table, with) = read("true");
+        assert!(has_table);
+        assert!(!with.is_empty());
+        assert_eq!(with, without);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::errors::{ ErrorKind, Result };
+use crate::pack::bitmap::Bitmap;
+
+/// A bitmap compressed with EWAH (as used by `.bitmap` files.)
+///
+/// The compressed words are a sequence of "run length words", each followed by
+/// some number of literal words. A run length word describes a run of words that
+/// are all zeroes or all ones, and how many literal words follow it.
+#[derive(Debug, Clone)]
+pub struct Ewah {
+    bit_size: u32,
+    words: Vec<u64>
+}
+
+impl Ewah {
+    /// Read a serialized EWAH bitmap from the start of `bytes`, returning it along
+    /// with the number of bytes it used.
+    pub fn read(bytes: &[u8]) -> Result<(Ewah, usize)> {
+        if bytes.len() < 8 {
+            return Err(ErrorKind::CorruptedBitmapIndex.into())
+        }
+
+        let bit_size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let word_count = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+
+        // header, words, then the position of the last run length word (which we
+        // don't need, since we never append to a bitmap we've read.)
+        let len = word_count.checked_mul(8)
+            .and_then(|xs| xs.checked_add(12))
+            .ok_or(ErrorKind::CorruptedBitmapIndex)?;
+
+        if bytes.len() < len {
+            return Err(ErrorKind::CorruptedBitmapIndex.into())
+        }
+
+        let words = bytes[8..8 + word_count * 8].chunks_exact(8).map(|word| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(word);
+            u64::from_be_bytes(buf)
+        }).collect();
+
+        Ok((Ewah { bit_size, words }, len))
+    }
+
+    /// The number of bits described by this bitmap.
+    pub fn bit_size(&self) -> u32 {
+        self.bit_size
+    }
+
+    pub fn decode(&self) -> Result<Bitmap> {
+        let word_count = (self.bit_size as usize).div_ceil(64);
+        let mut output = Vec::with_capacity(word_count);
+        let mut words = self.words.iter();
+
+        while let Some(rlw) = words.next() {
+            let running_bit = rlw & 1 == 1;
+            let running_len = ((rlw >> 1) & 0xffff_ffff) as usize;
+            let literal_len = (rlw >> 33) as usize;
+
+            if output.len() + running_len + literal_len > word_count {
+                return Err(ErrorKind::CorruptedBitmapIndex.into())
+            }
+
+            let fill = if running_bit { u64::MAX } else { 0 };
+            output.resize(output.len() + running_len, fill);
+
+            for _ in 0..literal_len {
+                output.push(*words.next().ok_or(ErrorKind::CorruptedBitmapIndex)?);
+            }
+        }
+
+        Ok(Bitmap::from_words(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ewah;
+
+    fn serialize(bit_size: u32, words: &[u64]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&bit_size.to_be_bytes());
+        bytes.extend_from_slice(&(words.len() as u32).to_be_bytes());
+        for word in words {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn decodes_runs_and_literals() {
+        // two words of ones, one literal; then one word of zeroes, one literal
+        let bytes = serialize(320, &[
+            (1 << 33) | (2 << 1) | 1,
+            0b1010,
+            (1 << 33) | (1 << 1),
+            1 << 63
+        ]);
+
+        let (ewah, len) = Ewah::read(&bytes).expect("failed to read");
+        assert_eq!(len, bytes.len());
+
+        let bitmap = ewah.decode().expect("failed to decode");
+        let ones: Vec<usize> = bitmap.ones().collect();
+        let mut expected: Vec<usize> = (0..128).collect();
+        expected.extend(&[129, 131, 319]);
+        assert_eq!(ones, expected);
+    }
+
+    #[test]
+    fn rejects_runs_past_the_end() {
+        let bytes = serialize(64, &[(3 << 1) | 1]);
+        let (ewah, _) = Ewah::read(&bytes).expect("failed to read");
+        assert!(ewah.decode().is_err());
+
+        let bytes = serialize(64, &[1 << 33]);
+        let (ewah, _) = Ewah::read(&bytes).expect("failed to read");
+        assert!(ewah.decode().is_err());
+    }
+}
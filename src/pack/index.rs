@@ -6,13 +6,36 @@ use rayon::prelude::*;
 use std::io::SeekFrom;
 use std::fmt::Debug;
 
-use crate::stores::{ StorageSet, Queryable };
-use crate::errors::{ ErrorKind, Result };
+use flate2::bufread::ZlibDecoder;
+use std::collections::{ HashMap, HashSet };
+
+use crate::stores::{ Limits, StorageSet, Queryable };
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::delta::{ apply as apply_delta, result_size };
 use crate::pack::iter::PackfileIterator;
+use crate::pack::internal_type::PackfileType;
+use crate::pack::read::{ packfile_read_header, read_ref_base, PackfileEntryMeta };
+use crate::objects::Type;
+use crate::pack::rev;
 use crate::id::Id;
 
+const V2_MAGIC: &[u8; 4] = b"\xfftOc";
+
+/// The format of a packfile index.
+///
+/// Version 1 has no magic number, stores each offset alongside its id, and has
+/// no crcs; its offsets are limited to 32 bits. Version 2 is what git writes by
+/// default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Version {
+    V1,
+    #[default]
+    V2
+}
+
+/// Write a (version 2) index for the packfile in `input`.
 pub fn write<R, W, S>(
-    mut input: R,
+    input: R,
     output: &mut W,
     storage_set: Option<&StorageSet<S>>
 ) -> Result<()> where
@@ -20,19 +43,70 @@ pub fn write<R, W, S>(
     W: Write,
     S: Queryable + Sync {
 
+    write_version(input, output, storage_set, Version::V2)
+}
+
+/// Write an index for the packfile in `input` in the format given by `version`.
+pub fn write_version<R, W, S>(
+    input: R,
+    output: &mut W,
+    storage_set: Option<&StorageSet<S>>,
+    version: Version
+) -> Result<()> where
+    R: BufRead + Seek + Clone + Debug + Sync,
+    W: Write,
+    S: Queryable + Sync {
+
+    let pack = pack_entries(input, storage_set)?;
+    write_entries(pack.entries, &pack.packfile_checksum, output, version)
+}
+
+/// What indexing a packfile finds out about it.
+pub(crate) struct PackEntries {
+    /// The id, offset and crc32 of each object, in packfile order.
+    pub entries: Vec<(Id, u64, u32)>,
+
+    /// The bases of every `REF_DELTA` in the packfile.
+    pub ref_bases: Vec<Id>,
+
+    pub packfile_checksum: Id
+}
+
+/// Find the id, offset and crc32 of every object in the packfile in `input`.
+pub(crate) fn pack_entries<R, S>(
+    mut input: R,
+    storage_set: Option<&StorageSet<S>>
+) -> Result<PackEntries> where
+    R: BufRead + Seek + Clone + Debug + Sync,
+    S: Queryable + Sync {
+
     let len = input.seek(SeekFrom::End(0))?;
     input.seek(SeekFrom::Start(0))?;
 
-    let iter = PackfileIterator::new(input.clone())?;
+    let limits = storage_set.map(|storage_set| *storage_set.limits()).unwrap_or_default();
+    let mut iter = PackfileIterator::new(input.clone())?.with_limits(limits);
+    let object_count = iter.object_count();
     let mut offsets = Vec::with_capacity(4096);
+    let mut ref_bases = Vec::new();
 
     // first pass: find all offsets and non-delta'd ids
-    let objects: Vec<_> = iter.map(|(offset, pf_type, id)| {
+    let objects = iter.by_ref().map(|(offset, meta, id)| {
         offsets.push(offset);
-        (offset, pf_type, id)
-    }).collect();
+        let entry = PendingEntry::new(offset, &meta, id)?;
+        if let PendingEntry::RefDelta(base) = &entry {
+            ref_bases.push(base.clone());
+        }
+        Ok((offset, entry))
+    }).collect::<Result<Vec<_>>>()?;
+    if let Some(e) = iter.take_error() {
+        return Err(e)
+    }
     offsets.push(len - 20);
 
+    if objects.len() != object_count as usize {
+        return Err(ErrorKind::WrongObjectCount { expected: object_count, actual: objects.len() as u32 }.into())
+    }
+
     // second pass: calculate crcs between offsets
     let windows: Vec<_> = offsets.windows(2).collect();
     let crcs: Vec<_> = windows.par_iter().filter_map(|offset| {
@@ -52,106 +126,337 @@ pub fn write<R, W, S>(
     }
 
     // third pass: calculate delta reprs
-    let mut decompressed: Vec<_> = objects
-                        .into_par_iter()
-                        .enumerate()
-                        .filter_map(|(idx, (offset, pf_type, id))| {
-        if let Some(id) = id {
-            return Some((idx, offset, id))
+    let entries = resolve_entries(input.clone(), objects, &crcs, storage_set)?;
+
+    input.seek(SeekFrom::End(-20))?;
+    let mut packfile_checksum = [0u8; 20];
+    input.read_exact(&mut packfile_checksum)?;
+
+    Ok(PackEntries {
+        entries,
+        ref_bases,
+        packfile_checksum: packfile_checksum.into()
+    })
+}
+
+/// How much inflated base data resolving deltas may hold on to at once, shared
+/// between threads. (git's `core.deltaBaseCacheLimit` defaults to the same.)
+pub(crate) const DELTA_BASE_CACHE_LIMIT: usize = 96 << 20;
+
+/// What the first pass over a packfile knows about an entry.
+#[derive(Debug, Clone)]
+pub(crate) enum PendingEntry {
+    Object(Type, Id),
+
+    /// A delta against the entry at this (absolute) offset.
+    OffsetDelta(u64),
+
+    RefDelta(Id)
+}
+
+impl PendingEntry {
+    /// Describe the entry at `offset`, dropping any delta instructions (which are
+    /// read again when the delta is resolved.)
+    pub(crate) fn new(offset: u64, meta: &PackfileEntryMeta, id: Option<Id>) -> Result<Self> {
+        Ok(match (meta.expected_type(), id) {
+            (PackfileType::Plain(kind), Some(id)) => PendingEntry::Object(*kind, id),
+            (PackfileType::Plain(_), None) => return Err(Error::from(ErrorKind::CorruptedPackfile).at_offset(offset)),
+            (PackfileType::OffsetDelta((distance, _)), _) => {
+                let base = offset.checked_sub(*distance).ok_or(ErrorKind::CorruptedPackfile).at_offset(offset)?;
+                PendingEntry::OffsetDelta(base)
+            },
+            (PackfileType::RefDelta((base, _)), _) => PendingEntry::RefDelta(base.clone())
+        })
+    }
+}
+
+/// Find the ids of the deltas among `objects` by resolving the packfile in
+/// `input` from each base to its deltas, returning the id, offset and crc32 of
+/// every object in packfile order.
+pub(crate) fn resolve_entries<R, S>(
+    input: R,
+    objects: Vec<(u64, PendingEntry)>,
+    crcs: &[u32],
+    storage_set: Option<&StorageSet<S>>
+) -> Result<Vec<(Id, u64, u32)>> where
+    R: BufRead + Seek + Clone + Sync,
+    S: Queryable + Sync {
+
+    let limit = DELTA_BASE_CACHE_LIMIT / rayon::current_num_threads().max(1);
+    resolve_entries_limited(input, objects, crcs, storage_set, limit)
+}
+
+fn resolve_entries_limited<R, S>(
+    input: R,
+    objects: Vec<(u64, PendingEntry)>,
+    crcs: &[u32],
+    storage_set: Option<&StorageSet<S>>,
+    limit: usize
+) -> Result<Vec<(Id, u64, u32)>> where
+    R: BufRead + Seek + Clone + Sync,
+    S: Queryable + Sync {
+
+    let mut trees = DeltaTrees {
+        input,
+        offsets: objects.iter().map(|(offset, _)| *offset).collect(),
+        ofs_children: HashMap::new(),
+        ref_children: HashMap::new(),
+        storage_set,
+        limits: storage_set.map(|storage_set| *storage_set.limits()).unwrap_or_default(),
+        limit
+    };
+
+    for (idx, (_, entry)) in objects.iter().enumerate() {
+        match entry {
+            PendingEntry::OffsetDelta(base) => trees.ofs_children.entry(*base).or_default().push(idx),
+            PendingEntry::RefDelta(base) => trees.ref_children.entry(base.clone()).or_default().push(idx),
+            PendingEntry::Object(..) => ()
         }
+    }
 
-        let mut input = input.clone();
-        let mut output = Vec::new();
-        let object_type = pf_type.decompress(
-            offset,
-            &mut input,
-            &mut output,
-            storage_set
-        ).ok()?;
-        let mut hash = Sha1::new();
-        let header = format!("{} {}\0", object_type.as_str(), output.len());
-        hash.input(header.as_bytes());
-        hash.input(&output[..]);
-        let mut id_output = [0u8; 20];
-        hash.result(&mut id_output);
-        Some((idx, offset, id_output.into()))
+    let mut ids: Vec<Option<Id>> = objects.iter().map(|(_, entry)| match entry {
+        PendingEntry::Object(_, id) => Some(id.clone()),
+        _ => None
     }).collect();
 
-    // sort the results by id hash (instead of offset order)
-    decompressed.par_sort_unstable_by(|lhs, rhs| {
-        lhs.2.cmp(&rhs.2)
-    });
+    // resolve everything that descends from an object in the packfile...
+    let resolved = objects.par_iter().filter_map(|(offset, entry)| match entry {
+        PendingEntry::Object(kind, id) => Some(trees.resolve(Root::Packed(*offset), *kind, id)),
+        _ => None
+    }).collect::<Result<Vec<_>>>()?;
 
-    let mut fanout = [0u32; 256]; // each value in fanout holds the upper bound index of the object starting with the incoming byte
-    let mut byte = 0u8;
-    fanout[0xff] = (decompressed.len() as u32).to_be();
+    for (idx, id) in resolved.into_iter().flatten() {
+        ids[idx] = Some(id);
+    }
 
-    let mut offsets = Vec::with_capacity(decompressed.len());
-    let mut large_offsets = Vec::new();
-    let mut crcs_out = Vec::with_capacity(decompressed.len());
-    let mut ids = Vec::with_capacity(decompressed.len());
-    for (idx, (crc_idx, offset, id)) in decompressed.into_iter().enumerate() {
-        if byte != id.as_ref()[0] {
-            fanout[byte as usize] = (idx as u32).to_be();
-            byte += 1;
+    // ...then anything left that refers to a base outside of it (a thin pack.)
+    let mut external: Vec<&Id> = objects.iter().zip(ids.iter()).filter_map(|((_, entry), id)| match (entry, id) {
+        (PendingEntry::RefDelta(base), None) => Some(base),
+        _ => None
+    }).collect();
+    external.sort();
+    external.dedup();
+
+    for base in external {
+        let first = trees.ref_children[base][0];
+        if ids[first].is_some() {
+            continue
         }
 
-        ids.push(id);
+        let storage_set = storage_set.ok_or(ErrorKind::NeedStorageSet)?;
+        let kind = match read_ref_base(storage_set, base, &mut std::io::sink())? {
+            Some(kind) => kind,
+            None => return Err(Error::from(ErrorKind::MissingDeltaBase(base.clone())).at_offset(objects[first].0))
+        };
 
-        if offset > 0x7fff_ffff {
-            offsets.push((large_offsets.len() as u32 & 0x8000_0000).to_be());
-            large_offsets.push(offset.to_be());
-        } else {
-            offsets.push((offset as u32).to_be());
+        for (idx, id) in trees.resolve(Root::External(base.clone()), kind, base)? {
+            ids[idx] = Some(id);
         }
-
-        crcs_out.push(crcs[crc_idx].to_be());
     }
 
-    let mut shasum = Sha1::new();
+    objects.iter().zip(ids).zip(crcs).map(|(((offset, entry), id), crc)| match (id, entry) {
+        (Some(id), _) => Ok((id, *offset, *crc)),
+        (None, PendingEntry::RefDelta(base)) => Err(Error::from(ErrorKind::MissingDeltaBase(base.clone())).at_offset(*offset)),
+        (None, _) => Err(Error::from(ErrorKind::CorruptedPackfile).at_offset(*offset))
+    }).collect()
+}
+
+// Where the object at the bottom of a delta tree comes from.
+enum Root {
+    Packed(u64),
+    External(Id)
+}
 
-    let magic_byte = b"\xfftOc";
-    shasum.input(magic_byte);
-    output.write_all(magic_byte)?;
+// An object on the path from a root to the delta being resolved.
+struct Frame {
+    offset: Option<u64>,
+    data: Option<Vec<u8>>,
+    children: Vec<usize>,
+    next: usize
+}
 
-    let version_bytes = 2u32.to_be().to_ne_bytes();
-    shasum.input(&version_bytes);
-    output.write_all(&version_bytes)?;
+struct DeltaTrees<'a, R, S: Queryable> {
+    input: R,
+    offsets: Vec<u64>,
+    ofs_children: HashMap<u64, Vec<usize>>,
+    ref_children: HashMap<Id, Vec<usize>>,
+    storage_set: Option<&'a StorageSet<S>>,
+    limits: Limits,
+    limit: usize
+}
 
-    let fanout_bytes = unsafe { std::mem::transmute::<[u32; 256], [u8; 256 * 4]>(fanout) };
-    shasum.input(&fanout_bytes);
-    output.write_all(&fanout_bytes)?;
+impl<'a, R, S> DeltaTrees<'a, R, S> where
+    R: BufRead + Seek + Clone,
+    S: Queryable {
 
-    for id in ids {
-        let id_bytes = id.as_ref();
-        shasum.input(id_bytes);
-        output.write_all(id_bytes)?;
+    fn children(&self, offset: Option<u64>, id: &Id) -> Vec<usize> {
+        let by_offset = offset.and_then(|offset| self.ofs_children.get(&offset));
+        let by_id = self.ref_children.get(id);
+        by_offset.into_iter().chain(by_id).flatten().copied().collect()
     }
 
-    for crc in crcs_out {
-        let crc_bytes = crc.to_be().to_ne_bytes();
-        shasum.input(&crc_bytes);
-        output.write_all(&crc_bytes)?;
+    // Walk the deltas based (directly or not) on `root` depth first, returning
+    // the index and id of each. Every base is inflated once and kept while its
+    // deltas are resolved, unless that would hold more than `limit` bytes; then
+    // the bases nearest the root are dropped and rebuilt if they're needed again.
+    fn resolve(&self, root: Root, kind: Type, id: &Id) -> Result<Vec<(usize, Id)>> {
+        let mut input = self.input.clone();
+        let root_offset = match root {
+            Root::Packed(offset) => Some(offset),
+            Root::External(_) => None
+        };
+
+        let mut resolved = Vec::new();
+        let mut seen = HashSet::new();
+        let mut held = 0;
+        let mut stack = vec![Frame {
+            offset: root_offset,
+            data: None,
+            children: self.children(root_offset, id),
+            next: 0
+        }];
+
+        while let Some(top) = stack.last_mut() {
+            if top.next == top.children.len() {
+                held -= top.data.as_ref().map_or(0, Vec::len);
+                stack.pop();
+                continue
+            }
+
+            let child = top.children[top.next];
+            top.next += 1;
+
+            // every delta has one base, so meeting one twice means it's (through
+            // its id) a base of itself, and resolving it would never end
+            if !seen.insert(child) {
+                return Err(Error::from(ErrorKind::CorruptedPackfile).at_offset(self.offsets[child]))
+            }
+            let last = top.next == top.children.len();
+
+            let top = stack.len() - 1;
+            if stack[top].data.is_none() {
+                let data = self.rebuild(&mut input, &root, &stack)?;
+                held += data.len();
+                stack[top].data = Some(data);
+            }
+
+            let offset = self.offsets[child];
+            let data = apply(&mut input, offset, stack[top].data.as_deref().unwrap_or_default(), &self.limits)?;
+
+            // the last delta against a base is done with it
+            if last {
+                if let Some(base) = stack[top].data.take() {
+                    held -= base.len();
+                }
+            }
+            let id = kind.hash(&data);
+            let children = self.children(Some(offset), &id);
+            resolved.push((child, id));
+
+            if children.is_empty() {
+                continue
+            }
+
+            held += data.len();
+            stack.push(Frame { offset: Some(offset), data: Some(data), children, next: 0 });
+
+            let top = stack.len() - 1;
+            for frame in stack[..top].iter_mut() {
+                if held <= self.limit {
+                    break
+                }
+
+                if let Some(data) = frame.data.take() {
+                    held -= data.len();
+                }
+            }
+        }
+
+        Ok(resolved)
     }
 
-    for offset in offsets {
-        let offset_bytes = offset.to_be().to_ne_bytes();
-        shasum.input(&offset_bytes);
-        output.write_all(&offset_bytes)?;
+    // Rebuild the data of the last frame in `stack` from the nearest frame below
+    // it that still has its data, or from the root.
+    fn rebuild(&self, input: &mut R, root: &Root, stack: &[Frame]) -> Result<Vec<u8>> {
+        let (start, mut data) = match stack.iter().rposition(|frame| frame.data.is_some()) {
+            Some(idx) => (idx + 1, stack[idx].data.clone().unwrap_or_default()),
+            None => (1, match root {
+                Root::Packed(offset) => read_entry(input, *offset, &self.limits)?,
+                Root::External(id) => {
+                    let mut data = Vec::new();
+                    let storage_set = self.storage_set.ok_or(ErrorKind::NeedStorageSet)?;
+                    if read_ref_base(storage_set, id, &mut data)?.is_none() {
+                        return Err(ErrorKind::MissingDeltaBase(id.clone()).into())
+                    }
+                    data
+                }
+            })
+        };
+
+        for frame in &stack[start..] {
+            if let Some(offset) = frame.offset {
+                data = apply(input, offset, &data, &self.limits)?;
+            }
+        }
+
+        Ok(data)
     }
+}
 
-    for large_offset in large_offsets {
-        let large_offset_bytes = large_offset.to_be().to_ne_bytes();
-        shasum.input(&large_offset_bytes);
-        output.write_all(&large_offset_bytes)?;
+// Inflate the data of the entry at `offset` (for a delta, its instructions.)
+fn read_entry<R: BufRead + Seek>(input: &mut R, offset: u64, limits: &Limits) -> Result<Vec<u8>> {
+    input.seek(SeekFrom::Start(offset))?;
+    let header = packfile_read_header(input).at_offset(offset)?;
+    limits.check_object_size(header.size()).at_offset(offset)?;
+
+    let mut data = Vec::new();
+    ZlibDecoder::new(input.by_ref())
+        .take(header.size() + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| Error::inflate(ErrorKind::CorruptedPackfile, e))
+        .at_offset(offset)?;
+
+    if data.len() as u64 != header.size() {
+        return Err(Error::from(ErrorKind::CorruptedPackfile).at_offset(offset))
     }
 
-    input.seek(SeekFrom::End(-20))?;
-    let mut packfile_checksum_bytes = Vec::with_capacity(20);
+    Ok(data)
+}
 
-    input.read_to_end(&mut packfile_checksum_bytes)?;
-    shasum.input(&packfile_checksum_bytes);
-    output.write_all(&packfile_checksum_bytes)?;
+// Apply the delta at `offset` to `base`.
+fn apply<R: BufRead + Seek>(input: &mut R, offset: u64, base: &[u8], limits: &Limits) -> Result<Vec<u8>> {
+    let instructions = read_entry(input, offset, limits)?;
+    limits.check_delta_output_size(result_size(&instructions)? as u64).at_offset(offset)?;
+    let mut output = Vec::new();
+    apply_delta(&instructions, base, &mut output).at_offset(offset)?;
+    Ok(output)
+}
+
+/// Write an index from `(id, offset, crc32)` entries (in any order) for the
+/// packfile whose trailing checksum is `packfile_checksum`.
+pub(crate) fn write_entries<W: Write>(
+    mut entries: Vec<(Id, u64, u32)>,
+    packfile_checksum: &Id,
+    output: &mut W,
+    version: Version
+) -> Result<()> {
+    // sort the results by id hash (instead of offset order)
+    entries.par_sort_unstable_by(|lhs, rhs| {
+        lhs.0.cmp(&rhs.0)
+    });
+
+    let mut shasum = Sha1::new();
+    let mut emit = |bytes: &[u8]| -> Result<()> {
+        shasum.input(bytes);
+        output.write_all(bytes)?;
+        Ok(())
+    };
+
+    match version {
+        Version::V1 => write_v1_entries(&entries, &mut emit)?,
+        Version::V2 => write_v2_entries(&entries, &mut emit)?
+    }
+    emit(packfile_checksum.as_ref())?;
 
     let mut checksum = [0u8; 20];
     shasum.result(&mut checksum);
@@ -160,17 +465,129 @@ pub fn write<R, W, S>(
     Ok(())
 }
 
-pub fn read<R: Read>(mut input: R) -> Result<Index> {
+// each value in fanout holds the number of objects whose first byte is less than or equal to its index
+fn fanout(entries: &[(Id, u64, u32)]) -> [u32; 256] {
+    let mut fanout = [0u32; 256];
+    for (id, _, _) in entries {
+        fanout[id.as_ref()[0] as usize] += 1;
+    }
+
+    for idx in 1..256 {
+        fanout[idx] += fanout[idx - 1];
+    }
+
+    fanout
+}
+
+fn write_v1_entries<F>(entries: &[(Id, u64, u32)], emit: &mut F) -> Result<()> where
+    F: FnMut(&[u8]) -> Result<()> {
+
+    for count in fanout(entries).iter() {
+        emit(&count.to_be_bytes())?;
+    }
+
+    // version 1 has no crcs, and only has room for 32-bit offsets
+    for (id, offset, _) in entries {
+        if *offset > u64::from(u32::MAX) {
+            return Err(ErrorKind::OffsetTooLargeForIndexVersion { offset: *offset, version: 1 }.into())
+        }
+
+        emit(&(*offset as u32).to_be_bytes())?;
+        emit(id.as_ref())?;
+    }
+
+    Ok(())
+}
+
+fn write_v2_entries<F>(entries: &[(Id, u64, u32)], emit: &mut F) -> Result<()> where
+    F: FnMut(&[u8]) -> Result<()> {
+
+    emit(V2_MAGIC)?;
+    emit(&2u32.to_be_bytes())?;
+
+    for count in fanout(entries).iter() {
+        emit(&count.to_be_bytes())?;
+    }
+
+    for (id, _, _) in entries {
+        emit(id.as_ref())?;
+    }
+
+    for (_, _, crc) in entries {
+        emit(&crc.to_be_bytes())?;
+    }
+
+    let mut large_offsets = Vec::new();
+    for (_, offset, _) in entries {
+        if *offset > 0x7fff_ffff {
+            emit(&(large_offsets.len() as u32 | 0x8000_0000).to_be_bytes())?;
+            large_offsets.push(*offset);
+        } else {
+            emit(&(*offset as u32).to_be_bytes())?;
+        }
+    }
+
+    for large_offset in large_offsets {
+        emit(&large_offset.to_be_bytes())?;
+    }
+
+    Ok(())
+}
+
+pub fn read<R: Read>(input: R) -> Result<Index> {
+    let (fanout, ids, offsets, crcs, packfile_checksum) = read_entries(input)?;
+
+    let mut offset_idx_sorted: Vec<(usize, &u64)> = offsets.iter().enumerate().collect();
+    offset_idx_sorted.sort_unstable_by_key(|(_, offset)| *offset);
+
+    let mut next_offsets_indices = vec![NO_NEXT_OFFSET; offset_idx_sorted.len()];
+    for window in offset_idx_sorted.windows(2) {
+        next_offsets_indices[window[0].0] = window[1].0;
+    }
+
+    Ok(Index {
+        fanout,
+        ids,
+        offsets,
+        order: OffsetOrder::Sorted(next_offsets_indices),
+        crcs,
+        packfile_checksum
+    })
+}
+
+/// Read a packfile index alongside its reverse index (`.rev` file.) The reverse
+/// index provides the offset order of the objects, saving us from sorting the
+/// offsets ourselves.
+pub fn read_with_reverse_index<R: Read, S: Read>(input: R, reverse_index: S) -> Result<Index> {
+    let (fanout, ids, offsets, crcs, packfile_checksum) = read_entries(input)?;
+    let positions = rev::read(reverse_index, &offsets, &packfile_checksum)?;
+
+    Ok(Index {
+        fanout,
+        ids,
+        offsets,
+        order: OffsetOrder::Reverse(positions),
+        crcs,
+        packfile_checksum
+    })
+}
+
+type IndexEntries = ([u32; 256], Vec<Id>, Vec<u64>, Option<Vec<u32>>, Id);
+
+fn read_entries<R: Read>(mut input: R) -> Result<IndexEntries> {
     let mut magic = [0u8; 4];
     input.read_exact(&mut magic)?;
-    let mut version = [0u8; 4];
-    input.read_exact(&mut version)?;
 
-    if &magic != b"\xfftOc" {
-        return Err(ErrorKind::InvalidPackfileIndex.into())
+    // version 1 indexes have no header, and start with the fanout table. (A
+    // fanout that begins with the magic number would need billions of objects.)
+    if &magic != V2_MAGIC {
+        return read_v1_entries(magic, input)
     }
 
-    if version != 2u32.to_be().to_ne_bytes() {
+    let mut version = [0u8; 4];
+    input.read_exact(&mut version)?;
+
+    if version != 2u32.to_be_bytes() {
         return Err(ErrorKind::UnsupportedPackfileIndexVersion.into())
     }
 
@@ -206,67 +623,324 @@ pub fn read<R: Read>(mut input: R) -> Result<Index> {
         }
     }).collect();
 
-    let mut offset_idx_sorted: Vec<(usize, &u64)> = offsets.iter().enumerate().collect();
-    offset_idx_sorted.sort_unstable_by_key(|(_, offset)| *offset);
+    let mut packfile_checksum = [0u8; 20];
+    input.read_exact(&mut packfile_checksum)?;
+
+    Ok((fanout, ids, offsets, Some(crc_vec), packfile_checksum.into()))
+}
 
-    let mut next_offsets_indices = vec![0; offset_idx_sorted.len()];
-    let mut idx = 0;
-    while idx < offset_idx_sorted.len() - 1 {
-        next_offsets_indices[offset_idx_sorted[idx].0] = offset_idx_sorted[idx + 1].0;
-        idx += 1;
+fn read_v1_entries<R: Read>(first: [u8; 4], mut input: R) -> Result<IndexEntries> {
+    let mut fanout = [0u32; 256];
+    fanout[0] = u32::from_be_bytes(first);
+    input.read_u32_into::<BigEndian>(&mut fanout[1..])?;
+
+    if fanout.windows(2).any(|counts| counts[0] > counts[1]) {
+        return Err(ErrorKind::InvalidPackfileIndex.into())
     }
 
-    Ok(Index {
-        fanout,
-        ids,
-        offsets,
-        next_offsets_indices,
-        crcs: crc_vec
-    })
+    let object_count = fanout[255] as usize;
+    let mut ids = Vec::with_capacity(object_count);
+    let mut offsets = Vec::with_capacity(object_count);
+    let mut entry = [0u8; 24];
+    for _ in 0..object_count {
+        input.read_exact(&mut entry)?;
+        offsets.push(u64::from(u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]])));
+        ids.push(Id::new(&entry[4..]));
+    }
+
+    let mut packfile_checksum = [0u8; 20];
+    input.read_exact(&mut packfile_checksum)?;
+
+    Ok((fanout, ids, offsets, None, packfile_checksum.into()))
+}
+
+const NO_NEXT_OFFSET: usize = usize::MAX;
+
+// How we find the object following a given object in the packfile.
+enum OffsetOrder {
+    // For each object (in id order), the position of the next object in offset order.
+    Sorted(Vec<usize>),
+
+    // The positions of all objects (in id order), sorted by offset; read from a `.rev` file.
+    Reverse(Vec<u32>)
 }
 
 pub struct Index {
     fanout: [u32; 256],
     ids: Vec<Id>,
     offsets: Vec<u64>,
-    next_offsets_indices: Vec<usize>,
-    crcs: Vec<u32>,
+    order: OffsetOrder,
+    crcs: Option<Vec<u32>>,
+    packfile_checksum: Id
 }
 
 impl Index {
-    pub fn crcs(&self) -> &[u32] {
-        &self.crcs
+    /// The crc32 of each object's packed bytes, in id order. Version 1 indexes
+    /// don't record them.
+    pub fn crcs(&self) -> Option<&[u32]> {
+        self.crcs.as_deref()
+    }
+
+    pub fn ids(&self) -> &[Id] {
+        &self.ids
+    }
+
+    pub fn offsets(&self) -> &[u64] {
+        &self.offsets
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The trailing checksum of the packfile this index describes.
+    pub fn packfile_checksum(&self) -> &Id {
+        &self.packfile_checksum
+    }
+
+    /// Return the position (in id order) of `id` in this index.
+    pub fn position(&self, id: &Id) -> Option<usize> {
+        let first = id.as_ref()[0] as usize;
+        let lo = if first > 0 { self.fanout[first - 1] as usize } else { 0 };
+        let hi = self.fanout[first] as usize;
+
+        Some(lo + self.ids.get(lo..hi)?.binary_search(id).ok()?)
+    }
+
+    /// Return the positions (in id order) of every object, sorted by their offset
+    /// in the packfile.
+    pub fn positions_by_offset(&self) -> Vec<u32> {
+        match &self.order {
+            OffsetOrder::Reverse(positions) => positions.clone(),
+            OffsetOrder::Sorted(_) => {
+                let mut positions: Vec<u32> = (0..self.offsets.len() as u32).collect();
+                positions.sort_unstable_by_key(|idx| self.offsets[*idx as usize]);
+                positions
+            }
+        }
+    }
+
+    /// Return the offset of `id` in the packfile and the offset at which the
+    /// following object starts. If `id` is the last object in the packfile, the
+    /// end offset is `u64::MAX`; readers should stop at the packfile trailer.
     pub fn get_bounds(&self, id: &Id) -> Option<(u64, u64)> {
-        let as_bytes: &[u8] = id.as_ref();
-        let mut lo = if as_bytes[0] > 0 {
-            self.fanout[(as_bytes[0] - 1) as usize]
-        } else {
-            0
-        };
-        let mut hi = self.fanout[as_bytes[0] as usize];
-        let len = self.offsets.len();
-        loop {
-            let middle = ((lo + hi) >> 1) as usize;
-
-            match id.partial_cmp(&self.ids[middle]) {
-                Some(std::cmp::Ordering::Less) => { hi = middle as u32; },
-                Some(std::cmp::Ordering::Greater) => { lo = (middle + 1) as u32; }
-                Some(std::cmp::Ordering::Equal) => {
-                    return Some((
-                        self.offsets[middle],
-                        self.offsets[self.next_offsets_indices[middle]]
-                    ));
-                },
-                None => {
-                    return None;
+        let position = self.position(id)?;
+        let start = self.offsets[position];
+
+        let end = match &self.order {
+            OffsetOrder::Sorted(next_offsets_indices) => {
+                match next_offsets_indices[position] {
+                    NO_NEXT_OFFSET => u64::MAX,
+                    next => self.offsets[next]
+                }
+            },
+
+            OffsetOrder::Reverse(positions) => {
+                let offsets = &self.offsets;
+                let pack_position = positions.binary_search_by_key(&start, |idx| offsets[*idx as usize]).ok()?;
+                match positions.get(pack_position + 1) {
+                    Some(next) => offsets[*next as usize],
+                    None => u64::MAX
                 }
             }
+        };
 
-            if lo >= hi || middle >= len {
-                return None
-            }
+        Some((start, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::str::FromStr;
+
+    use crate::test_support::{ commit_file, git, scratch_repo };
+    use crate::pack::window::{ write_with_deltas, Options };
+    use crate::pack::iter::PackfileIterator;
+    use crate::pack::write::Writer;
+    use crate::objects::Type;
+    use crate::stores::StorageSet;
+    use crate::errors::ErrorKind;
+    use crate::id::Id;
+
+    #[test]
+    fn reverse_index_matches_sorted_offsets() {
+        let index_bytes = include_bytes!("../../fixtures/pack_index");
+        let rev_bytes = include_bytes!("../../fixtures/pack_reverse_index");
+
+        let sorted = super::read(Cursor::new(&index_bytes[..])).expect("failed to read index");
+        let reversed = super::read_with_reverse_index(
+            Cursor::new(&index_bytes[..]),
+            Cursor::new(&rev_bytes[..])
+        ).expect("failed to read reverse index");
+
+        for id in sorted.ids() {
+            assert_eq!(sorted.get_bounds(id), reversed.get_bounds(id));
+        }
+
+        // the last object in the pack is bounded by the end of the pack
+        let last = sorted.ids().iter().map(|id| sorted.get_bounds(id).unwrap()).max().unwrap();
+        assert_eq!(last, (318, u64::MAX));
+    }
+
+    #[test]
+    fn reverse_index_checksum_is_checked() {
+        let index_bytes = include_bytes!("../../fixtures/pack_index");
+        let rev_bytes = include_bytes!("../../fixtures/pack_reverse_index");
+
+        let mut trailer = rev_bytes.to_vec();
+        let last = trailer.len() - 1;
+        trailer[last] ^= 1;
+        let error = super::read_with_reverse_index(Cursor::new(&index_bytes[..]), Cursor::new(&trailer[..]))
+            .err().expect("the checksum was changed");
+        assert_eq!(error.kind(), &ErrorKind::CorruptedReverseIndex);
+    }
+
+    #[test]
+    fn write_index_works() {
+        let packfile = include_bytes!("../../fixtures/packfile");
+
+        let mut output = Vec::new();
+        super::write(Cursor::new(&packfile[..]), &mut output, None::<&StorageSet<()>>).expect("failed to write index");
+        assert_eq!(&output[..], &include_bytes!("../../fixtures/pack_index")[..]);
+    }
+
+    #[test]
+    fn read_v1_index_works() {
+        let v1 = super::read(Cursor::new(&include_bytes!("../../fixtures/pack_index_v1")[..]))
+            .expect("failed to read v1 index");
+        let v2 = super::read(Cursor::new(&include_bytes!("../../fixtures/pack_index")[..]))
+            .expect("failed to read v2 index");
+
+        assert_eq!(v1.ids(), v2.ids());
+        assert_eq!(v1.offsets(), v2.offsets());
+        assert_eq!(v1.packfile_checksum(), v2.packfile_checksum());
+        assert!(v1.crcs().is_none());
+        assert!(v2.crcs().is_some());
+        for id in v2.ids() {
+            assert_eq!(v1.get_bounds(id), v2.get_bounds(id));
+        }
+    }
+
+    #[test]
+    fn write_v1_index_works() {
+        let packfile = include_bytes!("../../fixtures/packfile");
+
+        let mut output = Vec::new();
+        super::write_version(
+            Cursor::new(&packfile[..]),
+            &mut output,
+            None::<&StorageSet<()>>,
+            super::Version::V1
+        ).expect("failed to write index");
+        assert_eq!(&output[..], &include_bytes!("../../fixtures/pack_index_v1")[..]);
+
+        // version 1 only has room for 32-bit offsets
+        let entries = [(Id::from_str("0000000000000000000000000000000000000001").unwrap(), 1 << 32, 0)];
+        let error = super::write_v1_entries(&entries, &mut |_| Ok(())).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::OffsetTooLargeForIndexVersion { offset: 1 << 32, version: 1 });
+    }
+
+    #[test]
+    fn write_reverse_index_works() {
+        let index_bytes = include_bytes!("../../fixtures/pack_index");
+        let index = super::read(Cursor::new(&index_bytes[..])).expect("failed to read index");
+
+        let mut output = Vec::new();
+        crate::pack::rev::write(&index, &mut output).expect("failed to write reverse index");
+        assert_eq!(&output[..], &include_bytes!("../../fixtures/pack_reverse_index")[..]);
+    }
+
+    #[test]
+    fn resolves_long_chains_without_holding_bases() {
+        let root = scratch_repo("pack-index-chains");
+        let mut contents: String = (0..200).map(|xs| format!("line {}\n", xs)).collect();
+        for idx in 0..40 {
+            contents = contents.replace(&format!("line {}\n", idx * 5), &format!("changed {}\n", idx));
+            commit_file(&root, "file.txt", &contents, 1_600_000_000 + idx * 100);
         }
+        git(&root, &["repack", "-adfq", "--depth=50", "--window=50"]);
+
+        let pack_dir = root.join(".git/objects/pack");
+        let pack_path = std::fs::read_dir(&pack_dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension() == Some("pack".as_ref()))
+            .unwrap();
+        let packfile = std::fs::read(&pack_path).unwrap();
+        let expected = super::read(Cursor::new(std::fs::read(pack_path.with_extension("idx")).unwrap())).unwrap();
+
+        let objects = PackfileIterator::new(Cursor::new(&packfile[..])).unwrap().map(|(offset, meta, id)| {
+            (offset, super::PendingEntry::new(offset, &meta, id).unwrap())
+        }).collect::<Vec<_>>();
+        let deltas = objects.iter().filter(|(_, entry)| matches!(entry, super::PendingEntry::OffsetDelta(_))).count();
+        assert!(deltas > 30);
+
+        // with no room for bases, every one of them is rebuilt from its root
+        let crcs = vec![0; objects.len()];
+        let entries = super::resolve_entries_limited(Cursor::new(&packfile[..]), objects, &crcs, None::<&StorageSet<()>>, 0)
+            .expect("failed to resolve deltas");
+
+        assert_eq!(entries.len(), expected.ids().len());
+        for (id, offset, _) in entries {
+            assert_eq!(expected.get_bounds(&id).map(|(start, _)| start), Some(offset));
+        }
+    }
+
+    #[test]
+    fn missing_bases_are_errors() {
+        let root = scratch_repo("pack-index-missing");
+        let contents: String = (0..300).map(|xs| format!("line {}\n", xs)).collect();
+        commit_file(&root, "file.txt", &contents, 1_600_000_000);
+        commit_file(&root, "file.txt", &contents.replace("line 150", "changed"), 1_600_000_100);
+        git(&root, &["repack", "-adfq"]);
+
+        let objects: Vec<(Id, u32)> = git(&root, &["rev-list", "--objects", "HEAD~1..HEAD"]).lines().map(|line| {
+            (Id::from_str(&line[..40]).unwrap(), 0)
+        }).collect();
+        let storage_set = root.storage();
+        let mut thin = Vec::new();
+        let options = Options { window: 0, thin: true, ..Default::default() };
+        write_with_deltas(&storage_set, &objects, options, &mut thin, &mut Vec::new()).expect("failed to write pack");
+
+        let error = super::write(Cursor::new(&thin[..]), &mut Vec::new(), None::<&StorageSet<()>>)
+            .expect_err("ref deltas need a storage set");
+        assert_eq!(error.kind(), &ErrorKind::NeedStorageSet);
+
+        let other = scratch_repo("pack-index-missing-other");
+        let empty = other.storage();
+        let error = super::write(Cursor::new(&thin[..]), &mut Vec::new(), Some(&empty))
+            .expect_err("the bases are missing");
+        assert!(matches!(error.kind(), ErrorKind::MissingDeltaBase(_)));
+        assert!(error.offset().is_some());
+
+        super::write(Cursor::new(&thin[..]), &mut Vec::new(), Some(&storage_set)).expect("the bases are in the repository");
+
+        // a truncated packfile's last entry runs into its trailer
+        let mut truncated = thin[..thin.len() - 40].to_vec();
+        truncated.extend_from_slice(&[0; 20]);
+        let error = super::write(Cursor::new(&truncated[..]), &mut Vec::new(), Some(&storage_set))
+            .expect_err("the packfile is truncated");
+        assert!(error.is_corrupt());
+        assert!(error.offset().is_some());
+    }
+
+    #[test]
+    fn deltas_that_are_their_own_bases_are_errors() {
+        // a ref delta against "xyz" that copies all of it, so it's "xyz" again
+        let base = Type::Blob.hash(b"xyz");
+        let mut packfile = Vec::new();
+        let mut writer = Writer::new(&mut packfile, 2).unwrap();
+        writer.add_delta(Id::from_str("0000000000000000000000000000000000000001").unwrap(), &base, &[3, 3, 0x90, 3]).unwrap();
+        writer.add(Type::Blob, b"xyz").unwrap();
+        writer.finish(&mut Vec::new()).unwrap();
+
+        let error = super::write(Cursor::new(&packfile[..]), &mut Vec::new(), None::<&StorageSet<()>>)
+            .expect_err("the delta is its own base");
+        assert_eq!(error.kind(), &ErrorKind::CorruptedPackfile);
+        assert_eq!(error.offset(), Some(12));
     }
 }
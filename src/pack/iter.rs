@@ -3,8 +3,9 @@ use std::io::{ BufRead, Seek, Write };
 
 use crate::pack::internal_type::PackfileType;
 use crate::pack::read::PackfileEntryMeta;
-use crate::errors::{ Result, ErrorKind };
-use crate::pack::read::packfile_read;
+use crate::errors::{ Error, Result, ErrorKind };
+use crate::pack::read::packfile_read_limited;
+use crate::stores::Limits;
 use crate::id::Id;
 
 pub struct PackfileIterator<R: BufRead + Seek + std::fmt::Debug> {
@@ -14,6 +15,8 @@ pub struct PackfileIterator<R: BufRead + Seek + std::fmt::Debug> {
     buffer: Vec<u8>,
     header_buffer: Vec<u8>,
     current_offset: u64,
+    limits: Limits,
+    error: Option<Error>
 }
 
 impl<R: BufRead + Seek + std::fmt::Debug> PackfileIterator<R> {
@@ -31,7 +34,7 @@ impl<R: BufRead + Seek + std::fmt::Debug> PackfileIterator<R> {
         let version = u32::from_be_bytes(version_bytes);
         match version {
             2 | 3 => (),
-            _ => return Err(ErrorKind::NotImplemented.into())
+            _ => return Err(ErrorKind::UnsupportedPackfileVersion(version).into())
         };
 
         let mut object_count_bytes = [0u8; 4];
@@ -44,9 +47,27 @@ impl<R: BufRead + Seek + std::fmt::Debug> PackfileIterator<R> {
             current_offset: 12,
             buffer: Vec::with_capacity(65535),
             header_buffer: Vec::with_capacity(128),
+            limits: Limits::default(),
+            error: None,
             stream
         })
     }
+
+    /// Stop at the first entry bigger than `limits` allow.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The error that ended iteration early, if any.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// The number of objects the packfile header says it contains.
+    pub fn object_count(&self) -> u32 {
+        self.object_count
+    }
 }
 
 impl<R: BufRead + Seek + std::fmt::Debug> Iterator for PackfileIterator<R> {
@@ -62,11 +83,14 @@ impl<R: BufRead + Seek + std::fmt::Debug> Iterator for PackfileIterator<R> {
 
         let offset = self.current_offset;
         let mut bytes_read = 0;
-        let meta = packfile_read(
-            &mut self.stream,
-            &mut self.buffer,
-            &mut bytes_read
-        ).ok()?;
+        let meta = match packfile_read_limited(&mut self.stream, &mut self.buffer, &mut bytes_read, &self.limits) {
+            Ok(meta) => meta,
+            Err(e) => {
+                self.index = self.object_count;
+                self.error = Some(e.at_offset(offset));
+                return None
+            }
+        };
 
         self.current_offset += bytes_read;
 
@@ -0,0 +1,155 @@
+use std::convert::TryInto;
+
+use crate::chunk::{ ChunkTable, be_u32, be_u64, read_fanout };
+use crate::errors::{ ErrorKind, Result };
+use crate::id::Id;
+
+const MIDX_HEADER_SIZE: usize = 12;
+const MIDX_LARGE_OFFSET: u32 = 0x8000_0000;
+
+/// A multi-pack-index (`objects/pack/multi-pack-index`), mapping every object in
+/// a set of packfiles to the pack containing it and its offset in that pack.
+///
+/// See `gitformat-pack(5)` for details on the format.
+#[derive(Debug)]
+pub struct MultiPackIndex {
+    pack_names: Vec<String>,
+    fanout: [u32; 256],
+    ids: Vec<Id>,
+    pack_ids: Vec<u32>,
+    offsets: Vec<u64>,
+    checksum: Id
+}
+
+pub fn read(bytes: &[u8]) -> Result<MultiPackIndex> {
+    if bytes.len() < MIDX_HEADER_SIZE + 20 || &bytes[0..4] != b"MIDX" {
+        return Err(ErrorKind::InvalidMultiPackIndex.into())
+    }
+
+    // version 1, sha1 object ids
+    if bytes[4] != 1 || bytes[5] != 1 {
+        return Err(ErrorKind::UnsupportedMultiPackIndexVersion.into())
+    }
+
+    let chunk_count = bytes[6] as usize;
+    let pack_count = be_u32(&bytes[8..12], 0) as usize;
+    let table = ChunkTable::read(bytes, MIDX_HEADER_SIZE, chunk_count)?;
+
+    let pnam = table.require(b"PNAM", bytes)?;
+    let pack_names: Vec<String> = pnam.split(|xs| *xs == 0)
+        .filter(|xs| !xs.is_empty())
+        .map(|xs| String::from_utf8_lossy(xs).into_owned())
+        .collect();
+
+    if pack_names.len() != pack_count {
+        return Err(ErrorKind::InvalidMultiPackIndex.into())
+    }
+
+    let fanout = read_fanout(table.require(b"OIDF", bytes)?)?;
+    let object_count = fanout[255] as usize;
+
+    let oidl = table.require(b"OIDL", bytes)?;
+    if oidl.len() < object_count * 20 {
+        return Err(ErrorKind::InvalidMultiPackIndex.into())
+    }
+    let ids = Id::read_packed_ids(&mut &oidl[..], object_count)?;
+
+    let ooff = table.require(b"OOFF", bytes)?;
+    if ooff.len() < object_count * 8 {
+        return Err(ErrorKind::InvalidMultiPackIndex.into())
+    }
+    let loff = table.get(b"LOFF", bytes).unwrap_or(&[]);
+
+    let mut pack_ids = Vec::with_capacity(object_count);
+    let mut offsets = Vec::with_capacity(object_count);
+    for idx in 0..object_count {
+        let pack_id = be_u32(ooff, idx * 2);
+        let offset = be_u32(ooff, idx * 2 + 1);
+
+        if pack_id as usize >= pack_count {
+            return Err(ErrorKind::InvalidMultiPackIndex.into())
+        }
+
+        let offset = if offset & MIDX_LARGE_OFFSET != 0 {
+            let large_idx = (offset & !MIDX_LARGE_OFFSET) as usize;
+            if (large_idx + 1) * 8 > loff.len() {
+                return Err(ErrorKind::InvalidMultiPackIndex.into())
+            }
+            be_u64(loff, large_idx)
+        } else {
+            offset as u64
+        };
+
+        pack_ids.push(pack_id);
+        offsets.push(offset);
+    }
+
+    let checksum: [u8; 20] = bytes[bytes.len() - 20..].try_into().unwrap();
+
+    Ok(MultiPackIndex {
+        pack_names,
+        fanout,
+        ids,
+        pack_ids,
+        offsets,
+        checksum: checksum.into()
+    })
+}
+
+impl MultiPackIndex {
+    /// The names of the pack indexes covered by this multi-pack-index, relative to
+    /// `objects/pack`. Object locations refer to packs by their position in this list.
+    pub fn pack_names(&self) -> &[String] {
+        &self.pack_names
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn ids(&self) -> &[Id] {
+        &self.ids
+    }
+
+    pub fn checksum(&self) -> &Id {
+        &self.checksum
+    }
+
+    /// Find the pack (as an index into `pack_names`) and offset for `id`.
+    pub fn find(&self, id: &Id) -> Option<(usize, u64)> {
+        let first = id.as_ref()[0] as usize;
+        let lo = if first > 0 { self.fanout[first - 1] as usize } else { 0 };
+        let hi = self.fanout[first] as usize;
+
+        let idx = lo + self.ids.get(lo..hi)?.binary_search(id).ok()?;
+        Some((self.pack_ids[idx] as usize, self.offsets[idx]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::id::Id;
+
+    #[test]
+    fn read_midx_works() {
+        let midx = super::read(include_bytes!("../../fixtures/multi_pack_index")).expect("failed to parse");
+
+        assert_eq!(midx.pack_names(), &["pack-ac04939ad291747cb090c8758f549b5ebabdcc48.idx".to_string()]);
+        assert_eq!(midx.len(), 5);
+
+        let id = Id::from_str("d5d3ae9b365275c0d3657f4add7a4dbdf960f783").unwrap();
+        assert_eq!(midx.find(&id), Some((0, 276)));
+
+        let id = Id::from_str("872e26b3fbebe64a2a85b271fed6916b964b4fde").unwrap();
+        assert_eq!(midx.find(&id), Some((0, 12)));
+
+        let missing = Id::from_str("872e26b3fbebe64a2a85b271fed6916b964b4fdf").unwrap();
+        assert_eq!(midx.find(&missing), None);
+    }
+}
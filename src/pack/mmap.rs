@@ -2,8 +2,8 @@ use std::io::{ Cursor, Write, Seek, SeekFrom };
 use memmap::Mmap;
 
 use crate::stores::{ Queryable, StorageSet };
-use crate::pack::read::packfile_read;
-use crate::errors::Result;
+use crate::pack::read::{ packfile_read_cached, packfile_read_limited, packfile_read_type, DeltaBaseCache };
+use crate::errors::{ ErrorKind, Result };
 use crate::pack::Packfile;
 use crate::objects::Type;
 
@@ -21,10 +21,11 @@ impl Reader {
 
 impl Packfile for Reader {
     fn read_bounds<W: Write, S: Queryable>(&self, start: u64, end: u64, output: &mut W, backends: &StorageSet<S>) -> Result<Type> {
+        let end = std::cmp::min(end, self.mmap.len() as u64);
         let mut cursor = Cursor::new(&self.mmap[ .. end as usize]);
         cursor.seek(SeekFrom::Start(start))?;
 
-        let packfile_entry_meta = packfile_read(&mut cursor, output, &mut 0)?;
+        let packfile_entry_meta = packfile_read_limited(&mut cursor, output, &mut 0, backends.limits())?;
         let obj_type = packfile_entry_meta.decompress(
             start,
             &mut cursor,
@@ -34,6 +35,27 @@ impl Packfile for Reader {
 
         Ok(obj_type)
     }
+
+    fn read_cached<W: Write, S: Queryable>(&self, start: u64, output: &mut W, backends: &StorageSet<S>, cache: &mut DeltaBaseCache) -> Result<Type> {
+        let mut cursor = Cursor::new(&self.mmap[..]);
+        packfile_read_cached(&mut cursor, start, output, Some(backends), cache)
+    }
+
+    fn read_type<S: Queryable>(&self, start: u64, backends: &StorageSet<S>) -> Result<Type> {
+        let mut cursor = Cursor::new(&self.mmap[..]);
+        packfile_read_type(&mut cursor, start, Some(backends))
+    }
+
+    fn read_raw<W: Write>(&self, start: u64, end: u64, output: &mut W) -> Result<()> {
+        let trailer = self.mmap.len().saturating_sub(20) as u64;
+        let end = std::cmp::min(end, trailer);
+        if start > end {
+            return Err(ErrorKind::CorruptedPackfile.into())
+        }
+
+        output.write_all(&self.mmap[start as usize .. end as usize])?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
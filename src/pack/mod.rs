@@ -1,16 +1,27 @@
 use std::io::Write;
 
 use crate::stores::{ Queryable, StorageSet };
+use crate::pack::read::DeltaBaseCache;
 use crate::errors::Result;
 use crate::objects::Type;
 use crate::id::Id;
 
 pub mod index;
+pub mod midx;
+pub mod rev;
+pub mod mtimes;
+pub mod bitmap;
+pub mod ewah;
 pub mod mmap;
 pub mod any;
 pub mod iter;
 pub mod internal_type;
-mod read;
+pub mod read;
+pub mod write;
+pub mod window;
+pub mod thin;
+pub mod stream;
+pub mod verify;
 
 #[derive(Debug)]
 pub struct IndexEntry {
@@ -38,8 +49,55 @@ impl IndexEntry {
     }
 }
 
-pub struct Fanout ([u32; 256]);
+/// An object's entry in a packfile as it is stored there: still compressed and,
+/// for deltas, not yet applied to its base. Used to copy entries between packs
+/// without inflating them.
+#[derive(Debug, Clone)]
+pub struct StoredEntry {
+    kind: u8,
+    size: u64,
+    base: Option<Id>,
+    data: Vec<u8>
+}
+
+impl StoredEntry {
+    pub fn new(kind: u8, size: u64, base: Option<Id>, data: Vec<u8>) -> Self {
+        StoredEntry { kind, size, base, data }
+    }
+
+    /// The raw entry type: 1-4 for plain objects, `OFS_DELTA` or `REF_DELTA`.
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+
+    /// The inflated size of the entry (for deltas, the size of the instructions.)
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The id of the delta base, if this entry is a delta.
+    pub fn base(&self) -> Option<&Id> {
+        self.base.as_ref()
+    }
+
+    /// The compressed data following the entry header.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
 
 pub trait Packfile {
     fn read_bounds<W: Write, S: Queryable>(&self, start: u64, end: u64, output: &mut W, backends: &StorageSet<S>) -> Result<Type>;
+
+    /// Read the object at `start` as `read_bounds` does, reusing the delta bases
+    /// in `cache` and adding any it resolves (see `read::packfile_read_cached`.)
+    fn read_cached<W: Write, S: Queryable>(&self, start: u64, output: &mut W, backends: &StorageSet<S>, cache: &mut DeltaBaseCache) -> Result<Type>;
+
+    /// Find the type of the object at `start` without inflating it.
+    fn read_type<S: Queryable>(&self, start: u64, backends: &StorageSet<S>) -> Result<Type>;
+
+    /// Copy the entry between `start` and `end` to `output` as it is stored (that
+    /// is, without inflating it or resolving deltas.) Stops at the packfile's
+    /// trailing checksum if `end` is past it.
+    fn read_raw<W: Write>(&self, start: u64, end: u64, output: &mut W) -> Result<()>;
 }
@@ -0,0 +1,113 @@
+use crypto::{ sha1::Sha1, digest::Digest };
+use byteorder::{ BigEndian, ReadBytesExt };
+use std::io::prelude::*;
+
+use crate::errors::{ ErrorKind, Result };
+use crate::pack::index::Index;
+use crate::id::Id;
+
+const MTME_MAGIC: &[u8; 4] = b"MTME";
+
+/// Read the modification times listed by a cruft pack's `.mtimes` file, for a
+/// packfile index with `count` objects and the given `packfile_checksum`. Times
+/// are in seconds since the epoch, in the same order as the index's ids.
+pub fn read<R: Read>(mut input: R, count: usize, packfile_checksum: &Id) -> Result<Vec<u32>> {
+    // everything up to the trailing checksum is hashed by it
+    let mut contents = vec![0u8; 12 + count * 4 + 20];
+    input.read_exact(&mut contents)?;
+
+    let mut checksum = [0u8; 20];
+    input.read_exact(&mut checksum)?;
+
+    let mut shasum = Sha1::new();
+    shasum.input(&contents);
+    let mut actual = [0u8; 20];
+    shasum.result(&mut actual);
+    if actual != checksum {
+        return Err(ErrorKind::CorruptedMtimes.into())
+    }
+
+    let (header, rest) = contents.split_at(12);
+    if &header[0..4] != MTME_MAGIC {
+        return Err(ErrorKind::CorruptedMtimes.into())
+    }
+
+    // version 1, sha1 object ids
+    if header[4..12] != [0, 0, 0, 1, 0, 0, 0, 1] {
+        return Err(ErrorKind::UnsupportedMtimesVersion.into())
+    }
+
+    let (mut mtime_bytes, pack_checksum) = rest.split_at(count * 4);
+    let mut mtimes = vec![0u32; count];
+    mtime_bytes.read_u32_into::<BigEndian>(&mut mtimes)?;
+
+    if pack_checksum != packfile_checksum.as_ref() {
+        return Err(ErrorKind::CorruptedMtimes.into())
+    }
+
+    Ok(mtimes)
+}
+
+/// Write a `.mtimes` file for `index`, given the modification time of each of
+/// its objects in index order.
+pub fn write<W: Write>(index: &Index, mtimes: &[u32], output: &mut W) -> Result<()> {
+    if mtimes.len() != index.len() {
+        return Err(ErrorKind::WrongObjectCount {
+            expected: index.len() as u32,
+            actual: mtimes.len() as u32
+        }.into())
+    }
+
+    let mut shasum = Sha1::new();
+    let mut emit = |bytes: &[u8]| -> Result<()> {
+        shasum.input(bytes);
+        output.write_all(bytes)?;
+        Ok(())
+    };
+
+    emit(MTME_MAGIC)?;
+    emit(&1u32.to_be_bytes())?;
+    emit(&1u32.to_be_bytes())?;
+
+    for mtime in mtimes {
+        emit(&mtime.to_be_bytes())?;
+    }
+
+    emit(index.packfile_checksum().as_ref())?;
+
+    let mut checksum = [0u8; 20];
+    shasum.result(&mut checksum);
+    output.write_all(&checksum)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::pack::index;
+    use crate::errors::ErrorKind;
+
+    #[test]
+    fn round_trips_and_checks_checksums() {
+        let index = index::read(Cursor::new(&include_bytes!("../../fixtures/pack_index")[..])).expect("failed to read index");
+        let mtimes: Vec<u32> = (0..index.len() as u32).map(|idx| 1_600_000_000 + idx).collect();
+
+        let mut output = Vec::new();
+        super::write(&index, &mtimes, &mut output).expect("failed to write mtimes");
+        let read = super::read(&output[..], index.len(), index.packfile_checksum()).expect("failed to read mtimes");
+        assert_eq!(read, mtimes);
+
+        // a flipped bit in a time is caught by the trailing checksum
+        output[12] ^= 1;
+        let error = super::read(&output[..], index.len(), index.packfile_checksum()).expect_err("the checksum is wrong");
+        assert_eq!(error.kind(), &ErrorKind::CorruptedMtimes);
+
+        let error = super::write(&index, &mtimes[1..], &mut Vec::new()).expect_err("a time is missing");
+        assert_eq!(error.kind(), &ErrorKind::WrongObjectCount {
+            expected: index.len() as u32,
+            actual: index.len() as u32 - 1
+        });
+        assert!(!error.is_corrupt());
+    }
+}
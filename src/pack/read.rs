@@ -1,15 +1,19 @@
 use flate2::bufread::ZlibDecoder;
 use std::convert::TryInto;
+use lru::LruCache;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::fmt::Debug;
 
-use crate::delta::{ DeltaDecoder, DeltaDecoderStream };
+use crate::delta::{ apply as apply_delta, result_size };
 use crate::pack::internal_type::PackfileType;
-use crate::stores::{ Queryable, StorageSet };
+use crate::stores::{ Limits, Queryable, StorageSet };
 use crate::delta::{ OFS_DELTA, REF_DELTA };
-use crate::errors::{ Result, ErrorKind };
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
 use crate::objects::Type;
+use crate::id::Id;
 
 #[derive(Debug)]
 pub struct PackfileEntryMeta {
@@ -26,134 +30,655 @@ impl PackfileEntryMeta {
         self.expected_size
     }
 
+    /// Finish reading the object whose entry starts at `initial` and was read
+    /// into this, writing it to `output` and returning its type. Plain objects
+    /// were already written out by `packfile_read`; for deltas, the chain of
+    /// bases is resolved from the bottom up, alternating between two buffers,
+    /// and the last delta is applied straight to `output`.
     pub fn decompress<R, W, S>(self, initial: u64, input: &mut R, output: &mut W, backends: Option<&StorageSet<S>>) -> Result<Type>
         where R: Debug + Read + BufRead + Seek,
               W: Write,
               S: Queryable {
-        Ok(match self.expected_type {
-            PackfileType::Plain(t) => {
-                t
-            },
+        let (base, instructions) = match self.expected_type {
+            PackfileType::Plain(t) => return Ok(t),
+            PackfileType::OffsetDelta((offset, instructions)) => (DeltaBase::Offset(offset), instructions),
+            PackfileType::RefDelta((id, instructions)) => (DeltaBase::Ref(id), instructions)
+        };
 
-            PackfileType::OffsetDelta((offset, instructions)) => {
-                let mut intermediary = Vec::new();
-                let current_position = input.seek(SeekFrom::Current(0))?;
-                let object_start = initial - offset;
-                input.seek(SeekFrom::Start(object_start))?;
-
-                let meta = packfile_read(
-                    input,
-                    &mut intermediary,
-                    &mut 0,
-                )?;
-
-                let object_type = meta.decompress(
-                    object_start,
-                    input,
-                    &mut intermediary,
-                    backends
-                )?;
-
-                input.seek(SeekFrom::Start(current_position))?;
-                let delta_decoder = DeltaDecoder::new(&instructions, intermediary)?;
-                let mut stream: DeltaDecoderStream = delta_decoder.into();
-                std::io::copy(&mut stream, output)?;
-                object_type
-            },
+        let limits = backends.map(|backends| *backends.limits()).unwrap_or_default();
+        limits.check_delta_output_size(result_size(&instructions)? as u64).at_offset(initial)?;
+
+        let current_position = input.stream_position()?;
+        let mut data = Vec::new();
+        let (object_type, deltas) = read_delta_chain(input, initial, base, &mut data, backends, &limits)?;
+
+        let mut target = Vec::new();
+        let mut delta_instructions = Vec::new();
+        for (start, data_start, size) in deltas.into_iter().rev() {
+            input.seek(SeekFrom::Start(data_start))?;
+            delta_instructions.clear();
+            inflate_entry(input.by_ref(), &mut delta_instructions, size).at_offset(start)?;
+            limits.check_delta_output_size(result_size(&delta_instructions)? as u64).at_offset(start)?;
+
+            target.clear();
+            apply_delta(&delta_instructions, &data, &mut target).at_offset(start)?;
+            std::mem::swap(&mut data, &mut target);
+        }
 
-            PackfileType::RefDelta((id, instructions)) => {
-                if backends.is_none() {
-                    return Err(ErrorKind::NeedStorageSet.into())
+        input.seek(SeekFrom::Start(current_position))?;
+        apply_delta(&instructions, &data, output)?;
+        Ok(object_type)
+    }
+}
+
+// The start of a delta entry, the start of its compressed instructions, and
+// their inflated size.
+type DeltaEntry = (u64, u64, u64);
+
+// Follow delta bases, starting with `base` (the base of the delta entry at
+// `position`), down to an object that isn't a delta, and inflate it into
+// `output`. Returns its type, along with the start of each delta entry passed
+// on the way, nearest first.
+fn read_delta_chain<R, S>(
+    input: &mut R,
+    mut position: u64,
+    mut base: DeltaBase,
+    output: &mut Vec<u8>,
+    backends: Option<&StorageSet<S>>,
+    limits: &Limits
+) -> Result<(Type, Vec<DeltaEntry>)>
+    where R: BufRead + Seek,
+          S: Queryable {
+    let mut deltas = Vec::new();
+    loop {
+        let offset = match base {
+            DeltaBase::Offset(offset) => offset,
+            DeltaBase::Ref(id) => {
+                let backends = backends.ok_or(ErrorKind::NeedStorageSet)?;
+                return match read_ref_base(backends, &id, output).at_offset(position)? {
+                    Some(object_type) => Ok((object_type, deltas)),
+                    None => Err(ErrorKind::MissingDeltaBase(id).into())
                 }
+            }
+        };
 
-                let mut base_data = Vec::new();
-                let t = match backends.unwrap().get(&id, &mut base_data)? {
-                    Some(xs) => xs,
-                    None => return Err(ErrorKind::CorruptedPackfile.into())
-                };
+        // a base must come before its delta, or the chain could loop
+        position = match position.checked_sub(offset) {
+            Some(xs) if offset > 0 => xs,
+            _ => return Err(Error::from(ErrorKind::CorruptedPackfile).at_offset(position))
+        };
 
-                let delta_decoder = DeltaDecoder::new(&instructions, base_data)?;
-                let mut stream: DeltaDecoderStream = delta_decoder.into();
-                std::io::copy(&mut stream, output)?;
-                t
+        input.seek(SeekFrom::Start(position))?;
+        let header = packfile_read_header(input).at_offset(position)?;
+        limits.check_object_size(header.size).at_offset(position)?;
+        let data_start = position + header.len();
+        match header.base {
+            Some(next) => {
+                deltas.push((position, data_start, header.size));
+                base = next;
+            },
+
+            None => {
+                let object_type = header.kind.try_into().at_offset(position)?;
+                inflate_entry(input.by_ref(), output, header.size).at_offset(position)?;
+                return Ok((object_type, deltas))
             }
-        })
+        }
+    }
+}
+
+// The deepest REF_DELTA bases can nest (git's default pack.depth is 50): each
+// base is read through the storage set, which takes a few more stack frames.
+const MAX_REF_DELTA_DEPTH: usize = 128;
+
+thread_local! {
+    // the REF_DELTA bases being read on this thread, outermost first
+    static RESOLVING: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
 
+// Pops the base pushed by `read_ref_base`, however reading it ends.
+struct Resolving;
+
+impl Drop for Resolving {
+    fn drop(&mut self) {
+        RESOLVING.with(|resolving| resolving.borrow_mut().pop());
     }
 }
 
-pub fn packfile_read<R: BufRead, W: Write>(
+/// Read the REF_DELTA base `id` from `backends` into `output`. The base may be
+/// a delta itself, possibly in another packfile; one that depends on itself, or
+/// a chain nested more than `MAX_REF_DELTA_DEPTH` deep, is corrupt.
+pub(crate) fn read_ref_base<W, S>(backends: &StorageSet<S>, id: &Id, output: &mut W) -> Result<Option<Type>>
+    where W: Write,
+          S: Queryable {
+    let _resolving = RESOLVING.with(|resolving| {
+        let mut resolving = resolving.borrow_mut();
+        if resolving.len() >= MAX_REF_DELTA_DEPTH || resolving.contains(id) {
+            return Err(Error::from(ErrorKind::CorruptedPackfile).for_object(id))
+        }
+
+        resolving.push(id.clone());
+        Ok(Resolving)
+    })?;
+
+    backends.get(id, output)
+}
+
+/// Delta bases resolved while reading objects from one packfile, by the offset
+/// of their entry, so that deltas sharing a base don't each rebuild it. Once the
+/// bases held add up to more than `limit` bytes, the least recently used are
+/// dropped.
+pub struct DeltaBaseCache {
+    entries: LruCache<u64, (Type, Rc<Vec<u8>>)>,
+    held: usize,
+    limit: usize
+}
+
+impl DeltaBaseCache {
+    pub fn new(limit: usize) -> Self {
+        DeltaBaseCache {
+            entries: LruCache::unbounded(),
+            held: 0,
+            limit
+        }
+    }
+
+    fn get(&mut self, offset: u64) -> Option<(Type, Rc<Vec<u8>>)> {
+        self.entries.get(&offset).cloned()
+    }
+
+    fn insert(&mut self, offset: u64, object_type: Type, data: Vec<u8>) -> Rc<Vec<u8>> {
+        let data = Rc::new(data);
+        if data.len() > self.limit {
+            return data
+        }
+
+        self.held += data.len();
+        if let Some((_, (_, previous))) = self.entries.push(offset, (object_type, data.clone())) {
+            self.held -= previous.len();
+        }
+
+        while self.held > self.limit {
+            match self.entries.pop_lru() {
+                Some((_, (_, evicted))) => self.held -= evicted.len(),
+                None => break
+            }
+        }
+
+        data
+    }
+}
+
+/// Read the object whose entry starts at `start` into `output`, as
+/// `packfile_read` and `decompress` do together, but stop following its delta
+/// chain at the first base found in `cache`, and add the bases resolved on the
+/// way to it. Reading many objects in the order they're stored shares the work
+/// of resolving their common bases.
+pub fn packfile_read_cached<R, W, S>(
     input: &mut R,
+    start: u64,
     output: &mut W,
-    read_bytes: &mut u64
-) -> Result<PackfileEntryMeta> {
+    backends: Option<&StorageSet<S>>,
+    cache: &mut DeltaBaseCache
+) -> Result<Type>
+    where R: BufRead + Seek,
+          W: Write,
+          S: Queryable {
+    let limits = backends.map(|backends| *backends.limits()).unwrap_or_default();
+
+    let mut deltas: Vec<DeltaEntry> = Vec::new();
+    let mut position = start;
+    let (object_type, mut data) = loop {
+        if let Some(cached) = cache.get(position) {
+            break cached
+        }
+
+        input.seek(SeekFrom::Start(position))?;
+        let header = packfile_read_header(input).at_offset(position)?;
+        limits.check_object_size(header.size).at_offset(position)?;
+        let data_start = position + header.len();
+
+        match header.base {
+            None => {
+                let object_type = header.kind.try_into().at_offset(position)?;
+                if deltas.is_empty() {
+                    inflate_entry(input.by_ref(), output, header.size).at_offset(position)?;
+                    return Ok(object_type)
+                }
+
+                let mut data = Vec::new();
+                inflate_entry(input.by_ref(), &mut data, header.size).at_offset(position)?;
+                break (object_type, cache.insert(position, object_type, data))
+            },
+
+            Some(DeltaBase::Offset(offset)) => {
+                deltas.push((position, data_start, header.size));
+
+                // a base must come before its delta, or the chain could loop
+                position = match position.checked_sub(offset) {
+                    Some(xs) if offset > 0 => xs,
+                    _ => return Err(Error::from(ErrorKind::CorruptedPackfile).at_offset(position))
+                };
+            },
+
+            Some(DeltaBase::Ref(id)) => {
+                deltas.push((position, data_start, header.size));
+
+                let backends = backends.ok_or(ErrorKind::NeedStorageSet)?;
+                let mut data = Vec::new();
+                match read_ref_base(backends, &id, &mut data).at_offset(position)? {
+                    Some(object_type) => break (object_type, Rc::new(data)),
+                    None => return Err(Error::from(ErrorKind::MissingDeltaBase(id)).at_offset(position))
+                }
+            }
+        }
+    };
+
+    let top = match deltas.first() {
+        Some((top, _, _)) => *top,
+        None => {
+            output.write_all(&data)?;
+            return Ok(object_type)
+        }
+    };
+
+    let mut instructions = Vec::new();
+    for (entry_start, data_start, size) in deltas.into_iter().rev() {
+        input.seek(SeekFrom::Start(data_start))?;
+        instructions.clear();
+        inflate_entry(input.by_ref(), &mut instructions, size).at_offset(entry_start)?;
+        limits.check_delta_output_size(result_size(&instructions)? as u64).at_offset(entry_start)?;
+
+        if entry_start == top {
+            apply_delta(&instructions, &data, output).at_offset(entry_start)?;
+            break
+        }
+
+        let mut target = Vec::new();
+        apply_delta(&instructions, &data, &mut target).at_offset(entry_start)?;
+        data = cache.insert(entry_start, object_type, target);
+    }
+
+    Ok(object_type)
+}
+
+/// Where a delta entry's base object can be found.
+#[derive(Debug, Clone)]
+pub enum DeltaBase {
+    /// The base is this many bytes before the start of the delta entry.
+    Offset(u64),
+
+    /// The base is the object with this id.
+    Ref(Id)
+}
+
+/// The header of a packfile entry, up to the start of its compressed data.
+#[derive(Debug)]
+pub struct PackfileEntryHeader {
+    kind: u8,
+    size: u64,
+    base: Option<DeltaBase>,
+    length: u64
+}
+
+impl PackfileEntryHeader {
+    /// The raw entry type: 1-4 for plain objects, `OFS_DELTA` or `REF_DELTA`.
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+
+    /// The inflated size of the entry (for deltas, the size of the delta instructions.)
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn base(&self) -> Option<&DeltaBase> {
+        self.base.as_ref()
+    }
+
+    /// The number of bytes taken up by the header.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+pub fn packfile_read_header<R: Read>(input: &mut R) -> Result<PackfileEntryHeader> {
     let mut byte = [0u8; 1];
     input.read_exact(&mut byte)?;
 
-    let obj_type = (byte[0] & 0x70) >> 4;
+    let kind = (byte[0] & 0x70) >> 4;
     let mut size = (byte[0] & 0xf) as u64;
-    let mut count = 0;
+    let mut length = 1;
     let mut continuation = byte[0] & 0x80;
 
     while continuation > 0 {
         input.read_exact(&mut byte)?;
         continuation = byte[0] & 0x80;
 
-        size |= ((byte[0] & 0x7f) as u64) << (4 + 7 * count);
-        count += 1;
+        // sizes that don't fit in 64 bits can't be right
+        let shift = 4 + 7 * (length - 1);
+        let bits = (byte[0] & 0x7f) as u64;
+        if shift >= 64 || (bits << shift) >> shift != bits {
+            return Err(ErrorKind::CorruptedPackfile.into())
+        }
+        size |= bits << shift;
+        length += 1;
     }
 
-    match obj_type {
-        0..=4 => {
-            let mut deflate_stream = ZlibDecoder::new(input);
-            std::io::copy(&mut deflate_stream, output)?;
-            *read_bytes = 1 + count + deflate_stream.total_in();
-            Ok(PackfileEntryMeta {
-                expected_type: PackfileType::Plain(obj_type.try_into()?),
-                expected_size: size
-            })
-        },
-
+    let base = match kind {
         OFS_DELTA => {
             input.read_exact(&mut byte)?;
+            length += 1;
             let mut offset = u64::from(byte[0] & 0x7F);
 
             while byte[0] & 0x80 > 0 {
-                offset += 1;
-                offset <<= 7;
+                if offset >= 1 << 56 {
+                    return Err(ErrorKind::CorruptedPackfile.into())
+                }
+
+                offset = (offset + 1) << 7;
                 input.read_exact(&mut byte)?;
                 offset += u64::from(byte[0] & 0x7F);
-                count += 1;
+                length += 1;
             }
 
-            let mut deflate_stream = ZlibDecoder::new(input);
-            let mut instructions = Vec::new();
-            deflate_stream.read_to_end(&mut instructions)?;
-
-            *read_bytes = 2 + count + deflate_stream.total_in();
-            Ok(PackfileEntryMeta {
-                expected_type: PackfileType::OffsetDelta((offset, instructions)),
-                expected_size: size
-            })
+            Some(DeltaBase::Offset(offset))
         },
 
         REF_DELTA => {
             let mut ref_bytes = [0u8; 20];
             input.read_exact(&mut ref_bytes)?;
-            let id = ref_bytes.into();
+            length += 20;
+            Some(DeltaBase::Ref(ref_bytes.into()))
+        },
 
-            let mut deflate_stream = ZlibDecoder::new(input);
-            let mut instructions = Vec::new();
-            deflate_stream.read_to_end(&mut instructions)?;
-            *read_bytes = 21 + count + deflate_stream.total_in();
-            Ok(PackfileEntryMeta {
-                expected_type: PackfileType::RefDelta((id, instructions)),
-                expected_size: size
-            })
+        0..=4 => None,
+
+        _ => return Err(ErrorKind::InvalidObjectType.into())
+    };
+
+    Ok(PackfileEntryHeader {
+        kind,
+        size,
+        base,
+        length
+    })
+}
+
+/// Find the type of the object stored at `start` by reading entry headers (following
+/// delta bases as necessary) without inflating any object data.
+pub fn packfile_read_type<R, S>(input: &mut R, start: u64, backends: Option<&StorageSet<S>>) -> Result<Type>
+    where R: Read + Seek,
+          S: Queryable {
+    let mut position = start;
+    loop {
+        input.seek(SeekFrom::Start(position))?;
+        let header = packfile_read_header(input).at_offset(position)?;
+
+        match header.base {
+            None => return header.kind.try_into().at_offset(position),
+            Some(DeltaBase::Offset(offset)) => {
+                // a base must come before its delta, or this would never end
+                position = match position.checked_sub(offset) {
+                    Some(xs) if offset > 0 => xs,
+                    _ => return Err(Error::from(ErrorKind::CorruptedPackfile).at_offset(position))
+                };
+            },
+            Some(DeltaBase::Ref(id)) => {
+                let backends = match backends {
+                    Some(xs) => xs,
+                    None => return Err(ErrorKind::NeedStorageSet.into())
+                };
+
+                return match read_ref_base(backends, &id, &mut std::io::sink()).at_offset(position)? {
+                    Some(xs) => Ok(xs),
+                    None => Err(ErrorKind::MissingDeltaBase(id).into())
+                }
+            }
+        }
+    }
+}
+
+fn inflate_error(e: std::io::Error) -> Error {
+    Error::inflate(ErrorKind::CorruptedPackfile, e)
+}
+
+// Inflate the entry data at the start of `input` into `output`, stopping one
+// byte past the `size` its header declared so that a zlib bomb can't produce
+// more than that. Returns the number of compressed bytes read.
+fn inflate_entry<R: BufRead, W: Write>(input: R, output: &mut W, size: u64) -> Result<u64> {
+    let mut deflate_stream = ZlibDecoder::new(input);
+    let actual = std::io::copy(&mut deflate_stream.by_ref().take(size.saturating_add(1)), output).map_err(inflate_error)?;
+    if actual != size {
+        return Err(ErrorKind::ObjectSizeMismatch { expected: size, actual }.into())
+    }
+
+    Ok(deflate_stream.total_in())
+}
+
+pub fn packfile_read<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    read_bytes: &mut u64
+) -> Result<PackfileEntryMeta> {
+    packfile_read_limited(input, output, read_bytes, &Limits::default())
+}
+
+/// Like `packfile_read`, but refuse to inflate entries bigger than `limits`
+/// allow. Delta entries are held to `limits.object_size`; the objects they
+/// produce are checked when they're resolved.
+pub fn packfile_read_limited<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    read_bytes: &mut u64,
+    limits: &Limits
+) -> Result<PackfileEntryMeta> {
+    let header = packfile_read_header(input)?;
+    limits.check_object_size(header.size)?;
+
+    let expected_type = match header.base {
+        None => {
+            let object_type = header.kind.try_into()?;
+            *read_bytes = header.length + inflate_entry(input, output, header.size)?;
+            PackfileType::Plain(object_type)
         },
 
-        _ => {
-            Err(ErrorKind::BadLooseObject.into())
+        Some(base) => {
+            let mut instructions = Vec::new();
+            *read_bytes = header.length + inflate_entry(input, &mut instructions, header.size)?;
+            match base {
+                DeltaBase::Offset(offset) => PackfileType::OffsetDelta((offset, instructions)),
+                DeltaBase::Ref(id) => PackfileType::RefDelta((id, instructions))
+            }
+        }
+    };
+
+    Ok(PackfileEntryMeta {
+        expected_type,
+        expected_size: header.size
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::{ Cursor, Write };
+    use std::str::FromStr;
+
+    use crate::test_support::{ commit_file, git, scratch_repo };
+    use crate::stores::Limits;
+    use crate::errors::ErrorKind;
+    use crate::pack::verify::verify;
+    use crate::pack::write::Writer;
+    use crate::pack::index;
+    use crate::objects::Type;
+    use crate::id::Id;
+    use super::{ packfile_read, packfile_read_cached, packfile_read_limited, packfile_read_type, DeltaBaseCache, MAX_REF_DELTA_DEPTH };
+
+    fn find_pack(root: &std::path::Path) -> std::path::PathBuf {
+        std::fs::read_dir(root.join(".git/objects/pack")).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension() == Some("pack".as_ref()))
+            .unwrap()
+    }
+
+    // a blob entry whose header declares `size` bytes, holding `data`
+    fn blob_entry(size: u8, data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![0x30 | size], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn checks_inflated_sizes() {
+        let entry = blob_entry(3, b"abc");
+        let mut output = Vec::new();
+        let mut read_bytes = 0;
+        packfile_read(&mut Cursor::new(&entry[..]), &mut output, &mut read_bytes).expect("the entry is fine");
+        assert_eq!(output, b"abc");
+        assert_eq!(read_bytes, entry.len() as u64);
+
+        let error = packfile_read(&mut Cursor::new(&blob_entry(5, b"abc")[..]), &mut Vec::new(), &mut 0).expect_err("the entry is short");
+        assert_eq!(error.kind(), &ErrorKind::ObjectSizeMismatch { expected: 5, actual: 3 });
+
+        // a bomb stops one byte past its declared size
+        let bomb = blob_entry(3, &vec![0u8; 1 << 20]);
+        let mut output = Vec::new();
+        let error = packfile_read(&mut Cursor::new(&bomb[..]), &mut output, &mut 0).expect_err("the entry is long");
+        assert_eq!(error.kind(), &ErrorKind::ObjectSizeMismatch { expected: 3, actual: 4 });
+        assert_eq!(output.len(), 4);
+
+        let limits = Limits { object_size: Some(2), ..Limits::default() };
+        let mut output = Vec::new();
+        let error = packfile_read_limited(&mut Cursor::new(&entry[..]), &mut output, &mut 0, &limits).expect_err("the entry is over the limit");
+        assert_eq!(error.kind(), &ErrorKind::SizeLimitExceeded { size: 3, limit: 2 });
+        assert!(!error.is_corrupt());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn refuses_objects_over_the_limits() {
+        let root = scratch_repo("pack-read-limits");
+        let contents: String = (0..10000).map(|xs| format!("line {}\n", xs)).collect();
+        commit_file(&root, "file.txt", &contents, 1_600_000_000);
+        commit_file(&root, "file.txt", &contents.replace("line 5000\n", "changed\n"), 1_600_000_100);
+        git(&root, &["repack", "-adfq"]);
+
+        let blobs: Vec<Id> = ["HEAD:file.txt", "HEAD~1:file.txt"].iter()
+            .map(|rev| Id::from_str(git(&root, &["rev-parse", rev]).trim()).unwrap())
+            .collect();
+
+        // one of the blobs is stored whole, the other as a small delta against it
+        let limited = root.storage().with_limits(Limits { delta_output_size: Some(1000), ..Limits::default() });
+        let errors: Vec<_> = blobs.iter().filter_map(|id| limited.get(id, &mut Vec::new()).err()).collect();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind(), ErrorKind::SizeLimitExceeded { limit: 1000, .. }));
+
+        let limited = root.storage().with_limits(Limits { object_size: Some(1000), ..Limits::default() });
+        for id in &blobs {
+            let error = limited.get(id, &mut Vec::new()).expect_err("the blob is over the limit");
+            assert!(matches!(error.kind(), ErrorKind::SizeLimitExceeded { limit: 1000, .. }));
         }
+        assert!(limited.get(&Id::from_str(git(&root, &["rev-parse", "HEAD"]).trim()).unwrap(), &mut Vec::new()).unwrap().is_some());
+
+        let pack = std::fs::read(find_pack(&root)).unwrap();
+        let error = index::write(Cursor::new(&pack[..]), &mut Vec::new(), Some(&limited)).expect_err("the pack has big objects");
+        assert!(matches!(error.kind(), ErrorKind::SizeLimitExceeded { limit: 1000, .. }));
+    }
+
+    #[test]
+    fn resolves_deep_delta_chains() {
+        let root = scratch_repo("pack-read-chains");
+        let mut contents: String = (0..200).map(|xs| format!("line {}\n", xs)).collect();
+        for idx in 0..40 {
+            contents = contents.replace(&format!("line {}\n", idx * 5), &format!("changed {}\n", idx));
+            commit_file(&root, "file.txt", &contents, 1_600_000_000 + idx * 100);
+        }
+        git(&root, &["repack", "-adfq", "--depth=50", "--window=50"]);
+
+        let pack_path = find_pack(&root);
+        let verification = verify(&std::fs::read(&pack_path).unwrap(), &std::fs::read(pack_path.with_extension("idx")).unwrap()).unwrap();
+        assert!(verification.chain_lengths().keys().any(|depth| *depth >= 15));
+
+        let storage_set = root.storage();
+        for line in git(&root, &["rev-list", "--objects", "--all"]).lines() {
+            let id = Id::from_str(&line[..40]).unwrap();
+            let mut data = Vec::new();
+            let kind = storage_set.get(&id, &mut data).expect("failed to read object").expect("object is missing");
+            assert_eq!(kind.hash(&data), id);
+        }
+
+        // reading in offset order shares bases, however few the cache can hold
+        let pack = std::fs::read(&pack_path).unwrap();
+        let index = index::read(&std::fs::read(pack_path.with_extension("idx")).unwrap()[..]).unwrap();
+        let mut objects: Vec<_> = index.ids().iter().map(|id| (index.get_bounds(id).unwrap().0, id)).collect();
+        objects.sort();
+
+        for limit in [0, 4096, usize::MAX].iter() {
+            let mut cache = DeltaBaseCache::new(*limit);
+            for (start, id) in &objects {
+                let mut data = Vec::new();
+                let kind = packfile_read_cached(&mut Cursor::new(&pack[..]), *start, &mut data, Some(&storage_set), &mut cache).expect("failed to read object");
+                assert_eq!(&kind.hash(&data), *id);
+            }
+            assert!(cache.held <= *limit);
+        }
+    }
+
+    #[test]
+    fn refuses_ref_delta_cycles() {
+        let root = scratch_repo("pack-read-cycles");
+        let id = |name: &str| Type::Blob.hash(name.as_bytes());
+        let chain = |idx: usize| if idx == 0 { Type::Blob.hash(b"xyz") } else { id(&format!("chain-{}", idx)) };
+        let instructions = [3, 3, 3, b'a', b'b', b'c'];
+
+        // "self" is its own base; "a" is a REF_DELTA against "b", which is an
+        // OFS_DELTA against "a"; "chain-N" is a REF_DELTA against "chain-(N-1)"
+        let depth = MAX_REF_DELTA_DEPTH + 10;
+        let mut pack = Vec::new();
+        let mut writer = Writer::new(&mut pack, depth as u32 + 4).unwrap();
+        writer.add_delta(id("self"), &id("self"), &instructions).unwrap();
+        writer.add_delta(id("a"), &id("b"), &instructions).unwrap();
+        writer.add_delta(id("b"), &id("a"), &instructions).unwrap();
+        for idx in (1..=depth).rev() {
+            writer.add_delta(chain(idx), &chain(idx - 1), &instructions).unwrap();
+        }
+        writer.add(Type::Blob, b"xyz").unwrap();
+        let mut index = Vec::new();
+        let checksum = writer.finish(&mut index).unwrap();
+
+        let pack_path = root.join(format!(".git/objects/pack/pack-{}.pack", checksum));
+        std::fs::write(&pack_path, &pack).unwrap();
+        std::fs::write(pack_path.with_extension("idx"), &index).unwrap();
+
+        let storage_set = root.storage();
+        let index = index::read(&index[..]).unwrap();
+        for name in ["self", "a", "b"].iter() {
+            let error = storage_set.get(&id(name), &mut Vec::new()).expect_err("the delta depends on itself");
+            assert_eq!(error.kind(), &ErrorKind::CorruptedPackfile);
+
+            let start = index.get_bounds(&id(name)).unwrap().0;
+            let error = packfile_read_cached(&mut Cursor::new(&pack[..]), start, &mut Vec::new(), Some(&storage_set), &mut DeltaBaseCache::new(0))
+                .expect_err("the delta depends on itself");
+            assert_eq!(error.kind(), &ErrorKind::CorruptedPackfile);
+
+            let error = packfile_read_type(&mut Cursor::new(&pack[..]), start, Some(&storage_set)).expect_err("the delta depends on itself");
+            assert_eq!(error.kind(), &ErrorKind::CorruptedPackfile);
+        }
+
+        let error = index::write(Cursor::new(&pack[..]), &mut Vec::new(), Some(&storage_set)).expect_err("the pack has cycles");
+        assert!(error.is_corrupt());
+
+        // long chains are fine, up to a point
+        let mut data = Vec::new();
+        storage_set.get(&chain(MAX_REF_DELTA_DEPTH), &mut data).expect("failed to read object");
+        assert_eq!(data, b"abc");
+        let error = storage_set.get(&chain(depth), &mut Vec::new()).expect_err("the chain is too deep");
+        assert_eq!(error.kind(), &ErrorKind::CorruptedPackfile);
     }
 }
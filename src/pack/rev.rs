@@ -0,0 +1,88 @@
+use crypto::{ sha1::Sha1, digest::Digest };
+use byteorder::{ BigEndian, ReadBytesExt };
+use std::io::prelude::*;
+
+use crate::errors::{ ErrorKind, Result };
+use crate::pack::index::Index;
+use crate::id::Id;
+
+const RIDX_MAGIC: &[u8; 4] = b"RIDX";
+
+/// Read the positions listed by a reverse index (`.rev` file) for a packfile index with the
+/// given `offsets` and `packfile_checksum`.
+pub fn read<R: Read>(mut input: R, offsets: &[u64], packfile_checksum: &Id) -> Result<Vec<u32>> {
+    // everything up to the trailing checksum is hashed by it
+    let mut contents = vec![0u8; 12 + offsets.len() * 4 + 20];
+    input.read_exact(&mut contents)?;
+
+    let mut checksum = [0u8; 20];
+    input.read_exact(&mut checksum)?;
+
+    let mut shasum = Sha1::new();
+    shasum.input(&contents);
+    let mut actual = [0u8; 20];
+    shasum.result(&mut actual);
+    if actual != checksum {
+        return Err(ErrorKind::CorruptedReverseIndex.into())
+    }
+
+    let (header, rest) = contents.split_at(12);
+    if &header[0..4] != RIDX_MAGIC {
+        return Err(ErrorKind::CorruptedReverseIndex.into())
+    }
+
+    // version 1, sha1 object ids
+    if header[4..12] != [0, 0, 0, 1, 0, 0, 0, 1] {
+        return Err(ErrorKind::UnsupportedReverseIndexVersion.into())
+    }
+
+    let (mut position_bytes, pack_checksum) = rest.split_at(offsets.len() * 4);
+    let mut positions = vec![0u32; offsets.len()];
+    position_bytes.read_u32_into::<BigEndian>(&mut positions)?;
+
+    if pack_checksum != packfile_checksum.as_ref() {
+        return Err(ErrorKind::CorruptedReverseIndex.into())
+    }
+
+    // Make sure the positions are in bounds and strictly increasing by offset. This
+    // is a linear scan, which is still much cheaper than sorting.
+    let mut last = None;
+    for position in positions.iter() {
+        let offset = match offsets.get(*position as usize) {
+            Some(xs) => *xs,
+            None => return Err(ErrorKind::CorruptedReverseIndex.into())
+        };
+
+        if last.map(|last| last >= offset).unwrap_or(false) {
+            return Err(ErrorKind::CorruptedReverseIndex.into())
+        }
+        last = Some(offset);
+    }
+
+    Ok(positions)
+}
+
+/// Write a reverse index for `index`.
+pub fn write<W: Write>(index: &Index, output: &mut W) -> Result<()> {
+    let mut shasum = Sha1::new();
+    let mut emit = |bytes: &[u8]| -> Result<()> {
+        shasum.input(bytes);
+        output.write_all(bytes)?;
+        Ok(())
+    };
+
+    emit(RIDX_MAGIC)?;
+    emit(&1u32.to_be_bytes())?;
+    emit(&1u32.to_be_bytes())?;
+
+    for position in index.positions_by_offset() {
+        emit(&position.to_be_bytes())?;
+    }
+
+    emit(index.packfile_checksum().as_ref())?;
+
+    let mut checksum = [0u8; 20];
+    shasum.result(&mut checksum);
+    output.write_all(&checksum)?;
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use crc::{ Crc, Digest as CrcDigest, CRC_32_ISO_HDLC };
+use crypto::{ sha1::Sha1, digest::Digest };
+use memmap::MmapOptions;
+use std::io::{ self, BufRead, BufReader, BufWriter, Cursor, Read, Write };
+use std::path::Path;
+
+use crate::errors::{ ErrorKind, Result, ResultExt };
+use crate::stores::{ Limits, Queryable, StorageSet };
+use crate::pack::index::{ resolve_entries, write_entries, PendingEntry, Version };
+use crate::pack::internal_type::PackfileType;
+use crate::pack::read::packfile_read_limited;
+use crate::id::Id;
+
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+// Passes everything read from `inner` on to `output`, hashing it (and taking
+// the crc32 of the current entry) along the way. Only bytes that are consumed
+// count as read, so zlib streams can be read through it without overshooting.
+struct Tee<'a, R: BufRead, W: Write> {
+    inner: R,
+    output: W,
+    hash: Sha1,
+    crc: CrcDigest<'a, u32>,
+    offset: u64,
+    error: Option<io::Error>
+}
+
+impl<'a, R: BufRead, W: Write> Tee<'a, R, W> {
+    // Finish the current entry, returning its crc32.
+    fn finish_entry(&mut self) -> Result<u32> {
+        if let Some(error) = self.error.take() {
+            return Err(error.into())
+        }
+
+        let crc = std::mem::replace(&mut self.crc, CRC32.digest());
+        Ok(crc.finalize())
+    }
+}
+
+impl<'a, R: BufRead, W: Write> Read for Tee<'a, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<'a, R: BufRead, W: Write> BufRead for Tee<'a, R, W> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt == 0 {
+            return
+        }
+
+        // the buffer was filled by the caller, so this doesn't read anything
+        if let Ok(buf) = self.inner.fill_buf() {
+            let consumed = &buf[..amt];
+            self.hash.input(consumed);
+            self.crc.update(consumed);
+            if let Err(e) = self.output.write_all(consumed) {
+                self.error.get_or_insert(e);
+            }
+        }
+
+        self.offset += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+/// Index a packfile as it is read from `input`, which needn't be seekable (a
+/// pipe, say.) The packfile is copied to a temporary file next to `pack_path` as
+/// it's read; offsets, crc32s and the ids of non-delta objects are found in the
+/// same pass. Once the trailing checksum has been checked, deltas are resolved
+/// from the copy on disk and the index (in the format given by `version`) is
+/// written to `index`. Only then is the copy moved to `pack_path`, so a bad
+/// packfile never replaces a good one. Returns the packfile checksum.
+pub fn index_stream<R, X, S>(
+    input: R,
+    pack_path: &Path,
+    index: &mut X,
+    storage_set: Option<&StorageSet<S>>,
+    version: Version
+) -> Result<Id> where
+    R: Read,
+    X: Write,
+    S: Queryable + Sync {
+
+    let mut tmp = pack_path.to_path_buf();
+    tmp.set_extension(format!("pack-tmp-{}", std::process::id()));
+
+    match index_copy(input, &tmp, index, storage_set, version).in_file(pack_path) {
+        Ok(checksum) => {
+            std::fs::rename(&tmp, pack_path).in_file(pack_path)?;
+            Ok(checksum)
+        },
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+fn index_copy<R, X, S>(
+    input: R,
+    tmp: &Path,
+    index: &mut X,
+    storage_set: Option<&StorageSet<S>>,
+    version: Version
+) -> Result<Id> where
+    R: Read,
+    X: Write,
+    S: Queryable + Sync {
+
+    let limits = storage_set.map(|storage_set| *storage_set.limits()).unwrap_or_default();
+    let (objects, crcs, checksum) = copy_pack(input, tmp, limits)?;
+
+    let file = std::fs::File::open(tmp)?;
+    let mmap = unsafe { MmapOptions::new().map(&file) }?;
+
+    let entries = resolve_entries(Cursor::new(&mmap[..]), objects, &crcs, storage_set)?;
+    write_entries(entries, &checksum, index, version)?;
+    Ok(checksum)
+}
+
+type Copied = (Vec<(u64, PendingEntry)>, Vec<u32>, Id);
+
+fn copy_pack<R: Read>(input: R, pack_path: &Path, limits: Limits) -> Result<Copied> {
+    let mut output = BufWriter::new(std::fs::File::create(pack_path)?);
+    let mut tee = Tee {
+        inner: BufReader::with_capacity(1 << 16, input),
+        output: &mut output,
+        hash: Sha1::new(),
+        crc: CRC32.digest(),
+        offset: 0,
+        error: None
+    };
+
+    let mut header = [0u8; 12];
+    tee.read_exact(&mut header)?;
+    if &header[0..4] != b"PACK" {
+        return Err(ErrorKind::CorruptedPackfile.into())
+    }
+
+    let version = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if version != 2 && version != 3 {
+        return Err(ErrorKind::UnsupportedPackfileVersion(version).into())
+    }
+    tee.finish_entry()?;
+
+    // the count hasn't been checked against anything yet, so don't trust it
+    // with more than a modest preallocation
+    let object_count = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let capacity = object_count.min(1 << 16) as usize;
+    let mut objects = Vec::with_capacity(capacity);
+    let mut crcs = Vec::with_capacity(capacity);
+    let mut data = Vec::new();
+    for _ in 0..object_count {
+        let offset = tee.offset;
+        data.clear();
+        let meta = packfile_read_limited(&mut tee, &mut data, &mut 0, &limits).at_offset(offset)?;
+
+        let id = match meta.expected_type() {
+            PackfileType::Plain(kind) => Some(kind.hash(&data)),
+            _ => None
+        };
+
+        crcs.push(tee.finish_entry().at_offset(offset)?);
+        objects.push((offset, PendingEntry::new(offset, &meta, id)?));
+    }
+
+    let mut actual = [0u8; 20];
+    tee.hash.result(&mut actual);
+
+    // the trailer isn't part of what it hashes, so it's read around the tee
+    let mut trailer = [0u8; 20];
+    tee.inner.read_exact(&mut trailer).at_offset(tee.offset)?;
+    if trailer != actual {
+        return Err(ErrorKind::CorruptedPackfile.into())
+    }
+
+    output.write_all(&trailer)?;
+    output.flush()?;
+    Ok((objects, crcs, trailer.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::test_support::scratch_dir;
+    use crate::stores::StorageSet;
+    use crate::pack::index::Version;
+    use crate::errors::ErrorKind;
+
+    // A reader that hands out a few bytes at a time, like a pipe might.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = self.0.len().min(buf.len()).min(7);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn indexes_streamed_packs() {
+        let root = scratch_dir("pack-stream");
+        let packfile = include_bytes!("../../fixtures/packfile");
+        let pack_path = root.join("test.pack");
+
+        let mut index = Vec::new();
+        let checksum = super::index_stream(Trickle(packfile), &pack_path, &mut index, None::<&StorageSet<()>>, Version::V2)
+            .expect("failed to index stream");
+
+        assert_eq!(&std::fs::read(&pack_path).unwrap()[..], &packfile[..]);
+        assert_eq!(&index[..], &include_bytes!("../../fixtures/pack_index")[..]);
+        assert_eq!(checksum.to_string(), "ac04939ad291747cb090c8758f549b5ebabdcc48");
+    }
+
+    #[test]
+    fn rejects_bad_checksums_and_truncation() {
+        let root = scratch_dir("pack-stream-bad");
+        let pack_path = root.join("test.pack");
+
+        let mut packfile = include_bytes!("../../fixtures/packfile").to_vec();
+        let last = packfile.len() - 1;
+        packfile[last] ^= 1;
+        let error = super::index_stream(&packfile[..], &pack_path, &mut Vec::new(), None::<&StorageSet<()>>, Version::V2)
+            .expect_err("the checksum is wrong");
+        assert_eq!(error.kind(), &ErrorKind::CorruptedPackfile);
+
+        let truncated = &packfile[..packfile.len() - 30];
+        let error = super::index_stream(truncated, &pack_path, &mut Vec::new(), None::<&StorageSet<()>>, Version::V2)
+            .expect_err("the pack is truncated");
+        assert_eq!(error.path(), Some(pack_path.as_path()));
+
+        // neither leaves anything behind, or replaces an existing pack
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+        std::fs::write(&pack_path, b"existing").unwrap();
+        super::index_stream(&packfile[..], &pack_path, &mut Vec::new(), None::<&StorageSet<()>>, Version::V2)
+            .expect_err("the checksum is wrong");
+        assert_eq!(std::fs::read(&pack_path).unwrap(), b"existing");
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rejects_absurd_object_counts() {
+        let root = scratch_dir("pack-stream-count");
+        let pack_path = root.join("test.pack");
+
+        let header = [b'P', b'A', b'C', b'K', 0, 0, 0, 2, 0xff, 0xff, 0xff, 0xff];
+        let error = super::index_stream(&header[..], &pack_path, &mut Vec::new(), None::<&StorageSet<()>>, Version::V2)
+            .expect_err("there are no objects");
+        assert_eq!(error.path(), Some(pack_path.as_path()));
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+use std::io::{ BufRead, Read, Seek, SeekFrom, Write };
+use std::fmt::Debug;
+
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::stores::{ Queryable, StorageSet };
+use crate::pack::index::{ pack_entries, Version };
+use crate::pack::write::Writer;
+use crate::id::Id;
+
+/// Complete the thin packfile in `input`: write a copy of it to `pack` with the
+/// `REF_DELTA` bases it's missing (read from `storage_set`) appended, along with
+/// its index (in the format given by `version`) to `index`. Returns the new
+/// packfile checksum and the ids of the objects that were appended.
+///
+/// Existing entries are copied byte-for-byte and keep their offsets, so their
+/// `OFS_DELTA`s stay valid; only the object count in the header and the
+/// trailing checksum change.
+pub fn fix_thin<R, W, X, S>(
+    mut input: R,
+    pack: W,
+    index: &mut X,
+    storage_set: &StorageSet<S>,
+    version: Version
+) -> Result<(Id, Vec<Id>)> where
+    R: BufRead + Seek + Clone + Debug + Sync,
+    W: Write,
+    X: Write,
+    S: Queryable + Sync {
+
+    let len = input.seek(SeekFrom::End(0))?;
+    let existing = pack_entries(input.clone(), Some(storage_set)).during("indexing thin packfile")?;
+
+    let ids: HashSet<&Id> = existing.entries.iter().map(|(id, _, _)| id).collect();
+    let mut missing: Vec<&Id> = existing.ref_bases.iter().filter(|id| !ids.contains(id)).collect();
+    missing.sort();
+    missing.dedup();
+
+    let mut writer = Writer::new(pack, (existing.entries.len() + missing.len()) as u32)?;
+
+    let ends = existing.entries.iter().skip(1).map(|(_, offset, _)| *offset).chain(std::iter::once(len - 20));
+    let mut entry = Vec::new();
+    for ((id, start, _), end) in existing.entries.iter().zip(ends) {
+        entry.clear();
+        input.seek(SeekFrom::Start(*start))?;
+        (&mut input).take(end - start).read_to_end(&mut entry)?;
+        writer.write_entry(id.clone(), &entry).for_object(id).at_offset(*start)?;
+    }
+
+    let mut data = Vec::new();
+    for id in missing.iter() {
+        data.clear();
+        let kind = match storage_set.get(id, &mut data)? {
+            Some(xs) => xs,
+            None => return Err(Error::from(ErrorKind::MissingDeltaBase((*id).clone())))
+        };
+        writer.add(kind, &data)?;
+    }
+
+    let checksum = writer.finish_with_version(index, version)?;
+    Ok((checksum, missing.into_iter().cloned().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::str::FromStr;
+
+    use crate::test_support::{ commit_file, git, scratch_repo };
+    use crate::pack::window::{ write_with_deltas, Options };
+    use crate::pack::index::Version;
+    use crate::id::Id;
+
+    #[test]
+    fn appends_missing_bases() {
+        let root = scratch_repo("pack-thin");
+        let contents: String = (0..300).map(|xs| format!("line {}\n", xs)).collect();
+        commit_file(&root, "file.txt", &contents, 1_600_000_000);
+        commit_file(&root, "file.txt", &contents.replace("line 150", "changed"), 1_600_000_100);
+        git(&root, &["repack", "-adfq"]);
+
+        // the objects in the last commit, as a thin pack against the first
+        let objects: Vec<(Id, u32)> = git(&root, &["rev-list", "--objects", "HEAD~1..HEAD"]).lines().map(|line| {
+            (Id::from_str(&line[..40]).unwrap(), 0)
+        }).collect();
+        let storage_set = root.storage();
+        let mut thin = Vec::new();
+        let options = Options { window: 0, thin: true, ..Default::default() };
+        write_with_deltas(&storage_set, &objects, options, &mut thin, &mut Vec::new()).expect("failed to write pack");
+
+        let mut pack = Vec::new();
+        let mut index = Vec::new();
+        let (checksum, appended) = super::fix_thin(Cursor::new(&thin[..]), &mut pack, &mut index, &storage_set, Version::V2)
+            .expect("failed to fix thin pack");
+        assert!(!appended.is_empty());
+        assert_eq!(u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]) as usize, objects.len() + appended.len());
+
+        // git can index it without any other objects around
+        let other = scratch_repo("pack-thin-other");
+        std::fs::write(other.join("test.pack"), &pack).unwrap();
+        assert_eq!(git(&other, &["index-pack", "-o", "test.idx", "test.pack"]).trim(), checksum.to_string());
+        assert_eq!(std::fs::read(other.join("test.idx")).unwrap(), index);
+    }
+}
@@ -0,0 +1,361 @@
+use crc::{ Crc, CRC_32_ISO_HDLC };
+use crypto::{ sha1::Sha1, digest::Digest };
+use flate2::bufread::ZlibDecoder;
+use rayon::prelude::*;
+use std::collections::{ BTreeMap, HashMap };
+use std::convert::TryInto;
+use std::io::{ Cursor, Read };
+
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::pack::index::{ read, resolve_entries, Index, PendingEntry };
+use crate::pack::read::{ packfile_read_header, DeltaBase };
+use crate::stores::StorageSet;
+use crate::objects::Type;
+use crate::id::Id;
+
+/// An object in a verified packfile, as `git verify-pack -v` describes it.
+#[derive(Debug, Clone)]
+pub struct VerifiedObject {
+    id: Id,
+    kind: Type,
+    size: u64,
+    stored_size: u64,
+    offset: u64,
+    depth: usize,
+    base: Option<Id>
+}
+
+impl VerifiedObject {
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    /// The type of the object (for deltas, the type of the object they produce.)
+    pub fn kind(&self) -> Type {
+        self.kind
+    }
+
+    /// The inflated size of the entry (for deltas, the size of the delta instructions.)
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The number of bytes the entry takes up in the packfile.
+    pub fn stored_size(&self) -> u64 {
+        self.stored_size
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The number of deltas between this object and a non-delta object.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The object this one is a delta against.
+    pub fn base(&self) -> Option<&Id> {
+        self.base.as_ref()
+    }
+}
+
+/// The result of checking a packfile against its index.
+#[derive(Debug)]
+pub struct Verification {
+    objects: Vec<VerifiedObject>,
+    packfile_checksum: Id
+}
+
+impl Verification {
+    /// Every object in the packfile, in packfile order.
+    pub fn objects(&self) -> &[VerifiedObject] {
+        &self.objects
+    }
+
+    pub fn packfile_checksum(&self) -> &Id {
+        &self.packfile_checksum
+    }
+
+    /// The number of objects at each delta depth (0 being non-delta objects.)
+    pub fn chain_lengths(&self) -> BTreeMap<usize, usize> {
+        let mut chain_lengths = BTreeMap::new();
+        for object in &self.objects {
+            *chain_lengths.entry(object.depth).or_insert(0) += 1;
+        }
+        chain_lengths
+    }
+}
+
+// What scanning a single entry finds, before deltas are resolved.
+struct Scanned {
+    position: usize,
+    offset: u64,
+    stored_size: u64,
+    size: u64,
+    entry: PendingEntry
+}
+
+/// Check a packfile against its index, as `git verify-pack` does: both trailing
+/// checksums, that the index describes this packfile, that every entry's crc32
+/// matches its stored bytes, and that every object inflates to its recorded size
+/// and hashes to the id the index gives it.
+pub fn verify(pack: &[u8], index: &[u8]) -> Result<Verification> {
+    check_trailer(index, ErrorKind::CorruptedPackfileIndex).during("checking index checksum")?;
+    let packfile_checksum = check_trailer(pack, ErrorKind::CorruptedPackfile).during("checking packfile checksum")?;
+
+    let index = read(Cursor::new(index)).during("reading pack index")?;
+    if index.packfile_checksum() != &packfile_checksum {
+        return Err(Error::from(ErrorKind::CorruptedPackfileIndex).during("matching index to packfile"))
+    }
+
+    if pack.len() < 32 || &pack[0..4] != b"PACK" {
+        return Err(ErrorKind::CorruptedPackfile.into())
+    }
+
+    let object_count = u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]);
+    if object_count as usize != index.len() {
+        return Err(ErrorKind::WrongObjectCount { expected: index.len() as u32, actual: object_count }.into())
+    }
+
+    let positions = index.positions_by_offset();
+    let ends = positions.iter().skip(1).map(|position| index.offsets()[*position as usize]);
+    let bounds: Vec<_> = positions.iter().zip(ends.chain(std::iter::once(pack.len() as u64 - 20))).collect();
+
+    let scanned = bounds.into_par_iter().map(|(position, end)| {
+        let position = *position as usize;
+        let offset = index.offsets()[position];
+        scan(pack, &index, position, offset, end).for_object(&index.ids()[position]).at_offset(offset)
+    }).collect::<Result<Vec<_>>>()?;
+
+    let objects: Vec<_> = scanned.iter().map(|scanned| (scanned.offset, scanned.entry.clone())).collect();
+    let crcs = vec![0; objects.len()];
+    let no_storage = StorageSet::new(());
+    let entries = resolve_entries(Cursor::new(pack), objects, &crcs, Some(&no_storage)).during("resolving deltas")?;
+
+    for (scanned, (actual, _, _)) in scanned.iter().zip(entries.iter()) {
+        let expected = &index.ids()[scanned.position];
+        if actual != expected {
+            return Err(Error::from(ErrorKind::ObjectHashMismatch(actual.clone())).for_object(expected).at_offset(scanned.offset))
+        }
+    }
+
+    Ok(Verification {
+        objects: describe(&index, &scanned)?,
+        packfile_checksum
+    })
+}
+
+// Check the SHA-1 that ends `bytes` against the rest of them, returning it.
+fn check_trailer(bytes: &[u8], kind: ErrorKind) -> Result<Id> {
+    if bytes.len() < 20 {
+        return Err(kind.into())
+    }
+
+    let (body, trailer) = bytes.split_at(bytes.len() - 20);
+    let mut hash = Sha1::new();
+    hash.input(body);
+    let mut actual = [0u8; 20];
+    hash.result(&mut actual);
+
+    if actual != trailer {
+        return Err(kind.into())
+    }
+
+    Ok(actual.into())
+}
+
+fn scan(pack: &[u8], index: &Index, position: usize, offset: u64, end: u64) -> Result<Scanned> {
+    if offset >= end || end > pack.len() as u64 {
+        return Err(ErrorKind::CorruptedPackfileIndex.into())
+    }
+
+    let raw = &pack[offset as usize..end as usize];
+    if let Some(crcs) = index.crcs() {
+        let expected = crcs[position];
+        let actual = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(raw);
+        if expected != actual {
+            return Err(ErrorKind::Crc32Mismatch { expected, actual }.into())
+        }
+    }
+
+    let header = packfile_read_header(&mut &raw[..])?;
+    let entry = match header.base() {
+        Some(DeltaBase::Offset(distance)) => {
+            PendingEntry::OffsetDelta(offset.checked_sub(*distance).ok_or(ErrorKind::CorruptedPackfile)?)
+        },
+        Some(DeltaBase::Ref(base)) => PendingEntry::RefDelta(base.clone()),
+        None => {
+            let kind: Type = header.kind().try_into()?;
+            let mut data = Vec::new();
+            ZlibDecoder::new(&raw[header.len() as usize..])
+                .take(header.size() + 1)
+                .read_to_end(&mut data)
+                .map_err(|e| Error::inflate(ErrorKind::CorruptedPackfile, e))?;
+
+            if data.len() as u64 != header.size() {
+                return Err(ErrorKind::ObjectSizeMismatch { expected: header.size(), actual: data.len() as u64 }.into())
+            }
+
+            let actual = kind.hash(&data);
+            if actual != index.ids()[position] {
+                return Err(ErrorKind::ObjectHashMismatch(actual).into())
+            }
+            PendingEntry::Object(kind, actual)
+        }
+    };
+
+    Ok(Scanned {
+        position,
+        offset,
+        stored_size: end - offset,
+        size: header.size(),
+        entry
+    })
+}
+
+// Find the type, depth and base of every (already resolved) object.
+fn describe(index: &Index, scanned: &[Scanned]) -> Result<Vec<VerifiedObject>> {
+    let by_offset: HashMap<u64, usize> = scanned.iter().enumerate().map(|(idx, scanned)| (scanned.offset, idx)).collect();
+    let base_of = |scanned: &Scanned| -> Option<usize> {
+        match &scanned.entry {
+            PendingEntry::Object(..) => None,
+            PendingEntry::OffsetDelta(base) => by_offset.get(base).copied(),
+            PendingEntry::RefDelta(base) => index.get_bounds(base).and_then(|(offset, _)| by_offset.get(&offset).copied())
+        }
+    };
+
+    // resolve each chain once, remembering the type and depth of everything on it
+    let mut resolved: Vec<Option<(Type, usize)>> = vec![None; scanned.len()];
+    for start in 0..scanned.len() {
+        let mut chain = Vec::new();
+        let mut idx = start;
+        let (kind, depth) = loop {
+            if let Some(xs) = resolved[idx] {
+                break xs
+            }
+
+            match (&scanned[idx].entry, base_of(&scanned[idx])) {
+                (PendingEntry::Object(kind, _), _) => break (*kind, 0),
+                (_, Some(base)) if chain.len() < scanned.len() => {
+                    chain.push(idx);
+                    idx = base;
+                },
+                _ => return Err(Error::from(ErrorKind::CorruptedPackfile).at_offset(scanned[idx].offset))
+            }
+        };
+
+        resolved[idx] = Some((kind, depth));
+        for (distance, delta) in chain.iter().rev().enumerate() {
+            resolved[*delta] = Some((kind, depth + distance + 1));
+        }
+    }
+
+    Ok(scanned.iter().zip(resolved).map(|(object, resolved)| {
+        let (kind, depth) = resolved.unwrap_or((Type::Blob, 0));
+        VerifiedObject {
+            id: index.ids()[object.position].clone(),
+            kind,
+            size: object.size,
+            stored_size: object.stored_size,
+            offset: object.offset,
+            depth,
+            base: base_of(object).map(|base| index.ids()[scanned[base].position].clone())
+        }
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crypto::{ sha1::Sha1, digest::Digest };
+
+    use crate::test_support::{ commit_file, git, scratch_repo };
+    use crate::errors::ErrorKind;
+
+    #[test]
+    fn verifies_fixture() {
+        let packfile = include_bytes!("../../fixtures/packfile");
+        let index = include_bytes!("../../fixtures/pack_index");
+
+        let verification = super::verify(&packfile[..], &index[..]).expect("failed to verify");
+        assert_eq!(verification.objects().len(), 5);
+        assert_eq!(verification.packfile_checksum().to_string(), "ac04939ad291747cb090c8758f549b5ebabdcc48");
+
+        // v1 indexes have no crcs, but can still be checked
+        let v1 = include_bytes!("../../fixtures/pack_index_v1");
+        super::verify(&packfile[..], &v1[..]).expect("failed to verify with v1 index");
+    }
+
+    #[test]
+    fn rejects_damage() {
+        let packfile = include_bytes!("../../fixtures/packfile");
+        let index = include_bytes!("../../fixtures/pack_index");
+
+        let mut damaged = index.to_vec();
+        damaged[1100] ^= 1;
+        let error = super::verify(&packfile[..], &damaged).expect_err("index checksum is wrong");
+        assert_eq!(error.kind(), &ErrorKind::CorruptedPackfileIndex);
+
+        let mut damaged = packfile.to_vec();
+        damaged[100] ^= 1;
+        let error = super::verify(&damaged, &index[..]).expect_err("packfile checksum is wrong");
+        assert_eq!(error.kind(), &ErrorKind::CorruptedPackfile);
+
+        let other = include_bytes!("../../fixtures/bitmap_packfile");
+        let error = super::verify(&other[..], &index[..]).expect_err("index is for another pack");
+        assert_eq!(error.kind(), &ErrorKind::CorruptedPackfileIndex);
+    }
+
+    #[test]
+    fn matches_git_verify_pack() {
+        let root = scratch_repo("pack-verify");
+        let mut contents: String = (0..200).map(|xs| format!("line {}\n", xs)).collect();
+        for idx in 0..20 {
+            contents = contents.replace(&format!("line {}\n", idx * 7), &format!("changed {}\n", idx));
+            commit_file(&root, "file.txt", &contents, 1_600_000_000 + idx * 100);
+        }
+        git(&root, &["repack", "-adfq"]);
+
+        let pack_dir = root.join(".git/objects/pack");
+        let index_path = std::fs::read_dir(&pack_dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension() == Some("idx".as_ref()))
+            .unwrap();
+        let packfile = std::fs::read(index_path.with_extension("pack")).unwrap();
+        let index = std::fs::read(&index_path).unwrap();
+        let verification = super::verify(&packfile, &index).expect("failed to verify");
+
+        let expected = git(&root, &["verify-pack", "-v", index_path.to_str().unwrap()]);
+        let expected_objects: Vec<_> = expected.lines().take(verification.objects().len()).collect();
+        for (object, line) in verification.objects().iter().zip(expected_objects) {
+            let mut description = format!(
+                "{} {:<6} {} {} {}",
+                object.id(),
+                object.kind().as_str(),
+                object.size(),
+                object.stored_size(),
+                object.offset()
+            );
+            if let Some(base) = object.base() {
+                description.push_str(&format!(" {} {}", object.depth(), base));
+            }
+            assert_eq!(description, line);
+        }
+        assert!(verification.chain_lengths().len() > 1);
+
+        // a crc32 that doesn't match the stored bytes (in an otherwise intact index)
+        let delta = verification.objects().iter().position(|object| object.base().is_some()).unwrap();
+        let position = crate::pack::index::read(std::io::Cursor::new(&index)).unwrap()
+            .position(verification.objects()[delta].id()).unwrap();
+        let mut damaged = index.clone();
+        let crc_start = 8 + 256 * 4 + verification.objects().len() * 20 + position * 4;
+        damaged[crc_start] ^= 1;
+        let mut hash = Sha1::new();
+        hash.input(&damaged[..damaged.len() - 20]);
+        let len = damaged.len();
+        hash.result(&mut damaged[len - 20..]);
+        let error = super::verify(&packfile, &damaged).expect_err("the crc32 is wrong");
+        assert!(matches!(error.kind(), ErrorKind::Crc32Mismatch { .. }));
+        assert_eq!(error.offset(), Some(verification.objects()[delta].offset()));
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::io::Write;
+
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::stores::{ Queryable, StorageSet };
+use crate::pack::write::Writer;
+use crate::pack::StoredEntry;
+use crate::delta::DeltaEncoder;
+use crate::objects::Type;
+use crate::id::Id;
+
+/// How hard to look for delta bases when writing a packfile.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// How many of the preceding (similar) objects to try as a base for each
+    /// object. Zero disables deltas.
+    pub window: usize,
+
+    /// The longest chain of deltas to allow.
+    pub depth: usize,
+
+    /// Copy objects that are already packed as they are stored, rather than
+    /// inflating and recompressing them; this includes keeping existing deltas.
+    pub reuse: bool,
+
+    /// Allow reused deltas against bases that aren't being written, making a
+    /// thin pack (as sent to a client that already has the bases.)
+    pub thin: bool
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { window: 10, depth: 50, reuse: true, thin: false }
+    }
+}
+
+/// Hash the path an object was found at, so that objects at the same path (or
+/// with the same file name) sort next to each other. Later characters count the
+/// most, and whitespace is ignored. This is the hash git keeps in `.bitmap`
+/// name-hash caches.
+pub fn name_hash(path: &[u8]) -> u32 {
+    path.iter().filter(|byte| !byte.is_ascii_whitespace()).fold(0u32, |hash, byte| {
+        (hash >> 2).wrapping_add((*byte as u32) << 24)
+    })
+}
+
+// How an object will be written.
+enum Entry {
+    // From its contents.
+    Plain,
+
+    // As a copy of its stored entry, which isn't a delta.
+    Stored(StoredEntry),
+
+    // As a delta that we found.
+    Delta(Vec<u8>),
+
+    // As a copy of its stored delta.
+    Reused(StoredEntry)
+}
+
+struct Candidate<'a> {
+    id: &'a Id,
+    kind: Type,
+    name_hash: u32,
+    size: usize,
+    entry: Entry,
+    // the candidate this is a delta against, if any
+    base: Option<usize>,
+    // whether a reused delta refers to this candidate
+    reused_base: bool
+}
+
+impl<'a> Candidate<'a> {
+    fn is_reused(&self) -> bool {
+        matches!(self.entry, Entry::Reused(_))
+    }
+}
+
+/// Write a packfile containing the objects `objects` (ids with the name hash of
+/// their path, or 0) to `pack`, and its index to `index`, storing objects as
+/// deltas against each other where that saves space. Returns the packfile
+/// checksum.
+///
+/// This follows git's pack-objects: objects are sorted by type, name hash and
+/// size (largest first, since deleting data makes for smaller deltas than
+/// adding it), and each is diffed against the `window` objects before it. Bases
+/// are always written before the objects that refer to them, so every delta is
+/// an `OFS_DELTA` (except reused deltas in a thin pack, whose base is missing.)
+///
+/// With `options.reuse`, objects that are already packed are copied without
+/// being recompressed, keeping their existing deltas; only the others are
+/// searched for new deltas.
+///
+/// Each object is inflated once. Contents are kept until the object is written,
+/// unless it turns out to be written as a delta or a copy of its stored entry.
+pub fn write_with_deltas<S, W, X>(
+    storage_set: &StorageSet<S>,
+    objects: &[(Id, u32)],
+    options: Options,
+    pack: W,
+    index: &mut X
+) -> Result<Id> where
+    S: Queryable,
+    W: Write,
+    X: Write {
+
+    let mut seen = HashSet::with_capacity(objects.len());
+    let mut candidates = Vec::with_capacity(objects.len());
+    let mut contents = Vec::with_capacity(objects.len());
+    for (id, name_hash) in objects {
+        if !seen.insert(id) {
+            continue
+        }
+
+        let mut data = Vec::new();
+        let kind = read(storage_set, id, &mut data)?;
+        candidates.push(Candidate {
+            id,
+            kind,
+            name_hash: *name_hash,
+            size: data.len(),
+            entry: Entry::Plain,
+            base: None,
+            reused_base: false
+        });
+        contents.push(data);
+    }
+
+    if options.reuse {
+        reuse_deltas(storage_set, &mut candidates, options)?;
+    }
+
+    if options.window > 0 && options.depth > 0 {
+        find_deltas(&mut candidates, &mut contents, options);
+    }
+
+    for (candidate, data) in candidates.iter().zip(contents.iter_mut()) {
+        if !matches!(candidate.entry, Entry::Plain) {
+            *data = Vec::new();
+        }
+    }
+
+    let mut writer = Writer::new(pack, candidates.len() as u32)?;
+    for idx in 0..candidates.len() {
+        write_candidate(&candidates, &contents, idx, &mut writer)?;
+    }
+
+    writer.finish(index)
+}
+
+fn read<S: Queryable>(storage_set: &StorageSet<S>, id: &Id, data: &mut Vec<u8>) -> Result<Type> {
+    data.clear();
+    match storage_set.get(id, data)? {
+        Some(kind) => Ok(kind),
+        None => Err(Error::from(ErrorKind::ObjectNotFound).for_object(id))
+    }
+}
+
+fn type_order(kind: Type) -> u8 {
+    match kind {
+        Type::Commit => 1,
+        Type::Tree => 2,
+        Type::Blob => 3,
+        Type::Tag => 4
+    }
+}
+
+// Keep the stored deltas of objects that are already packed, as long as their
+// base is also being written (or we're writing a thin pack.)
+fn reuse_deltas<S: Queryable>(storage_set: &StorageSet<S>, candidates: &mut [Candidate], options: Options) -> Result<()> {
+    let by_id: HashMap<Id, usize> = candidates.iter().enumerate().map(|(idx, candidate)| {
+        (candidate.id.clone(), idx)
+    }).collect();
+
+    for candidate in candidates.iter_mut() {
+        let entry = match storage_set.stored_entry(candidate.id)? {
+            Some(xs) => xs,
+            None => continue
+        };
+
+        let base = match entry.base() {
+            Some(base) => by_id.get(base).cloned(),
+            None => {
+                candidate.entry = Entry::Stored(entry);
+                continue
+            }
+        };
+
+        if base.is_some() || options.thin {
+            candidate.base = base;
+            candidate.entry = Entry::Reused(entry);
+        }
+    }
+
+    // Objects may be stored in more than one pack, against different bases, so
+    // the chains we've put together may be too long (or even cycles.)
+    for idx in 0..candidates.len() {
+        if candidates[idx].is_reused() && depth(candidates, idx, options.depth) > options.depth {
+            candidates[idx].entry = Entry::Plain;
+            candidates[idx].base = None;
+        }
+    }
+
+    for idx in 0..candidates.len() {
+        if let (true, Some(base)) = (candidates[idx].is_reused(), candidates[idx].base) {
+            candidates[base].reused_base = true;
+        }
+    }
+
+    Ok(())
+}
+
+// The length of the delta chain leading to `idx`, counting at most `limit` + 1.
+fn depth(candidates: &[Candidate], mut idx: usize, limit: usize) -> usize {
+    let mut depth = 0;
+    while let Some(base) = candidates[idx].base {
+        depth += 1;
+        if depth > limit {
+            break
+        }
+        idx = base;
+    }
+    depth
+}
+
+// Search for deltas, taking each object's contents from `contents` (and putting
+// them back when they leave the window, if the object is still written plain.)
+fn find_deltas(candidates: &mut [Candidate], contents: &mut [Vec<u8>], options: Options) {
+    let mut order: Vec<usize> = (0..candidates.len()).collect();
+    order.sort_by(|lhs, rhs| {
+        let (lhs_candidate, rhs_candidate) = (&candidates[*lhs], &candidates[*rhs]);
+        type_order(rhs_candidate.kind).cmp(&type_order(lhs_candidate.kind))
+            .then(rhs_candidate.name_hash.cmp(&lhs_candidate.name_hash))
+            .then(rhs_candidate.size.cmp(&lhs_candidate.size))
+            .then(lhs.cmp(rhs))
+    });
+
+    let mut window: VecDeque<(usize, DeltaEncoder<Vec<u8>>)> = VecDeque::with_capacity(options.window + 1);
+    for target_idx in order {
+        let target = std::mem::take(&mut contents[target_idx]);
+
+        // Reused deltas stay as they are. So do their bases: giving them a delta
+        // could make a chain too long, or a cycle.
+        let searching = !candidates[target_idx].is_reused() && !candidates[target_idx].reused_base;
+        for (base_idx, encoder) in window.iter().rev().filter(|_| searching) {
+            let base_depth = depth(candidates, *base_idx, options.depth);
+            if let Some(delta) = try_delta(candidates, *base_idx, base_depth, target_idx, encoder, &target, options) {
+                candidates[target_idx].entry = Entry::Delta(delta);
+                candidates[target_idx].base = Some(*base_idx);
+            }
+        }
+
+        window.push_back((target_idx, DeltaEncoder::new(target)));
+        if window.len() > options.window {
+            let (idx, encoder) = window.pop_front().unwrap();
+            restore(candidates, contents, idx, encoder);
+        }
+    }
+
+    for (idx, encoder) in window {
+        restore(candidates, contents, idx, encoder);
+    }
+}
+
+fn restore(candidates: &[Candidate], contents: &mut [Vec<u8>], idx: usize, encoder: DeltaEncoder<Vec<u8>>) {
+    if let Entry::Plain = candidates[idx].entry {
+        contents[idx] = encoder.into_base();
+    }
+}
+
+// Try `base_idx` (whose delta chain is `base_depth` long) as a delta base for
+// `target_idx`, returning the delta instructions if they're an improvement on
+// what we have for it so far.
+fn try_delta(
+    candidates: &[Candidate],
+    base_idx: usize,
+    base_depth: usize,
+    target_idx: usize,
+    encoder: &DeltaEncoder<Vec<u8>>,
+    target_data: &[u8],
+    options: Options
+) -> Option<Vec<u8>> {
+    let (base, target) = (&candidates[base_idx], &candidates[target_idx]);
+    if base.kind != target.kind || base_depth >= options.depth {
+        return None
+    }
+
+    // A delta is only worth it if it's at most half the size of the object (less
+    // the space taken by the base reference); if we already have a delta, it has
+    // to beat that. Either way, bases further down a chain have to do better.
+    let (max_size, current_depth) = match &target.entry {
+        Entry::Delta(delta) => (delta.len(), depth(candidates, target_idx, options.depth)),
+        _ => ((target.size / 2).checked_sub(20)?, 1)
+    };
+    let max_size = max_size * (options.depth - base_depth) / (options.depth - current_depth + 1);
+    if max_size == 0 {
+        return None
+    }
+
+    // the delta has to insert at least this much
+    if target.size.saturating_sub(base.size) >= max_size || target.size < base.size / 32 {
+        return None
+    }
+
+    let delta = encoder.encode_limited(target_data, max_size)?;
+    match &target.entry {
+        Entry::Delta(current) if delta.len() > current.len() => None,
+        Entry::Delta(current) if delta.len() == current.len() && base_depth + 1 >= current_depth => None,
+        _ => Some(delta)
+    }
+}
+
+fn write_candidate<W: Write>(candidates: &[Candidate], contents: &[Vec<u8>], idx: usize, writer: &mut Writer<W>) -> Result<()> {
+    // bases have to be written first; chains are at most `depth` long, but we
+    // still don't want to recurse down them.
+    let mut chain = vec![idx];
+    while let Some(base_idx) = candidates[*chain.last().unwrap()].base {
+        if writer.contains(candidates[base_idx].id) {
+            break
+        }
+        chain.push(base_idx);
+    }
+
+    for idx in chain.into_iter().rev() {
+        let candidate = &candidates[idx];
+        if writer.contains(candidate.id) {
+            continue
+        }
+
+        match (&candidate.entry, candidate.base) {
+            (Entry::Delta(delta), Some(base_idx)) => writer.add_delta(candidate.id.clone(), candidates[base_idx].id, delta),
+            (Entry::Reused(entry), _) | (Entry::Stored(entry), _) => writer.add_stored(candidate.id.clone(), entry),
+            _ => writer.add(candidate.kind, &contents[idx]).map(|_| ())
+        }.for_object(candidate.id).during("writing packfile")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::str::FromStr;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    use crate::test_support::{ commit_file, git, scratch_repo };
+    use crate::stores::{ fs, Queryable, StorageSet };
+    use crate::pack::StoredEntry;
+    use crate::objects::Type;
+    use crate::errors::Result;
+    use crate::id::Id;
+
+    // Count the objects read through a store.
+    struct Counting<Q: Queryable> {
+        inner: Q,
+        reads: AtomicUsize
+    }
+
+    impl<Q: Queryable> Queryable for Counting<Q> {
+        fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(id, output, backends)
+        }
+
+        fn stored_entry(&self, id: &Id) -> Result<Option<StoredEntry>> {
+            self.inner.stored_entry(id)
+        }
+    }
+
+    #[test]
+    fn name_hashes_match_git() {
+        // the hashes git records in a `.bitmap` name-hash cache
+        assert_eq!(super::name_hash(b""), 0);
+        assert_eq!(super::name_hash(b"a"), 0x6100_0000);
+        assert_eq!(super::name_hash(b"ab"), 0x6200_0000 + (0x6100_0000 >> 2));
+        assert_eq!(super::name_hash(b"a b"), super::name_hash(b"ab"));
+    }
+
+    #[test]
+    fn deltified_packs_are_comparable_to_git() {
+        let root = scratch_repo("pack-window");
+        let mut contents: Vec<String> = (0..400).map(|xs| format!("line {}\n", xs)).collect();
+        for revision in 0..30 {
+            contents[revision * 13] = format!("changed in revision {}\n", revision);
+            contents.insert(revision * 7, format!("inserted in revision {}\n", revision));
+            commit_file(&root, "file.txt", &contents.concat(), 1_600_000_000 + revision as u64 * 100);
+            commit_file(&root, "dir/other.txt", &contents[..200].concat(), 1_600_000_050 + revision as u64 * 100);
+        }
+
+        let objects: Vec<(Id, u32)> = git(&root, &["rev-list", "--objects", "HEAD"]).lines().map(|line| {
+            let path = line.get(41..).unwrap_or("");
+            (Id::from_str(&line[..40]).unwrap(), super::name_hash(path.as_bytes()))
+        }).collect();
+
+        let storage_set = root.storage();
+        let mut pack = Vec::new();
+        let mut index = Vec::new();
+        super::write_with_deltas(&storage_set, &objects, super::Options::default(), &mut pack, &mut index)
+            .expect("failed to write pack");
+
+        let mut plain = Vec::new();
+        super::write_with_deltas(&storage_set, &objects, super::Options { window: 0, reuse: false, ..Default::default() }, &mut plain, &mut Vec::new())
+            .expect("failed to write pack");
+
+        // every object is read once, whether it's written plain or as a delta
+        let loose = fs::loose_from_path(&root).expect("failed to open storage");
+        let counting = StorageSet::new(Counting { inner: loose, reads: AtomicUsize::new(0) });
+        let mut counted = Vec::new();
+        super::write_with_deltas(&counting, &objects, super::Options::default(), &mut counted, &mut Vec::new())
+            .expect("failed to write pack");
+        assert_eq!(counted, pack);
+        assert_eq!(counting.backend().reads.load(Ordering::SeqCst), objects.len());
+
+        git(&root, &["repack", "-adfq"]);
+        let git_size: u64 = std::fs::read_dir(root.join(".git/objects/pack")).unwrap().filter_map(|entry| {
+            let path = entry.unwrap().path();
+            if path.extension()? == "pack" { Some(std::fs::metadata(path).unwrap().len()) } else { None }
+        }).sum();
+
+        assert!(pack.len() * 2 < plain.len(), "{} vs. {} bytes undeltified", pack.len(), plain.len());
+        assert!((pack.len() as u64) < git_size * 3 / 2, "{} vs. {} bytes from git", pack.len(), git_size);
+
+        std::fs::write(root.join("test.pack"), &pack).unwrap();
+        git(&root, &["index-pack", "-o", "test.idx", "test.pack"]);
+        assert_eq!(std::fs::read(root.join("test.idx")).unwrap(), index);
+        let verified = git(&root, &["verify-pack", "-v", "test.idx"]);
+        assert!(verified.contains("chain length = 1"));
+    }
+
+    #[test]
+    fn reuses_stored_deltas() {
+        let root = scratch_repo("pack-reuse");
+        let mut contents: Vec<String> = (0..300).map(|xs| format!("line {}\n", xs)).collect();
+        for revision in 0..10 {
+            contents[revision * 17] = format!("changed in revision {}\n", revision);
+            commit_file(&root, "file.txt", &contents.concat(), 1_600_000_000 + revision as u64 * 100);
+        }
+        git(&root, &["repack", "-adfq"]);
+
+        let objects: Vec<(Id, u32)> = git(&root, &["rev-list", "--objects", "HEAD"]).lines().map(|line| {
+            (Id::from_str(&line[..40]).unwrap(), 0)
+        }).collect();
+
+        // a delta and its base, according to git: `<id> <type> <size> <size in pack> <offset> <depth> <base>`
+        let pack_dir = root.join(".git/objects/pack");
+        let index_path = std::fs::read_dir(&pack_dir).unwrap().map(|entry| entry.unwrap().path()).find(|path| {
+            path.extension().map(|xs| xs == "idx").unwrap_or(false)
+        }).unwrap();
+        let verified = git(&root, &["verify-pack", "-v", index_path.to_str().unwrap()]);
+        let (delta, base) = verified.lines().map(|line| line.split_whitespace().collect::<Vec<_>>()).find(|fields| {
+            fields.len() == 7 && fields[1] == "blob"
+        }).map(|fields| (Id::from_str(fields[0]).unwrap(), Id::from_str(fields[6]).unwrap())).unwrap();
+
+        let storage_set = root.storage();
+        let stored = storage_set.stored_entry(&delta).unwrap().expect("the object is packed");
+        assert_eq!(stored.base(), Some(&base));
+
+        // with no window, every delta in the new pack is a reused one
+        let no_search = super::Options { window: 0, ..Default::default() };
+        let mut pack = Vec::new();
+        let mut index = Vec::new();
+        super::write_with_deltas(&storage_set, &objects, no_search, &mut pack, &mut index).expect("failed to write pack");
+
+        std::fs::write(root.join("test.pack"), &pack).unwrap();
+        git(&root, &["index-pack", "-o", "test.idx", "test.pack"]);
+        assert_eq!(std::fs::read(root.join("test.idx")).unwrap(), index);
+        let reused = git(&root, &["verify-pack", "-v", "test.idx"]);
+        assert!(reused.contains(&format!(" {}\n", base)));
+
+        // a thin pack keeps the delta even though its base isn't included ...
+        let thin = super::Options { window: 0, thin: true, ..Default::default() };
+        let mut pack = Vec::new();
+        super::write_with_deltas(&storage_set, &[(delta.clone(), 0)], thin, &mut pack, &mut Vec::new())
+            .expect("failed to write pack");
+        assert_eq!(pack[12] >> 4 & 0x7, crate::delta::REF_DELTA);
+
+        // ... and a complete pack doesn't
+        let mut pack = Vec::new();
+        super::write_with_deltas(&storage_set, &[(delta, 0)], no_search, &mut pack, &mut Vec::new())
+            .expect("failed to write pack");
+        assert_eq!(pack[12] >> 4 & 0x7, 3);
+    }
+}
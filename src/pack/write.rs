@@ -0,0 +1,351 @@
+use crc::{ Crc, CRC_32_ISO_HDLC };
+use crypto::{ sha1::Sha1, digest::Digest };
+use flate2::{ write::ZlibEncoder, Compression };
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::stores::{ Queryable, StorageSet };
+use crate::pack::index::{ self, Version };
+use crate::pack::StoredEntry;
+use crate::delta::{ OFS_DELTA, REF_DELTA };
+use crate::objects::Type;
+use crate::id::Id;
+
+/// Writes a version 2 packfile to `output` one object at a time, keeping track
+/// of what it needs to write the packfile's index afterwards.
+///
+/// The packfile header holds the number of objects, so it has to be known up
+/// front; `finish` fails if a different number of objects was added.
+pub struct Writer<W: Write> {
+    output: W,
+    hash: Sha1,
+    offset: u64,
+    object_count: u32,
+    entries: Vec<(Id, u64, u32)>,
+    positions: HashMap<Id, usize>
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(output: W, object_count: u32) -> Result<Self> {
+        let mut writer = Writer {
+            output,
+            hash: Sha1::new(),
+            offset: 0,
+            object_count,
+            entries: Vec::with_capacity(object_count as usize),
+            positions: HashMap::with_capacity(object_count as usize)
+        };
+
+        writer.emit(b"PACK")?;
+        writer.emit(&2u32.to_be_bytes())?;
+        writer.emit(&object_count.to_be_bytes())?;
+        Ok(writer)
+    }
+
+    /// The number of objects written so far.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.positions.contains_key(id)
+    }
+
+    /// The offset at which the object `id` was written, if it has been.
+    pub fn offset(&self, id: &Id) -> Option<u64> {
+        self.positions.get(id).map(|position| self.entries[*position].1)
+    }
+
+    /// Add an object of type `kind` with contents `data`, returning its id.
+    pub fn add(&mut self, kind: Type, data: &[u8]) -> Result<Id> {
+        let id = kind.hash(data);
+
+        let mut entry = entry_header(type_code(kind), data.len() as u64);
+        let mut encoder = ZlibEncoder::new(entry, Compression::default());
+        encoder.write_all(data)?;
+        entry = encoder.finish()?;
+
+        self.write_entry(id.clone(), &entry)?;
+        Ok(id)
+    }
+
+    /// Add the object `id` as delta `instructions` against the object `base`.
+    /// Bases already in this packfile are referred to by offset (`OFS_DELTA`);
+    /// any other base by id (`REF_DELTA`), which makes this a thin pack.
+    pub fn add_delta(&mut self, id: Id, base: &Id, instructions: &[u8]) -> Result<()> {
+        let mut entry = self.delta_header(base, instructions.len() as u64);
+        let mut encoder = ZlibEncoder::new(entry, Compression::default());
+        encoder.write_all(instructions)?;
+        entry = encoder.finish()?;
+
+        self.write_entry(id, &entry)
+    }
+
+    /// Copy the object `id` from another packfile without inflating it. A delta's
+    /// base is referred to as in `add_delta`.
+    pub fn add_stored(&mut self, id: Id, entry: &StoredEntry) -> Result<()> {
+        let mut header = match entry.base() {
+            None => entry_header(entry.kind(), entry.size()),
+            Some(base) => self.delta_header(base, entry.size())
+        };
+
+        header.extend_from_slice(entry.data());
+        self.write_entry(id, &header)
+    }
+
+    /// Write the packfile's trailing checksum, and its (version 2) index to
+    /// `index`. Returns the packfile checksum, which git uses to name the files.
+    pub fn finish<I: Write>(self, index: &mut I) -> Result<Id> {
+        self.finish_with_version(index, Version::V2)
+    }
+
+    pub fn finish_with_version<I: Write>(mut self, index: &mut I, version: Version) -> Result<Id> {
+        if self.entries.len() != self.object_count as usize {
+            return Err(ErrorKind::WrongObjectCount {
+                expected: self.object_count,
+                actual: self.entries.len() as u32
+            }.into())
+        }
+
+        let mut checksum = [0u8; 20];
+        self.hash.result(&mut checksum);
+        self.output.write_all(&checksum)?;
+        self.output.flush()?;
+
+        let checksum: Id = checksum.into();
+        index::write_entries(self.entries, &checksum, index, version)?;
+        Ok(checksum)
+    }
+
+    fn delta_header(&self, base: &Id, size: u64) -> Vec<u8> {
+        match self.offset(base) {
+            Some(base_offset) => {
+                let mut header = entry_header(OFS_DELTA, size);
+                header.extend(delta_offset(self.offset - base_offset));
+                header
+            },
+            None => {
+                let mut header = entry_header(REF_DELTA, size);
+                header.extend_from_slice(base.as_ref());
+                header
+            }
+        }
+    }
+
+    /// Write a complete entry (header and compressed data) for the object `id`.
+    pub(crate) fn write_entry(&mut self, id: Id, entry: &[u8]) -> Result<()> {
+        if self.positions.contains_key(&id) {
+            return Err(ErrorKind::DuplicateObject(id).into())
+        }
+
+        if self.entries.len() >= self.object_count as usize {
+            return Err(ErrorKind::WrongObjectCount {
+                expected: self.object_count,
+                actual: self.entries.len() as u32 + 1
+            }.into())
+        }
+
+        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(entry);
+        self.positions.insert(id.clone(), self.entries.len());
+        self.entries.push((id, self.offset, crc));
+        self.emit(entry)
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hash.input(bytes);
+        self.output.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+fn type_code(kind: Type) -> u8 {
+    match kind {
+        Type::Commit => 1,
+        Type::Tree => 2,
+        Type::Blob => 3,
+        Type::Tag => 4
+    }
+}
+
+/// Encode the header of a packfile entry: the type in bits 4-6 of the first
+/// byte, and the size as a little-endian varint (four bits in the first byte,
+/// seven in each byte after that.)
+pub(crate) fn entry_header(kind: u8, size: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(10);
+    let mut byte = (kind << 4) | (size & 0xf) as u8;
+    let mut size = size >> 4;
+    while size > 0 {
+        header.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    header.push(byte);
+    header
+}
+
+/// Encode the distance back to an `OFS_DELTA` base. This is a big-endian varint
+/// where each continuation adds one to the value, so there's only one way to
+/// write any distance.
+fn delta_offset(mut distance: u64) -> Vec<u8> {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance > 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+/// Write a packfile containing `objects` to `pack`, and its index to `index`.
+/// Returns the packfile checksum.
+pub fn write<I, T, W, X>(objects: I, pack: W, index: &mut X) -> Result<Id> where
+    I: IntoIterator<Item = (Type, T)>,
+    I::IntoIter: ExactSizeIterator,
+    T: AsRef<[u8]>,
+    W: Write,
+    X: Write {
+
+    let objects = objects.into_iter();
+    let mut writer = Writer::new(pack, objects.len() as u32)?;
+    for (kind, data) in objects {
+        writer.add(kind, data.as_ref())?;
+    }
+
+    writer.finish(index)
+}
+
+/// Write a packfile containing the objects `ids` (read from `storage_set`) to
+/// `pack`, and its index to `index`. Ids given more than once are only written
+/// once. Returns the packfile checksum.
+pub fn write_ids<S, W, X>(storage_set: &StorageSet<S>, ids: &[Id], pack: W, index: &mut X) -> Result<Id> where
+    S: Queryable,
+    W: Write,
+    X: Write {
+
+    let mut seen = std::collections::HashSet::with_capacity(ids.len());
+    let ids: Vec<_> = ids.iter().filter(|id| seen.insert(*id)).collect();
+
+    let mut writer = Writer::new(pack, ids.len() as u32)?;
+    let mut data = Vec::new();
+    for id in ids {
+        data.clear();
+        let kind = match storage_set.get(id, &mut data)? {
+            Some(xs) => xs,
+            None => return Err(Error::from(ErrorKind::ObjectNotFound).for_object(id))
+        };
+
+        writer.add(kind, &data).for_object(id).during("writing packfile")?;
+    }
+
+    writer.finish(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::str::FromStr;
+
+    use crate::test_support::{ commit_file, git, scratch_repo };
+    use crate::pack::index::read as read_index;
+    use crate::errors::ErrorKind;
+    use crate::delta::DeltaEncoder;
+    use crate::objects::Type;
+    use crate::id::Id;
+
+    #[test]
+    fn encodes_entry_headers() {
+        assert_eq!(super::entry_header(3, 0xf), vec![0x3f]);
+        assert_eq!(super::entry_header(1, 0x10), vec![0x90, 0x01]);
+        assert_eq!(super::entry_header(2, 0x1234), vec![0xa4, 0xa3, 0x02]);
+    }
+
+    #[test]
+    fn encodes_delta_offsets() {
+        // these round trip through `packfile_read_header`
+        for distance in [1u64, 0x7f, 0x80, 0x4000, 0x4080, 0x1234_5678].iter() {
+            let mut entry = super::entry_header(crate::delta::OFS_DELTA, 0);
+            entry.extend(super::delta_offset(*distance));
+            let header = crate::pack::read::packfile_read_header(&mut entry.as_slice()).unwrap();
+            match header.base() {
+                Some(crate::pack::read::DeltaBase::Offset(xs)) => assert_eq!(xs, distance),
+                _ => panic!("expected an offset delta")
+            }
+            assert_eq!(header.len() as usize, entry.len());
+        }
+    }
+
+    #[test]
+    fn writes_deltas_git_accepts() {
+        let root = scratch_repo("pack-write-delta");
+        let base: Vec<u8> = (0..4000u32).flat_map(|xs| xs.to_string().into_bytes()).collect();
+        let mut target = base.clone();
+        target.splice(5000..5000, b"a small change".iter().cloned());
+        let mut other = target.clone();
+        other.truncate(9000);
+
+        let base_id = Type::Blob.hash(&base);
+        let target_id = Type::Blob.hash(&target);
+        let other_id = Type::Blob.hash(&other);
+
+        let mut pack = Vec::new();
+        let mut writer = super::Writer::new(&mut pack, 3).unwrap();
+        writer.add(Type::Blob, &base).unwrap();
+        let encoder = DeltaEncoder::new(&base);
+        writer.add_delta(target_id.clone(), &base_id, &encoder.encode(&target)).unwrap();
+        // a chain: other is a delta against target
+        writer.add_delta(other_id.clone(), &target_id, &DeltaEncoder::new(&target).encode(&other)).unwrap();
+        writer.finish(&mut Vec::new()).unwrap();
+
+        std::fs::write(root.join("test.pack"), &pack).unwrap();
+        git(&root, &["index-pack", "-o", "test.idx", "test.pack"]);
+        let verified = git(&root, &["verify-pack", "-v", "test.idx"]);
+        assert!(verified.contains(&format!("{} blob", other_id)));
+        assert!(verified.contains("chain length = 2: 1 object"));
+    }
+
+    #[test]
+    fn writes_packs_git_accepts() {
+        let root = scratch_repo("pack-write");
+        for (idx, path) in ["a", "b/c", "b/d"].iter().enumerate() {
+            commit_file(&root, path, &idx.to_string().repeat(100), 1_600_000_000 + idx as u64 * 100);
+        }
+
+        let ids: Vec<Id> = git(&root, &["rev-list", "--objects", "HEAD"]).lines().map(|line| {
+            Id::from_str(&line[..40]).unwrap()
+        }).collect();
+
+        let storage_set = root.storage();
+        let mut pack = Vec::new();
+        let mut index = Vec::new();
+        let checksum = super::write_ids(&storage_set, &ids, &mut pack, &mut index).expect("failed to write pack");
+
+        let pack_path = root.join("test.pack");
+        std::fs::write(&pack_path, &pack).unwrap();
+        let expected = git(&root, &["index-pack", "-o", "test.idx", "test.pack"]);
+        assert_eq!(expected.trim(), checksum.to_string());
+        assert_eq!(std::fs::read(root.join("test.idx")).unwrap(), index);
+
+        let index = read_index(Cursor::new(&index)).expect("failed to read index");
+        assert_eq!(index.len(), ids.len());
+    }
+
+    #[test]
+    fn rejects_duplicates_and_wrong_counts() {
+        let mut writer = super::Writer::new(Vec::new(), 2).unwrap();
+        writer.add(Type::Blob, b"hello").unwrap();
+
+        let error = writer.add(Type::Blob, b"hello").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::DuplicateObject(Type::Blob.hash(b"hello")));
+
+        let error = writer.finish(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::WrongObjectCount { expected: 2, actual: 1 });
+    }
+}
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::path::Path;
+use std::path::{ Path, PathBuf };
 use std::fs::File;
 use std::io::Read;
 
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
 use crate::id::Id;
 
 #[derive(Copy, Clone, Debug)]
@@ -32,20 +33,20 @@ impl Ref {
         self.kind
     }
 
-    pub fn load(path: &Path, kind: Kind) -> Result<Ref, std::io::Error> {
-        let mut f = File::open(path)?;
+    pub fn load(path: &Path, kind: Kind) -> Result<Ref> {
+        let mut f = File::open(path).in_file(path)?;
         let mut buffer = Vec::new();
-        f.read_to_end(&mut buffer)?;
+        f.read_to_end(&mut buffer).in_file(path)?;
 
         if let Ok(contents) = std::str::from_utf8(&buffer) {
             if contents.len() < 5 {
-                return Err(std::io::ErrorKind::InvalidData.into());
+                return Err(Error::from(ErrorKind::InvalidRef).in_file(path));
             }
 
-            if &contents[0..16] == "ref: refs/heads/" {
+            if let Some(target) = contents.strip_prefix("ref: refs/heads/") {
                 return Ok(Ref {
                     kind,
-                    ptr: RefPtr::Indirect(String::from(contents[16..].trim()))
+                    ptr: RefPtr::Indirect(String::from(target.trim()))
                 });
             }
 
@@ -59,7 +60,7 @@ impl Ref {
             }
         }
 
-        Err(std::io::ErrorKind::InvalidData.into())
+        Err(Error::from(ErrorKind::InvalidRef).in_file(path))
     }
 }
 
@@ -68,11 +69,11 @@ fn recurse_dir(
     dirs: &mut Vec<String>,
     map: &mut HashMap<String, Ref>,
     k: Kind
-) -> Result<(), std::io::Error> {
-    for entry in std::fs::read_dir(root)? {
-        let entry = entry?;
+) -> Result<()> {
+    for entry in std::fs::read_dir(root).in_file(root)? {
+        let entry = entry.in_file(root)?;
 
-        let typ = entry.file_type()?;
+        let typ = entry.file_type().in_file(&entry.path())?;
         let os_filename = entry.file_name();
         let opt_filename = os_filename.to_str();
         if opt_filename.is_none() {
@@ -95,8 +96,155 @@ fn recurse_dir(
     Ok(())
 }
 
+// Read the ids of every (non-symbolic) ref under `dir`.
+fn ref_ids(dir: &Path, ids: &mut Vec<Id>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(xs) => xs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::from(e).in_file(dir))
+    };
+
+    for entry in entries {
+        let path = entry.in_file(dir)?.path();
+        if path.is_dir() {
+            ref_ids(&path, ids)?;
+            continue
+        }
+
+        let contents = std::fs::read(&path).in_file(&path)?;
+        if contents.starts_with(b"ref:") {
+            continue
+        }
+
+        let id = std::str::from_utf8(&contents).ok()
+            .and_then(|contents| contents.get(0..40))
+            .and_then(|hex| Id::from_str(hex).ok())
+            .ok_or(ErrorKind::InvalidRef)
+            .in_file(&path)?;
+        ids.push(id);
+    }
+
+    Ok(())
+}
+
+/// The administrative directories (`.git/worktrees/<name>`) of the linked
+/// worktrees of the repository at `path`, each with its own `HEAD`, index,
+/// reflogs and per-worktree refs.
+pub fn worktree_dirs(path: &Path) -> Result<Vec<PathBuf>> {
+    let worktrees = path.join(".git").join("worktrees");
+    let entries = match std::fs::read_dir(&worktrees) {
+        Ok(xs) => xs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::from(e).in_file(&worktrees))
+    };
+
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let dir = entry.in_file(&worktrees)?.path();
+        if dir.is_dir() {
+            dirs.push(dir);
+        }
+    }
+
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// The ids pointed at by every ref in the repository at `path`: everything under
+/// `.git/refs` (not only branches, remotes and tags), `packed-refs` and a
+/// detached `HEAD`, along with the per-worktree refs and `HEAD` of each linked
+/// worktree. An unreadable ref is an error rather than being skipped, so that
+/// nothing it points at is mistaken for garbage.
+pub fn tips(path: &Path) -> Result<Vec<Id>> {
+    let git_dir = path.join(".git");
+    let mut ids = Vec::new();
+    ref_ids(&git_dir.join("refs"), &mut ids)?;
+
+    let mut heads = vec![git_dir.join("HEAD")];
+    for worktree in worktree_dirs(path)? {
+        ref_ids(&worktree.join("refs"), &mut ids)?;
+        heads.push(worktree.join("HEAD"));
+    }
+
+    let packed_refs = git_dir.join("packed-refs");
+    match std::fs::read(&packed_refs) {
+        Ok(contents) => {
+            let contents = std::str::from_utf8(&contents).map_err(|_| ErrorKind::Encoding).in_file(&packed_refs)?;
+
+            // peeled lines (`^<id>`) name the targets of the tag above them
+            for line in contents.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                let hex = line.strip_prefix('^').unwrap_or(line);
+                let id = hex.get(0..40)
+                    .and_then(|hex| Id::from_str(hex).ok())
+                    .ok_or(ErrorKind::InvalidRef)
+                    .in_file(&packed_refs)?;
+                ids.push(id);
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(Error::from(e).in_file(&packed_refs))
+    }
+
+    for head in heads {
+        if let Ok(Ref { ptr: RefPtr::Direct(id), .. }) = Ref::load(&head, Kind::Local) {
+            ids.push(id);
+        }
+    }
+
+    ids.sort();
+    ids.dedup();
+    Ok(ids)
+}
+
+// Read the old and new ids of every entry in the reflogs under `dir`.
+fn reflog_ids(dir: &Path, ids: &mut Vec<Id>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(xs) => xs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::from(e).in_file(dir))
+    };
+
+    for entry in entries {
+        let path = entry.in_file(dir)?.path();
+        if path.is_dir() {
+            reflog_ids(&path, ids)?;
+            continue
+        }
+
+        let contents = std::fs::read(&path).in_file(&path)?;
+        for line in contents.split(|byte| *byte == b'\n').filter(|line| line.len() >= 81) {
+            for hex in [&line[0..40], &line[41..81]].iter() {
+                let id = std::str::from_utf8(hex).ok()
+                    .and_then(|hex| Id::from_str(hex).ok())
+                    .ok_or(ErrorKind::InvalidRef)
+                    .in_file(&path)?;
+
+                // creations and deletions are logged against the null id
+                if id != Id::default() {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The ids recorded in every reflog (`.git/logs`, and those of each linked
+/// worktree) of the repository at `path`, both before and after each update.
+pub fn reflogs(path: &Path) -> Result<Vec<Id>> {
+    let mut ids = Vec::new();
+    reflog_ids(&path.join(".git").join("logs"), &mut ids)?;
+    for worktree in worktree_dirs(path)? {
+        reflog_ids(&worktree.join("logs"), &mut ids)?;
+    }
+    ids.sort();
+    ids.dedup();
+    Ok(ids)
+}
+
 impl RefSet {
-    pub fn from_path(path: &Path) -> Result<RefSet, std::io::Error> {
+    pub fn from_path(path: &Path) -> Result<RefSet> {
         let mut root = std::path::PathBuf::new();
         let mut map = HashMap::new();
         let mut dirs = Vec::new();
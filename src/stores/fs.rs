@@ -1,57 +1,585 @@
 use crate::stores::loose::Store as LooseStore;
-use crate::pack::index::read as read_packidx;
+use crate::pack::index::{ read as read_packidx, read_with_reverse_index as read_packidx_with_rev };
+use crate::pack::midx::{ read as read_midx, MultiPackIndex };
+use crate::pack::bitmap::{ read as read_bitmap, BitmapIndex };
+use crate::pack::mtimes::read as read_mtimes;
+use crate::pack::index::Index;
 use crate::pack::mmap::Reader as MmapPackReader;
-use crate::stores::pack::Store as PackStore;
-use crate::stores::StorageSet;
+use crate::stores::pack::{ read_sorted, Store as PackStore };
+use crate::stores::{ Queryable, StorageSet };
+use crate::commit_graph::CommitGraph;
+use crate::pack::{ Packfile, StoredEntry };
+use crate::objects::Type;
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::id::Id;
+use once_cell::sync::OnceCell;
 use memmap::MmapOptions;
 use rayon::prelude::*;
 
-use std::path::Path;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::{ Duration, SystemTime };
+use std::io::Write;
 
-type GitFSStore = (Vec<PackStore<MmapPackReader>>, LooseStore);
-
-pub fn from(path: &Path) -> Result<StorageSet<GitFSStore>, std::io::Error> {
-    let packfiles = packfiles_from_path(path)?;
+pub fn from(path: &Path) -> Result<StorageSet<Store>> {
+    let objects = objects_dir(path);
+    let scan = Scan::now(&objects);
+    let packs = Packs::from_path(path)?;
     let loose = loose_from_path(path)?;
 
-    Ok(StorageSet::new((
-        packfiles,
-        loose
-    )))
+    let storage_set = StorageSet::new(Store {
+        packs,
+        loose,
+        objects,
+        scan: Mutex::new(scan)
+    });
+
+    // Like git, treat an unreadable commit-graph as absent; walks will read
+    // commits from the object database instead.
+    Ok(match CommitGraph::from_path(path) {
+        Ok(Some(commit_graph)) => storage_set.with_commit_graph(commit_graph),
+        _ => storage_set
+    })
 }
 
-pub fn loose_from_path(path: &Path) -> Result<LooseStore, std::io::Error> {
-    let mut root = std::path::PathBuf::new();
-    root.push(path);
-    root.push(".git");
-    root.push("objects");
+/// The object database of a repository on disk: its packfiles, followed by its
+/// loose objects.
+///
+/// When an object can't be found in either, the pack directory and the loose
+/// object fan-out directories are rescanned (as git does with
+/// `reprepare_packed_git`) in case a concurrent `gc` or `fetch` has added new
+/// packs or objects, and both are queried once more. Directories that haven't
+/// changed since they were last scanned aren't scanned again, so a run of
+/// misses doesn't rescan on every lookup.
+pub struct Store {
+    packs: Packs,
+    loose: LooseStore,
+    objects: PathBuf,
+    scan: Mutex<Scan>
+}
 
-    let mut filter = [false; 256];
-    for entry in std::fs::read_dir(root.as_path())? {
-        let entry = entry?;
-        let os_filename = entry.file_name();
-        if os_filename.len() != 2 {
-            continue
+impl Store {
+    pub fn packs(&self) -> &Packs {
+        &self.packs
+    }
+
+    pub fn loose(&self) -> &LooseStore {
+        &self.loose
+    }
+
+    /// Rescan the pack directory and the loose object fan-out directories if
+    /// either has changed since the last scan. Returns `true` if the packs or
+    /// the fan-out directories found changed.
+    pub fn reprepare(&self) -> Result<bool> {
+        let mut scan = self.scan.lock().unwrap_or_else(|e| e.into_inner());
+        let next = Scan::now(&self.objects);
+        if !scan.is_stale(&next) {
+            return Ok(false)
         }
 
-        let result = match usize::from_str_radix(&os_filename.to_string_lossy(), 16) {
-            Ok(xs) => xs,
-            Err(_) => continue
+        let loose_changed = self.loose.set_filter(loose_fanout(&self.objects)?);
+        let packs_changed = self.packs.reprepare()?;
+        *scan = next;
+        Ok(loose_changed || packs_changed)
+    }
+
+    // Read each of `ids` from the loose objects, as `Queryable::get_batch`.
+    fn get_loose_batch<S, F>(&self, ids: Vec<Id>, each: &F, backends: &StorageSet<S>) -> Result<Vec<Id>>
+        where S: Queryable,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut data = Vec::new();
+        for id in ids {
+            data.clear();
+            let opt = self.loose.get(&id, &mut data, backends)
+                .map_err(|e| e.for_object(&id).in_file(&loose_path(&self.objects, &id)))?;
+
+            match opt {
+                Some(kind) => each(&id, kind, &data)?,
+                None => missing.push(id)
+            }
+        }
+
+        Ok(missing)
+    }
+}
+
+// Directory modification times older than this, relative to the time of a
+// scan, are trusted to show every change made since: file systems with coarse
+// timestamps can otherwise hide a change made in the same tick as the scan.
+const RACY_SCAN_WINDOW: Duration = Duration::from_secs(2);
+
+// When the object and pack directories were last scanned, and their
+// modification times as of then.
+struct Scan {
+    at: SystemTime,
+    objects: Option<SystemTime>,
+    packs: Option<SystemTime>
+}
+
+impl Scan {
+    // Read the times before scanning, so that anything that changes during the
+    // scan makes the next one stale.
+    fn now(objects: &Path) -> Self {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        Scan {
+            at: SystemTime::now(),
+            objects: modified(objects),
+            packs: modified(&objects.join("pack"))
+        }
+    }
+
+    fn is_stale(&self, next: &Scan) -> bool {
+        // a directory that was missing and still is hasn't changed
+        let racy = |modified: Option<SystemTime>| modified.is_some_and(|modified| {
+            self.at.duration_since(modified).map_or(true, |age| age < RACY_SCAN_WINDOW)
+        });
+
+        self.objects != next.objects || self.packs != next.packs || racy(self.objects) || racy(self.packs)
+    }
+}
+
+impl Queryable for Store {
+    fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>> {
+        let opt = self.packs.get(id, output, backends)?;
+        if opt.is_some() {
+            return Ok(opt)
+        }
+
+        let opt = self.loose.get(id, output, backends).map_err(|e| e.in_file(&loose_path(&self.objects, id)))?;
+        if opt.is_some() {
+            return Ok(opt)
+        }
+
+        if !self.reprepare()? {
+            return Ok(None)
+        }
+
+        let opt = self.packs.get(id, output, backends)?;
+        if opt.is_some() {
+            return Ok(opt)
+        }
+
+        self.loose.get(id, output, backends).map_err(|e| e.in_file(&loose_path(&self.objects, id)))
+    }
+
+    fn stored_entry(&self, id: &Id) -> Result<Option<StoredEntry>> {
+        self.packs.stored_entry(id)
+    }
+
+    fn get_batch<S, F>(&self, ids: Vec<Id>, parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<Vec<Id>>
+        where S: Queryable + Sync,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> + Sync {
+        let unpacked = self.packs.get_batch(ids, parallel, each, backends)?;
+        let missing = self.get_loose_batch(unpacked, each, backends)?;
+        if missing.is_empty() || !self.reprepare()? {
+            return Ok(missing)
+        }
+
+        let unpacked = self.packs.get_batch(missing, parallel, each, backends)?;
+        self.get_loose_batch(unpacked, each, backends)
+    }
+}
+
+struct Pack {
+    index_path: PathBuf,
+    pack_path: PathBuf,
+    store: PackStore<MmapPackReader>,
+    bitmap: Option<BitmapIndex>,
+    mtimes: Option<Vec<u32>>,
+    modified: SystemTime
+}
+
+impl Pack {
+    // Cruft packs record a time for each object; the objects of any other pack
+    // are as old as the pack itself.
+    fn mtime(&self, position: usize) -> SystemTime {
+        match self.mtimes.as_ref().and_then(|mtimes| mtimes.get(position)) {
+            Some(seconds) => SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from(*seconds)),
+            None => self.modified
+        }
+    }
+}
+
+/// A pack that has a reachability bitmap (`.bitmap` file.)
+pub struct BitmappedPack(Arc<Pack>);
+
+impl BitmappedPack {
+    pub fn index(&self) -> &Index {
+        self.0.store.index()
+    }
+
+    pub fn bitmap(&self) -> &BitmapIndex {
+        self.0.bitmap.as_ref().expect("bitmapped packs have a bitmap")
+    }
+}
+
+// Packs covered by a multi-pack-index are opened without reading their own
+// `.idx`; lookups go through the multi-pack-index instead.
+struct MultiPackIndexPacks {
+    index: MultiPackIndex,
+    packs: Vec<Option<MultiPackIndexPack>>
+}
+
+struct MultiPackIndexPack {
+    path: PathBuf,
+    packfile: MmapPackReader,
+    end: u64,
+    modified: SystemTime,
+    store: OnceCell<Option<PackStore<MmapPackReader>>>
+}
+
+impl MultiPackIndexPack {
+    // The multi-pack-index records neither CRC32s nor where objects end, so the
+    // pack's own index is opened for them the first time it's needed. It's
+    // `None` if that index can't be read; lookups then go by the
+    // multi-pack-index alone, unless they have to check CRC32s.
+    fn store(&self) -> Option<&PackStore<MmapPackReader>> {
+        self.store.get_or_init(|| open_pack(&self.path.with_extension("idx")).ok()).as_ref()
+    }
+
+    // The pack's own index, when reads have to be checked against its CRC32s:
+    // if it can't be read, that's an error.
+    fn checked_store(&self) -> Result<&PackStore<MmapPackReader>> {
+        match self.store() {
+            Some(store) => Ok(store),
+            None => {
+                let index_path = self.path.with_extension("idx");
+                let error = open_pack(&index_path).err().unwrap_or_else(|| ErrorKind::CorruptedPackfileIndex.into());
+                Err(error.in_file(&index_path))
+            }
+        }
+    }
+}
+
+impl MultiPackIndexPacks {
+    fn covers(&self, index_path: &Path) -> bool {
+        let filename = match index_path.file_name().and_then(|xs| xs.to_str()) {
+            Some(xs) => xs,
+            None => return false
         };
-        filter[result] = true;
+
+        self.index.pack_names().iter().zip(self.packs.iter()).any(|(name, pack)| {
+            pack.is_some() && name == filename
+        })
+    }
+}
+
+#[derive(Default)]
+struct PackList {
+    multi_pack_index: Option<Arc<MultiPackIndexPacks>>,
+    packs: Vec<Arc<Pack>>
+}
+
+impl PackList {
+    fn same_as(&self, other: &PackList) -> bool {
+        let same_midx = match (&self.multi_pack_index, &other.multi_pack_index) {
+            (Some(lhs), Some(rhs)) => Arc::ptr_eq(lhs, rhs),
+            (None, None) => true,
+            _ => false
+        };
+
+        same_midx && self.packs.len() == other.packs.len() && self.packs.iter().zip(other.packs.iter()).all(|(lhs, rhs)| {
+            Arc::ptr_eq(lhs, rhs)
+        })
+    }
+}
+
+/// The set of packfiles found in `objects/pack`.
+///
+/// If the directory contains a `multi-pack-index`, objects in the packs it covers
+/// are found with a single lookup in that index. Packs it does not cover are
+/// queried one at a time through their own `.idx` files. A covered pack's own
+/// `.idx` is still read once an object is read from the pack, for the CRC32s
+/// and object bounds that the multi-pack-index doesn't record.
+///
+/// Readers take a snapshot of the current list of packs for the duration of a
+/// lookup. Because each pack is reference counted, a pack that disappears from
+/// disk during a `reprepare` stays mapped until the last lookup using it
+/// finishes.
+pub struct Packs {
+    root: PathBuf,
+    current: RwLock<Arc<PackList>>,
+    reprepare_lock: Mutex<()>
+}
+
+impl Packs {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let root = pack_dir(path);
+        let packs = open_packs(&root, &PackList::default())?;
+
+        Ok(Packs {
+            root,
+            current: RwLock::new(Arc::new(packs)),
+            reprepare_lock: Mutex::new(())
+        })
     }
 
+    /// The number of packs available for lookups.
+    pub fn len(&self) -> usize {
+        let snapshot = self.snapshot();
+        let midx_count = snapshot.multi_pack_index.as_ref().map(|midx| {
+            midx.packs.iter().filter(|xs| xs.is_some()).count()
+        }).unwrap_or(0);
+
+        midx_count + snapshot.packs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether lookups are currently served by a multi-pack-index.
+    pub fn has_multi_pack_index(&self) -> bool {
+        self.snapshot().multi_pack_index.is_some()
+    }
+
+    /// Rescan the pack directory, opening packs that have appeared since the last
+    /// scan and dropping packs that have been removed. Returns `true` if the set of
+    /// packs changed.
+    pub fn reprepare(&self) -> Result<bool> {
+        let _guard = self.reprepare_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let existing = self.snapshot();
+
+        let packs = open_packs(&self.root, &existing)?;
+        if packs.same_as(&existing) {
+            return Ok(false)
+        }
+
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        *current = Arc::new(packs);
+        Ok(true)
+    }
+
+    /// Return the ids of every packed object, sorted and without duplicates.
+    pub fn ids(&self) -> Vec<Id> {
+        let snapshot = self.snapshot();
+        let mut ids: Vec<Id> = snapshot.multi_pack_index.iter()
+            .flat_map(|midx| midx.index.ids().iter().cloned())
+            .chain(snapshot.packs.iter().flat_map(|pack| pack.store.index().ids().iter().cloned()))
+            .collect();
+
+        ids.par_sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Find the type of the packed object `id` without inflating it.
+    pub fn object_type<S: Queryable>(&self, id: &Id, backends: &StorageSet<S>) -> Result<Option<Type>> {
+        let snapshot = self.snapshot();
+
+        if let Some(midx) = &snapshot.multi_pack_index {
+            if let Some((pack_id, offset)) = midx.index.find(id) {
+                if let Some(Some(pack)) = midx.packs.get(pack_id) {
+                    let read = pack.packfile.read_type(offset, backends);
+                    return Ok(Some(read.for_object(id).in_file(&pack.path).at_offset(offset)?))
+                }
+            }
+        }
+
+        for pack in snapshot.packs.iter() {
+            let opt = pack.store.object_type(id, backends).in_file(&pack.pack_path)?;
+            if opt.is_some() {
+                return Ok(opt)
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Return a pack with a reachability bitmap, if there is one. Only packs that
+    /// aren't covered by a multi-pack-index are considered.
+    pub fn bitmapped_pack(&self) -> Option<BitmappedPack> {
+        let snapshot = self.snapshot();
+        let pack = snapshot.packs.iter().find(|pack| pack.bitmap.is_some())?;
+        Some(BitmappedPack(pack.clone()))
+    }
+
+    /// Whether there are any cruft packs (packs with a `.mtimes` file.)
+    pub fn has_cruft_packs(&self) -> bool {
+        self.snapshot().packs.iter().any(|pack| pack.mtimes.is_some())
+    }
+
+    /// Find when the packed object `id` was last written: its time in a cruft
+    /// pack, or the modification time of any other pack it's in. If it's in more
+    /// than one pack, the most recent time is used, as git does.
+    ///
+    /// Packs covered by a multi-pack-index only have their own modification time.
+    pub fn mtime(&self, id: &Id) -> Option<SystemTime> {
+        let snapshot = self.snapshot();
+
+        let midx_mtime = snapshot.multi_pack_index.as_ref().and_then(|midx| {
+            let (pack_id, _) = midx.index.find(id)?;
+            midx.packs.get(pack_id)?.as_ref().map(|pack| pack.modified)
+        });
+
+        snapshot.packs.iter()
+            .filter_map(|pack| pack.store.index().position(id).map(|position| pack.mtime(position)))
+            .chain(midx_mtime)
+            .max()
+    }
+
+    /// Return every packed object along with when it was last written (as for
+    /// `mtime`), sorted by id.
+    pub fn mtimes(&self) -> Vec<(Id, SystemTime)> {
+        let snapshot = self.snapshot();
+        let mut mtimes: Vec<(Id, SystemTime)> = snapshot.multi_pack_index.iter()
+            .flat_map(|midx| midx.index.ids().iter().filter_map(move |id| {
+                let (pack_id, _) = midx.index.find(id)?;
+                midx.packs.get(pack_id)?.as_ref().map(|pack| (id.clone(), pack.modified))
+            }))
+            .chain(snapshot.packs.iter().flat_map(|pack| {
+                pack.store.index().ids().iter().enumerate().map(move |(position, id)| (id.clone(), pack.mtime(position)))
+            }))
+            .collect();
+
+        // most recent first, so that deduplicating keeps it
+        mtimes.par_sort_unstable_by(|lhs, rhs| lhs.0.cmp(&rhs.0).then(rhs.1.cmp(&lhs.1)));
+        mtimes.dedup_by(|lhs, rhs| lhs.0 == rhs.0);
+        mtimes
+    }
+
+    fn snapshot(&self) -> Arc<PackList> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl Queryable for Packs {
+    fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>> {
+        let snapshot = self.snapshot();
+
+        if let Some(midx) = &snapshot.multi_pack_index {
+            if let Some((pack_id, offset)) = midx.index.find(id) {
+                if let Some(Some(pack)) = midx.packs.get(pack_id) {
+                    let store = match backends.verifies() {
+                        true => Some(pack.checked_store().for_object(id)?),
+                        false => pack.store()
+                    };
+
+                    if let Some(store) = store {
+                        return match store.get(id, output, backends).in_file(&pack.path)? {
+                            Some(kind) => Ok(Some(kind)),
+                            None => Err(Error::from(ErrorKind::CorruptedPackfileIndex).for_object(id).in_file(&pack.path.with_extension("idx")))
+                        }
+                    }
+
+                    return backends.read_checked(id, output, |mut output| {
+                        Ok(Some(pack.packfile.read_bounds(offset, pack.end, &mut output, backends)?))
+                    }).for_object(id).in_file(&pack.path).at_offset(offset)
+                }
+            }
+        }
+
+        for pack in snapshot.packs.iter() {
+            let opt = pack.store.get(id, output, backends).in_file(&pack.pack_path)?;
+            if opt.is_some() {
+                return Ok(opt)
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Multi-pack-indexes don't record the CRC32s needed to check entries before
+    // they're copied, so covered packs are searched through their own index (or
+    // not at all, if it can't be read.)
+    fn stored_entry(&self, id: &Id) -> Result<Option<StoredEntry>> {
+        let snapshot = self.snapshot();
+
+        if let Some(midx) = &snapshot.multi_pack_index {
+            if let Some((pack_id, _)) = midx.index.find(id) {
+                if let Some(Some(pack)) = midx.packs.get(pack_id) {
+                    if let Some(store) = pack.store() {
+                        let opt = store.stored_entry(id).in_file(&pack.path)?;
+                        if opt.is_some() {
+                            return Ok(opt)
+                        }
+                    }
+                }
+            }
+        }
+
+        for pack in snapshot.packs.iter() {
+            let opt = pack.store.stored_entry(id).in_file(&pack.pack_path)?;
+            if opt.is_some() {
+                return Ok(opt)
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Objects are grouped by the pack that a lookup would read them from, and
+    // each group is read in offset order.
+    fn get_batch<S, F>(&self, ids: Vec<Id>, parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<Vec<Id>>
+        where S: Queryable + Sync,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> + Sync {
+        let snapshot = self.snapshot();
+        let midx_packs = snapshot.multi_pack_index.as_ref().map_or(0, |midx| midx.packs.len());
+
+        let mut groups: Vec<Vec<(u64, Id)>> = vec![Vec::new(); midx_packs + snapshot.packs.len()];
+        let mut missing = Vec::new();
+        'ids: for id in ids {
+            if let Some(midx) = &snapshot.multi_pack_index {
+                if let Some((pack_id, offset)) = midx.index.find(&id) {
+                    if let Some(Some(_)) = midx.packs.get(pack_id) {
+                        groups[pack_id].push((offset, id));
+                        continue
+                    }
+                }
+            }
+
+            for (idx, pack) in snapshot.packs.iter().enumerate() {
+                if let Some((start, _)) = pack.store.index().get_bounds(&id) {
+                    groups[midx_packs + idx].push((start, id));
+                    continue 'ids
+                }
+            }
+
+            missing.push(id);
+        }
+
+        let read_group = |(idx, objects): (usize, &mut Vec<(u64, Id)>)| -> Result<()> {
+            if objects.is_empty() {
+                return Ok(())
+            }
+
+            objects.sort_unstable();
+            match snapshot.multi_pack_index.as_ref().and_then(|midx| midx.packs.get(idx)) {
+                // checking CRC32s needs the pack's own index, as in `get`
+                Some(Some(pack)) => match backends.verifies() {
+                    true => pack.checked_store().and_then(|store| store.read_batch(objects, parallel, each, backends)),
+                    false => read_sorted(&pack.packfile, objects, parallel, each, backends)
+                }.in_file(&pack.path),
+                _ => {
+                    let pack = &snapshot.packs[idx - midx_packs];
+                    pack.store.read_batch(objects, parallel, each, backends).in_file(&pack.pack_path)
+                }
+            }
+        };
+
+        if parallel {
+            groups.par_iter_mut().enumerate().try_for_each(read_group)?;
+        } else {
+            groups.iter_mut().enumerate().try_for_each(read_group)?;
+        }
+
+        Ok(missing)
+    }
+}
+
+pub fn loose_from_path(path: &Path) -> Result<LooseStore> {
+    let root = objects_dir(path);
+    let filter = loose_fanout(&root)?;
+
     let loose_store = LooseStore::new(move |id| {
-        let as_str = id.to_string();
-        let mut pb = root.clone();
-        pb.push(&as_str[0..2]);
-        pb.push(&as_str[2..40]);
+        let pb = loose_path(&root, id);
         match std::fs::File::open(pb.as_path()) {
             Ok(f) => Ok(Some(Box::new(f))),
             Err(e) => {
                 match e.kind() {
                     std::io::ErrorKind::NotFound => Ok(None),
-                    _ => Err(e.into())
+                    _ => Err(Error::from(e).in_file(&pb))
                 }
             }
         }
@@ -60,14 +588,67 @@ pub fn loose_from_path(path: &Path) -> Result<LooseStore, std::io::Error> {
     Ok(loose_store)
 }
 
-pub fn packfiles_from_path(path: &Path) -> Result<Vec<PackStore<MmapPackReader>>, std::io::Error> {
+// Find which fan-out directories exist in the objects directory `root`, by
+// the first byte of the ids they hold.
+fn loose_fanout(root: &Path) -> Result<[bool; 256]> {
+    let mut filter = [false; 256];
+    for entry in std::fs::read_dir(root).in_file(root)? {
+        let entry = entry.in_file(root)?;
+        let os_filename = entry.file_name();
+        if os_filename.len() != 2 {
+            continue
+        }
+
+        let result = match usize::from_str_radix(&os_filename.to_string_lossy(), 16) {
+            Ok(xs) => xs,
+            Err(_) => continue
+        };
+        filter[result] = true;
+    }
+
+    Ok(filter)
+}
+
+pub fn packfiles_from_path(path: &Path) -> Result<Vec<PackStore<MmapPackReader>>> {
+    let root = pack_dir(path);
+
+    let candidates = index_paths(&root)?;
+    let stores: Vec<PackStore<MmapPackReader>> = candidates.into_par_iter().map(|entry_path| {
+        open_pack(&entry_path)
+    }).collect::<Result<Vec<_>>>()?;
+
+    Ok(stores)
+}
+
+pub(crate) fn objects_dir(path: &Path) -> PathBuf {
     let mut root = std::path::PathBuf::new();
     root.push(path);
     root.push(".git");
     root.push("objects");
-    root.push("pack");
+    root
+}
+
+pub(crate) fn pack_dir(path: &Path) -> PathBuf {
+    objects_dir(path).join("pack")
+}
 
-    let candidates: Vec<_> = std::fs::read_dir(root.as_path())?.filter_map(|entry| {
+pub(crate) fn loose_path(objects: &Path, id: &Id) -> PathBuf {
+    let as_str = id.to_string();
+    let mut pb = objects.to_path_buf();
+    pb.push(&as_str[0..2]);
+    pb.push(&as_str[2..40]);
+    pb
+}
+
+// The pack indexes in `root`; like git, a missing pack directory has none.
+pub(crate) fn index_paths(root: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::from(e).in_file(root))
+    };
+
+    let mut candidates: Vec<_> = entries.filter_map(|entry| {
         let entry = entry.ok()?;
         let os_filename = entry.file_name();
         let filename = os_filename.to_str();
@@ -76,27 +657,430 @@ pub fn packfiles_from_path(path: &Path) -> Result<Vec<PackStore<MmapPackReader>>
             return None
         }
 
-        Some(entry)
+        Some(entry.path())
+    }).collect();
+
+    // keep a stable order so that rescans can be compared against the previous scan
+    candidates.sort();
+    Ok(candidates)
+}
+
+// Open every pack in `root`, reusing already-open packs from `existing` where
+// their index is still present on disk.
+fn open_packs(root: &Path, existing: &PackList) -> Result<PackList> {
+    let multi_pack_index = open_multi_pack_index(root, existing.multi_pack_index.as_ref())?;
+    let candidates: Vec<_> = index_paths(root)?.into_iter().filter(|index_path| {
+        match &multi_pack_index {
+            Some(midx) => !midx.covers(index_path),
+            None => true
+        }
     }).collect();
 
-    let stores: Vec<PackStore<MmapPackReader>> = candidates.into_par_iter().map(|entry|  -> Result<PackStore<MmapPackReader>, std::io::Error> {
-        let mut entry_path = entry.path();
+    let packs: Vec<Option<Arc<Pack>>> = candidates.into_par_iter().map(|index_path| {
+        if let Some(pack) = existing.packs.iter().find(|pack| pack.index_path == index_path) {
+            return Ok(Some(pack.clone()))
+        }
+
+        let opened = open_pack(&index_path).and_then(|store| {
+            let bitmap = open_bitmap(&index_path, store.index())?;
+            let mtimes = open_mtimes(&index_path, store.index())?;
+            let mut pack_path = index_path.clone();
+            pack_path.set_extension("pack");
+            let modified = std::fs::metadata(&pack_path).and_then(|metadata| metadata.modified()).in_file(&pack_path)?;
+            Ok(Pack { index_path, pack_path, store, bitmap, mtimes, modified })
+        });
+
+        match opened {
+            Ok(pack) => Ok(Some(Arc::new(pack))),
+
+            // the pack was removed between listing the directory and opening it
+            Err(e) if e.kind() == &ErrorKind::Io(std::io::ErrorKind::NotFound) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }).collect::<Result<Vec<_>>>()?;
+
+    Ok(PackList {
+        multi_pack_index,
+        packs: packs.into_iter().flatten().collect()
+    })
+}
+
+fn open_multi_pack_index(
+    root: &Path,
+    existing: Option<&Arc<MultiPackIndexPacks>>
+) -> Result<Option<Arc<MultiPackIndexPacks>>> {
+    let path = root.join("multi-pack-index");
+    let file = match std::fs::File::open(&path) {
+        Ok(xs) => xs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::from(e).in_file(&path))
+    };
+    let mmap = unsafe { MmapOptions::new().map(&file).in_file(&path)? };
 
-        let index_file = std::fs::File::open(entry_path.clone())?;
-        let index_mmap = unsafe { MmapOptions::new().map(&index_file)? };
-        let idx = match read_packidx(std::io::Cursor::new(index_mmap)) {
+    if let Some(existing) = existing {
+        if mmap.len() >= 20 && &mmap[mmap.len() - 20..] == existing.index.checksum().as_ref() {
+            return Ok(Some(existing.clone()))
+        }
+    }
+
+    // Like git, treat an unreadable multi-pack-index as absent and fall back to
+    // the individual pack indexes.
+    let index = match read_midx(&mmap[..]) {
+        Ok(xs) => xs,
+        Err(_) => return Ok(None)
+    };
+
+    let packs = index.pack_names().par_iter().map(|name| {
+        let mut pack_path = root.join(name);
+        pack_path.set_extension("pack");
+
+        let file = match std::fs::File::open(pack_path.as_path()) {
             Ok(xs) => xs,
-            Err(_) => return Err(std::io::ErrorKind::InvalidData.into())
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::from(e).in_file(&pack_path))
         };
+        let mmap = unsafe { MmapOptions::new().map(&file).in_file(&pack_path)? };
+        let end = (mmap.len() as u64).saturating_sub(20);
+        let modified = file.metadata().and_then(|metadata| metadata.modified()).in_file(&pack_path)?;
+        Ok(Some(MultiPackIndexPack {
+            path: pack_path,
+            packfile: MmapPackReader::new(mmap),
+            end,
+            modified,
+            store: OnceCell::new()
+        }))
+    }).collect::<Result<Vec<_>>>()?;
 
-        entry_path.set_extension("pack");
+    Ok(Some(Arc::new(MultiPackIndexPacks {
+        index,
+        packs
+    })))
+}
 
-        let file = std::fs::File::open(entry_path.as_path())?;
-        let mmap = unsafe { MmapOptions::new().map(&file)? };
-        let packfile = MmapPackReader::new(mmap);
+fn open_pack(index_path: &Path) -> Result<PackStore<MmapPackReader>> {
+    let index_file = std::fs::File::open(index_path).in_file(index_path)?;
+    let index_mmap = unsafe { MmapOptions::new().map(&index_file).in_file(index_path)? };
 
-        Ok(PackStore::new(packfile, idx))
-    }).collect::<Result<Vec<_>, _>>()?;
+    // Prefer the reverse index, if there is one, to avoid sorting offsets. If it
+    // turns out to be unusable we can still fall back to sorting.
+    let mut rev_path = index_path.to_path_buf();
+    rev_path.set_extension("rev");
+    let with_rev = match std::fs::File::open(rev_path.as_path()) {
+        Ok(rev_file) => {
+            let rev_mmap = unsafe { MmapOptions::new().map(&rev_file).in_file(&rev_path)? };
+            read_packidx_with_rev(
+                std::io::Cursor::new(&index_mmap[..]),
+                std::io::Cursor::new(&rev_mmap[..])
+            ).ok()
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(Error::from(e).in_file(&rev_path))
+    };
 
-    Ok(stores)
+    let idx = match with_rev {
+        Some(xs) => xs,
+        None => read_packidx(std::io::Cursor::new(index_mmap)).in_file(index_path).during("reading pack index")?
+    };
+
+    let mut pack_path = index_path.to_path_buf();
+    pack_path.set_extension("pack");
+
+    let file = std::fs::File::open(pack_path.as_path()).in_file(&pack_path)?;
+    let mmap = unsafe { MmapOptions::new().map(&file).in_file(&pack_path)? };
+    let packfile = MmapPackReader::new(mmap);
+
+    Ok(PackStore::new(packfile, idx))
+}
+
+// Like git, ignore a bitmap that doesn't match its pack (or can't be read) rather
+// than failing to open the pack; reachability queries will walk the graph instead.
+fn open_bitmap(index_path: &Path, index: &Index) -> Result<Option<BitmapIndex>> {
+    let mut bitmap_path = index_path.to_path_buf();
+    bitmap_path.set_extension("bitmap");
+
+    let file = match std::fs::File::open(bitmap_path.as_path()) {
+        Ok(xs) => xs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::from(e).in_file(&bitmap_path))
+    };
+    let mmap = unsafe { MmapOptions::new().map(&file).in_file(&bitmap_path)? };
+
+    Ok(read_bitmap(&mmap[..], index).ok())
+}
+
+// Ignore a `.mtimes` file that doesn't match its pack, too. Its objects are
+// then as old as the pack, which is no older than any time recorded for them.
+fn open_mtimes(index_path: &Path, index: &Index) -> Result<Option<Vec<u32>>> {
+    let mut mtimes_path = index_path.to_path_buf();
+    mtimes_path.set_extension("mtimes");
+
+    let file = match std::fs::File::open(mtimes_path.as_path()) {
+        Ok(xs) => xs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::from(e).in_file(&mtimes_path))
+    };
+    let mmap = unsafe { MmapOptions::new().map(&file).in_file(&mtimes_path)? };
+
+    Ok(read_mtimes(&mmap[..], index.len(), index.packfile_checksum()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    use crate::test_support::{ commit_file, git, scratch_dir, scratch_repo, Scratch };
+    use crate::errors::ErrorKind;
+    use crate::id::Id;
+
+    fn empty_objects_dir(name: &str) -> Scratch {
+        let root = scratch_dir(name);
+        std::fs::create_dir_all(root.join(".git/objects/pack")).expect("failed to create scratch repo");
+        root
+    }
+
+    #[test]
+    fn reprepare_picks_up_new_and_removed_packs() {
+        let root = empty_objects_dir("reprepare");
+        let storage_set = root.storage();
+        let id = Id::from_str("872e26b3fbebe64a2a85b271fed6916b964b4fde").unwrap();
+
+        let mut output = Vec::new();
+        assert!(storage_set.get(&id, &mut output).expect("lookup failed").is_none());
+
+        let pack_dir = root.join(".git/objects/pack");
+        std::fs::write(pack_dir.join("pack-fixture.pack"), &include_bytes!("../../fixtures/packfile")[..]).unwrap();
+        std::fs::write(pack_dir.join("pack-fixture.idx"), &include_bytes!("../../fixtures/pack_index")[..]).unwrap();
+
+        // the miss triggers a rescan of the pack directory
+        let mut output = Vec::new();
+        assert!(storage_set.get(&id, &mut output).expect("lookup failed").is_some());
+        assert!(output.starts_with(b"tree "));
+
+        std::fs::remove_file(pack_dir.join("pack-fixture.idx")).unwrap();
+        std::fs::remove_file(pack_dir.join("pack-fixture.pack")).unwrap();
+
+        let packs = storage_set.backend().packs();
+        assert_eq!(packs.len(), 1);
+        assert!(packs.reprepare().expect("rescan failed"));
+        assert!(packs.is_empty());
+        assert!(!packs.reprepare().expect("rescan failed"));
+    }
+
+    #[test]
+    fn rescans_only_changed_directories() {
+        let root = empty_objects_dir("rescan");
+        let objects = root.join(".git/objects");
+        let set_mtime = |path: &std::path::Path, time: std::time::SystemTime| {
+            std::fs::File::open(path).unwrap().set_modified(time).unwrap();
+        };
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        set_mtime(&objects, an_hour_ago);
+        set_mtime(&objects.join("pack"), an_hour_ago);
+
+        let storage_set = root.storage();
+        let id = Id::from_str("0e876bad77dfebf1453c338598068484414939f1").unwrap();
+        assert!(!storage_set.backend().reprepare().expect("rescan failed"));
+
+        // a new fan-out directory that the directory's time doesn't show is missed...
+        std::fs::create_dir(objects.join("0e")).unwrap();
+        std::fs::write(objects.join("0e/876bad77dfebf1453c338598068484414939f1"), &include_bytes!("../../fixtures/loose_commit")[..]).unwrap();
+        set_mtime(&objects, an_hour_ago);
+        assert!(storage_set.get(&id, &mut Vec::new()).expect("lookup failed").is_none());
+
+        // ...until the time changes, and the fan-out filter is rebuilt
+        set_mtime(&objects, std::time::SystemTime::now());
+        assert!(storage_set.get(&id, &mut Vec::new()).expect("lookup failed").is_some());
+    }
+
+    #[test]
+    fn missing_pack_directories_are_not_rescanned() {
+        let root = scratch_dir("rescan-no-packs");
+        let objects = root.join(".git/objects");
+        std::fs::create_dir_all(&objects).unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::open(&objects).unwrap().set_modified(an_hour_ago).unwrap();
+
+        let storage_set = root.storage();
+        let id = Id::from_str("0e876bad77dfebf1453c338598068484414939f1").unwrap();
+        assert!(storage_set.get(&id, &mut Vec::new()).expect("lookup failed").is_none());
+
+        // a miss only rescans if a directory changed, and neither did
+        std::fs::create_dir(objects.join("0e")).unwrap();
+        std::fs::write(objects.join("0e/876bad77dfebf1453c338598068484414939f1"), &include_bytes!("../../fixtures/loose_commit")[..]).unwrap();
+        std::fs::File::open(&objects).unwrap().set_modified(an_hour_ago).unwrap();
+        assert!(storage_set.get(&id, &mut Vec::new()).expect("lookup failed").is_none());
+    }
+
+    #[test]
+    fn read_errors_name_the_object_and_its_location() {
+        let root = empty_objects_dir("corrupt");
+        let pack_dir = root.join(".git/objects/pack");
+
+        let mut packfile = include_bytes!("../../fixtures/packfile").to_vec();
+        for byte in packfile[16..32].iter_mut() {
+            *byte = !*byte;
+        }
+        std::fs::write(pack_dir.join("pack-fixture.pack"), &packfile).unwrap();
+        std::fs::write(pack_dir.join("pack-fixture.idx"), &include_bytes!("../../fixtures/pack_index")[..]).unwrap();
+
+        let storage_set = root.storage();
+        let id = Id::from_str("872e26b3fbebe64a2a85b271fed6916b964b4fde").unwrap();
+
+        let error = storage_set.get(&id, &mut Vec::new()).expect_err("the object is corrupt");
+        assert!(error.is_corrupt());
+        assert!(!error.is_missing());
+        assert_eq!(error.id(), Some(&id));
+        assert_eq!(error.path(), Some(pack_dir.join("pack-fixture.pack").as_path()));
+        assert_eq!(error.offset(), Some(12));
+        assert_eq!(error.operation(), Some("reading object"));
+    }
+
+    #[test]
+    fn verification_checks_packed_crc32s() {
+        let root = empty_objects_dir("verify");
+        let pack_dir = root.join(".git/objects/pack");
+
+        let mut packfile = include_bytes!("../../fixtures/packfile").to_vec();
+        std::fs::write(pack_dir.join("pack-fixture.pack"), &packfile).unwrap();
+        std::fs::write(pack_dir.join("pack-fixture.idx"), &include_bytes!("../../fixtures/pack_index")[..]).unwrap();
+
+        let id = Id::from_str("872e26b3fbebe64a2a85b271fed6916b964b4fde").unwrap();
+        let storage_set = root.storage().with_verification();
+        let mut output = Vec::new();
+        assert!(storage_set.get(&id, &mut output).expect("the pack is fine").is_some());
+        assert!(storage_set.stored_entry(&id).expect("the pack is fine").is_some());
+
+        // flip a bit in the first object's compressed data
+        packfile[20] ^= 1;
+        std::fs::write(pack_dir.join("pack-fixture.pack"), &packfile).unwrap();
+        let storage_set = root.storage().with_verification();
+
+        let error = storage_set.get(&id, &mut Vec::new()).expect_err("the object is corrupt");
+        match error.kind() {
+            ErrorKind::Crc32Mismatch { .. } => (),
+            kind => panic!("unexpected error: {}", kind)
+        };
+        assert_eq!(error.id(), Some(&id));
+        assert_eq!(error.path(), Some(pack_dir.join("pack-fixture.pack").as_path()));
+        assert_eq!(error.offset(), Some(12));
+
+        // stored entries are always checked, since they're copied without being inflated
+        let storage_set = root.storage();
+        let error = storage_set.stored_entry(&id).expect_err("the object is corrupt");
+        assert!(matches!(error.kind(), ErrorKind::Crc32Mismatch { .. }));
+    }
+
+    #[test]
+    fn multi_pack_index_is_used_for_covered_packs() {
+        let root = empty_objects_dir("midx");
+        let pack_dir = root.join(".git/objects/pack");
+        let name = "pack-ac04939ad291747cb090c8758f549b5ebabdcc48";
+
+        // the pack's own index is unreadable, so any lookup must go through the midx
+        std::fs::write(pack_dir.join(format!("{}.pack", name)), &include_bytes!("../../fixtures/packfile")[..]).unwrap();
+        std::fs::write(pack_dir.join(format!("{}.idx", name)), b"garbage").unwrap();
+        std::fs::write(pack_dir.join("multi-pack-index"), &include_bytes!("../../fixtures/multi_pack_index")[..]).unwrap();
+
+        let storage_set = root.storage();
+        let packs = storage_set.backend().packs();
+        assert!(packs.has_multi_pack_index());
+        assert_eq!(packs.len(), 1);
+
+        let id = Id::from_str("872e26b3fbebe64a2a85b271fed6916b964b4fde").unwrap();
+        let mut output = Vec::new();
+        assert!(storage_set.get(&id, &mut output).expect("lookup failed").is_some());
+        assert!(output.starts_with(b"tree "));
+
+        // stored entries can't be checked without the pack's own index
+        assert!(storage_set.stored_entry(&id).expect("lookup failed").is_none());
+
+        // checking CRC32s needs the pack's own index
+        let storage_set = root.storage().with_verification();
+        let error = storage_set.get(&id, &mut Vec::new()).expect_err("the pack index is unreadable");
+        assert_eq!(error.path(), Some(pack_dir.join(format!("{}.idx", name)).as_path()));
+        let error = storage_set.get_batch(std::slice::from_ref(&id), false, |_, _, _| Ok(())).expect_err("the pack index is unreadable");
+        assert_eq!(error.path(), Some(pack_dir.join(format!("{}.idx", name)).as_path()));
+
+        std::fs::write(pack_dir.join(format!("{}.idx", name)), &include_bytes!("../../fixtures/pack_index")[..]).unwrap();
+        let storage_set = root.storage().with_verification();
+        assert!(storage_set.get(&id, &mut Vec::new()).expect("the pack is fine").is_some());
+        assert!(storage_set.stored_entry(&id).expect("lookup failed").is_some());
+
+        let mut packfile = include_bytes!("../../fixtures/packfile").to_vec();
+        packfile[20] ^= 1;
+        std::fs::write(pack_dir.join(format!("{}.pack", name)), &packfile).unwrap();
+        let storage_set = root.storage().with_verification();
+        let error = storage_set.get(&id, &mut Vec::new()).expect_err("the object is corrupt");
+        assert!(matches!(error.kind(), ErrorKind::Crc32Mismatch { .. }));
+        assert_eq!(error.offset(), Some(12));
+
+        for parallel in [false, true].iter() {
+            let error = storage_set.get_batch(std::slice::from_ref(&id), *parallel, |_, _, _| Ok(())).expect_err("the object is corrupt");
+            assert!(matches!(error.kind(), ErrorKind::Crc32Mismatch { .. }));
+            assert_eq!(error.offset(), Some(12));
+        }
+    }
+
+    #[test]
+    fn batches_read_every_object_once() {
+        let root = scratch_repo("fs-batch");
+        let mut contents: String = (0..200).map(|xs| format!("line {}\n", xs)).collect();
+        let mut time = 1_600_000_000;
+        let mut commit = |contents: &mut String, count: u64| {
+            for _ in 0..count {
+                time += 100;
+                *contents = contents.replace(&format!("line {}\n", time % 200), &format!("changed {}\n", time));
+                commit_file(&root, "file.txt", contents, time);
+                commit_file(&root, &format!("dir/{}.txt", time), &format!("{}\n", time), time);
+            }
+        };
+
+        // a pack and an incremental pack covered by a multi-pack-index, another
+        // pack that isn't, and loose objects
+        commit(&mut contents, 20);
+        git(&root, &["repack", "-adq"]);
+        commit(&mut contents, 5);
+        git(&root, &["repack", "-dq"]);
+        git(&root, &["multi-pack-index", "write"]);
+        commit(&mut contents, 5);
+        git(&root, &["repack", "-dq"]);
+        commit(&mut contents, 2);
+
+        let mut ids: Vec<Id> = git(&root, &["rev-list", "--objects", "--all"]).lines()
+            .map(|line| Id::from_str(&line[..40]).unwrap())
+            .collect();
+        ids.sort();
+
+        let absent = Id::from_str("0000000000000000000000000000000000000001").unwrap();
+        let mut request = ids.clone();
+        request.push(absent.clone());
+
+        for storage_set in [root.storage(), root.storage().with_verification()] {
+            let packs = storage_set.backend().packs();
+            assert!(packs.has_multi_pack_index());
+            assert_eq!(packs.len(), 3);
+
+            for parallel in [false, true].iter() {
+                let read = Mutex::new(Vec::new());
+                let missing = storage_set.get_batch(&request, *parallel, |id, kind, data| {
+                    let mut expected = Vec::new();
+                    assert_eq!(storage_set.get(id, &mut expected).unwrap(), Some(kind));
+                    assert_eq!(data, &expected[..]);
+                    read.lock().unwrap().push(id.clone());
+                    Ok(())
+                }).expect("failed to read batch");
+
+                assert_eq!(missing, vec![absent.clone()]);
+                let mut read = read.into_inner().unwrap();
+                read.sort();
+                assert_eq!(read, ids);
+            }
+        }
+
+        // errors from the callback stop the batch
+        let storage_set = root.storage();
+        let error = storage_set.get_batch(&ids, true, |_, _, _| Err(ErrorKind::ObjectNotFound.into())).expect_err("the callback fails");
+        assert_eq!(error.kind(), &ErrorKind::ObjectNotFound);
+    }
 }
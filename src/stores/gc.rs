@@ -0,0 +1,1359 @@
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use std::collections::{ HashMap, HashSet };
+use std::io::{ BufReader, BufWriter, Write };
+use std::path::{ Path, PathBuf };
+use std::convert::TryInto;
+use std::str::FromStr;
+use std::time::{ Duration, SystemTime };
+
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::pack::index::read as read_packidx;
+use crate::pack::mtimes;
+use crate::pack::window::{ self, name_hash, write_with_deltas };
+use crate::stores::fs::{ self, index_paths, loose_path, objects_dir, pack_dir, Store };
+use crate::stores::stats::{ loose_type, PACK_EXTENSIONS };
+use crate::stores::StorageSet;
+use crate::walk::reachable::{ reachable, tag_target };
+use crate::objects::{ Object, Type };
+use crate::refs::{ reflogs, tips, worktree_dirs };
+use crate::id::Id;
+
+/// How `repack` goes about it.
+#[derive(Debug, Clone, Copy)]
+pub struct RepackOptions {
+    pub deltas: window::Options,
+
+    /// Write the unreachable objects of removed packs out as loose objects (like
+    /// `git repack -A`), so that they're only deleted once they expire, instead
+    /// of dropping them along with their packs (`git repack -a`.)
+    pub keep_unreachable: bool,
+
+    /// Write unreachable objects (from removed packs, and loose) to a cruft pack
+    /// instead, recording when each was last written in a `.mtimes` file (like
+    /// `git repack --cruft`.) Objects that have expired are left out, unless
+    /// they're reachable from ones that haven't. This takes precedence over
+    /// `keep_unreachable`.
+    pub cruft: Option<Expire>
+}
+
+impl Default for RepackOptions {
+    fn default() -> Self {
+        RepackOptions { deltas: window::Options::default(), keep_unreachable: true, cruft: None }
+    }
+}
+
+/// When `gc` with `auto` set decides there's work to do, like git's `gc.auto`
+/// and `gc.autoPackLimit`. Zero disables either check.
+#[derive(Debug, Clone, Copy)]
+pub struct AutoLimits {
+    pub loose_objects: usize,
+    pub packs: usize
+}
+
+impl Default for AutoLimits {
+    fn default() -> Self {
+        AutoLimits { loose_objects: 6700, packs: 50 }
+    }
+}
+
+/// How long an unreachable loose object is kept before `prune` removes it, like
+/// git's `gc.pruneExpire`. Parses git's approxidate forms for it: `never`,
+/// `now`, and relative dates like `2.weeks.ago` or `3 days ago`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expire {
+    Never,
+
+    /// Remove objects last modified at least this long ago.
+    After(Duration)
+}
+
+impl Default for Expire {
+    fn default() -> Self {
+        Expire::After(Duration::from_secs(14 * DAY))
+    }
+}
+
+const DAY: u64 = 24 * 60 * 60;
+
+impl FromStr for Expire {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let invalid = || Error::from(ErrorKind::InvalidExpiry(input.to_string()));
+        match input.trim() {
+            "never" | "false" => return Ok(Expire::Never),
+            "now" | "all" => return Ok(Expire::After(Duration::from_secs(0))),
+            _ => ()
+        }
+
+        let mut words: Vec<&str> = input.split(['.', ' ']).filter(|word| !word.is_empty()).collect();
+        if words.last() == Some(&"ago") {
+            words.pop();
+        }
+
+        let (count, unit) = match words.as_slice() {
+            [count, unit] => (count.parse::<u64>().map_err(|_| invalid())?, unit.trim_end_matches('s')),
+            _ => return Err(invalid())
+        };
+
+        let seconds = match unit {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => DAY,
+            "week" => 7 * DAY,
+            "month" => 30 * DAY,
+            "year" => 365 * DAY,
+            _ => return Err(invalid())
+        };
+
+        Ok(Expire::After(Duration::from_secs(count.saturating_mul(seconds))))
+    }
+}
+
+/// How `prune` goes about it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PruneOptions {
+    pub expire: Expire,
+
+    /// Only list the objects that would be removed.
+    pub dry_run: bool
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GcOptions {
+    pub repack: RepackOptions,
+
+    /// Only collect garbage if the repository is over these limits.
+    pub auto: Option<AutoLimits>,
+
+    /// Prune unreachable loose objects (including those the repack wrote out)
+    /// once they're this old.
+    pub prune: Expire,
+
+    /// Keep unreachable objects in a cruft pack rather than loose, expiring
+    /// them after `prune` (like `git gc --cruft`.)
+    pub cruft: bool
+}
+
+/// What `gc` did.
+#[derive(Debug)]
+pub struct Collected {
+    repacked: Repacked,
+    pruned: Pruned
+}
+
+impl Collected {
+    pub fn repacked(&self) -> &Repacked {
+        &self.repacked
+    }
+
+    pub fn pruned(&self) -> &Pruned {
+        &self.pruned
+    }
+}
+
+/// What `prune` removed (or, for a dry run, would have.)
+#[derive(Debug, Default)]
+pub struct Pruned {
+    objects: Vec<(Id, Option<Type>, PathBuf)>
+}
+
+impl Pruned {
+    /// The id, type (if it could be read) and path of each pruned object.
+    pub fn objects(&self) -> &[(Id, Option<Type>, PathBuf)] {
+        &self.objects
+    }
+}
+
+/// What `repack` did.
+#[derive(Debug, Default)]
+pub struct Repacked {
+    pack: Option<PathBuf>,
+    packed: usize,
+    removed_packs: Vec<PathBuf>,
+    removed_loose: usize,
+    loosened: usize,
+    cruft_pack: Option<PathBuf>,
+    crufted: usize,
+    expired: usize
+}
+
+impl Repacked {
+    /// The pack that was written, unless there was nothing to pack.
+    pub fn pack(&self) -> Option<&Path> {
+        self.pack.as_deref()
+    }
+
+    /// The number of objects in the new pack.
+    pub fn packed(&self) -> usize {
+        self.packed
+    }
+
+    /// The packs (named by their `.pack` file) that were removed.
+    pub fn removed_packs(&self) -> &[PathBuf] {
+        &self.removed_packs
+    }
+
+    /// The number of loose objects removed because they are now packed.
+    pub fn removed_loose(&self) -> usize {
+        self.removed_loose
+    }
+
+    /// The number of unreachable objects from removed packs written out loose.
+    pub fn loosened(&self) -> usize {
+        self.loosened
+    }
+
+    /// The cruft pack that was written, if one was asked for and there were
+    /// unreachable objects to put in it.
+    pub fn cruft_pack(&self) -> Option<&Path> {
+        self.cruft_pack.as_deref()
+    }
+
+    /// The number of unreachable objects in the cruft pack.
+    pub fn crufted(&self) -> usize {
+        self.crufted
+    }
+
+    /// The number of expired unreachable objects left out of the cruft pack.
+    /// Those from removed packs are gone; loose ones are left for `prune`.
+    pub fn expired(&self) -> usize {
+        self.expired
+    }
+}
+
+/// Pack every object reachable from the refs, reflogs and indexes of the
+/// repository at `path` (and its worktrees) into one new pack, then remove the
+/// packs and loose objects it makes redundant.
+///
+/// Packs with a `.keep` file are left alone, and objects in them aren't copied
+/// into the new pack. The new pack is complete on disk before anything is
+/// removed, so an interrupted repack leaves extra copies of objects behind, not
+/// missing ones.
+pub fn repack(path: &Path, options: &RepackOptions) -> Result<Repacked> {
+    let storage_set = fs::from(path)?;
+    let pack_dir = pack_dir(path);
+    let objects = objects_dir(path);
+
+    let existing = index_paths(&pack_dir)?;
+    let (kept, old): (Vec<PathBuf>, Vec<PathBuf>) = existing.into_iter().partition(|index_path| {
+        index_path.with_extension("keep").exists()
+    });
+
+    let mut kept_ids = HashSet::new();
+    for index_path in &kept {
+        kept_ids.extend(read_index_ids(index_path)?);
+    }
+
+    let tips = tips(path).during("reading refs")?;
+    let extra = other_tips(path)?;
+    let to_pack: Vec<(Id, u32)> = walk(&storage_set, &tips, &extra).during("finding reachable objects")?
+        .into_iter()
+        .filter(|(id, _)| !kept_ids.contains(id))
+        .collect();
+
+    let mut repacked = Repacked { packed: to_pack.len(), ..Default::default() };
+    if !to_pack.is_empty() {
+        repacked.pack = Some(write_pack(&storage_set, &pack_dir, &to_pack, options.deltas, None)?);
+    }
+
+    let packed: HashSet<Id> = to_pack.into_iter().map(|(id, _)| id).collect();
+    let mut crufted = HashSet::new();
+    if let Some(expire) = options.cruft {
+        let unreachable = unreachable_mtimes(&storage_set, &objects, &old, |id| packed.contains(id) || kept_ids.contains(id))?;
+        let cruft = cruft_objects(&storage_set, &unreachable, expire, |id| packed.contains(id) || kept_ids.contains(id))?;
+        repacked.expired = unreachable.len() - cruft.len();
+        repacked.crufted = cruft.len();
+
+        if !cruft.is_empty() {
+            let mut to_write: Vec<(Id, u32)> = cruft.keys().map(|id| (id.clone(), 0)).collect();
+            to_write.sort();
+            repacked.cruft_pack = Some(write_pack(&storage_set, &pack_dir, &to_write, options.deltas, Some(&cruft))?);
+        }
+        crufted.extend(cruft.into_keys());
+    }
+
+    let old: Vec<PathBuf> = old.into_iter().filter(|index_path| {
+        let pack_path = index_path.with_extension("pack");
+        Some(pack_path.as_path()) != repacked.pack() && Some(pack_path.as_path()) != repacked.cruft_pack()
+    }).collect();
+
+    // a multi-pack-index would refer to the packs about to be removed
+    if !old.is_empty() {
+        let midx = pack_dir.join("multi-pack-index");
+        match std::fs::remove_file(&midx) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::from(e).in_file(&midx)),
+            _ => ()
+        }
+    }
+
+    for index_path in old {
+        let pack_path = index_path.with_extension("pack");
+
+        if options.cruft.is_none() && options.keep_unreachable {
+            let pack_mtime = std::fs::metadata(&pack_path).and_then(|metadata| metadata.modified()).in_file(&pack_path)?;
+            for id in read_index_ids(&index_path)? {
+                if packed.contains(&id) || kept_ids.contains(&id) || loose_path(&objects, &id).exists() {
+                    continue
+                }
+
+                // objects from cruft packs keep the time recorded for them
+                let mtime = storage_set.backend().packs().mtime(&id).unwrap_or(pack_mtime);
+                write_loose(&storage_set, &objects, &id, mtime).for_object(&id)?;
+                repacked.loosened += 1;
+            }
+        }
+
+        remove_pack(&index_path)?;
+        repacked.removed_packs.push(pack_path);
+    }
+
+    repacked.removed_loose = remove_packed_loose(&objects, |id| {
+        packed.contains(id) || kept_ids.contains(id) || crufted.contains(id)
+    })?;
+    Ok(repacked)
+}
+
+// Find the objects that are in the packs at `index_paths` or loose, but not
+// `reachable`, along with when each was last written.
+fn unreachable_mtimes<F: Fn(&Id) -> bool>(
+    storage_set: &StorageSet<Store>,
+    objects: &Path,
+    index_paths: &[PathBuf],
+    reachable: F
+) -> Result<HashMap<Id, SystemTime>> {
+    let mut unreachable = HashMap::new();
+    let packed = storage_set.backend().packs().mtimes();
+    for index_path in index_paths {
+        for id in read_index_ids(index_path)? {
+            if reachable(&id) {
+                continue
+            }
+
+            let position = packed.binary_search_by(|(candidate, _)| candidate.cmp(&id));
+            if let Ok(position) = position {
+                unreachable.insert(id, packed[position].1);
+            }
+        }
+    }
+
+    for (id, loose) in loose_objects(objects)? {
+        if reachable(&id) {
+            continue
+        }
+
+        let mtime = std::fs::metadata(&loose).and_then(|metadata| metadata.modified()).in_file(&loose)?;
+        let entry = unreachable.entry(id).or_insert(mtime);
+        *entry = (*entry).max(mtime);
+    }
+
+    Ok(unreachable)
+}
+
+// Choose which of the `unreachable` objects go in a cruft pack: those that
+// haven't expired, and those they refer to.
+fn cruft_objects<F: Fn(&Id) -> bool>(
+    storage_set: &StorageSet<Store>,
+    unreachable: &HashMap<Id, SystemTime>,
+    expire: Expire,
+    reachable: F
+) -> Result<HashMap<Id, SystemTime>> {
+    let cutoff = match expire {
+        Expire::Never => return Ok(unreachable.clone()),
+        Expire::After(expire) => SystemTime::now().checked_sub(expire).unwrap_or(SystemTime::UNIX_EPOCH)
+    };
+
+    let recent: Vec<Id> = unreachable.iter().filter(|(_, mtime)| **mtime > cutoff).map(|(id, _)| id.clone()).collect();
+    let rescued = mark_present(storage_set, reachable, recent)?;
+    Ok(unreachable.iter()
+        .filter(|(id, _)| rescued.contains(*id))
+        .map(|(id, mtime)| (id.clone(), *mtime))
+        .collect())
+}
+
+/// Whether the repository at `path` has enough loose objects or packs that
+/// `gc --auto` would collect garbage. Like git, loose objects are estimated
+/// from the `17/` fanout directory alone.
+pub fn needs_gc(path: &Path, limits: &AutoLimits) -> Result<bool> {
+    if limits.loose_objects > 0 {
+        let fanout = objects_dir(path).join("17");
+        let threshold = limits.loose_objects.div_ceil(256);
+        let count = match std::fs::read_dir(&fanout) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok()).filter(|entry| {
+                let name = entry.file_name();
+                name.len() == 38 && name.to_str().is_some_and(|name| name.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            }).count(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(Error::from(e).in_file(&fanout))
+        };
+
+        if count > threshold {
+            return Ok(true)
+        }
+    }
+
+    if limits.packs > 0 {
+        let pack_dir = pack_dir(path);
+        let packs = index_paths(&pack_dir)?;
+
+        let count = packs.iter().filter(|index_path| !index_path.with_extension("keep").exists()).count();
+        if count > limits.packs {
+            return Ok(true)
+        }
+    }
+
+    Ok(false)
+}
+
+/// Collect garbage in the repository at `path`: repack everything that's
+/// reachable, then prune expired unreachable objects. Returns `None` if
+/// `options.auto` is set and there's nothing to do.
+pub fn gc(path: &Path, options: &GcOptions) -> Result<Option<Collected>> {
+    if let Some(limits) = &options.auto {
+        if !needs_gc(path, limits)? {
+            return Ok(None)
+        }
+    }
+
+    let mut repack_options = options.repack;
+    if options.cruft {
+        repack_options.cruft = Some(options.prune);
+    }
+
+    let repacked = repack(path, &repack_options)?;
+    let pruned = prune(path, &PruneOptions { expire: options.prune, dry_run: false })?;
+    Ok(Some(Collected { repacked, pruned }))
+}
+
+/// Remove the loose objects of the repository at `path` that can't be reached
+/// from its refs, reflogs or index (or those of its worktrees), and that haven't
+/// been modified within `options.expire`.
+///
+/// Objects reachable from unexpired unreachable objects, loose or packed, are
+/// kept too (as git does since 2.2), so that an object that was just written,
+/// and is about to be referred to, doesn't lose the objects it refers to.
+pub fn prune(path: &Path, options: &PruneOptions) -> Result<Pruned> {
+    let expire = match options.expire {
+        Expire::Never => return Ok(Pruned::default()),
+        Expire::After(xs) => xs
+    };
+
+    let cutoff = SystemTime::now().checked_sub(expire).unwrap_or(SystemTime::UNIX_EPOCH);
+    let objects = objects_dir(path);
+    let mut candidates = Vec::new();
+    let mut recent = Vec::new();
+    for (id, loose) in loose_objects(&objects)? {
+        let mtime = std::fs::metadata(&loose).and_then(|metadata| metadata.modified()).in_file(&loose)?;
+        if mtime > cutoff {
+            recent.push(id);
+        } else {
+            candidates.push((id, loose));
+        }
+    }
+
+    if candidates.is_empty() {
+        return Ok(Pruned::default())
+    }
+
+    let storage_set = fs::from(path)?;
+    let tips = tips(path).during("reading refs")?;
+    let bitmapped = storage_set.backend().packs().bitmapped_pack();
+    let reachable = reachable(&storage_set, bitmapped.as_ref().map(|pack| (pack.index(), pack.bitmap())), &tips)
+        .during("finding reachable objects")?;
+
+    let mut extra = other_tips(path)?;
+    extra.extend(recent);
+    extra.extend(storage_set.backend().packs().mtimes().into_iter()
+        .filter(|(_, mtime)| *mtime > cutoff)
+        .map(|(id, _)| id));
+    let also_reachable = mark_present(&storage_set, |id| reachable.contains(id), extra)?;
+
+    let mut pruned = Pruned::default();
+    for (id, loose) in candidates {
+        if reachable.contains(&id) || also_reachable.contains(&id) {
+            continue
+        }
+
+        let kind = loose_type(&loose).ok();
+        if !options.dry_run {
+            std::fs::remove_file(&loose).in_file(&loose)?;
+        }
+        pruned.objects.push((id, kind, loose));
+    }
+
+    if !options.dry_run {
+        for fanout in 0..=255u8 {
+            let _ = std::fs::remove_dir(objects.join(format!("{:02x}", fanout)));
+        }
+    }
+
+    Ok(pruned)
+}
+
+// The objects kept alive by something other than a ref: everything in the
+// reflogs and indexes of the repository at `path` and its worktrees (what git's
+// `--reflog --indexed-objects` add.) Reflogs especially may name objects that
+// are long gone.
+fn other_tips(path: &Path) -> Result<Vec<Id>> {
+    let mut ids = reflogs(path).during("reading reflogs")?;
+    ids.extend(index_ids(&path.join(".git").join("index"))?);
+    for worktree in worktree_dirs(path)? {
+        ids.extend(index_ids(&worktree.join("index"))?);
+    }
+    Ok(ids)
+}
+
+fn read_index_ids(index_path: &Path) -> Result<Vec<Id>> {
+    let file = std::fs::File::open(index_path).in_file(index_path)?;
+    let index = read_packidx(BufReader::new(file)).in_file(index_path)?;
+    Ok(index.ids().to_vec())
+}
+
+// Find every object reachable from `tips`, along with the name hash of the path
+// it was found at: commits and tags first, then trees and blobs (the order
+// `git rev-list --objects` lists them in.) Objects reachable from `extra` are
+// included too, skipping over any that are missing.
+fn walk(storage_set: &StorageSet<Store>, tips: &[Id], extra: &[Id]) -> Result<Vec<(Id, u32)>> {
+    let mut seen = HashSet::new();
+    let mut commits = Vec::new();
+    let mut contents = Vec::new();
+
+    // everything reachable from `tips` is found before anything from `extra`, so
+    // an object is never skipped as missing if a tip needs it
+    let mut pending: Vec<(Id, Option<Type>, u32, bool)> = extra.iter().rev().map(|id| (id.clone(), None, 0, false))
+        .chain(tips.iter().rev().map(|id| (id.clone(), None, 0, true)))
+        .collect();
+    while let Some((id, kind, hash, required)) = pending.pop() {
+        if !seen.insert(id.clone()) {
+            continue
+        }
+
+        // blobs are only checked for when they're written, unless they may be missing
+        if let Some(Type::Blob) = kind {
+            if required || present(storage_set, &id)? {
+                contents.push((id, hash));
+            }
+            continue
+        }
+
+        let object = match storage_set.get_and_load(&id) {
+            Ok(Some(xs)) => xs,
+            Ok(None) if !required => continue,
+            Err(e) if !required && e.is_missing() => continue,
+            Ok(None) => return Err(Error::from(ErrorKind::ObjectNotFound).for_object(&id)),
+            Err(e) => return Err(e.for_object(&id))
+        };
+
+        match object {
+            Object::Commit(commit) => {
+                pending.extend(commit.tree().map(|tree| (tree.clone(), Some(Type::Tree), 0, required)));
+                pending.extend(commit.parents().iter().rev().map(|parent| (parent.clone(), Some(Type::Commit), 0, required)));
+                commits.push((id, 0));
+            },
+
+            Object::Tree(tree) => {
+                for (name, entry) in tree.entries().iter().rev() {
+                    if entry.mode.is_submodule() {
+                        continue
+                    }
+
+                    let kind = if entry.mode.is_tree() { Type::Tree } else { Type::Blob };
+                    pending.push((entry.id.clone(), Some(kind), name_hash(name), required));
+                }
+                contents.push((id, hash));
+            },
+
+            Object::Tag(tag) => {
+                let target = tag_target(&tag).ok_or(ErrorKind::InvalidTag).for_object(&id)?;
+                pending.push((target, None, 0, required));
+                commits.push((id, 0));
+            },
+
+            Object::Blob(_) => contents.push((id, hash))
+        }
+    }
+
+    commits.extend(contents);
+    Ok(commits)
+}
+
+fn present(storage_set: &StorageSet<Store>, id: &Id) -> Result<bool> {
+    match storage_set.get(id, &mut std::io::sink()) {
+        Ok(xs) => Ok(xs.is_some()),
+        Err(e) if e.is_missing() => Ok(false),
+        Err(e) => Err(e.for_object(id))
+    }
+}
+
+// Find the objects reachable from `tips` that `known` doesn't already cover,
+// skipping over any that are missing (reflogs, for instance, may well mention
+// objects that are long gone.)
+fn mark_present<F: Fn(&Id) -> bool>(storage_set: &StorageSet<Store>, known: F, tips: Vec<Id>) -> Result<HashSet<Id>> {
+    let mut seen = HashSet::new();
+    let mut pending: Vec<(Id, Option<Type>)> = tips.into_iter().map(|id| (id, None)).collect();
+    while let Some((id, kind)) = pending.pop() {
+        if known(&id) || seen.contains(&id) {
+            continue
+        }
+
+        if let Some(Type::Blob) = kind {
+            seen.insert(id);
+            continue
+        }
+
+        let object = match storage_set.get_and_load(&id) {
+            Ok(Some(xs)) => xs,
+            Ok(None) => continue,
+            Err(e) if e.is_missing() => continue,
+            Err(e) => return Err(e.for_object(&id))
+        };
+
+        match object {
+            Object::Commit(commit) => {
+                pending.extend(commit.parents().iter().map(|parent| (parent.clone(), Some(Type::Commit))));
+                pending.extend(commit.tree().map(|tree| (tree.clone(), Some(Type::Tree))));
+            },
+
+            Object::Tree(tree) => {
+                for entry in tree.entries().values().filter(|entry| !entry.mode.is_submodule()) {
+                    let kind = if entry.mode.is_tree() { Type::Tree } else { Type::Blob };
+                    pending.push((entry.id.clone(), Some(kind)));
+                }
+            },
+
+            Object::Tag(tag) => pending.extend(tag_target(&tag).map(|target| (target, None))),
+            Object::Blob(_) => ()
+        }
+        seen.insert(id);
+    }
+
+    Ok(seen)
+}
+
+// Read the ids of the entries (other than submodules), cached trees and
+// resolve-undo records of the index file at `path`. A split index keeps most of
+// its entries in a shared index next to it, which is read too.
+fn index_ids(path: &Path) -> Result<Vec<Id>> {
+    let bytes = match std::fs::read(path) {
+        Ok(xs) => xs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::from(e).in_file(path))
+    };
+
+    let index = read_index_file(&bytes).ok_or(ErrorKind::CorruptedIndexFile).in_file(path)?;
+    check_index_extension(&index).in_file(path)?;
+
+    let mut ids = index.ids;
+    if let Some(shared) = index.shared_index {
+        let shared_path = path.with_file_name(format!("sharedindex.{}", shared));
+        let bytes = std::fs::read(&shared_path).in_file(&shared_path)?;
+        let shared = read_index_file(&bytes).ok_or(ErrorKind::CorruptedIndexFile).in_file(&shared_path)?;
+        check_index_extension(&shared).in_file(&shared_path)?;
+        ids.extend(shared.ids);
+    }
+
+    Ok(ids)
+}
+
+// Extensions whose signature starts with a lowercase letter change how the rest
+// of the index has to be read, so one we don't know means we can't trust it.
+fn check_index_extension(index: &IndexFile) -> Result<()> {
+    match &index.unknown_extension {
+        Some(signature) => Err(ErrorKind::UnsupportedIndexExtension(signature.clone()).into()),
+        None => Ok(())
+    }
+}
+
+struct IndexFile {
+    ids: Vec<Id>,
+    shared_index: Option<Id>,
+    unknown_extension: Option<String>
+}
+
+fn read_index_file(bytes: &[u8]) -> Option<IndexFile> {
+    let u32_at = |offset: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+    };
+
+    if bytes.get(0..4)? != b"DIRC" || bytes.len() < 32 {
+        return None
+    }
+
+    let version = u32_at(4)?;
+    let count = u32_at(8)?;
+    if !(2..=4).contains(&version) {
+        return None
+    }
+
+    let mut ids = Vec::new();
+    let mut offset = 12;
+    for _ in 0..count {
+        // ctime, mtime, dev and ino come before the mode; the id follows the size
+        let mode = u32_at(offset + 24)?;
+        let id = Id::new(bytes.get(offset + 40..offset + 60)?);
+        let flags = u16::from_be_bytes(bytes.get(offset + 60..offset + 62)?.try_into().ok()?);
+        let mut path_start = offset + 62;
+        if flags & 0x4000 != 0 {
+            path_start += 2;
+        }
+
+        if version == 4 {
+            // the path is prefix-compressed against the previous entry's: a
+            // varint of bytes to drop, then the rest of it
+            while *bytes.get(path_start)? & 0x80 != 0 {
+                path_start += 1;
+            }
+            path_start += 1;
+        }
+
+        let path_len = bytes.get(path_start..)?.iter().position(|byte| *byte == 0)?;
+        offset = if version == 4 {
+            path_start + path_len + 1
+        } else {
+            // entries are padded with 1-8 NULs to a multiple of eight bytes
+            offset + ((path_start - offset + path_len + 8) & !7)
+        };
+
+        if mode & 0o170000 != 0o160000 {
+            ids.push(id);
+        }
+    }
+
+    // extensions run up to the trailing checksum
+    let end = bytes.len() - 20;
+    let mut shared_index = None;
+    let mut unknown_extension = None;
+    while offset + 8 <= end {
+        let signature = bytes.get(offset..offset + 4)?;
+        let size = u32_at(offset + 4)? as usize;
+        let data = bytes.get(offset + 8..(offset + 8).checked_add(size)?)?;
+        match signature {
+            b"TREE" => read_cache_tree(data, &mut ids)?,
+            b"REUC" => read_resolve_undo(data, &mut ids)?,
+
+            // the id of the shared index, then bitmaps of the entries it has that
+            // this one deletes or replaces
+            b"link" => shared_index = Some(Id::new(data.get(0..20)?)).filter(|id| *id != Id::default()),
+
+            // sparse directory entries are trees, and are already in `ids`
+            b"sdir" => (),
+
+            _ if signature[0].is_ascii_lowercase() => {
+                unknown_extension.get_or_insert_with(|| String::from_utf8_lossy(signature).into_owned());
+            },
+            _ => ()
+        }
+        offset += 8 + size;
+    }
+
+    Some(IndexFile { ids, shared_index, unknown_extension })
+}
+
+// Each resolve-undo record is a path, NUL, then the octal modes of the three
+// conflict stages (each NUL-terminated), then the id of each stage whose mode
+// isn't zero.
+fn read_resolve_undo(mut data: &[u8], ids: &mut Vec<Id>) -> Option<()> {
+    while !data.is_empty() {
+        let path_len = data.iter().position(|byte| *byte == 0)?;
+        data = &data[path_len + 1..];
+
+        let mut stages = 0;
+        for _ in 0..3 {
+            let mode_len = data.iter().position(|byte| *byte == 0)?;
+            let mode = u32::from_str_radix(std::str::from_utf8(&data[..mode_len]).ok()?, 8).ok()?;
+            if mode != 0 {
+                stages += 1;
+            }
+            data = &data[mode_len + 1..];
+        }
+
+        for _ in 0..stages {
+            ids.push(Id::new(data.get(0..20)?));
+            data = &data[20..];
+        }
+    }
+
+    Some(())
+}
+
+// Each cached tree is its path, NUL, its entry count (-1 if it's invalid),
+// a space, its subtree count, a newline and, if it's valid, its id.
+fn read_cache_tree(mut data: &[u8], ids: &mut Vec<Id>) -> Option<()> {
+    while !data.is_empty() {
+        let newline = data.iter().position(|byte| *byte == b'\n')?;
+        let header = std::str::from_utf8(&data[..newline]).ok()?;
+        let (_, counts) = header.split_once('\0')?;
+        let (entries, _) = counts.split_once(' ')?;
+        data = &data[newline + 1..];
+
+        if !entries.starts_with('-') {
+            ids.push(Id::new(data.get(0..20)?));
+            data = &data[20..];
+        }
+    }
+
+    Some(())
+}
+
+// Write `objects` to a new pack in `pack_dir`, returning the path of its `.pack`.
+// Given `mtimes`, it's written as a cruft pack.
+fn write_pack(
+    storage_set: &StorageSet<Store>,
+    pack_dir: &Path,
+    objects: &[(Id, u32)],
+    options: window::Options,
+    mtimes: Option<&HashMap<Id, SystemTime>>
+) -> Result<PathBuf> {
+    let pack_tmp = pack_dir.join(format!("tmp_pack_{}", std::process::id()));
+    let index_tmp = pack_dir.join(format!("tmp_idx_{}", std::process::id()));
+    let mtimes_tmp = pack_dir.join(format!("tmp_mtimes_{}", std::process::id()));
+
+    let result = (|| {
+        let pack = std::fs::File::create(&pack_tmp).in_file(&pack_tmp)?;
+        let index = std::fs::File::create(&index_tmp).in_file(&index_tmp)?;
+        let mut pack_writer = BufWriter::new(&pack);
+        let mut index_writer = BufWriter::new(&index);
+
+        let checksum = write_with_deltas(storage_set, objects, options, &mut pack_writer, &mut index_writer)?;
+        pack_writer.flush().in_file(&pack_tmp)?;
+        index_writer.flush().in_file(&index_tmp)?;
+        drop((pack_writer, index_writer));
+        pack.sync_all().in_file(&pack_tmp)?;
+        index.sync_all().in_file(&index_tmp)?;
+
+        if let Some(mtimes) = mtimes {
+            let index = read_packidx(BufReader::new(std::fs::File::open(&index_tmp)?)).in_file(&index_tmp)?;
+            let seconds: Vec<u32> = index.ids().iter().map(|id| {
+                let mtime = mtimes.get(id).and_then(|mtime| mtime.duration_since(SystemTime::UNIX_EPOCH).ok());
+                mtime.map(|mtime| mtime.as_secs().min(u64::from(u32::MAX)) as u32).unwrap_or(0)
+            }).collect();
+
+            let file = std::fs::File::create(&mtimes_tmp).in_file(&mtimes_tmp)?;
+            let mut writer = BufWriter::new(&file);
+            mtimes::write(&index, &seconds, &mut writer).in_file(&mtimes_tmp)?;
+            writer.flush().in_file(&mtimes_tmp)?;
+            drop(writer);
+            file.sync_all().in_file(&mtimes_tmp)?;
+        }
+        Ok(checksum)
+    })();
+
+    let checksum = match result {
+        Ok(xs) => xs,
+        Err(e) => {
+            let _ = std::fs::remove_file(&pack_tmp);
+            let _ = std::fs::remove_file(&index_tmp);
+            let _ = std::fs::remove_file(&mtimes_tmp);
+            return Err(e)
+        }
+    };
+
+    // the index goes last: a pack isn't visible until it has one
+    let pack_path = pack_dir.join(format!("pack-{}.pack", checksum));
+    let index_path = pack_path.with_extension("idx");
+    let mtimes_path = pack_path.with_extension("mtimes");
+    std::fs::rename(&pack_tmp, &pack_path).in_file(&pack_path)?;
+    if mtimes.is_some() {
+        std::fs::rename(&mtimes_tmp, &mtimes_path).in_file(&mtimes_path)?;
+    } else {
+        // an identical pack may already be here as a cruft pack
+        match std::fs::remove_file(&mtimes_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::from(e).in_file(&mtimes_path)),
+            _ => ()
+        }
+    }
+    std::fs::rename(&index_tmp, &index_path).in_file(&index_path)?;
+    Ok(pack_path)
+}
+
+// Remove the pack whose index is `index_path`, index first so that readers stop
+// using it before the packfile goes away.
+fn remove_pack(index_path: &Path) -> Result<()> {
+    std::fs::remove_file(index_path).in_file(index_path)?;
+    for extension in PACK_EXTENSIONS.iter().filter(|extension| **extension != "idx" && **extension != "keep") {
+        let path = index_path.with_extension(extension);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::from(e).in_file(&path)),
+            _ => ()
+        }
+    }
+    Ok(())
+}
+
+// Write `id` out as a loose object whose modification time is `mtime`.
+fn write_loose(storage_set: &StorageSet<Store>, objects: &Path, id: &Id, mtime: SystemTime) -> Result<()> {
+    let mut data = Vec::new();
+    let kind = storage_set.get(id, &mut data)?.ok_or(ErrorKind::ObjectNotFound)?;
+
+    let path = loose_path(objects, id);
+    let dir = path.parent().unwrap_or(objects);
+    std::fs::create_dir_all(dir).in_file(dir)?;
+
+    let tmp = dir.join(format!("tmp_obj_{}", std::process::id()));
+    let result = (|| {
+        let file = std::fs::File::create(&tmp)?;
+        let mut encoder = ZlibEncoder::new(BufWriter::new(&file), Compression::default());
+        write!(encoder, "{} {}\0", kind.as_str(), data.len())?;
+        encoder.write_all(&data)?;
+        encoder.finish()?.flush()?;
+        file.set_modified(mtime)?;
+        file.sync_all()
+    })();
+
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(Error::from(e).in_file(&tmp))
+    }
+
+    std::fs::rename(&tmp, &path).in_file(&path)
+}
+
+// List the id and path of every loose object under `objects`.
+fn loose_objects(objects: &Path) -> Result<Vec<(Id, PathBuf)>> {
+    let mut loose = Vec::new();
+    for fanout in 0..=255u8 {
+        let dir = objects.join(format!("{:02x}", fanout));
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(xs) => xs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(Error::from(e).in_file(&dir))
+        };
+
+        for entry in entries {
+            let path = entry.in_file(&dir)?.path();
+            let id = path.file_name()
+                .and_then(|name| name.to_str())
+                .filter(|name| name.len() == 38)
+                .and_then(|name| Id::from_str(&format!("{:02x}{}", fanout, name)).ok());
+
+            if let Some(id) = id {
+                loose.push((id, path));
+            }
+        }
+    }
+
+    Ok(loose)
+}
+
+// Remove the loose objects for which `packed` is true (as `git prune-packed`
+// does), along with any fanout directories that leaves empty. Returns the
+// number of objects removed.
+fn remove_packed_loose<F: Fn(&Id) -> bool>(objects: &Path, packed: F) -> Result<usize> {
+    let mut removed = 0;
+    for (id, path) in loose_objects(objects)? {
+        if packed(&id) {
+            std::fs::remove_file(&path).in_file(&path)?;
+            removed += 1;
+        }
+    }
+
+    for fanout in 0..=255u8 {
+        // only succeeds if nothing is left in it
+        let _ = std::fs::remove_dir(objects.join(format!("{:02x}", fanout)));
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::{ Path, PathBuf };
+    use std::str::FromStr;
+    use std::time::{ Duration, SystemTime };
+
+    use crate::test_support::{ commit_file, git, git_succeeds, git_with_input, scratch_repo, Scratch };
+    use crate::stores::fs::{ loose_path, objects_dir, pack_dir };
+    use crate::id::Id;
+    use super::Expire;
+
+    fn index_paths(root: &Path) -> Vec<PathBuf> {
+        super::index_paths(&pack_dir(root)).unwrap()
+    }
+
+    fn rev_parse(root: &Path, name: &str) -> Id {
+        Id::from_str(git(root, &["rev-parse", name]).trim()).unwrap()
+    }
+
+    // A repository with a kept pack, a pack holding an unreachable commit, and
+    // loose objects (one of them unreachable.) Returns the unreachable ids.
+    fn messy_repo(name: &str) -> (Scratch, Id, Id) {
+        let root = scratch_repo(name);
+        commit_file(&root, "a", "first", 1_600_000_000);
+        git(&root, &["repack", "-adq"]);
+        let kept = index_paths(&root).remove(0);
+        std::fs::write(kept.with_extension("keep"), "").unwrap();
+
+        git(&root, &["checkout", "-q", "-b", "side"]);
+        commit_file(&root, "side", "unreachable", 1_600_000_100);
+        let side = rev_parse(&root, "HEAD");
+        git(&root, &["checkout", "-q", "main"]);
+        git(&root, &["repack", "-dq"]);
+        git(&root, &["branch", "-D", "side"]);
+
+        commit_file(&root, "b/c", "second", 1_600_000_200);
+        git(&root, &["tag", "-a", "-m", "tag", "v1"]);
+        commit_file(&root, "b/d", "third", 1_600_000_300);
+        // (git runs without stdin here, so this is the empty blob)
+        let dangling = Id::from_str(git(&root, &["hash-object", "-w", "--stdin"]).trim()).unwrap();
+
+        // the side branch is still in HEAD's reflog
+        git(&root, &["reflog", "expire", "--expire=now", "--all"]);
+        (root, side, dangling)
+    }
+
+    #[test]
+    fn repacks_reachable_objects() {
+        let (root, side, dangling) = messy_repo("gc-repack");
+        let reachable: HashSet<String> = git(&root, &["rev-list", "--objects", "--all"]).lines().map(|line| {
+            line[..40].to_string()
+        }).collect();
+        let kept = index_paths(&root).into_iter().find(|path| path.with_extension("keep").exists()).unwrap();
+
+        let repacked = super::repack(&root, &Default::default()).expect("failed to repack");
+        let pack = repacked.pack().expect("wrote a pack").to_path_buf();
+        assert_eq!(repacked.removed_packs().len(), 1);
+        assert!(repacked.loosened() >= 3);
+        assert!(repacked.removed_loose() > 0);
+
+        let mut remaining = index_paths(&root);
+        remaining.sort();
+        let mut expected = vec![kept.clone(), pack.with_extension("idx")];
+        expected.sort();
+        assert_eq!(remaining, expected);
+
+        // everything reachable is packed, once, and git agrees the repository is whole
+        let packed: HashSet<String> = super::read_index_ids(&pack.with_extension("idx")).unwrap().iter().map(|id| id.to_string()).collect();
+        let kept_ids: HashSet<String> = super::read_index_ids(&kept).unwrap().iter().map(|id| id.to_string()).collect();
+        assert!(packed.is_disjoint(&kept_ids));
+        assert_eq!(packed.union(&kept_ids).cloned().collect::<HashSet<_>>(), reachable);
+        git(&root, &["fsck", "--full"]);
+
+        // unreachable objects are loose, and nothing else is
+        let objects = objects_dir(&root);
+        assert!(loose_path(&objects, &side).exists());
+        let loose: Vec<String> = git(&root, &["count-objects", "-v"]).lines().filter(|line| line.starts_with("count:")).map(String::from).collect();
+        assert_eq!(loose, vec![format!("count: {}", repacked.loosened() + 1)]);
+        assert!(loose_path(&objects, &dangling).exists());
+    }
+
+    #[test]
+    fn drops_unreachable_objects_if_asked() {
+        let (root, side, _) = messy_repo("gc-repack-drop");
+        let options = super::RepackOptions { keep_unreachable: false, ..Default::default() };
+        let repacked = super::repack(&root, &options).expect("failed to repack");
+        assert_eq!(repacked.loosened(), 0);
+        assert!(!loose_path(&objects_dir(&root), &side).exists());
+        git(&root, &["fsck", "--full"]);
+
+        // repacking again writes the same pack and removes nothing
+        let again = super::repack(&root, &options).expect("failed to repack");
+        assert_eq!(again.pack(), repacked.pack());
+        assert!(again.removed_packs().is_empty());
+        git(&root, &["fsck", "--full"]);
+    }
+
+    #[test]
+    fn repacks_objects_only_reflogs_indexes_and_worktrees_refer_to() {
+        let root = scratch_repo("gc-repack-other-tips");
+        commit_file(&root, "a", "first", 1_600_000_000);
+        commit_file(&root, "b", "undone", 1_600_000_100);
+        let undone = rev_parse(&root, "HEAD");
+        git(&root, &["reset", "-q", "--hard", "HEAD~1"]);
+
+        std::fs::write(root.join("staged"), "staged").unwrap();
+        git(&root, &["add", "staged"]);
+        let staged = rev_parse(&root, ":staged");
+
+        let worktree = root.join("wt");
+        git(&root, &["worktree", "add", "-q", "--detach", "wt"]);
+        commit_file(&worktree, "c", "detached", 1_600_000_200);
+        let detached = rev_parse(&worktree, "HEAD");
+        std::fs::write(worktree.join("staged"), "staged in the worktree").unwrap();
+        git(&worktree, &["add", "staged"]);
+        let worktree_staged = rev_parse(&worktree, ":staged");
+
+        let options = super::RepackOptions { keep_unreachable: false, ..Default::default() };
+        let repacked = super::repack(&root, &options).expect("failed to repack");
+        let packed = super::read_index_ids(&repacked.pack().unwrap().with_extension("idx")).unwrap();
+        for id in [&undone, &staged, &detached, &worktree_staged] {
+            assert!(packed.contains(id));
+        }
+        assert_eq!(super::loose_objects(&objects_dir(&root)).unwrap().len(), 0);
+        git(&root, &["fsck", "--full"]);
+    }
+
+    #[test]
+    fn auto_limits_match_git() {
+        let root = scratch_repo("gc-auto");
+        commit_file(&root, "a", "first", 1_600_000_000);
+        git(&root, &["repack", "-dq"]);
+        commit_file(&root, "b", "second", 1_600_000_100);
+        git(&root, &["repack", "-dq"]);
+
+        let limits = |loose_objects, packs| super::AutoLimits { loose_objects, packs };
+        assert!(!super::needs_gc(&root, &limits(0, 2)).unwrap());
+        assert!(super::needs_gc(&root, &limits(0, 1)).unwrap());
+
+        // loose objects are estimated from the 17/ directory
+        let fanout = objects_dir(&root).join("17");
+        std::fs::create_dir_all(&fanout).unwrap();
+        std::fs::write(fanout.join("0".repeat(38)), "").unwrap();
+        assert!(!super::needs_gc(&root, &limits(1, 0)).unwrap());
+        std::fs::write(fanout.join("1".repeat(38)), "").unwrap();
+        assert!(super::needs_gc(&root, &limits(1, 0)).unwrap());
+        assert!(!super::needs_gc(&root, &limits(257, 0)).unwrap());
+
+        let options = super::GcOptions { auto: Some(limits(0, 2)), ..Default::default() };
+        assert!(super::gc(&root, &options).unwrap().is_none());
+    }
+
+    fn set_mtime(root: &Path, id: &Id, mtime: SystemTime) {
+        let file = std::fs::File::options().write(true).open(loose_path(&objects_dir(root), id)).unwrap();
+        file.set_modified(mtime).unwrap();
+    }
+
+    #[test]
+    fn parses_expiry_dates() {
+        let days = |count: u64| Expire::After(Duration::from_secs(count * 24 * 60 * 60));
+        assert_eq!("2.weeks.ago".parse::<Expire>().unwrap(), days(14));
+        assert_eq!("3 days ago".parse::<Expire>().unwrap(), days(3));
+        assert_eq!("1.hour".parse::<Expire>().unwrap(), Expire::After(Duration::from_secs(60 * 60)));
+        assert_eq!("now".parse::<Expire>().unwrap(), Expire::After(Duration::from_secs(0)));
+        assert_eq!("never".parse::<Expire>().unwrap(), Expire::Never);
+        assert_eq!(Expire::default(), days(14));
+        assert!("soon".parse::<Expire>().is_err());
+        assert!("2.fortnights.ago".parse::<Expire>().is_err());
+    }
+
+    #[test]
+    fn prunes_what_git_would() {
+        let root = scratch_repo("gc-prune");
+        commit_file(&root, "a", "first", 1_600_000_000);
+        commit_file(&root, "b", "second", 1_600_000_100);
+
+        let hash = |contents: &str| Id::from_str(&git_with_input(&root, &["hash-object", "-w", "--stdin"], contents)).unwrap();
+        let old_blob = hash("old and unreachable");
+        let recent_blob = hash("recent and unreachable");
+
+        // only in the index
+        std::fs::write(root.join("staged"), "staged").unwrap();
+        git(&root, &["add", "staged"]);
+        let staged = hash("staged");
+
+        // only in the reflog
+        commit_file(&root, "c", "undone", 1_600_000_200);
+        let undone = rev_parse(&root, "HEAD");
+        git(&root, &["reset", "-q", "--soft", "HEAD~1"]);
+        git(&root, &["rm", "-q", "--cached", "c"]);
+
+        // old, but reachable from a recent unreachable commit
+        let base = hash("kept by a recent commit");
+        let tree = Id::from_str(&git_with_input(&root, &["mktree"], &format!("100644 blob {}\tf\n", base))).unwrap();
+        let recent_commit = Id::from_str(&git_with_input(&root, &["commit-tree", &tree.to_string(), "-m", "recent"], "")).unwrap();
+
+        let month_ago = SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+        for (id, _) in super::loose_objects(&objects_dir(&root)).unwrap() {
+            set_mtime(&root, &id, month_ago);
+        }
+        set_mtime(&root, &recent_blob, SystemTime::now());
+        set_mtime(&root, &recent_commit, SystemTime::now());
+
+        let options = super::PruneOptions { dry_run: true, ..Default::default() };
+        let dry_run = super::prune(&root, &options).expect("failed to prune");
+        let mut ours: Vec<String> = dry_run.objects().iter().map(|(id, kind, _)| {
+            format!("{} {}", id, kind.map(|kind| kind.as_str()).unwrap_or("unknown"))
+        }).collect();
+        ours.sort();
+        let mut theirs: Vec<String> = git(&root, &["prune", "-n", "--expire=2.weeks.ago"]).lines().map(String::from).collect();
+        theirs.sort();
+        assert_eq!(ours, theirs);
+        assert_eq!(ours, vec![format!("{} blob", old_blob)]);
+        assert!(loose_path(&objects_dir(&root), &old_blob).exists());
+
+        let pruned = super::prune(&root, &Default::default()).expect("failed to prune");
+        assert_eq!(pruned.objects().len(), 1);
+        for id in [&recent_blob, &staged, &undone, &base, &tree, &recent_commit].iter() {
+            assert!(loose_path(&objects_dir(&root), id).exists());
+        }
+        assert!(!loose_path(&objects_dir(&root), &old_blob).exists());
+        git(&root, &["fsck", "--full"]);
+
+        // once everything has expired, only the unreachable objects go
+        let now = super::PruneOptions { expire: Expire::After(Duration::from_secs(0)), dry_run: false };
+        let pruned = super::prune(&root, &now).expect("failed to prune");
+        let mut pruned: Vec<&Id> = pruned.objects().iter().map(|(id, _, _)| id).collect();
+        pruned.sort();
+        let mut expected = vec![&recent_blob, &base, &tree, &recent_commit];
+        expected.sort();
+        assert_eq!(pruned, expected);
+        git(&root, &["fsck", "--full"]);
+    }
+
+    #[test]
+    fn reads_split_indexes() {
+        let root = scratch_repo("gc-split-index");
+
+        // files as new as the index would be racily clean, and copied into the split index
+        let write_old = |name: &str, contents: &str| {
+            std::fs::write(root.join(name), contents).unwrap();
+            let file = std::fs::File::options().write(true).open(root.join(name)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        };
+        write_old("a", "committed");
+        git(&root, &["add", "a"]);
+        git(&root, &["commit", "-q", "-m", "a"]);
+        write_old("staged", "staged");
+        git(&root, &["add", "staged"]);
+        git(&root, &["update-index", "--split-index"]);
+        write_old("more", "staged after splitting");
+        git(&root, &["-c", "splitIndex.maxPercentChange=100", "add", "more"]);
+
+        // most entries are only in the shared index
+        let index_path = root.join(".git/index");
+        let split = super::read_index_file(&std::fs::read(&index_path).unwrap()).unwrap();
+        assert!(split.shared_index.is_some());
+        assert!(!split.ids.contains(&rev_parse(&root, ":a")));
+
+        let ids = super::index_ids(&index_path).expect("failed to read index");
+        for name in [":a", ":staged", ":more"] {
+            assert!(ids.contains(&rev_parse(&root, name)));
+        }
+
+        // an index can't be read if it needs an extension we don't know
+        git(&root, &["update-index", "--no-split-index"]);
+        let mut bytes = std::fs::read(&index_path).unwrap();
+        let trailer = bytes.len() - 20;
+        bytes.splice(trailer..trailer, b"xtra\0\0\0\0".iter().cloned());
+        std::fs::write(&index_path, &bytes).unwrap();
+        let error = super::index_ids(&index_path).expect_err("the extension is unknown");
+        assert_eq!(error.kind(), &crate::errors::ErrorKind::UnsupportedIndexExtension("xtra".into()));
+    }
+
+    fn seconds(time: SystemTime) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    fn has_object(root: &Path, id: &Id) -> bool {
+        git_succeeds(root, &["cat-file", "-e", &id.to_string()])
+    }
+
+    // A messy repository whose unreachable objects were written a month ago,
+    // along with a recent unreachable commit on top of the unreachable side
+    // branch. Returns the side branch, the dangling blob and the recent commit.
+    fn aged_repo(name: &str) -> (Scratch, Id, Id, Id) {
+        let (root, side, dangling) = messy_repo(name);
+        let month_ago = SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+        for index_path in index_paths(&root) {
+            let file = std::fs::File::options().write(true).open(index_path.with_extension("pack")).unwrap();
+            file.set_modified(month_ago).unwrap();
+        }
+        set_mtime(&root, &dangling, month_ago);
+
+        let tree = rev_parse(&root, "HEAD^{tree}").to_string();
+        let recent = git_with_input(&root, &["commit-tree", &tree, "-p", &side.to_string(), "-m", "recent"], "");
+        (root, side, dangling, Id::from_str(&recent).unwrap())
+    }
+
+    #[test]
+    fn writes_cruft_packs_git_can_read() {
+        let (root, side, dangling, recent) = aged_repo("gc-cruft");
+        let month_ago = seconds(std::fs::metadata(loose_path(&objects_dir(&root), &dangling)).unwrap().modified().unwrap());
+
+        let options = super::RepackOptions { cruft: Some(Expire::Never), ..Default::default() };
+        let repacked = super::repack(&root, &options).expect("failed to repack");
+        let cruft = repacked.cruft_pack().expect("there are unreachable objects");
+        assert!(cruft.with_extension("mtimes").exists());
+        assert_eq!(repacked.expired(), 0);
+        assert_eq!(repacked.loosened(), 0);
+
+        let objects = |args: &[&str]| -> HashSet<Id> {
+            git(&root, args).lines().map(|line| Id::from_str(&line[..40]).unwrap()).collect()
+        };
+        let mut unreachable: HashSet<Id> = objects(&["rev-list", "--objects", &recent.to_string()])
+            .difference(&objects(&["rev-list", "--objects", "--all"]))
+            .cloned()
+            .collect();
+        unreachable.insert(dangling.clone());
+        let crufted: HashSet<Id> = super::read_index_ids(&cruft.with_extension("idx")).unwrap().into_iter().collect();
+        assert_eq!(crufted, unreachable);
+        assert_eq!(repacked.crufted(), unreachable.len());
+        assert!(!loose_path(&objects_dir(&root), &dangling).exists());
+        assert!(!loose_path(&objects_dir(&root), &recent).exists());
+        git(&root, &["fsck", "--full"]);
+
+        let storage_set = root.storage();
+        let packs = storage_set.backend().packs();
+        assert!(packs.has_cruft_packs());
+        assert_eq!(packs.mtime(&dangling), Some(month_ago));
+        assert_eq!(packs.mtime(&side), Some(month_ago));
+        assert!(packs.mtime(&recent).unwrap() > month_ago);
+        drop(storage_set);
+
+        // git expires the objects we recorded as old, and reads back the rest
+        git(&root, &["repack", "--cruft", "-dq", "--cruft-expiration=2.weeks.ago"]);
+        assert!(!has_object(&root, &dangling));
+        assert!(has_object(&root, &recent));
+        assert!(has_object(&root, &side));
+
+        let storage_set = root.storage();
+        assert!(storage_set.backend().packs().mtime(&recent).unwrap() > month_ago);
+    }
+
+    #[test]
+    fn cruft_packs_expire_unreachable_objects() {
+        let (root, side, dangling, recent) = aged_repo("gc-cruft-expire");
+
+        // old objects that only a reflog and the index refer to
+        commit_file(&root, "undone", "undone", 1_600_000_400);
+        let undone = rev_parse(&root, "HEAD");
+        git(&root, &["reset", "-q", "--hard", "HEAD~1"]);
+        std::fs::write(root.join("staged"), "staged").unwrap();
+        git(&root, &["add", "staged"]);
+        let staged = rev_parse(&root, ":staged");
+        let month_ago = SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+        for (id, _) in super::loose_objects(&objects_dir(&root)).unwrap() {
+            if id != recent {
+                set_mtime(&root, &id, month_ago);
+            }
+        }
+
+        let options = super::RepackOptions { cruft: Some(Expire::default()), ..Default::default() };
+        let repacked = super::repack(&root, &options).expect("failed to repack");
+
+        // the old side branch is kept for the recent commit on top of it; the
+        // expired loose blob is left for prune
+        let cruft = repacked.cruft_pack().expect("there are recent unreachable objects");
+        let crufted = super::read_index_ids(&cruft.with_extension("idx")).unwrap();
+        assert!(crufted.contains(&recent));
+        assert!(crufted.contains(&side));
+        assert!(!crufted.contains(&dangling));
+        assert_eq!(repacked.expired(), 1);
+        assert!(loose_path(&objects_dir(&root), &dangling).exists());
+
+        let pruned = super::prune(&root, &Default::default()).expect("failed to prune");
+        assert_eq!(pruned.objects().len(), 1);
+        assert_eq!(pruned.objects()[0].0, dangling);
+        git(&root, &["fsck", "--full"]);
+
+        // repacking again leaves the cruft pack as it was
+        let again = super::repack(&root, &options).expect("failed to repack");
+        assert_eq!(again.cruft_pack(), repacked.cruft_pack());
+        assert_eq!(again.expired(), 0);
+        assert!(again.removed_packs().is_empty());
+
+        // once nothing is recent, it all goes
+        let now = super::RepackOptions { cruft: Some(Expire::After(Duration::from_secs(0))), ..Default::default() };
+        let expired = super::repack(&root, &now).expect("failed to repack");
+        assert!(expired.cruft_pack().is_none());
+        assert_eq!(expired.expired(), crufted.len());
+        assert!(!has_object(&root, &recent));
+        assert!(!has_object(&root, &side));
+        assert!(has_object(&root, &undone));
+        assert!(has_object(&root, &staged));
+        git(&root, &["fsck", "--full"]);
+    }
+}
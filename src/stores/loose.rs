@@ -1,9 +1,10 @@
 use flate2::bufread::ZlibDecoder;
 use std::io::prelude::*;
 use std::io::{ BufReader };
+use std::sync::RwLock;
 
 use crate::stores::{ Queryable, StorageSet };
-use crate::errors::{ Result, ErrorKind };
+use crate::errors::{ Error, ErrorKind, Result };
 use crate::objects::Type;
 use crate::id::Id;
 
@@ -11,7 +12,7 @@ type Reader = dyn Fn(&Id) -> Result<Option<Box<dyn std::io::Read>>> + Send + Syn
 
 pub struct Store {
     read: Box<Reader>,
-    filter: [bool; 256]
+    filter: RwLock<[bool; 256]>
 }
 
 impl Store {
@@ -21,14 +22,23 @@ impl Store {
 
         Store {
             read: Box::new(func),
-            filter
+            filter: RwLock::new(filter)
         }
     }
+
+    /// Replace the filter of first id bytes that may have objects (for example,
+    /// after rescanning the fan-out directories.) Returns `true` if it changed.
+    pub fn set_filter(&self, filter: [bool; 256]) -> bool {
+        let mut current = self.filter.write().unwrap_or_else(|e| e.into_inner());
+        let changed = *current != filter;
+        *current = filter;
+        changed
+    }
 }
 
 impl Queryable for Store {
-    fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, _: &StorageSet<S>) -> Result<Option<Type>> {
-        if !self.filter[id.as_ref()[0] as usize] {
+    fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>> {
+        if !self.filter.read().unwrap_or_else(|e| e.into_inner())[id.as_ref()[0] as usize] {
             return Ok(None)
         }
 
@@ -41,32 +51,63 @@ impl Queryable for Store {
             ZlibDecoder::new(BufReader::new(maybe_reader.unwrap()))
         );
 
-        let mut type_vec = Vec::new();
-        let mut size_vec = Vec::new();
+        let (loaded_type, expected) = read_header(&mut reader)?;
 
-        reader.read_until(0x20, &mut type_vec)?;
-        reader.read_until(0, &mut size_vec)?;
+        // the declared size is held to any limit, and the data to it
+        backends.limits().check_object_size(expected)?;
 
-        let loaded_type = match &type_vec[..] {
-            b"commit " => Type::Commit,
-            b"blob " => Type::Blob,
-            b"tree " => Type::Tree,
-            b"tag " => Type::Tag,
-            &_ => return Err(ErrorKind::BadLooseObject.into())
-        };
+        backends.read_checked(id, output, |output| {
+            let actual = std::io::copy(&mut reader.by_ref().take(expected.saturating_add(1)), output).map_err(inflate_error)?;
+            if actual != expected {
+                return Err(ErrorKind::ObjectSizeMismatch { expected, actual }.into())
+            }
+
+            Ok(Some(loaded_type))
+        })
+    }
+}
 
-        std::io::copy(&mut reader, output)?;
-        Ok(Some(loaded_type))
+// Longer than any header git writes: "commit " and a twenty digit size.
+const MAX_HEADER_LEN: u64 = 32;
+
+/// Read the `<type> <size>\0` header from the start of an inflated loose object,
+/// giving up if it runs past `MAX_HEADER_LEN` bytes.
+pub(crate) fn read_header<R: BufRead>(reader: &mut R) -> Result<(Type, u64)> {
+    let mut header = Vec::new();
+    reader.by_ref().take(MAX_HEADER_LEN).read_until(0, &mut header).map_err(inflate_error)?;
+    if header.pop() != Some(0) {
+        return Err(ErrorKind::BadLooseObject.into())
     }
+
+    let space = header.iter().position(|byte| *byte == b' ').ok_or(ErrorKind::BadLooseObject)?;
+    let kind = match &header[..space] {
+        b"commit" => Type::Commit,
+        b"blob" => Type::Blob,
+        b"tree" => Type::Tree,
+        b"tag" => Type::Tag,
+        _ => return Err(ErrorKind::BadLooseObject.into())
+    };
+
+    let size = std::str::from_utf8(&header[space + 1..]).ok()
+        .and_then(|size| size.parse::<u64>().ok())
+        .ok_or(ErrorKind::BadLooseObject)?;
+    Ok((kind, size))
+}
+
+fn inflate_error(e: std::io::Error) -> Error {
+    Error::inflate(ErrorKind::BadLooseObject, e)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::stores::{ Queryable, StorageSet };
+    use crate::stores::{ Limits, Queryable, StorageSet };
     use crate::objects::Object;
     use crate::id::Id;
 
-    use std::io::Cursor;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::{ Cursor, Write };
+    use std::str::FromStr;
     use super::{ Store, ErrorKind };
 
     #[test]
@@ -123,10 +164,80 @@ mod tests {
 
         match store.get(&Id::default(), &mut vec![], &storage_set) {
             Ok(_) => panic!("expected failure!"),
-            Err(e) => assert_eq!(e.description(), "BadLooseObject")
+            Err(e) => assert_eq!(e.kind(), &ErrorKind::BadLooseObject)
         };
     }
 
+    #[test]
+    fn verifies_contents_and_sizes() {
+        let store = Store::new(|_| Ok(Some(Box::new(include_bytes!("../../fixtures/loose_commit") as &[u8]))), None);
+        let storage_set = StorageSet::new(()).with_verification();
+
+        let id = Id::from_str("0e876bad77dfebf1453c338598068484414939f1").unwrap();
+        assert!(store.get(&id, &mut vec![], &storage_set).expect("the object is fine").is_some());
+
+        let error = store.get(&Id::default(), &mut vec![], &storage_set).expect_err("the object has another id");
+        assert_eq!(error.kind(), &ErrorKind::ObjectHashMismatch(id));
+        assert!(error.is_corrupt());
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blob 5\0abc").unwrap();
+        let bytes = encoder.finish().unwrap();
+        let store = Store::new(move |_| Ok(Some(Box::new(Cursor::new(bytes.clone())))), None);
+
+        // the size is checked whether or not the contents are
+        for storage_set in [StorageSet::new(()), storage_set].iter() {
+            let error = store.get(&Id::default(), &mut vec![], storage_set).expect_err("the header has the wrong size");
+            assert_eq!(error.kind(), &ErrorKind::ObjectSizeMismatch { expected: 5, actual: 3 });
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blob five\0abc").unwrap();
+        let bytes = encoder.finish().unwrap();
+        let store = Store::new(move |_| Ok(Some(Box::new(Cursor::new(bytes.clone())))), None);
+        let error = store.get(&Id::default(), &mut vec![], &StorageSet::new(())).expect_err("the header has no size");
+        assert_eq!(error.kind(), &ErrorKind::BadLooseObject);
+    }
+
+    #[test]
+    fn refuses_objects_over_the_limit() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blob 3\0abc").unwrap();
+        encoder.write_all(&vec![0u8; 1 << 20]).unwrap();
+        let bytes = encoder.finish().unwrap();
+        let store = Store::new(move |_| Ok(Some(Box::new(Cursor::new(bytes.clone())))), None);
+
+        let storage_set = StorageSet::new(()).with_limits(Limits { object_size: Some(2), ..Limits::default() });
+        let error = store.get(&Id::default(), &mut vec![], &storage_set).expect_err("the object is over the limit");
+        assert_eq!(error.kind(), &ErrorKind::SizeLimitExceeded { size: 3, limit: 2 });
+
+        // the declared size is held to the data
+        let storage_set = StorageSet::new(()).with_limits(Limits { object_size: Some(100), ..Limits::default() });
+        let mut output = vec![];
+        let error = store.get(&Id::default(), &mut output, &storage_set).expect_err("the object is bigger than it says");
+        assert_eq!(error.kind(), &ErrorKind::ObjectSizeMismatch { expected: 3, actual: 4 });
+    }
+
+    #[test]
+    fn refuses_headers_without_an_end() {
+        // a bomb with no space or NUL in it is only inflated as far as a header could go
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&vec![b'a'; 1 << 24]).unwrap();
+        let bytes = encoder.finish().unwrap();
+        let store = Store::new(move |_| Ok(Some(Box::new(Cursor::new(bytes.clone())))), None);
+
+        let storage_set = StorageSet::new(()).with_limits(Limits { object_size: Some(100), ..Limits::default() });
+        let error = store.get(&Id::default(), &mut vec![], &storage_set).expect_err("the header never ends");
+        assert_eq!(error.kind(), &ErrorKind::BadLooseObject);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blob 3 \0abc").unwrap();
+        let bytes = encoder.finish().unwrap();
+        let store = Store::new(move |_| Ok(Some(Box::new(Cursor::new(bytes.clone())))), None);
+        let error = store.get(&Id::default(), &mut vec![], &StorageSet::new(())).expect_err("the size is malformed");
+        assert_eq!(error.kind(), &ErrorKind::BadLooseObject);
+    }
+
     #[test]
     fn handles_idtoreadable_misses() {
         let store = Store::new(|_| Ok(None), None);
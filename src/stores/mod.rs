@@ -3,17 +3,47 @@ use std::io::Cursor;
 use std::io::Write;
 
 use crate::walk::commits::CommitIterator;
+use crate::commit_graph::CommitGraph;
 use crate::walk::tree::TreeIterator;
 use crate::objects::{Type, Object};
-use crate::errors::Result;
+use crate::pack::StoredEntry;
+use crate::errors::{ ErrorKind, Result, ResultExt };
 use crate::id::Id;
 
 pub mod loose;
 pub mod pack;
 pub mod fs;
+pub mod stats;
+pub mod gc;
 
 pub trait Queryable {
     fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>>;
+
+    /// Return the packfile entry for `id` as it is stored, if this store keeps it
+    /// in a packfile, so that it can be copied into another packfile as-is.
+    fn stored_entry(&self, _id: &Id) -> Result<Option<StoredEntry>> {
+        Ok(None)
+    }
+
+    /// Read each of `ids` this store has, passing them to `each`, and return the
+    /// rest. Stores that can read many objects at once faster than one at a time
+    /// (packs, by reading in offset order) do so, and may read in parallel when
+    /// asked to; `each` is then called from several threads, in no set order.
+    fn get_batch<S, F>(&self, ids: Vec<Id>, _parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<Vec<Id>>
+        where S: Queryable + Sync,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> + Sync {
+        let mut missing = Vec::new();
+        let mut data = Vec::new();
+        for id in ids {
+            data.clear();
+            match self.get(&id, &mut data, backends).for_object(&id)? {
+                Some(kind) => each(&id, kind, &data)?,
+                None => missing.push(id)
+            }
+        }
+
+        Ok(missing)
+    }
 }
 
 impl Queryable for () {
@@ -26,6 +56,16 @@ impl<Q: Queryable> Queryable for (Q,) {
     fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>> {
         self.0.get(id, output, backends)
     }
+
+    fn stored_entry(&self, id: &Id) -> Result<Option<StoredEntry>> {
+        self.0.stored_entry(id)
+    }
+
+    fn get_batch<S, F>(&self, ids: Vec<Id>, parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<Vec<Id>>
+        where S: Queryable + Sync,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> + Sync {
+        self.0.get_batch(ids, parallel, each, backends)
+    }
 }
 
 impl<H: Queryable, T: Queryable> Queryable for (H, T) {
@@ -37,6 +77,26 @@ impl<H: Queryable, T: Queryable> Queryable for (H, T) {
 
         self.1.get(id, output, backends)
     }
+
+    fn stored_entry(&self, id: &Id) -> Result<Option<StoredEntry>> {
+        let opt = self.0.stored_entry(id)?;
+        if opt.is_some() {
+            return Ok(opt)
+        }
+
+        self.1.stored_entry(id)
+    }
+
+    fn get_batch<S, F>(&self, ids: Vec<Id>, parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<Vec<Id>>
+        where S: Queryable + Sync,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> + Sync {
+        let missing = self.0.get_batch(ids, parallel, each, backends)?;
+        if missing.is_empty() {
+            return Ok(missing)
+        }
+
+        self.1.get_batch(missing, parallel, each, backends)
+    }
 }
 
 impl<Q: Queryable> Queryable for Vec<Q> {
@@ -50,28 +110,176 @@ impl<Q: Queryable> Queryable for Vec<Q> {
 
         Ok(None)
     }
+
+    fn stored_entry(&self, id: &Id) -> Result<Option<StoredEntry>> {
+        for queryable in self {
+            let opt = queryable.stored_entry(id)?;
+            if opt.is_some() {
+                return Ok(opt)
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn get_batch<S, F>(&self, mut ids: Vec<Id>, parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<Vec<Id>>
+        where S: Queryable + Sync,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> + Sync {
+        for queryable in self {
+            if ids.is_empty() {
+                break
+            }
+
+            ids = queryable.get_batch(ids, parallel, each, backends)?;
+        }
+
+        Ok(ids)
+    }
+}
+
+/// Limits on the size of the objects read through a storage set, for reading
+/// untrusted data: a few bytes of zlib data or delta instructions can produce
+/// gigabytes of output. Reads that would go over a limit fail with
+/// `ErrorKind::SizeLimitExceeded` before inflating anything. `None` is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The largest object, or packed delta, to inflate (by its declared size.)
+    pub object_size: Option<u64>,
+
+    /// The largest object to build by applying a delta. Objects built from
+    /// deltas are held to `object_size` as well.
+    pub delta_output_size: Option<u64>
+}
+
+impl Limits {
+    pub(crate) fn check_object_size(&self, size: u64) -> Result<()> {
+        check_limit(size, self.object_size)
+    }
+
+    pub(crate) fn check_delta_output_size(&self, size: u64) -> Result<()> {
+        check_limit(size, self.object_size)?;
+        check_limit(size, self.delta_output_size)
+    }
+}
+
+fn check_limit(size: u64, limit: Option<u64>) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(ErrorKind::SizeLimitExceeded { size, limit }.into()),
+        _ => Ok(())
+    }
 }
 
 pub struct StorageSet<Q: Queryable> {
-    backend: Q
+    backend: Q,
+    commit_graph: Option<CommitGraph>,
+    verify: bool,
+    limits: Limits
 }
 
 impl<Q: Queryable> StorageSet<Q> {
     pub fn new(backend: Q) -> StorageSet<Q> {
         StorageSet {
-            backend
+            backend,
+            commit_graph: None,
+            verify: false,
+            limits: Limits::default()
         }
     }
 
+    /// Check every object read through this storage set: its contents must hash to
+    /// the requested id, loose object headers must give the right size, and packed
+    /// objects must match the CRC32 recorded in their pack index. Reads of damaged
+    /// objects fail with an error naming the object and where it is stored.
+    pub fn with_verification(mut self) -> StorageSet<Q> {
+        self.verify = true;
+        self
+    }
+
+    /// Whether stores should check the objects they read (see `with_verification`.)
+    pub fn verifies(&self) -> bool {
+        self.verify
+    }
+
+    /// Refuse to read objects bigger than `limits` allow.
+    pub fn with_limits(mut self, limits: Limits) -> StorageSet<Q> {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Use `commit_graph` to answer questions about commit ancestry (when walking
+    /// commits) without reading commit objects from storage.
+    pub fn with_commit_graph(mut self, commit_graph: CommitGraph) -> StorageSet<Q> {
+        self.commit_graph = Some(commit_graph);
+        self
+    }
+
+    pub fn commit_graph(&self) -> Option<&CommitGraph> {
+        self.commit_graph.as_ref()
+    }
+
+    pub fn backend(&self) -> &Q {
+        &self.backend
+    }
+
     pub fn get<W: Write>(&self, id: &Id, output: &mut W) -> Result<Option<Type>> {
-        self.backend.get(id, output, self)
+        self.backend.get(id, output, self).for_object(id).during("reading object")
+    }
+
+    /// Read many objects at once (like `git cat-file --batch`), passing each one
+    /// found to `each` and returning the ids of those that weren't. Packed
+    /// objects are read a pack at a time, in the order they're stored, so that
+    /// reads move forward through each pack and a delta base shared by several
+    /// objects is only rebuilt once. If `parallel` is set, packs are read at the
+    /// same time on rayon's thread pool.
+    ///
+    /// Objects are passed to `each` in the order they're read rather than the
+    /// order of `ids`. An error from `each` stops the batch.
+    pub fn get_batch<F>(&self, ids: &[Id], parallel: bool, each: F) -> Result<Vec<Id>>
+        where Q: Sync,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> + Sync {
+        self.backend.get_batch(ids.to_vec(), parallel, &each, self).during("reading objects")
+    }
+
+    /// Return the packfile entry for `id` as it is stored, if it is packed (see
+    /// `Queryable::stored_entry`.) Its bytes are checked against the pack index's
+    /// CRC32.
+    pub fn stored_entry(&self, id: &Id) -> Result<Option<StoredEntry>> {
+        self.backend.stored_entry(id).for_object(id).during("reading packfile entry")
+    }
+
+    /// Read the object `id` from `read` and, if this storage set verifies reads,
+    /// check that its contents hash to `id` before passing them on to `output`.
+    pub fn read_checked<W, F>(&self, id: &Id, output: &mut W, read: F) -> Result<Option<Type>>
+        where W: Write,
+              F: FnOnce(&mut dyn Write) -> Result<Option<Type>> {
+        if !self.verify {
+            return read(output)
+        }
+
+        let mut data = Vec::new();
+        let kind = match read(&mut data)? {
+            Some(xs) => xs,
+            None => return Ok(None)
+        };
+
+        let actual = kind.hash(&data);
+        if &actual != id {
+            return Err(ErrorKind::ObjectHashMismatch(actual).into())
+        }
+
+        output.write_all(&data)?;
+        Ok(Some(kind))
     }
 
-    pub fn commits(&self, id: &Id, seen: Option<HashSet<Id>>) -> CommitIterator<Q> {
+    pub fn commits(&self, id: &Id, seen: Option<HashSet<Id>>) -> CommitIterator<'_, Q> {
         CommitIterator::new(self, id, seen)
     }
 
-    pub fn tree(&self, id: &Id) -> TreeIterator<Q> {
+    pub fn tree(&self, id: &Id) -> TreeIterator<'_, Q> {
         let result = match self.get_and_load(id) {
             Ok(xs) => xs,
             Err(_) => return TreeIterator::new(self, vec![])
@@ -84,7 +292,7 @@ impl<Q: Queryable> StorageSet<Q> {
         match result.unwrap() {
             Object::Commit(commit) => {
                 match commit.tree() {
-                    Some(tree) => self.tree(&tree),
+                    Some(tree) => self.tree(tree),
                     None => TreeIterator::new(self, vec![])
                 }
             },
@@ -100,7 +308,7 @@ impl<Q: Queryable> StorageSet<Q> {
     pub fn get_and_load(&self, id: &Id) -> Result<Option<Object>> {
         let mut data = Vec::new();
         match self.get(id, &mut data)? {
-            Some(typ) => Ok(Some(typ.load(&mut Cursor::new(&data))?)),
+            Some(typ) => Ok(Some(typ.load(&mut Cursor::new(&data)).for_object(id).during("parsing object")?)),
             None => Ok(None)
         }
     }
@@ -1,24 +1,98 @@
+use crc::{ Crc, CRC_32_ISO_HDLC };
+use once_cell::sync::OnceCell;
 use std::io::Write;
 
 use crate::stores::{ Queryable, StorageSet };
-use crate::pack::index::Index;
-use crate::errors::Result;
-use crate::pack::Packfile;
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::pack::index::{ Index, DELTA_BASE_CACHE_LIMIT };
+use crate::pack::read::{ packfile_read_header, DeltaBase, DeltaBaseCache };
+use crate::pack::{ Packfile, StoredEntry };
 use crate::objects::Type;
 use crate::id::Id;
 
 pub struct Store<P: Packfile> {
     packfile: P,
-    index: Index
+    index: Index,
+    by_offset: OnceCell<Vec<u32>>
 }
 
 impl<P: Packfile> Store<P> {
     pub fn new (packfile: P, index: Index) -> Self {
         Store {
             packfile,
-            index
+            index,
+            by_offset: OnceCell::new()
         }
     }
+
+    pub fn packfile(&self) -> &P {
+        &self.packfile
+    }
+
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Find the type of the object `id` without inflating it.
+    pub fn object_type<S: Queryable>(&self, id: &Id, backends: &StorageSet<S>) -> Result<Option<Type>> {
+        let (start, _) = match self.index.get_bounds(id) {
+            Some(xs) => xs,
+            None => return Ok(None)
+        };
+
+        Ok(Some(self.packfile.read_type(start, backends).for_object(id).at_offset(start)?))
+    }
+
+    /// Check the stored bytes of the object `id` against the CRC32 recorded for it
+    /// in the index. Version 1 indexes have no CRC32s, so there's nothing to check.
+    pub fn check_crc32(&self, id: &Id) -> Result<()> {
+        self.read_raw(id).map(|_| ())
+    }
+
+    /// The id of the object stored at `offset`, if an object starts there.
+    pub fn id_at_offset(&self, offset: u64) -> Option<&Id> {
+        let by_offset = self.by_offset.get_or_init(|| self.index.positions_by_offset());
+        let offsets = self.index.offsets();
+        let found = by_offset.binary_search_by_key(&offset, |position| offsets[*position as usize]).ok()?;
+        self.index.ids().get(by_offset[found] as usize)
+    }
+
+    /// Read the objects at each offset in `objects` (sorted by offset), passing
+    /// them to `each`. Delta bases shared between them are only rebuilt once;
+    /// `parallel` says whether other batches are being read at the same time.
+    pub fn read_batch<S, F>(&self, objects: &[(u64, Id)], parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<()>
+        where S: Queryable,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> {
+        if backends.verifies() {
+            for (_, id) in objects {
+                self.check_crc32(id)?;
+            }
+        }
+
+        read_sorted(&self.packfile, objects, parallel, each, backends)
+    }
+
+    // Read the stored bytes of `id` (checking them against the index's CRC32),
+    // along with the offset they start at.
+    fn read_raw(&self, id: &Id) -> Result<(u64, Vec<u8>)> {
+        let (position, (start, end)) = match (self.index.position(id), self.index.get_bounds(id)) {
+            (Some(position), Some(bounds)) => (position, bounds),
+            _ => return Err(ErrorKind::ObjectNotFound.into())
+        };
+
+        let mut raw = Vec::new();
+        self.packfile.read_raw(start, end, &mut raw).at_offset(start)?;
+
+        if let Some(crcs) = self.index.crcs() {
+            let expected = crcs[position];
+            let actual = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&raw);
+            if expected != actual {
+                return Err(Error::from(ErrorKind::Crc32Mismatch { expected, actual }).for_object(id).at_offset(start))
+            }
+        }
+
+        Ok((start, raw))
+    }
 }
 
 impl<P: Packfile> Queryable for Store<P> {
@@ -28,8 +102,81 @@ impl<P: Packfile> Queryable for Store<P> {
             None => return Ok(None)
         };
 
-        let obj_type = self.packfile.read_bounds(start, end, output, backends)?;
+        if backends.verifies() {
+            self.check_crc32(id)?;
+        }
+
+        backends.read_checked(id, output, |mut output| {
+            Ok(Some(self.packfile.read_bounds(start, end, &mut output, backends)?))
+        }).for_object(id).at_offset(start)
+    }
+
+    fn stored_entry(&self, id: &Id) -> Result<Option<StoredEntry>> {
+        if self.index.position(id).is_none() {
+            return Ok(None)
+        }
+
+        let (start, raw) = self.read_raw(id)?;
+        let header = packfile_read_header(&mut raw.as_slice()).for_object(id).at_offset(start)?;
+        let base = match header.base() {
+            None => None,
+            Some(DeltaBase::Ref(base)) => Some(base.clone()),
+            Some(DeltaBase::Offset(distance)) => {
+                let base = start.checked_sub(*distance).and_then(|offset| self.id_at_offset(offset));
+                match base {
+                    Some(base) => Some(base.clone()),
+                    None => return Err(Error::from(ErrorKind::CorruptedPackfile).for_object(id).at_offset(start))
+                }
+            }
+        };
 
-        Ok(Some(obj_type))
+        let data = raw[header.len() as usize..].to_vec();
+        Ok(Some(StoredEntry::new(header.kind(), header.size(), base, data)))
     }
+
+    fn get_batch<S, F>(&self, ids: Vec<Id>, _parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<Vec<Id>>
+        where S: Queryable + Sync,
+              F: Fn(&Id, Type, &[u8]) -> Result<()> + Sync {
+        let mut objects = Vec::with_capacity(ids.len());
+        let mut missing = Vec::new();
+        for id in ids {
+            match self.index.get_bounds(&id) {
+                Some((start, _)) => objects.push((start, id)),
+                None => missing.push(id)
+            }
+        }
+
+        objects.sort_unstable();
+        self.read_batch(&objects, false, each, backends)?;
+        Ok(missing)
+    }
+}
+
+/// Read the objects at each offset in `objects` (sorted by offset) from
+/// `packfile`, passing them to `each`, with a delta base cache shared between
+/// them. Like `Store::get`, contents are checked if `backends` verifies reads;
+/// CRC32s are left to the caller. If other batches are read in `parallel`, the
+/// cache gets a share of the limit rather than all of it.
+pub(crate) fn read_sorted<P, S, F>(packfile: &P, objects: &[(u64, Id)], parallel: bool, each: &F, backends: &StorageSet<S>) -> Result<()>
+    where P: Packfile,
+          S: Queryable,
+          F: Fn(&Id, Type, &[u8]) -> Result<()> {
+    let limit = match parallel {
+        true => DELTA_BASE_CACHE_LIMIT / rayon::current_num_threads().max(1),
+        false => DELTA_BASE_CACHE_LIMIT
+    };
+    let mut cache = DeltaBaseCache::new(limit);
+    let mut data = Vec::new();
+    for (start, id) in objects {
+        data.clear();
+        let kind = backends.read_checked(id, &mut data, |mut output| {
+            Ok(Some(packfile.read_cached(*start, &mut output, backends, &mut cache)?))
+        }).for_object(id).at_offset(*start)?;
+
+        if let Some(kind) = kind {
+            each(id, kind, &data)?;
+        }
+    }
+
+    Ok(())
 }
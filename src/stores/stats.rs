@@ -0,0 +1,396 @@
+use flate2::bufread::ZlibDecoder;
+use memmap::MmapOptions;
+use rayon::prelude::*;
+
+use std::collections::{ BTreeMap, HashMap };
+use std::convert::TryInto;
+use std::io::BufReader;
+use std::path::{ Path, PathBuf };
+
+use crate::errors::{ Error, ErrorKind, Result, ResultExt };
+use crate::pack::read::{ packfile_read_header, DeltaBase };
+use crate::pack::index::read as read_packidx;
+use crate::stores::loose::read_header as read_loose_header;
+use crate::stores::fs::{ objects_dir, pack_dir };
+use crate::objects::Type;
+use crate::id::Id;
+
+// files that may accompany a `.pack` in the pack directory
+pub(crate) const PACK_EXTENSIONS: &[&str] = &["pack", "idx", "rev", "bitmap", "keep", "promisor", "mtimes"];
+
+/// Object counts by type.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TypeCounts {
+    commits: usize,
+    trees: usize,
+    blobs: usize,
+    tags: usize
+}
+
+impl TypeCounts {
+    pub fn get(&self, kind: Type) -> usize {
+        match kind {
+            Type::Commit => self.commits,
+            Type::Tree => self.trees,
+            Type::Blob => self.blobs,
+            Type::Tag => self.tags
+        }
+    }
+
+    fn add(&mut self, kind: Type) {
+        match kind {
+            Type::Commit => self.commits += 1,
+            Type::Tree => self.trees += 1,
+            Type::Blob => self.blobs += 1,
+            Type::Tag => self.tags += 1
+        }
+    }
+
+    fn merge(&mut self, other: &TypeCounts) {
+        self.commits += other.commits;
+        self.trees += other.trees;
+        self.blobs += other.blobs;
+        self.tags += other.tags;
+    }
+}
+
+/// Statistics about a repository's object database, as reported by
+/// `git count-objects -v` (plus a breakdown of object types and delta chains.)
+///
+/// Sizes are in bytes.
+#[derive(Debug, Default)]
+pub struct Stats {
+    loose_count: usize,
+    loose_size: u64,
+    loose_types: TypeCounts,
+    pack_count: usize,
+    packed_count: usize,
+    pack_size: u64,
+    packed_types: TypeCounts,
+    chain_lengths: BTreeMap<usize, usize>,
+    garbage: Vec<PathBuf>,
+    garbage_size: u64
+}
+
+impl Stats {
+    pub fn loose_count(&self) -> usize {
+        self.loose_count
+    }
+
+    pub fn loose_size(&self) -> u64 {
+        self.loose_size
+    }
+
+    pub fn loose_types(&self) -> &TypeCounts {
+        &self.loose_types
+    }
+
+    pub fn pack_count(&self) -> usize {
+        self.pack_count
+    }
+
+    pub fn packed_count(&self) -> usize {
+        self.packed_count
+    }
+
+    /// The size of every `.pack` and `.idx` file.
+    pub fn pack_size(&self) -> u64 {
+        self.pack_size
+    }
+
+    pub fn packed_types(&self) -> &TypeCounts {
+        &self.packed_types
+    }
+
+    /// The number of packed objects at each delta chain length; objects that
+    /// aren't deltas have a chain length of zero.
+    pub fn chain_lengths(&self) -> &BTreeMap<usize, usize> {
+        &self.chain_lengths
+    }
+
+    /// Files in the object database that git wouldn't use: temporary files left
+    /// behind by interrupted writes, packs without an index (and vice versa), loose
+    /// objects that can't be read and anything else that doesn't belong.
+    pub fn garbage(&self) -> &[PathBuf] {
+        self.garbage.as_slice()
+    }
+
+    pub fn garbage_size(&self) -> u64 {
+        self.garbage_size
+    }
+}
+
+/// Gather statistics about the object database of the repository at `path`.
+pub fn count_objects(path: &Path) -> Result<Stats> {
+    let mut stats = Stats::default();
+    count_loose(&objects_dir(path), &mut stats)?;
+    count_packed(&pack_dir(path), &mut stats)?;
+    Ok(stats)
+}
+
+fn count_loose(root: &Path, stats: &mut Stats) -> Result<()> {
+    for entry in std::fs::read_dir(root).in_file(root)? {
+        let entry = entry.in_file(root)?;
+        let name = entry.file_name();
+        let is_fanout = name.len() == 2 && name.to_str().map(is_hex).unwrap_or(false);
+        if !is_fanout || !entry.file_type().in_file(&entry.path())?.is_dir() {
+            continue
+        }
+
+        let dir = entry.path();
+        for object in std::fs::read_dir(&dir).in_file(&dir)? {
+            let object = object.in_file(&dir)?;
+            let path = object.path();
+            let size = object.metadata().in_file(&path)?.len();
+            let name = object.file_name();
+
+            if name.len() != 38 || !name.to_str().map(is_hex).unwrap_or(false) {
+                stats.garbage_size += size;
+                stats.garbage.push(path);
+                continue
+            }
+
+            // an object that can't be read is as useless as a stray file
+            let kind = match loose_type(&path).in_file(&path) {
+                Ok(xs) => xs,
+                Err(e) if e.is_corrupt() => {
+                    stats.garbage_size += size;
+                    stats.garbage.push(path);
+                    continue
+                },
+                Err(e) => return Err(e)
+            };
+
+            stats.loose_count += 1;
+            stats.loose_size += size;
+            stats.loose_types.add(kind);
+        }
+    }
+
+    Ok(())
+}
+
+// Read just enough of a loose object to find its type.
+pub(crate) fn loose_type(path: &Path) -> Result<Type> {
+    let file = std::fs::File::open(path)?;
+    let mut reader = BufReader::new(ZlibDecoder::new(BufReader::new(file)));
+    let (kind, _) = read_loose_header(&mut reader)?;
+    Ok(kind)
+}
+
+fn count_packed(root: &Path, stats: &mut Stats) -> Result<()> {
+    let entries = match std::fs::read_dir(root) {
+        Ok(xs) => xs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::from(e).in_file(root))
+    };
+
+    let mut by_stem: BTreeMap<String, Vec<(String, PathBuf, u64)>> = BTreeMap::new();
+    for entry in entries {
+        let entry = entry.in_file(root)?;
+        let path = entry.path();
+        let size = entry.metadata().in_file(&path)?.len();
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if name == "multi-pack-index" || name.starts_with("multi-pack-index-") {
+            continue
+        }
+
+        let (stem, extension) = match name.rsplit_once('.') {
+            Some((stem, extension)) if stem.starts_with("pack-") && PACK_EXTENSIONS.contains(&extension) => {
+                (stem.to_string(), extension.to_string())
+            },
+            _ => {
+                stats.garbage_size += size;
+                stats.garbage.push(path);
+                continue
+            }
+        };
+
+        by_stem.entry(stem).or_default().push((extension, path, size));
+    }
+
+    let mut packs = Vec::new();
+    for (_, files) in by_stem {
+        let has = |wanted: &str| files.iter().any(|(extension, _, _)| extension == wanted);
+        if !has("pack") || !has("idx") {
+            for (_, path, size) in files {
+                stats.garbage_size += size;
+                stats.garbage.push(path);
+            }
+            continue
+        }
+
+        for (extension, path, size) in files {
+            if extension == "pack" || extension == "idx" {
+                stats.pack_size += size;
+            }
+
+            if extension == "idx" {
+                packs.push(path);
+            }
+        }
+    }
+
+    let counts = packs.par_iter().map(|index_path| {
+        count_pack(index_path).in_file(index_path)
+    }).collect::<Result<Vec<_>>>()?;
+
+    stats.pack_count = packs.len();
+    for (types, chain_lengths) in counts {
+        stats.packed_count += chain_lengths.values().sum::<usize>();
+        stats.packed_types.merge(&types);
+        for (length, count) in chain_lengths {
+            *stats.chain_lengths.entry(length).or_insert(0) += count;
+        }
+    }
+
+    stats.garbage.sort();
+    Ok(())
+}
+
+enum Entry {
+    Plain(Type),
+    Delta(u64)
+}
+
+fn count_pack(index_path: &Path) -> Result<(TypeCounts, BTreeMap<usize, usize>)> {
+    let index = read_packidx(BufReader::new(std::fs::File::open(index_path)?)).during("reading pack index")?;
+
+    let mut pack_path = index_path.to_path_buf();
+    pack_path.set_extension("pack");
+    let file = std::fs::File::open(&pack_path).in_file(&pack_path)?;
+    let mmap = unsafe { MmapOptions::new().map(&file).in_file(&pack_path)? };
+
+    if mmap.len() < 12 || u32::from_be_bytes([mmap[8], mmap[9], mmap[10], mmap[11]]) as usize != index.len() {
+        return Err(Error::from(ErrorKind::CorruptedPackfile).in_file(&pack_path).during("counting objects"))
+    }
+
+    // Only the entry headers are needed: they hold each object's type or delta base.
+    let offsets_by_id: HashMap<&Id, u64> = index.ids().iter().zip(index.offsets().iter().cloned()).collect();
+    let mut entries = HashMap::with_capacity(index.len());
+    for offset in index.offsets().iter().cloned() {
+        let mut input = mmap.get(offset as usize..).ok_or(ErrorKind::CorruptedPackfile).in_file(&pack_path).at_offset(offset)?;
+        let header = packfile_read_header(&mut input).in_file(&pack_path).at_offset(offset)?;
+        let entry = match header.base() {
+            None => Entry::Plain(header.kind().try_into().in_file(&pack_path).at_offset(offset)?),
+            Some(DeltaBase::Offset(distance)) => {
+                Entry::Delta(offset.checked_sub(*distance).ok_or(ErrorKind::CorruptedPackfile).in_file(&pack_path).at_offset(offset)?)
+            },
+            Some(DeltaBase::Ref(id)) => {
+                match offsets_by_id.get(id) {
+                    Some(base) => Entry::Delta(*base),
+                    None => return Err(Error::from(ErrorKind::MissingDeltaBase(id.clone())).in_file(&pack_path).at_offset(offset))
+                }
+            }
+        };
+        entries.insert(offset, entry);
+    }
+
+    // Resolve each chain once, remembering the type and depth of every object
+    // along the way.
+    let mut resolved: HashMap<u64, (Type, usize)> = HashMap::with_capacity(entries.len());
+    let mut types = TypeCounts::default();
+    let mut chain_lengths = BTreeMap::new();
+    for start in entries.keys() {
+        let mut chain = Vec::new();
+        let mut offset = *start;
+        let (kind, depth) = loop {
+            if let Some(xs) = resolved.get(&offset) {
+                break *xs
+            }
+
+            match entries.get(&offset) {
+                Some(Entry::Plain(kind)) => break (*kind, 0),
+                Some(Entry::Delta(base)) => {
+                    chain.push(offset);
+                    if chain.len() > entries.len() {
+                        return Err(Error::from(ErrorKind::CorruptedPackfile).in_file(&pack_path).at_offset(offset))
+                    }
+                    offset = *base;
+                },
+                None => return Err(Error::from(ErrorKind::CorruptedPackfile).in_file(&pack_path).at_offset(offset))
+            }
+        };
+
+        resolved.insert(offset, (kind, depth));
+        for (idx, delta) in chain.iter().rev().enumerate() {
+            resolved.insert(*delta, (kind, depth + idx + 1));
+        }
+
+        let (kind, depth) = resolved[start];
+        types.add(kind);
+        *chain_lengths.entry(depth).or_insert(0) += 1;
+    }
+
+    Ok((types, chain_lengths))
+}
+
+fn is_hex(name: &str) -> bool {
+    name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    use crate::test_support::{ commit_file, git, scratch_repo };
+    use crate::objects::Type;
+
+    #[test]
+    fn counts_loose_and_packed_objects() {
+        let root = scratch_repo("count-objects");
+        for idx in 0..4 {
+            commit_file(&root, "file", &"line\n".repeat(100 + idx), 1_600_000_000 + idx as u64 * 100);
+        }
+        git(&root, &["repack", "-adq"]);
+        commit_file(&root, "other", "new", 1_600_001_000);
+
+        let pack_dir = root.join(".git/objects/pack");
+        std::fs::write(pack_dir.join("tmp_pack_abc"), b"partial").unwrap();
+        std::fs::write(pack_dir.join("pack-0000000000000000000000000000000000000000.pack"), b"orphan").unwrap();
+
+        let stats = super::count_objects(&root).expect("failed to count objects");
+
+        // git agrees on everything but sizes: it counts disk blocks
+        let expected = git(&root, &["count-objects", "-v"]);
+        let field = |name: &str| -> usize {
+            expected.lines().find_map(|line| line.strip_prefix(name)).unwrap().trim().parse().unwrap()
+        };
+
+        assert_eq!(stats.loose_count(), field("count:"));
+        assert_eq!(stats.packed_count(), field("in-pack:"));
+        assert_eq!(stats.pack_count(), field("packs:"));
+        assert_eq!(stats.garbage().len(), field("garbage:"));
+
+        assert_eq!(stats.loose_types().get(Type::Commit), 1);
+        assert_eq!(stats.loose_types().get(Type::Blob), 1);
+        assert_eq!(stats.loose_types().get(Type::Tree), 1);
+        assert_eq!(stats.packed_types().get(Type::Commit), 4);
+        assert_eq!(stats.packed_types().get(Type::Blob), 4);
+        assert_eq!(stats.packed_types().get(Type::Tree), 4);
+
+        // the similar blobs are stored as deltas
+        assert_eq!(stats.chain_lengths().values().sum::<usize>(), 12);
+        assert!(stats.chain_lengths().keys().any(|length| *length > 0));
+
+        // an unreadable loose object doesn't stop the count
+        let corrupt = root.join(".git/objects/ab/cdef0123456789abcdef0123456789abcdef01");
+        std::fs::create_dir_all(corrupt.parent().unwrap()).unwrap();
+        std::fs::write(&corrupt, b"not zlib").unwrap();
+
+        // and one whose header never ends is only read as far as a header could go
+        let bomb = root.join(".git/objects/ab/cdef0123456789abcdef0123456789abcdef02");
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&vec![b'a'; 1 << 24]).unwrap();
+        std::fs::write(&bomb, encoder.finish().unwrap()).unwrap();
+
+        let recounted = super::count_objects(&root).expect("failed to count objects");
+        assert_eq!(recounted.loose_count(), stats.loose_count());
+        assert_eq!(recounted.packed_count(), stats.packed_count());
+        assert!(recounted.garbage().contains(&corrupt));
+        assert!(recounted.garbage().contains(&bomb));
+    }
+}
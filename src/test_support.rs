@@ -0,0 +1,141 @@
+use std::path::{ Path, PathBuf };
+use std::io::Write;
+use std::ops::Deref;
+use std::process::{ Command, Stdio };
+
+use crate::stores::{ fs, StorageSet };
+
+/// A directory for a test to work in, removed when it goes out of scope unless
+/// the test is failing (so that it can be looked at.)
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    /// Open the object storage of the repository in this directory.
+    pub fn storage(&self) -> StorageSet<fs::Store> {
+        fs::from(&self.0).expect("failed to open storage")
+    }
+}
+
+impl Deref for Scratch {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Scratch {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+/// Create an empty directory under the system temp dir for a test to work in.
+pub fn scratch_dir(name: &str) -> Scratch {
+    let mut root = std::env::temp_dir();
+    root.push(format!("git_rs-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).expect("failed to create scratch dir");
+    Scratch(root)
+}
+
+/// Create a new repository (using git) for a test to work in.
+pub fn scratch_repo(name: &str) -> Scratch {
+    let root = scratch_dir(name);
+    git(&root, &["init", "-q"]);
+    root
+}
+
+// Settings whose defaults differ between git versions (or that would have git
+// change the repository behind a test's back), pinned so tests see the same
+// repositories everywhere.
+const CONFIG: &[&str] = &[
+    "init.defaultBranch=main",
+    "init.defaultObjectFormat=sha1",
+    "init.defaultRefFormat=files",
+    "commitGraph.generationVersion=2",
+    "pack.writeReverseIndex=true",
+    "pack.writeBitmapHashCache=true",
+    "pack.writeBitmapLookupTable=false",
+    "index.version=2",
+    "index.skipHash=false",
+    "gc.auto=0",
+    "maintenance.auto=false"
+];
+
+// A git command run in `root`, isolated from the user's and system's config.
+fn command(root: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new("git");
+    for config in CONFIG {
+        command.args(["-c", config]);
+    }
+
+    command.args(args)
+        .current_dir(root)
+        .env("GIT_AUTHOR_NAME", "A")
+        .env("GIT_AUTHOR_EMAIL", "a@example.com")
+        .env("GIT_COMMITTER_NAME", "A")
+        .env("GIT_COMMITTER_EMAIL", "a@example.com")
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("HOME", root);
+    command
+}
+
+/// Run git in `root`, with author and committer dates set to `time` if given.
+pub fn git_at(root: &Path, args: &[&str], time: Option<u64>) -> String {
+    let mut command = command(root, args);
+    if let Some(time) = time {
+        let date = format!("{} +0000", time);
+        command.env("GIT_AUTHOR_DATE", &date).env("GIT_COMMITTER_DATE", &date);
+    }
+
+    let output = command.output().expect("failed to run git");
+    assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+pub fn git(root: &Path, args: &[&str]) -> String {
+    git_at(root, args, None)
+}
+
+/// Run git in `root`, returning whether it succeeded.
+pub fn git_succeeds(root: &Path, args: &[&str]) -> bool {
+    command(root, args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to run git")
+        .success()
+}
+
+/// Run git in `root` with `input` on its stdin, returning its output trimmed.
+pub fn git_with_input(root: &Path, args: &[&str], input: &str) -> String {
+    let mut child = command(root, args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run git");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+
+    let output = child.wait_with_output().expect("failed to run git");
+    assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/// Write `contents` to `path` (relative to `root`) and commit everything at `time`.
+pub fn commit_file(root: &Path, path: &str, contents: &str, time: u64) {
+    let target = root.join(path);
+    std::fs::create_dir_all(target.parent().unwrap()).unwrap();
+    std::fs::write(target, contents).unwrap();
+    git(root, &["add", "-A"]);
+    git_at(root, &["commit", "-q", "--allow-empty", "-m", path], Some(time));
+}
@@ -1,61 +1,149 @@
 use std::collections::{ HashSet, BinaryHeap };
+use once_cell::sync::OnceCell;
 
 use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
 use crate::objects::commit::Commit;
 use crate::objects::Object;
 use crate::id::Id;
 
-#[derive(Debug)]
-pub struct IdCommit(Id, Commit);
-
-impl std::cmp::Ord for IdCommit {
-    fn cmp(&self, next: &IdCommit) -> std::cmp::Ordering {
-        let lhs = self.1.committer();
-        let rhs = next.1.committer();
-
-        match (lhs, rhs) {
-            (Some(lhs), Some(rhs)) => {
-                match (lhs.timestamp(), rhs.timestamp()) {
-                    (Some(lhs), Some(rhs)) => lhs.cmp(rhs),
-                    _ => std::cmp::Ordering::Equal
-                }
-            },
+/// A commit produced by `CommitIterator`.
+///
+/// When the commit is found in the storage set's commit-graph, its parents, root
+/// tree, commit time and generation number come from the graph and the commit
+/// object itself is only read from storage if `load` is called -- for example, to
+/// get at its message or its author and committer.
+pub struct LazyCommit<'a, S: Queryable> {
+    storage_set: &'a StorageSet<S>,
+    id: Id,
+    parents: Vec<Id>,
+    tree: Option<Id>,
+    commit_time: Option<i64>,
+    generation: Option<u64>,
+    commit: OnceCell<Commit>
+}
+
+impl<'a, S: Queryable> LazyCommit<'a, S> {
+    fn resolve(storage_set: &'a StorageSet<S>, id: &Id) -> Option<Self> {
+        if let Some(graph_commit) = storage_set.commit_graph().and_then(|graph| graph.get(id)) {
+            return Some(LazyCommit {
+                storage_set,
+                id: id.clone(),
+                generation: Some(graph_commit.generation()),
+                commit_time: Some(graph_commit.commit_time() as i64),
+                tree: Some(graph_commit.tree().clone()),
+                parents: graph_commit.parents().to_vec(),
+                commit: OnceCell::new()
+            })
+        }
+
+        if let Object::Commit(commit) = storage_set.get_and_load(id).ok()?? {
+            let commit_time = commit.committer().as_ref()
+                .and_then(|committer| committer.timestamp())
+                .map(|timestamp| timestamp.timestamp());
+
+            Some(LazyCommit {
+                storage_set,
+                id: id.clone(),
+                generation: None,
+                commit_time,
+                tree: commit.tree().cloned(),
+                parents: commit.parents().to_vec(),
+                commit: OnceCell::with_value(commit)
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    pub fn parents(&self) -> &[Id] {
+        self.parents.as_slice()
+    }
+
+    pub fn tree(&self) -> Option<&Id> {
+        self.tree.as_ref()
+    }
+
+    /// The committer time, in seconds since the UNIX epoch.
+    pub fn commit_time(&self) -> Option<i64> {
+        self.commit_time
+    }
+
+    /// The commit's generation number, if it is known from a commit-graph.
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    /// Read the full commit from storage (if it hasn't been read already.)
+    pub fn load(&self) -> Result<&Commit> {
+        self.commit.get_or_try_init(|| {
+            match self.storage_set.get_and_load(&self.id)? {
+                Some(Object::Commit(commit)) => Ok(commit),
+                Some(_) => Err(ErrorKind::InvalidObjectType.into()),
+                None => Err(ErrorKind::ObjectNotFound.into())
+            }
+        })
+    }
+
+    pub fn into_commit(self) -> Result<Commit> {
+        self.load()?;
+        Ok(self.commit.into_inner().unwrap())
+    }
+}
+
+impl<'a, S: Queryable> std::fmt::Debug for LazyCommit<'a, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("LazyCommit")
+            .field("id", &self.id)
+            .field("parents", &self.parents)
+            .field("tree", &self.tree)
+            .field("commit_time", &self.commit_time)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl<'a, S: Queryable> std::cmp::Ord for LazyCommit<'a, S> {
+    fn cmp(&self, next: &LazyCommit<'a, S>) -> std::cmp::Ordering {
+        match (self.commit_time, next.commit_time) {
+            (Some(lhs), Some(rhs)) => lhs.cmp(&rhs),
             _ => std::cmp::Ordering::Equal
         }
     }
 }
 
-impl std::cmp::PartialOrd for IdCommit {
-    fn partial_cmp(&self, other: &IdCommit) -> Option<std::cmp::Ordering> {
+impl<'a, S: Queryable> std::cmp::PartialOrd for LazyCommit<'a, S> {
+    fn partial_cmp(&self, other: &LazyCommit<'a, S>) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl std::cmp::PartialEq for IdCommit {
-    fn eq(&self, other: &IdCommit) -> bool {
+impl<'a, S: Queryable> std::cmp::PartialEq for LazyCommit<'a, S> {
+    fn eq(&self, other: &LazyCommit<'a, S>) -> bool {
         self.cmp(other) == std::cmp::Ordering::Equal
     }
 }
 
-impl std::cmp::Eq for IdCommit { }
+impl<'a, S: Queryable> std::cmp::Eq for LazyCommit<'a, S> { }
 
 pub struct CommitIterator<'a, S: Queryable> {
     storage_set: &'a StorageSet<S>,
     seen: HashSet<Id>,
-    target: BinaryHeap<IdCommit>
+    target: BinaryHeap<LazyCommit<'a, S>>
 }
 
 impl<'a, S: Queryable> CommitIterator<'a, S> {
     pub fn new(storage_set: &'a StorageSet<S>, id: &Id, seen: Option<HashSet<Id>>) -> CommitIterator<'a, S> {
         let mut seen = seen.unwrap_or_default();
 
-        let first = storage_set.get_and_load(id).ok()
-            .unwrap_or(None);
-
         let mut target = BinaryHeap::with_capacity(4);
 
-        if let Some(Object::Commit(head)) = first {
-            target.push(IdCommit(id.clone(), head));
+        if let Some(head) = LazyCommit::resolve(storage_set, id) {
+            target.push(head);
         }
 
         seen.insert(id.clone());
@@ -68,7 +156,7 @@ impl<'a, S: Queryable> CommitIterator<'a, S> {
 }
 
 impl<'a, S: Queryable> Iterator for CommitIterator<'a, S> {
-    type Item = (Id, Commit);
+    type Item = (Id, LazyCommit<'a, S>);
 
     fn next(&mut self) -> Option<Self::Item> {
         // okay, so given a set of commits:
@@ -82,24 +170,55 @@ impl<'a, S: Queryable> Iterator for CommitIterator<'a, S> {
         let newest = self.target.pop()?;
 
         let seen = &mut self.seen;
-        let storage_set = &self.storage_set;
-        let parents = newest.1.parents().iter().filter_map(|id| {
+        let storage_set = self.storage_set;
+        let parents = newest.parents().iter().filter_map(|id| {
             if seen.contains(id) {
                 return None
             }
 
-            if let Object::Commit(commit) = storage_set.get_and_load(id).ok()?? {
-                seen.insert(id.clone());
-                Some(IdCommit(id.clone(), commit))
-            } else {
-                None
-            }
+            let parent = LazyCommit::resolve(storage_set, id)?;
+            seen.insert(id.clone());
+            Some(parent)
         });
 
         for parent in parents {
             self.target.push(parent);
         }
 
-        Some((newest.0, newest.1))
+        Some((newest.id.clone(), newest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::commit_graph::CommitGraph;
+    use crate::stores::StorageSet;
+    use crate::id::Id;
+
+    #[test]
+    fn walks_using_only_the_commit_graph() {
+        let graph = CommitGraph::read(include_bytes!("../../fixtures/commit_graph")).expect("failed to parse");
+
+        // there are no objects in this storage set: everything comes from the graph
+        let storage_set = StorageSet::new(()).with_commit_graph(graph);
+        let tip = Id::from_str("9d49f6b9da0ce02edceb49f7df7e547a9c968fb8").unwrap();
+
+        let walked: Vec<_> = storage_set.commits(&tip, None).map(|(id, commit)| {
+            (id.to_string(), commit.commit_time().unwrap())
+        }).collect();
+
+        assert_eq!(walked, vec![
+            ("9d49f6b9da0ce02edceb49f7df7e547a9c968fb8".to_string(), 1_600_000_400),
+            ("6c831b72c3c1281de911b411987b877c91b258d4".to_string(), 1_600_000_300),
+            ("67c5c34d22cdc50597aad21ab749b4440212c5a3".to_string(), 1_600_000_200),
+            ("16f1d83d4a25b64c71c1ca1c0785ba2f7980473d".to_string(), 1_600_000_100),
+            ("787524db9b0dc673241741a8cd5c03deddf6f6aa".to_string(), 1_600_000_000),
+        ]);
+
+        let (_, head) = storage_set.commits(&tip, None).next().unwrap();
+        assert_eq!(head.generation(), Some(1_600_000_400));
+        assert!(head.load().is_err());
     }
 }
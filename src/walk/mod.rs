@@ -1,2 +1,3 @@
 pub mod commits;
 pub mod tree;
+pub mod reachable;
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::errors::{ ErrorKind, Result, ResultExt };
+use crate::stores::{ Queryable, StorageSet };
+use crate::pack::bitmap::{ Bitmap, BitmapIndex };
+use crate::pack::index::Index;
+use crate::objects::{ Object, Type };
+use crate::objects::tag::Tag;
+use crate::id::Id;
+
+/// The set of objects reachable from some tips, as found by `reachable`.
+///
+/// Objects in the bitmapped pack are kept as a bitmap; everything else (loose
+/// objects, objects in other packs) is kept alongside it by id.
+pub struct Reachable<'a> {
+    bitmap: Option<(&'a Index, &'a BitmapIndex)>,
+    bits: Bitmap,
+    extended: HashMap<Id, Type>
+}
+
+impl<'a> Reachable<'a> {
+    pub fn len(&self) -> usize {
+        self.bits.count_ones() + self.extended.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Count the reachable objects of type `kind`.
+    pub fn count(&self, kind: Type) -> usize {
+        let packed = match self.bitmap {
+            Some((_, bitmap)) => self.bits.count_ones_and(bitmap.type_bitmap(kind)),
+            None => 0
+        };
+
+        packed + self.extended.values().filter(|xs| **xs == kind).count()
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        match self.pack_position(id) {
+            Some(position) => self.bits.get(position),
+            None => self.extended.contains_key(id)
+        }
+    }
+
+    /// Iterate over the ids of every reachable object: first those in the
+    /// bitmapped pack (in packfile order), then the rest.
+    pub fn ids(&self) -> impl Iterator<Item = &Id> {
+        let packed = self.bitmap.into_iter().flat_map(move |(index, bitmap)| {
+            self.bits.ones().filter_map(move |position| {
+                index.ids().get(bitmap.index_position(position)?)
+            })
+        });
+
+        packed.chain(self.extended.keys())
+    }
+
+    fn pack_position(&self, id: &Id) -> Option<usize> {
+        let (index, bitmap) = self.bitmap?;
+        bitmap.pack_position(index.position(id)?)
+    }
+
+    fn insert(&mut self, id: Id, kind: Type) {
+        match self.pack_position(&id) {
+            Some(position) => self.bits.set(position),
+            None => {
+                self.extended.insert(id, kind);
+            }
+        }
+    }
+}
+
+/// The id of the object a tag points at.
+pub(crate) fn tag_target(tag: &Tag) -> Option<Id> {
+    tag.as_ref().get(b"object".as_ref())
+        .and_then(|values| values.first())
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(|value| Id::from_str(value.trim()).ok())
+}
+
+/// Find every object reachable from `tips`.
+///
+/// If `bitmap` is given, commits that have a bitmap contribute all of their
+/// reachable objects at once, and the graph is only walked from tips (and
+/// their ancestors) that aren't covered by one. Without a bitmap this is a full
+/// walk of the commits, trees and tags reachable from `tips`.
+pub fn reachable<'a, S: Queryable>(
+    storage_set: &StorageSet<S>,
+    bitmap: Option<(&'a Index, &'a BitmapIndex)>,
+    tips: &[Id]
+) -> Result<Reachable<'a>> {
+    let mut reachable = Reachable {
+        bitmap,
+        bits: Bitmap::new(),
+        extended: HashMap::new()
+    };
+
+    // blobs are found through trees, which tell us their type; we never need to
+    // read them.
+    let mut pending: Vec<(Id, Option<Type>)> = tips.iter().map(|id| (id.clone(), None)).collect();
+    while let Some((id, kind)) = pending.pop() {
+        if reachable.contains(&id) {
+            continue
+        }
+
+        if let Some((_, bitmap)) = bitmap {
+            if let Some(bits) = bitmap.get(&id)? {
+                reachable.bits.or(&bits);
+                continue
+            }
+        }
+
+        if let Some(Type::Blob) = kind {
+            reachable.insert(id, Type::Blob);
+            continue
+        }
+
+        match storage_set.get_and_load(&id)? {
+            Some(Object::Commit(commit)) => {
+                pending.extend(commit.parents().iter().map(|parent| (parent.clone(), Some(Type::Commit))));
+                pending.extend(commit.tree().map(|tree| (tree.clone(), Some(Type::Tree))));
+                reachable.insert(id, Type::Commit);
+            },
+
+            Some(Object::Tree(tree)) => {
+                for entry in tree.entries().values() {
+                    if entry.mode.is_submodule() {
+                        continue
+                    }
+
+                    let kind = if entry.mode.is_tree() { Type::Tree } else { Type::Blob };
+                    pending.push((entry.id.clone(), Some(kind)));
+                }
+                reachable.insert(id, Type::Tree);
+            },
+
+            Some(Object::Tag(tag)) => {
+                let target = tag_target(&tag).ok_or(ErrorKind::InvalidTag).for_object(&id)?;
+
+                pending.push((target, None));
+                reachable.insert(id, Type::Tag);
+            },
+
+            Some(Object::Blob(_)) => reachable.insert(id, Type::Blob),
+            None => return Err(ErrorKind::ObjectNotFound.into())
+        }
+    }
+
+    Ok(reachable)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::test_support::{ commit_file, git, git_with_input, scratch_repo };
+    use crate::errors::ErrorKind;
+    use crate::objects::Type;
+    use crate::id::Id;
+
+    #[test]
+    fn bitmaps_and_walks_agree_with_git() {
+        let root = scratch_repo("reachable");
+        for (idx, path) in ["a", "b/c", "b/d", "e/f/g"].iter().enumerate() {
+            commit_file(&root, path, &idx.to_string(), 1_600_000_000 + idx as u64 * 100);
+        }
+        git(&root, &["tag", "-a", "-m", "tag", "v1", "HEAD~1"]);
+        git(&root, &["repack", "-adbq"]);
+
+        // these are loose, so they aren't in the bitmap
+        commit_file(&root, "b/c", "changed", 1_600_001_000);
+        commit_file(&root, "h", "new", 1_600_001_100);
+
+        let tips: Vec<Id> = ["HEAD", "v1"].iter().map(|name| {
+            Id::from_str(git(&root, &["rev-parse", name]).trim()).unwrap()
+        }).collect();
+
+        let expected: Vec<String> = git(&root, &["rev-list", "--objects", "HEAD", "v1"]).lines().map(|line| {
+            line[..40].to_string()
+        }).collect();
+        let commit_count = git(&root, &["rev-list", "--count", "HEAD"]);
+
+        let storage_set = root.storage();
+        let pack = storage_set.backend().packs().bitmapped_pack().expect("the pack has a bitmap");
+
+        let with_bitmap = super::reachable(&storage_set, Some((pack.index(), pack.bitmap())), &tips)
+            .expect("failed to find reachable objects");
+        let without_bitmap = super::reachable(&storage_set, None, &tips)
+            .expect("failed to find reachable objects");
+
+        for reachable in [with_bitmap, without_bitmap].iter() {
+            let mut ids: Vec<String> = reachable.ids().map(|id| id.to_string()).collect();
+            ids.sort();
+            let mut expected = expected.clone();
+            expected.sort();
+
+            assert_eq!(ids, expected);
+            assert_eq!(reachable.len(), expected.len());
+            assert_eq!(reachable.count(Type::Commit).to_string(), commit_count.trim());
+            assert_eq!(reachable.count(Type::Tag), 1);
+            assert!(reachable.contains(&tips[0]));
+        }
+    }
+
+    #[test]
+    fn tags_without_a_target_are_errors() {
+        let root = scratch_repo("reachable-bad-tag");
+        let tag = git_with_input(&root, &["hash-object", "-t", "tag", "--literally", "-w", "--stdin"], "object nope\ntype commit\ntag v1\n\nbad\n");
+        let tag = Id::from_str(&tag).unwrap();
+
+        let error = match super::reachable(&root.storage(), None, std::slice::from_ref(&tag)) {
+            Ok(_) => panic!("the tag has no target"),
+            Err(error) => error
+        };
+        assert_eq!(error.kind(), &ErrorKind::InvalidTag);
+        assert_eq!(error.id(), Some(&tag));
+    }
+}
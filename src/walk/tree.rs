@@ -15,7 +15,7 @@ pub struct TreeIterator<'a, S: Queryable> {
 }
 
 impl<'a, S: Queryable> TreeIterator<'a, S> {
-    pub fn new(storage_set: &'a StorageSet<S>, layers: Vec<IntoIter<Vec<u8>, TreeEntry>>) -> TreeIterator<S> {
+    pub fn new(storage_set: &'a StorageSet<S>, layers: Vec<IntoIter<Vec<u8>, TreeEntry>>) -> TreeIterator<'a, S> {
         TreeIterator {
             path_segments: PathBuf::from("."),
             storage_set,